@@ -1,6 +1,43 @@
-use std::net::SocketAddr;
+use crate::{
+    connection::{
+        connection_types::{
+            ConnectionLimits, DEFAULT_MAX_INBOUND, DEFAULT_MAX_OUTBOUND, DEFAULT_MAX_PER_NETGROUP,
+            DEFAULT_RESERVED_SLOTS, DEFAULT_TARGET_OUTBOUND,
+        },
+        liveness::{
+            Timeouts, DEFAULT_HANDSHAKE_TIMEOUT, DEFAULT_IDLE_TIMEOUT, DEFAULT_KEEPALIVE_INTERVAL,
+        },
+        peer_score::{ScoreConfig, ScoreWeights, DEFAULT_BAN_DURATION, DEFAULT_BAN_THRESHOLD},
+        reconnect::{
+            Backoff, DEFAULT_MAX_RECONNECT_ATTEMPTS, DEFAULT_MAX_RECONNECT_DELAY,
+            DEFAULT_RECONNECT_DELAY,
+        },
+        relay::{
+            RelayLimits, DEFAULT_CIRCUIT_BANDWIDTH, DEFAULT_CIRCUIT_DURATION, DEFAULT_MAX_CIRCUITS,
+        },
+    },
+    gossip::{
+        erasure::{ErasureConfig, DEFAULT_DATA_CHUNKS, DEFAULT_PARITY_CHUNKS},
+        GossipConfig, DEFAULT_BROADCAST_RETENTION, DEFAULT_FANOUT, DEFAULT_REQUEST_TIMEOUT,
+    },
+    mempool::{MempoolConfig, DEFAULT_MEMPOOL_EXPIRY, DEFAULT_MEMPOOL_SIZE},
+    messaging::{
+        agent::DEFAULT_AGENT_TTL,
+        delivery::{
+            DEFAULT_MAX_RETRANSMITS, DEFAULT_MAX_RETRANSMIT_DELAY, DEFAULT_RETRANSMIT_DELAY,
+        },
+    },
+};
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 use structopt::StructOpt;
 
+const DEFAULT_ROUTE_TTL_SECS: u64 = 180;
+const DEFAULT_ROUTE_HOLD_DOWN_SECS: u64 = 120;
+
 /// Configuration of a p2p node
 #[derive(StructOpt, Debug, Default, Clone)]
 #[structopt(rename_all = "kebab-case")]
@@ -18,6 +55,120 @@ pub struct Config {
     /// Bootstrap nodes
     #[structopt(short, long, default_value = "[]", parse(try_from_str = serde_json::from_str))]
     bootstrap_nodes: Vec<SocketAddr>,
+    /// Seconds after which a route that has not been refreshed expires
+    #[structopt(long)]
+    route_ttl: Option<u64>,
+    /// Seconds during which advertisements for an expired route are ignored
+    #[structopt(long)]
+    route_hold_down: Option<u64>,
+    /// File in which known peers are kept across restarts
+    #[structopt(long, parse(from_os_str))]
+    address_book: Option<PathBuf>,
+    /// Maximum number of connections initiated by peers
+    #[structopt(long)]
+    max_inbound: Option<usize>,
+    /// Maximum number of connections initiated by this node
+    #[structopt(long)]
+    max_outbound: Option<usize>,
+    /// Number of outbound connections to establish when bootstrapping
+    #[structopt(long)]
+    target_outbound: Option<usize>,
+    /// Number of slots, in each direction, only allow-listed peers may use
+    #[structopt(long)]
+    reserved_slots: Option<usize>,
+    /// Maximum number of outbound connections to a single /16 (IPv4) or /32 (IPv6)
+    #[structopt(long)]
+    max_per_netgroup: Option<usize>,
+    /// Peers allowed to use the reserved slots
+    #[structopt(long, default_value = "[]", parse(try_from_str = serde_json::from_str))]
+    allow_list: Vec<SocketAddr>,
+    /// Weight of each kind of misbehaviour, as JSON
+    #[structopt(long, parse(try_from_str = serde_json::from_str))]
+    score_weights: Option<ScoreWeights>,
+    /// Score at or under which a peer is banned
+    #[structopt(long, allow_hyphen_values = true)]
+    ban_threshold: Option<i64>,
+    /// Seconds during which a banned peer is refused
+    #[structopt(long)]
+    ban_duration: Option<u64>,
+    /// File in which banned peers are kept across restarts
+    #[structopt(long, parse(from_os_str))]
+    ban_list: Option<PathBuf>,
+    /// File in which unsent messages are kept across restarts
+    #[structopt(long, parse(from_os_str))]
+    outbox: Option<PathBuf>,
+    /// Seconds without hearing from a peer after which it is pinged
+    #[structopt(long)]
+    keepalive_interval: Option<u64>,
+    /// Seconds without hearing from a peer after which it is dropped
+    #[structopt(long)]
+    idle_timeout: Option<u64>,
+    /// Seconds given to a peer to identify itself, or to close a connection
+    #[structopt(long)]
+    handshake_timeout: Option<u64>,
+    /// Seconds before the first attempt to reconnect to a lost peer
+    #[structopt(long)]
+    reconnect_delay: Option<u64>,
+    /// Maximum number of seconds between two reconnection attempts
+    #[structopt(long)]
+    max_reconnect_delay: Option<u64>,
+    /// Number of attempts to reconnect to a lost peer before giving up
+    #[structopt(long)]
+    max_reconnect_attempts: Option<u32>,
+    /// Relay traffic between peers which cannot reach each other directly
+    #[structopt(long)]
+    relay: bool,
+    /// Maximum number of circuits relayed at once
+    #[structopt(long)]
+    relay_max_circuits: Option<usize>,
+    /// Number of bytes per second a single relayed circuit may carry
+    #[structopt(long)]
+    relay_bandwidth: Option<u64>,
+    /// Seconds after which a relayed circuit is closed
+    #[structopt(long)]
+    relay_max_duration: Option<u64>,
+    /// Ask recipients for signed delivery receipts, and retransmit messages until one arrives
+    #[structopt(long)]
+    delivery_receipts: bool,
+    /// Seconds before a message without delivery receipt is sent again
+    #[structopt(long)]
+    retransmit_delay: Option<u64>,
+    /// Maximum number of seconds between two retransmissions of a message
+    #[structopt(long)]
+    max_retransmit_delay: Option<u64>,
+    /// Number of retransmissions of a message before reporting it as unsent
+    #[structopt(long)]
+    max_retransmits: Option<u32>,
+    /// Number of hops an agent makes before its undelivered messages expire
+    #[structopt(long)]
+    agent_ttl: Option<u32>,
+    /// Number of peers each broadcast is relayed to
+    #[structopt(long)]
+    gossip_fanout: Option<usize>,
+    /// Payload size in bytes above which broadcasts are announced, and sent only on request
+    #[structopt(long)]
+    lazy_push_threshold: Option<usize>,
+    /// Seconds during which broadcasts are kept to answer requests for them
+    #[structopt(long)]
+    broadcast_retention: Option<u64>,
+    /// Seconds after which a requested broadcast is requested from another peer
+    #[structopt(long)]
+    broadcast_request_timeout: Option<u64>,
+    /// Disseminate broadcasts along epidemic broadcast trees rather than by push gossip
+    #[structopt(long)]
+    plumtree: bool,
+    /// Number of chunks a payload is split into, any as many of which rebuild it
+    #[structopt(long)]
+    data_chunks: Option<usize>,
+    /// Number of extra chunks a payload is coded into, so that as many can be lost
+    #[structopt(long)]
+    parity_chunks: Option<usize>,
+    /// Total size in bytes of the unconfirmed transactions held
+    #[structopt(long)]
+    mempool_size: Option<usize>,
+    /// Seconds after which an unconfirmed transaction is dropped
+    #[structopt(long)]
+    mempool_expiry: Option<u64>,
 }
 
 impl Config {
@@ -53,4 +204,258 @@ impl Config {
     pub fn should_deploy(&self) -> bool {
         self.deploy_agent
     }
+
+    /// Retrieves the time-to-live of a route
+    pub fn route_ttl(&self) -> Duration {
+        Duration::from_secs(self.route_ttl.unwrap_or(DEFAULT_ROUTE_TTL_SECS))
+    }
+
+    /// Set the time-to-live of a route
+    pub fn set_route_ttl(&mut self, ttl: Duration) {
+        self.route_ttl = Some(ttl.as_secs());
+    }
+
+    /// Retrieves the hold-down period of an expired route
+    pub fn route_hold_down(&self) -> Duration {
+        Duration::from_secs(self.route_hold_down.unwrap_or(DEFAULT_ROUTE_HOLD_DOWN_SECS))
+    }
+
+    /// Set the hold-down period of an expired route
+    pub fn set_route_hold_down(&mut self, hold_down: Duration) {
+        self.route_hold_down = Some(hold_down.as_secs());
+    }
+
+    /// Retrieves the path of the address book file, if persistence is enabled
+    pub fn address_book(&self) -> Option<&Path> {
+        self.address_book.as_deref()
+    }
+
+    /// Set the path of the address book file
+    pub fn set_address_book(&mut self, path: PathBuf) {
+        self.address_book = Some(path);
+    }
+
+    /// Retrieves the connection limits of the node
+    pub fn connection_limits(&self) -> ConnectionLimits {
+        ConnectionLimits {
+            max_inbound: self.max_inbound.unwrap_or(DEFAULT_MAX_INBOUND),
+            max_outbound: self.max_outbound.unwrap_or(DEFAULT_MAX_OUTBOUND),
+            target_outbound: self.target_outbound.unwrap_or(DEFAULT_TARGET_OUTBOUND),
+            reserved_slots: self.reserved_slots.unwrap_or(DEFAULT_RESERVED_SLOTS),
+            max_per_netgroup: self.max_per_netgroup.unwrap_or(DEFAULT_MAX_PER_NETGROUP),
+            allow_list: self.allow_list.iter().copied().collect(),
+        }
+    }
+
+    /// Set the maximum numbers of inbound and outbound connections
+    pub fn set_max_connections(&mut self, max_inbound: usize, max_outbound: usize) {
+        self.max_inbound = Some(max_inbound);
+        self.max_outbound = Some(max_outbound);
+    }
+
+    /// Set the number of outbound connections to establish when bootstrapping
+    pub fn set_target_outbound(&mut self, target_outbound: usize) {
+        self.target_outbound = Some(target_outbound);
+    }
+
+    /// Set the maximum number of outbound connections to a single network group
+    pub fn set_max_per_netgroup(&mut self, max_per_netgroup: usize) {
+        self.max_per_netgroup = Some(max_per_netgroup);
+    }
+
+    /// Reserve slots for allow-listed peers
+    pub fn set_reserved_slots<P>(&mut self, reserved_slots: usize, allow_list: P)
+    where
+        P: IntoIterator<Item = SocketAddr>,
+    {
+        self.reserved_slots = Some(reserved_slots);
+        self.allow_list = allow_list.into_iter().collect();
+    }
+
+    /// Retrieves how peers are scored and banned
+    pub fn score_config(&self) -> ScoreConfig {
+        ScoreConfig {
+            weights: self.score_weights.unwrap_or_default(),
+            ban_threshold: self.ban_threshold.unwrap_or(DEFAULT_BAN_THRESHOLD),
+            ban_duration: self
+                .ban_duration
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_BAN_DURATION),
+        }
+    }
+
+    /// Set how peers are scored and banned
+    pub fn set_score_config(&mut self, score_config: ScoreConfig) {
+        self.score_weights = Some(score_config.weights);
+        self.ban_threshold = Some(score_config.ban_threshold);
+        self.ban_duration = Some(score_config.ban_duration.as_secs());
+    }
+
+    /// Retrieves the path of the ban list file, if persistence is enabled
+    pub fn ban_list(&self) -> Option<&Path> {
+        self.ban_list.as_deref()
+    }
+
+    /// Set the path of the ban list file
+    pub fn set_ban_list(&mut self, path: PathBuf) {
+        self.ban_list = Some(path);
+    }
+
+    /// Retrieves the path of the file of unsent messages, if persistence is enabled
+    pub fn outbox(&self) -> Option<&Path> {
+        self.outbox.as_deref()
+    }
+
+    /// Set the path of the file of unsent messages
+    pub fn set_outbox(&mut self, path: PathBuf) {
+        self.outbox = Some(path);
+    }
+
+    /// Retrieves the timeouts governing the lifecycle of connections
+    pub fn timeouts(&self) -> Timeouts {
+        let secs_or = |secs: Option<u64>, default| secs.map(Duration::from_secs).unwrap_or(default);
+        Timeouts {
+            keepalive_interval: secs_or(self.keepalive_interval, DEFAULT_KEEPALIVE_INTERVAL),
+            idle_timeout: secs_or(self.idle_timeout, DEFAULT_IDLE_TIMEOUT),
+            handshake_timeout: secs_or(self.handshake_timeout, DEFAULT_HANDSHAKE_TIMEOUT),
+        }
+    }
+
+    /// Set the timeouts governing the lifecycle of connections
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.keepalive_interval = Some(timeouts.keepalive_interval.as_secs());
+        self.idle_timeout = Some(timeouts.idle_timeout.as_secs());
+        self.handshake_timeout = Some(timeouts.handshake_timeout.as_secs());
+    }
+
+    /// Retrieves the backoff between attempts to reconnect to a lost peer
+    pub fn backoff(&self) -> Backoff {
+        let secs_or = |secs: Option<u64>, default| secs.map(Duration::from_secs).unwrap_or(default);
+        Backoff {
+            base_delay: secs_or(self.reconnect_delay, DEFAULT_RECONNECT_DELAY),
+            max_delay: secs_or(self.max_reconnect_delay, DEFAULT_MAX_RECONNECT_DELAY),
+            max_attempts: self
+                .max_reconnect_attempts
+                .unwrap_or(DEFAULT_MAX_RECONNECT_ATTEMPTS),
+        }
+    }
+
+    /// Set the backoff between attempts to reconnect to a lost peer
+    pub fn set_backoff(&mut self, backoff: Backoff) {
+        self.reconnect_delay = Some(backoff.base_delay.as_secs());
+        self.max_reconnect_delay = Some(backoff.max_delay.as_secs());
+        self.max_reconnect_attempts = Some(backoff.max_attempts);
+    }
+
+    /// Retrieves the limits of the relay service, if this node relays traffic
+    pub fn relay_limits(&self) -> Option<RelayLimits> {
+        if !self.relay {
+            return None;
+        }
+        Some(RelayLimits {
+            max_circuits: self.relay_max_circuits.unwrap_or(DEFAULT_MAX_CIRCUITS),
+            bandwidth: self.relay_bandwidth.unwrap_or(DEFAULT_CIRCUIT_BANDWIDTH),
+            max_duration: self
+                .relay_max_duration
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_CIRCUIT_DURATION),
+        })
+    }
+
+    /// Enable the relay service with the given limits, or disable it with `None`
+    pub fn set_relay_limits(&mut self, limits: Option<RelayLimits>) {
+        self.relay = limits.is_some();
+        if let Some(limits) = limits {
+            self.relay_max_circuits = Some(limits.max_circuits);
+            self.relay_bandwidth = Some(limits.bandwidth);
+            self.relay_max_duration = Some(limits.max_duration.as_secs());
+        }
+    }
+
+    /// Retrieves the backoff between retransmissions of a message,
+    /// if delivery receipts are enabled
+    pub fn retransmission(&self) -> Option<Backoff> {
+        if !self.delivery_receipts {
+            return None;
+        }
+        let secs_or = |secs: Option<u64>, default| secs.map(Duration::from_secs).unwrap_or(default);
+        Some(Backoff {
+            base_delay: secs_or(self.retransmit_delay, DEFAULT_RETRANSMIT_DELAY),
+            max_delay: secs_or(self.max_retransmit_delay, DEFAULT_MAX_RETRANSMIT_DELAY),
+            max_attempts: self.max_retransmits.unwrap_or(DEFAULT_MAX_RETRANSMITS),
+        })
+    }
+
+    /// Enable delivery receipts with the given backoff between retransmissions,
+    /// or disable them with `None`
+    pub fn set_retransmission(&mut self, backoff: Option<Backoff>) {
+        self.delivery_receipts = backoff.is_some();
+        if let Some(backoff) = backoff {
+            self.retransmit_delay = Some(backoff.base_delay.as_secs());
+            self.max_retransmit_delay = Some(backoff.max_delay.as_secs());
+            self.max_retransmits = Some(backoff.max_attempts);
+        }
+    }
+
+    /// Retrieves the number of hops an agent makes before its undelivered messages expire
+    pub fn agent_ttl(&self) -> u32 {
+        self.agent_ttl.unwrap_or(DEFAULT_AGENT_TTL)
+    }
+
+    /// Set the number of hops an agent makes before its undelivered messages expire
+    pub fn set_agent_ttl(&mut self, ttl: u32) {
+        self.agent_ttl = Some(ttl);
+    }
+
+    /// Retrieves how broadcasts are disseminated
+    pub fn gossip(&self) -> GossipConfig {
+        let secs_or = |secs: Option<u64>, default| secs.map(Duration::from_secs).unwrap_or(default);
+        GossipConfig {
+            fanout: self.gossip_fanout.unwrap_or(DEFAULT_FANOUT),
+            lazy_threshold: self.lazy_push_threshold,
+            retention: secs_or(self.broadcast_retention, DEFAULT_BROADCAST_RETENTION),
+            request_timeout: secs_or(self.broadcast_request_timeout, DEFAULT_REQUEST_TIMEOUT),
+            plumtree: self.plumtree,
+        }
+    }
+
+    /// Set how broadcasts are disseminated
+    pub fn set_gossip(&mut self, gossip: GossipConfig) {
+        self.gossip_fanout = Some(gossip.fanout);
+        self.lazy_push_threshold = gossip.lazy_threshold;
+        self.broadcast_retention = Some(gossip.retention.as_secs());
+        self.broadcast_request_timeout = Some(gossip.request_timeout.as_secs());
+        self.plumtree = gossip.plumtree;
+    }
+
+    /// Retrieves how payloads are split into chunks
+    pub fn erasure(&self) -> ErasureConfig {
+        ErasureConfig {
+            data_chunks: self.data_chunks.unwrap_or(DEFAULT_DATA_CHUNKS),
+            parity_chunks: self.parity_chunks.unwrap_or(DEFAULT_PARITY_CHUNKS),
+        }
+    }
+
+    /// Set how payloads are split into chunks
+    pub fn set_erasure(&mut self, erasure: ErasureConfig) {
+        self.data_chunks = Some(erasure.data_chunks);
+        self.parity_chunks = Some(erasure.parity_chunks);
+    }
+
+    /// Retrieves the limits of the mempool
+    pub fn mempool(&self) -> MempoolConfig {
+        MempoolConfig {
+            max_size: self.mempool_size.unwrap_or(DEFAULT_MEMPOOL_SIZE),
+            expiry: self
+                .mempool_expiry
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_MEMPOOL_EXPIRY),
+        }
+    }
+
+    /// Set the limits of the mempool
+    pub fn set_mempool(&mut self, mempool: MempoolConfig) {
+        self.mempool_size = Some(mempool.max_size);
+        self.mempool_expiry = Some(mempool.expiry.as_secs());
+    }
 }
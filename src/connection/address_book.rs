@@ -0,0 +1,392 @@
+use super::{connection_types::ConnectionInfo, netgroup::netgroup};
+use crate::{crypto::hash::Hash, persistence, Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs, net::SocketAddr, path::Path, time::Duration};
+
+const NEW_BUCKET_COUNT: usize = 256;
+const TRIED_BUCKET_COUNT: usize = 64;
+const BUCKET_SIZE: usize = 32;
+const NEW_BUCKETS_PER_SOURCE_GROUP: u64 = 16;
+const TRIED_BUCKETS_PER_GROUP: u64 = 8;
+
+const HORIZON_SECS: u64 = 30 * 24 * 60 * 60;
+const MAX_RETRIES_WITHOUT_SUCCESS: u32 = 3;
+const MAX_FAILURES: u32 = 10;
+const MIN_FAIL_SECS: u64 = 7 * 24 * 60 * 60;
+const RECENT_SUCCESS_SECS: u64 = 24 * 60 * 60;
+
+/// What we know about a peer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddressRecord {
+    /// How to reach the peer
+    pub info: ConnectionInfo,
+    /// Seconds since the UNIX epoch at which the peer was last heard of
+    pub last_seen: u64,
+    /// Seconds since the UNIX epoch of the last successful connection
+    pub last_success: Option<u64>,
+    /// Number of successful connections
+    pub successes: u32,
+    /// Number of failed connection attempts since the last success
+    pub failures: u32,
+    /// Last observed round-trip time
+    pub rtt: Option<Duration>,
+    tried: bool,
+}
+
+impl AddressRecord {
+    /// Checks if the record is not worth keeping:
+    /// not heard of in a long time, or failing repeatedly.
+    pub fn is_terrible(&self, now: u64) -> bool {
+        if now.saturating_sub(self.last_seen) > HORIZON_SECS {
+            return true;
+        }
+        match self.last_success {
+            None => self.failures >= MAX_RETRIES_WITHOUT_SUCCESS,
+            Some(last) => self.failures >= MAX_FAILURES && now.saturating_sub(last) > MIN_FAIL_SECS,
+        }
+    }
+
+    /// Checks if we connected to the peer successfully in the last day.
+    pub fn is_recently_good(&self, now: u64) -> bool {
+        self.last_success
+            .is_some_and(|last| now.saturating_sub(last) <= RECENT_SUCCESS_SECS)
+    }
+}
+
+/// Known peers and their quality, split into `new` and `tried` tables.
+///
+/// Peers we only heard about go into `new` buckets selected by the group of
+/// the peer that told us, so a single source can only fill a few buckets.
+/// Peers we connected to successfully move into `tried` buckets selected by
+/// their own address group.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AddressBook {
+    key: Hash,
+    records: HashMap<Hash, AddressRecord>,
+    new_buckets: Vec<Vec<Hash>>,
+    tried_buckets: Vec<Vec<Hash>>,
+}
+
+impl Default for AddressBook {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AddressBook {
+    /// Creates a new, empty `AddressBook` with a random bucketing key.
+    pub fn new() -> Self {
+        Self {
+            key: Hash::random(),
+            records: Default::default(),
+            new_buckets: vec![Vec::new(); NEW_BUCKET_COUNT],
+            tried_buckets: vec![Vec::new(); TRIED_BUCKET_COUNT],
+        }
+    }
+
+    /// Load an `AddressBook` from a file, or create an empty one if it does not exist.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::new());
+        }
+        Ok(bincode::deserialize(&fs::read(path)?)?)
+    }
+
+    /// Atomically write the `AddressBook` to a file.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        persistence::save(self, path)
+    }
+
+    /// Retrieves the record of a peer.
+    pub fn get(&self, node_id: &Hash) -> Option<&AddressRecord> {
+        self.records.get(node_id)
+    }
+
+    /// Number of known peers.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Checks if no peer is known.
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Record a peer we heard about from `source`.
+    /// Returns `true` if the peer was not known before.
+    pub fn add(&mut self, info: ConnectionInfo, source: &SocketAddr, now: u64) -> bool {
+        let node_id = info.public_id.node_id;
+        if let Some(record) = self.records.get_mut(&node_id) {
+            record.last_seen = record.last_seen.max(now);
+            if !record.tried {
+                record.info = info;
+            }
+            return false;
+        }
+        let bucket = self.new_bucket(&info.socket_addr, source);
+        if self.new_buckets[bucket].len() >= BUCKET_SIZE && !self.evict_from_new(bucket, now) {
+            return false;
+        }
+        self.new_buckets[bucket].push(node_id);
+        let _ = self.records.insert(
+            node_id,
+            AddressRecord {
+                info,
+                last_seen: now,
+                last_success: None,
+                successes: 0,
+                failures: 0,
+                rtt: None,
+                tried: false,
+            },
+        );
+        true
+    }
+
+    /// Record a successful connection to a peer, moving it to the `tried` table.
+    pub fn mark_good(&mut self, info: ConnectionInfo, rtt: Option<Duration>, now: u64) {
+        let node_id = info.public_id.node_id;
+        if !self.records.contains_key(&node_id) {
+            let _ = self.add(info, &info.socket_addr, now);
+        }
+        let was_tried = match self.records.get_mut(&node_id) {
+            Some(record) => {
+                record.info = info;
+                record.last_seen = now;
+                record.last_success = Some(now);
+                record.successes = record.successes.saturating_add(1);
+                record.failures = 0;
+                record.rtt = rtt.or(record.rtt);
+                record.tried
+            }
+            None => return,
+        };
+        if !was_tried {
+            self.move_to_tried(&node_id, now);
+        }
+    }
+
+    /// Record an observed round-trip time to a peer.
+    pub fn observe_rtt(&mut self, node_id: &Hash, rtt: Duration) {
+        if let Some(record) = self.records.get_mut(node_id) {
+            record.rtt = Some(rtt);
+        }
+    }
+
+    /// Record a failed connection attempt, forgetting peers which keep failing.
+    pub fn mark_failed(&mut self, node_id: &Hash, now: u64) {
+        let terrible = match self.records.get_mut(node_id) {
+            Some(record) => {
+                record.failures = record.failures.saturating_add(1);
+                !record.tried && record.is_terrible(now)
+            }
+            None => return,
+        };
+        if terrible {
+            self.remove(node_id);
+        }
+    }
+
+    /// Pick up to `count` peers to connect to on startup,
+    /// preferring those we connected to recently and with the lowest latency.
+    pub fn select_for_bootstrap(&self, count: usize, now: u64) -> Vec<ConnectionInfo> {
+        let mut candidates = self
+            .records
+            .values()
+            .filter(|record| !record.is_terrible(now))
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|record| {
+            (
+                !record.is_recently_good(now),
+                !record.tried,
+                record.failures,
+                record.rtt.unwrap_or(Duration::MAX),
+                now.saturating_sub(record.last_seen),
+            )
+        });
+        candidates
+            .into_iter()
+            .take(count)
+            .map(|record| record.info)
+            .collect()
+    }
+
+    fn move_to_tried(&mut self, node_id: &Hash, now: u64) {
+        for bucket in &mut self.new_buckets {
+            bucket.retain(|id| id != node_id);
+        }
+        let socket_addr = match self.records.get(node_id) {
+            Some(record) => record.info.socket_addr,
+            None => return,
+        };
+        let bucket = self.tried_bucket(&socket_addr);
+        if self.tried_buckets[bucket].len() >= BUCKET_SIZE {
+            // Demote the tried peer we connected to least recently
+            let oldest = self.tried_buckets[bucket]
+                .iter()
+                .min_by_key(|id| self.records.get(*id).and_then(|record| record.last_success))
+                .copied();
+            if let Some(oldest) = oldest {
+                self.tried_buckets[bucket].retain(|id| *id != oldest);
+                self.demote(&oldest, now);
+            }
+        }
+        self.tried_buckets[bucket].push(*node_id);
+        if let Some(record) = self.records.get_mut(node_id) {
+            record.tried = true;
+        }
+    }
+
+    fn demote(&mut self, node_id: &Hash, now: u64) {
+        let socket_addr = match self.records.get_mut(node_id) {
+            Some(record) => {
+                record.tried = false;
+                record.info.socket_addr
+            }
+            None => return,
+        };
+        let bucket = self.new_bucket(&socket_addr, &socket_addr);
+        if self.new_buckets[bucket].len() >= BUCKET_SIZE && !self.evict_from_new(bucket, now) {
+            let _ = self.records.remove(node_id);
+            return;
+        }
+        self.new_buckets[bucket].push(*node_id);
+    }
+
+    /// Make room in a full `new` bucket by evicting a terrible entry,
+    /// or failing that, the entry heard of least recently.
+    fn evict_from_new(&mut self, bucket: usize, now: u64) -> bool {
+        let records = &self.records;
+        let victim = self.new_buckets[bucket]
+            .iter()
+            .find(|id| records.get(*id).is_none_or(|r| r.is_terrible(now)))
+            .or_else(|| {
+                self.new_buckets[bucket]
+                    .iter()
+                    .min_by_key(|id| records.get(*id).map_or(0, |r| r.last_seen))
+            })
+            .copied();
+        match victim {
+            Some(victim) => {
+                self.remove(&victim);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove(&mut self, node_id: &Hash) {
+        let _ = self.records.remove(node_id);
+        for bucket in self
+            .new_buckets
+            .iter_mut()
+            .chain(self.tried_buckets.iter_mut())
+        {
+            bucket.retain(|id| id != node_id);
+        }
+    }
+
+    fn new_bucket(&self, socket_addr: &SocketAddr, source: &SocketAddr) -> usize {
+        let source_group = netgroup(&source.ip());
+        let slot = self.keyed_hash(&[
+            netgroup(&socket_addr.ip()).as_bytes(),
+            source_group.as_bytes(),
+        ]) % NEW_BUCKETS_PER_SOURCE_GROUP;
+        (self.keyed_hash(&[source_group.as_bytes(), &slot.to_le_bytes()]) % NEW_BUCKET_COUNT as u64)
+            as usize
+    }
+
+    fn tried_bucket(&self, socket_addr: &SocketAddr) -> usize {
+        let group = netgroup(&socket_addr.ip());
+        let slot = self.keyed_hash(&[socket_addr.to_string().as_bytes()]) % TRIED_BUCKETS_PER_GROUP;
+        (self.keyed_hash(&[group.as_bytes(), &slot.to_le_bytes()]) % TRIED_BUCKET_COUNT as u64)
+            as usize
+    }
+
+    fn keyed_hash(&self, data: &[&[u8]]) -> u64 {
+        let mut parts = vec![self.key.as_ref()];
+        parts.extend_from_slice(data);
+        let hash = Hash::from_byte_arrays(&parts);
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&hash.as_ref()[..8]);
+        u64::from_le_bytes(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::connection_info;
+
+    fn info_at(ip: [u8; 4], port: u16) -> ConnectionInfo {
+        let mut info = connection_info(Hash::random(), port);
+        info.socket_addr = SocketAddr::from((ip, port));
+        info
+    }
+
+    #[test]
+    fn test_prefers_recently_good_peers() {
+        let now = 1_000_000;
+        let source = SocketAddr::from(([10, 0, 0, 1], 9000));
+        let mut book = AddressBook::new();
+        let heard_of = info_at([10, 1, 0, 1], 9000);
+        let slow = info_at([10, 2, 0, 1], 9000);
+        let fast = info_at([10, 3, 0, 1], 9000);
+        assert!(book.add(heard_of, &source, now));
+        book.mark_good(slow, Some(Duration::from_millis(300)), now);
+        book.mark_good(fast, Some(Duration::from_millis(20)), now);
+
+        assert_eq!(
+            book.select_for_bootstrap(3, now),
+            vec![fast, slow, heard_of]
+        );
+    }
+
+    #[test]
+    fn test_forgets_failing_peers() {
+        let now = 1_000_000;
+        let source = SocketAddr::from(([10, 0, 0, 1], 9000));
+        let mut book = AddressBook::new();
+        let info = info_at([10, 1, 0, 1], 9000);
+        assert!(book.add(info, &source, now));
+        for _ in 0..MAX_RETRIES_WITHOUT_SUCCESS {
+            book.mark_failed(&info.public_id.node_id, now);
+        }
+        assert!(book.is_empty());
+    }
+
+    #[test]
+    fn test_single_source_cannot_flood_new_table() {
+        let now = 1_000_000;
+        let attacker = SocketAddr::from(([6, 6, 6, 6], 9000));
+        let mut book = AddressBook::new();
+        for i in 0..5_000u32 {
+            let [_, _, a, b] = i.to_be_bytes();
+            let _ = book.add(info_at([a, b, 1, 1], 9000), &attacker, now);
+        }
+        let max = BUCKET_SIZE * NEW_BUCKETS_PER_SOURCE_GROUP as usize;
+        assert!(book.len() <= max);
+
+        let honest = info_at([10, 1, 0, 1], 9000);
+        book.mark_good(honest, None, now);
+        assert!(book.get(&honest.public_id.node_id).is_some());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let now = 1_000_000;
+        let path = std::env::temp_dir().join(format!("address_book_{}", Hash::random()));
+        let mut book = AddressBook::new();
+        let info = info_at([10, 1, 0, 1], 9000);
+        book.mark_good(info, Some(Duration::from_millis(42)), now);
+        assert!(book.save(&path).is_ok());
+
+        let loaded = AddressBook::load(&path);
+        assert!(loaded.is_ok());
+        let loaded = loaded.unwrap_or_default();
+        let record = loaded.get(&info.public_id.node_id);
+        assert!(record.is_some_and(|record| record.rtt == Some(Duration::from_millis(42))));
+        assert_eq!(loaded.select_for_bootstrap(1, now), vec![info]);
+        let _ = fs::remove_file(path);
+    }
+}
@@ -1,8 +1,193 @@
-use crate::PublicId;
-use std::{collections::HashMap, net::SocketAddr};
+use crate::{crypto::hash::Hash, PublicId};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+};
 
-/// Map of all connections
-pub type ConnectionMap = HashMap<SocketAddr, (Option<PublicId>, ConnectionState)>;
+/// Connections to a single peer, by address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerConnections {
+    /// Public identity of the peer
+    pub public_id: PublicId,
+    /// State of the connection at each address of the peer
+    pub addrs: HashMap<SocketAddr, ConnectionState>,
+}
+
+/// Map of all connections, keyed by the node ID of the peer.
+/// Connections to peers which have not identified themselves yet are kept apart,
+/// and every address is indexed back to its peer.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionMap {
+    peers: HashMap<Hash, PeerConnections>,
+    addr_index: HashMap<SocketAddr, Hash>,
+    unidentified: HashMap<SocketAddr, ConnectionState>,
+}
+
+impl ConnectionMap {
+    /// Creates a new, empty `ConnectionMap`.
+    pub fn new() -> Self {
+        Self {
+            peers: Default::default(),
+            addr_index: Default::default(),
+            unidentified: Default::default(),
+        }
+    }
+
+    /// Number of connections, counting every address of every peer
+    pub fn len(&self) -> usize {
+        self.addr_index.len() + self.unidentified.len()
+    }
+
+    /// Checks if there is no connection
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Checks if there is a connection at an address
+    pub fn contains_addr(&self, socket_addr: &SocketAddr) -> bool {
+        self.addr_index.contains_key(socket_addr) || self.unidentified.contains_key(socket_addr)
+    }
+
+    /// Checks if there is a connection to a node, at any address
+    pub fn contains_node(&self, node_id: &Hash) -> bool {
+        self.peers.contains_key(node_id)
+    }
+
+    /// Retrieves the public identity of the peer at an address, if known
+    pub fn public_id(&self, socket_addr: &SocketAddr) -> Option<PublicId> {
+        let node_id = self.addr_index.get(socket_addr)?;
+        self.peers.get(node_id).map(|peer| peer.public_id)
+    }
+
+    /// Retrieves the state of the connection at an address
+    pub fn state(&self, socket_addr: &SocketAddr) -> Option<&ConnectionState> {
+        match self.addr_index.get(socket_addr) {
+            Some(node_id) => self.peers.get(node_id)?.addrs.get(socket_addr),
+            None => self.unidentified.get(socket_addr),
+        }
+    }
+
+    /// Retrieves a mutable reference to the state of the connection at an address
+    pub fn state_mut(&mut self, socket_addr: &SocketAddr) -> Option<&mut ConnectionState> {
+        match self.addr_index.get(socket_addr) {
+            Some(node_id) => self.peers.get_mut(node_id)?.addrs.get_mut(socket_addr),
+            None => self.unidentified.get_mut(socket_addr),
+        }
+    }
+
+    /// Retrieves the connections to a node
+    pub fn peer(&self, node_id: &Hash) -> Option<&PeerConnections> {
+        self.peers.get(node_id)
+    }
+
+    /// Iterate over the connections to identified peers
+    pub fn peers(&self) -> impl Iterator<Item = &PeerConnections> {
+        self.peers.values()
+    }
+
+    /// Iterate over every connection, with the identity of the peer if known
+    pub fn iter(&self) -> impl Iterator<Item = (&SocketAddr, Option<PublicId>, &ConnectionState)> {
+        self.peers
+            .values()
+            .flat_map(|peer| {
+                peer.addrs
+                    .iter()
+                    .map(move |(socket_addr, state)| (socket_addr, Some(peer.public_id), state))
+            })
+            .chain(
+                self.unidentified
+                    .iter()
+                    .map(|(socket_addr, state)| (socket_addr, None, state)),
+            )
+    }
+
+    /// Iterate over the addresses of every connection
+    pub fn addrs(&self) -> impl Iterator<Item = &SocketAddr> {
+        self.addr_index.keys().chain(self.unidentified.keys())
+    }
+
+    /// Record a connection at an address, replacing any previous one.
+    pub fn insert(
+        &mut self,
+        socket_addr: SocketAddr,
+        public_id: Option<PublicId>,
+        state: ConnectionState,
+    ) {
+        let _ = self.remove(&socket_addr);
+        match public_id {
+            Some(public_id) => self.insert_identified(socket_addr, public_id, state),
+            None => {
+                let _ = self.unidentified.insert(socket_addr, state);
+            }
+        }
+    }
+
+    /// Attach the connection at an address to the peer which identified itself on it.
+    /// Returns `false` if there is no connection at that address.
+    pub fn identify(&mut self, socket_addr: &SocketAddr, public_id: PublicId) -> bool {
+        match self.remove(socket_addr) {
+            Some((_, state)) => {
+                self.insert_identified(*socket_addr, public_id, state);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove the connection at an address.
+    /// The peer is forgotten once its last address is removed.
+    pub fn remove(
+        &mut self,
+        socket_addr: &SocketAddr,
+    ) -> Option<(Option<PublicId>, ConnectionState)> {
+        if let Some(state) = self.unidentified.remove(socket_addr) {
+            return Some((None, state));
+        }
+        let node_id = self.addr_index.remove(socket_addr)?;
+        let peer = self.peers.get_mut(&node_id)?;
+        let public_id = peer.public_id;
+        let state = peer.addrs.remove(socket_addr)?;
+        if peer.addrs.is_empty() {
+            let _ = self.peers.remove(&node_id);
+        }
+        Some((Some(public_id), state))
+    }
+
+    fn insert_identified(
+        &mut self,
+        socket_addr: SocketAddr,
+        public_id: PublicId,
+        state: ConnectionState,
+    ) {
+        let peer = self
+            .peers
+            .entry(public_id.node_id)
+            .or_insert_with(|| PeerConnections {
+                public_id,
+                addrs: Default::default(),
+            });
+        let _ = peer.addrs.insert(socket_addr, state);
+        let _ = self.addr_index.insert(socket_addr, public_id.node_id);
+    }
+}
+
+/// Decide which of two connections to the same peer to keep.
+/// Both ends apply the same rule: the connection opened by the node with the
+/// smaller ID wins, and if both were opened by the same node the older one is kept.
+/// Returns `true` if the new connection is to be kept.
+pub fn keeps_new_connection(
+    self_id: &Hash,
+    peer_id: &Hash,
+    existing_outbound: bool,
+    new_outbound: bool,
+) -> bool {
+    if existing_outbound == new_outbound {
+        return false;
+    }
+    let self_is_smaller = self_id.as_ref() < peer_id.as_ref();
+    new_outbound == self_is_smaller
+}
 
 /// Connection state
 #[derive(Debug, Eq, PartialEq, Clone)]
@@ -13,13 +198,168 @@ pub enum ConnectionState {
     Incoming,
     /// Connection established
     Connected,
+    /// Connection being torn down
+    Disconnecting,
+}
+
+/// Outcome of a peer identifying itself
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Identified {
+    /// The connection was established by this identification
+    pub established: bool,
+    /// We initiated the connection, so the peer is known to accept connections there
+    pub outbound: bool,
+    /// An agent is to be deployed
+    pub deploy_agent: bool,
 }
 
 /// Connection information for a node
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ConnectionInfo {
     /// Public identity of the node
     pub public_id: PublicId,
     /// Address of the node
     pub socket_addr: SocketAddr,
 }
+
+/// Default maximum number of inbound connections
+pub const DEFAULT_MAX_INBOUND: usize = 8;
+/// Default maximum number of outbound connections
+pub const DEFAULT_MAX_OUTBOUND: usize = 8;
+/// Default number of outbound connections a node tries to maintain
+pub const DEFAULT_TARGET_OUTBOUND: usize = 5;
+/// Default number of slots, in each direction, kept for allow-listed peers
+pub const DEFAULT_RESERVED_SLOTS: usize = 1;
+/// Default maximum number of outbound connections to a single network group
+pub const DEFAULT_MAX_PER_NETGROUP: usize = 2;
+
+/// Limits on the number of connections of a node.
+/// Inbound and outbound connections are counted separately, so that
+/// peers connecting to us can never take the slots of the peers we chose.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    /// Maximum number of inbound connections
+    pub max_inbound: usize,
+    /// Maximum number of outbound connections
+    pub max_outbound: usize,
+    /// Number of outbound connections to establish when bootstrapping
+    pub target_outbound: usize,
+    /// Number of slots, in each direction, only allow-listed peers may use
+    pub reserved_slots: usize,
+    /// Maximum number of outbound connections to a single network group
+    pub max_per_netgroup: usize,
+    /// Peers allowed to use the reserved slots
+    pub allow_list: HashSet<SocketAddr>,
+}
+
+impl ConnectionLimits {
+    /// Checks if a peer may connect to us while we have `inbound` inbound connections
+    pub fn accepts_inbound(&self, inbound: usize, peer_addr: &SocketAddr) -> bool {
+        inbound < self.limit_for(self.max_inbound, peer_addr)
+    }
+
+    /// Checks if we may connect to a peer while we have `outbound` outbound connections
+    pub fn accepts_outbound(&self, outbound: usize, peer_addr: &SocketAddr) -> bool {
+        outbound < self.limit_for(self.max_outbound, peer_addr)
+    }
+
+    /// Checks if we have reached the number of outbound connections we aim for
+    pub fn has_target_outbound(&self, outbound: usize) -> bool {
+        outbound >= self.target_outbound.min(self.max_outbound)
+    }
+
+    fn limit_for(&self, max: usize, peer_addr: &SocketAddr) -> usize {
+        if self.allow_list.contains(peer_addr) {
+            max
+        } else {
+            max.saturating_sub(self.reserved_slots)
+        }
+    }
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            max_inbound: DEFAULT_MAX_INBOUND,
+            max_outbound: DEFAULT_MAX_OUTBOUND,
+            target_outbound: DEFAULT_TARGET_OUTBOUND,
+            reserved_slots: DEFAULT_RESERVED_SLOTS,
+            max_per_netgroup: DEFAULT_MAX_PER_NETGROUP,
+            allow_list: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::connection_info;
+
+    #[test]
+    fn test_reserved_slots() {
+        let allowed = SocketAddr::from(([10, 0, 0, 1], 9000));
+        let stranger = SocketAddr::from(([10, 0, 0, 2], 9000));
+        let limits = ConnectionLimits {
+            max_inbound: 4,
+            max_outbound: 2,
+            target_outbound: 2,
+            reserved_slots: 1,
+            max_per_netgroup: 2,
+            allow_list: std::iter::once(allowed).collect(),
+        };
+
+        assert!(limits.accepts_inbound(2, &stranger));
+        assert!(!limits.accepts_inbound(3, &stranger));
+        assert!(limits.accepts_inbound(3, &allowed));
+        assert!(!limits.accepts_inbound(4, &allowed));
+
+        assert!(!limits.accepts_outbound(1, &stranger));
+        assert!(limits.accepts_outbound(1, &allowed));
+        assert!(limits.has_target_outbound(2));
+    }
+
+    #[test]
+    fn test_peer_with_several_addresses() {
+        let info = connection_info(Hash::random(), 9000);
+        let v6 = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], 9000));
+        let mut entries = ConnectionMap::new();
+        entries.insert(
+            info.socket_addr,
+            Some(info.public_id),
+            ConnectionState::Connected,
+        );
+        entries.insert(v6, None, ConnectionState::Incoming);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries.peers().count(), 1);
+
+        assert!(entries.identify(&v6, info.public_id));
+        assert_eq!(entries.peers().count(), 1);
+        assert_eq!(entries.public_id(&v6), Some(info.public_id));
+        assert_eq!(
+            entries
+                .peer(&info.public_id.node_id)
+                .map(|peer| peer.addrs.len()),
+            Some(2)
+        );
+
+        assert!(entries.remove(&info.socket_addr).is_some());
+        assert!(entries.contains_node(&info.public_id.node_id));
+        assert!(entries.remove(&v6).is_some());
+        assert!(!entries.contains_node(&info.public_id.node_id));
+        assert!(entries.is_empty());
+    }
+
+    #[test]
+    fn test_simultaneous_open_tie_break() {
+        let a = Hash::random();
+        let b = Hash::random();
+        // The connection opened by `a` is outbound at `a` and inbound at `b`,
+        // and the one opened by `b` the other way round: both ends must keep the same one.
+        let a_keeps_own = keeps_new_connection(&a, &b, false, true);
+        let b_keeps_a = keeps_new_connection(&b, &a, true, false);
+        assert_eq!(a_keeps_own, b_keeps_a);
+        assert_eq!(a_keeps_own, a.as_ref() < b.as_ref());
+        assert!(!keeps_new_connection(&a, &b, true, true));
+        assert!(!keeps_new_connection(&a, &b, false, false));
+    }
+}
@@ -0,0 +1,158 @@
+use std::time::{Duration, Instant};
+
+/// Default time without hearing from a peer after which it is pinged
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+/// Default time without hearing from a peer after which it is dropped
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Default time given to a peer to identify itself, or to close a connection
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Weight of the previous estimate in the smoothed round-trip time, out of `RTT_DIVISOR`
+const RTT_HISTORY_WEIGHT: u32 = 7;
+const RTT_DIVISOR: u32 = 8;
+
+/// Timeouts governing the lifecycle of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Time without hearing from a peer after which it is pinged
+    pub keepalive_interval: Duration,
+    /// Time without hearing from a peer after which it is dropped
+    pub idle_timeout: Duration,
+    /// Time given to a peer to identify itself, or to close a connection
+    pub handshake_timeout: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            keepalive_interval: DEFAULT_KEEPALIVE_INTERVAL,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+        }
+    }
+}
+
+/// Liveness of a single connection
+#[derive(Debug, Clone, Copy)]
+pub struct Liveness {
+    opened_at: Instant,
+    last_heard: Instant,
+    closing_since: Option<Instant>,
+    pending_ping: Option<(u64, Instant)>,
+    rtt: Option<Duration>,
+}
+
+impl Liveness {
+    /// Creates a new `Liveness` for a connection opened at `now`.
+    pub fn new(now: Instant) -> Self {
+        Self {
+            opened_at: now,
+            last_heard: now,
+            closing_since: None,
+            pending_ping: None,
+            rtt: None,
+        }
+    }
+
+    /// Record that the peer was heard from.
+    pub fn heard(&mut self, now: Instant) {
+        self.last_heard = self.last_heard.max(now);
+    }
+
+    /// Record that a ping was sent to the peer.
+    pub fn ping_sent(&mut self, nonce: u64, now: Instant) {
+        self.pending_ping = Some((nonce, now));
+    }
+
+    /// Record an answer to a ping.
+    /// Returns the updated round-trip time estimate if the nonce is the expected one.
+    pub fn pong_received(&mut self, nonce: u64, now: Instant) -> Option<Duration> {
+        match self.pending_ping {
+            Some((expected, sent_at)) if expected == nonce => {
+                self.pending_ping = None;
+                self.heard(now);
+                let sample = now.saturating_duration_since(sent_at);
+                let rtt = match self.rtt {
+                    Some(rtt) => (rtt * RTT_HISTORY_WEIGHT + sample) / RTT_DIVISOR,
+                    None => sample,
+                };
+                self.rtt = Some(rtt);
+                Some(rtt)
+            }
+            _ => None,
+        }
+    }
+
+    /// Smoothed round-trip time, if the peer ever answered a ping
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Checks if the peer should be pinged: it has been quiet for `interval`
+    /// and is not already being pinged.
+    pub fn needs_ping(&self, now: Instant, interval: Duration) -> bool {
+        self.pending_ping.is_none() && now.saturating_duration_since(self.last_heard) >= interval
+    }
+
+    /// Checks if the peer has been quiet for longer than `timeout`.
+    pub fn is_idle(&self, now: Instant, timeout: Duration) -> bool {
+        now.saturating_duration_since(self.last_heard) > timeout
+    }
+
+    /// Checks if the connection has been open for longer than `timeout`.
+    pub fn is_older_than(&self, now: Instant, timeout: Duration) -> bool {
+        now.saturating_duration_since(self.opened_at) > timeout
+    }
+
+    /// Record that we started closing the connection.
+    pub fn start_closing(&mut self, now: Instant) {
+        let _ = self.closing_since.get_or_insert(now);
+    }
+
+    /// Checks if the connection has been closing for longer than `timeout`.
+    pub fn is_closing_for(&self, now: Instant, timeout: Duration) -> bool {
+        self.closing_since
+            .is_some_and(|since| now.saturating_duration_since(since) > timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rtt_ewma() {
+        let start = Instant::now();
+        let mut liveness = Liveness::new(start);
+
+        liveness.ping_sent(1, start);
+        assert_eq!(liveness.pong_received(2, start), None);
+        let rtt = liveness.pong_received(1, start + Duration::from_millis(80));
+        assert_eq!(rtt, Some(Duration::from_millis(80)));
+        assert_eq!(liveness.pong_received(1, start), None);
+
+        let later = start + Duration::from_secs(1);
+        liveness.ping_sent(3, later);
+        let rtt = liveness.pong_received(3, later + Duration::from_millis(160));
+        assert_eq!(rtt, Some(Duration::from_millis(90)));
+    }
+
+    #[test]
+    fn test_keepalive_and_idle() {
+        let start = Instant::now();
+        let timeouts = Timeouts::default();
+        let mut liveness = Liveness::new(start);
+        assert!(!liveness.needs_ping(start, timeouts.keepalive_interval));
+
+        let quiet = start + timeouts.keepalive_interval;
+        assert!(liveness.needs_ping(quiet, timeouts.keepalive_interval));
+        liveness.ping_sent(7, quiet);
+        assert!(!liveness.needs_ping(quiet, timeouts.keepalive_interval));
+
+        let dead = start + timeouts.idle_timeout + Duration::from_secs(1);
+        assert!(liveness.is_idle(dead, timeouts.idle_timeout));
+        liveness.heard(dead);
+        assert!(!liveness.is_idle(dead, timeouts.idle_timeout));
+        assert!(liveness.is_older_than(dead, timeouts.handshake_timeout));
+    }
+}
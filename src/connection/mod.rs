@@ -1,92 +1,1120 @@
 use crate::{
-    crypto::hash::Hash, Event, Message, PublicId, Result, RoutingTable, SharedRoutingTable,
+    crypto::hash::Hash, error::Error, Config, Event, Identity, Message, PublicId, Result,
+    RoutingTable, SharedRoutingTable,
 };
 use bytes::Bytes;
-use connection_types::{ConnectionInfo, ConnectionMap, ConnectionState};
+use connection_types::{
+    keeps_new_connection, ConnectionInfo, ConnectionLimits, ConnectionMap, ConnectionState,
+    Identified,
+};
 use crossbeam_channel::Sender;
+use ed25519_dalek::{Signature, Verifier};
+use liveness::{Liveness, Timeouts};
+use nat::{Introductions, ObservedAddrs};
+use netgroup::netgroup;
+use peer_exchange::{
+    unix_timestamp, ExchangeViolation, PeerExchange, SignedPeerRecord, MAX_ADDRS_PER_RECORD,
+    MAX_RECORDS_PER_MESSAGE,
+};
+use peer_score::{BanList, Misbehaviour, PeerScores};
 use qp2p::{Connection as QuicConnection, Endpoint as QuicEndpoint};
-use std::{collections::hash_map::Entry, net::SocketAddr};
+use rand::Rng;
+use reconnect::{Backoff, ReconnectAttempt, ReconnectScheduler};
+use relay::{handshake_bytes, CircuitEnd, CircuitError, CircuitFrame, RelayService};
+use routing::RoutingDelta;
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
 
+/// Persistent book of known peers
+pub mod address_book;
 /// Connection-related types
 pub mod connection_types;
+/// Keepalive and timeouts of connections
+pub mod liveness;
+/// NAT detection and hole punching through a rendezvous peer
+pub mod nat;
+/// Grouping of addresses by network prefix
+pub mod netgroup;
+/// Exchange of signed peer records
+pub mod peer_exchange;
+/// Scoring and banning of misbehaving peers
+pub mod peer_score;
+/// Reconnection to important peers
+pub mod reconnect;
+/// Relaying of traffic between peers which cannot reach each other
+pub mod relay;
 /// Implements a routing table.
 pub mod routing;
 
-pub(super) const MAX_CONNECTION_LEN: usize = 5;
-
 /// Manages the connection of a node
 pub struct Connection {
     entries: ConnectionMap,
+    outbound: HashSet<SocketAddr>,
+    liveness: HashMap<SocketAddr, Liveness>,
+    important: HashSet<SocketAddr>,
+    reconnect: ReconnectScheduler,
+    limits: ConnectionLimits,
     routing_table: RoutingTable,
+    peer_versions: HashMap<Hash, usize>,
+    shared_versions: HashMap<SocketAddr, usize>,
+    scores: PeerScores,
+    peer_exchange: PeerExchange,
+    observed_addrs: ObservedAddrs,
+    introductions: Introductions,
+    relay: Option<RelayService>,
+    relay_notices: Vec<(SocketAddr, u64, CircuitError)>,
+    pending_circuits: HashMap<u64, (SocketAddr, PublicId)>,
+    circuits: HashMap<u64, CircuitEnd>,
     is_bootstrapped: bool,
 }
 
 impl Connection {
-    /// Creates a new `Connection`.
+    /// Creates a new `Connection` with the default connection limits and scoring.
     pub fn new() -> Self {
+        Self::with_parts(Default::default(), Default::default(), Default::default())
+    }
+
+    /// Creates a new `Connection` with the connection limits, scoring and backoff of `config`,
+    /// enforcing the bans kept in the configured ban list.
+    /// Bootstrap nodes and allow-listed peers are reconnected to when lost.
+    /// Traffic is relayed for other peers only if the configuration enables it.
+    pub fn with_config(config: &Config) -> Result<Self> {
+        let bans = match config.ban_list() {
+            Some(path) => BanList::load(path)?,
+            None => BanList::new(),
+        };
+        let mut connection = Self::with_parts(
+            config.connection_limits(),
+            PeerScores::new(config.score_config(), bans),
+            config.backoff(),
+        );
+        connection.important.extend(config.bootstrap_nodes());
+        connection.relay = config.relay_limits().map(RelayService::new);
+        Ok(connection)
+    }
+
+    fn with_parts(limits: ConnectionLimits, scores: PeerScores, backoff: Backoff) -> Self {
         Self {
             entries: Default::default(),
+            outbound: Default::default(),
+            liveness: Default::default(),
+            important: limits.allow_list.clone(),
+            reconnect: ReconnectScheduler::new(backoff),
+            limits,
             routing_table: Default::default(),
+            peer_versions: Default::default(),
+            shared_versions: Default::default(),
+            scores,
+            peer_exchange: Default::default(),
+            observed_addrs: Default::default(),
+            introductions: Default::default(),
+            relay: None,
+            relay_notices: Default::default(),
+            pending_circuits: Default::default(),
+            circuits: Default::default(),
             is_bootstrapped: false,
         }
     }
 
-    /// Update records in the routing table
+    /// Update records in the routing table with a signed snapshot from a peer
     pub async fn update_routing_table(
         &mut self,
-        self_id: &Hash,
+        identity: &Identity,
+        peer_addr: &SocketAddr,
         peer_id: &Hash,
         shared_table: SharedRoutingTable,
+        signature: &[u8],
         quic: &mut QuicConnection,
     ) -> Result<()> {
-        let _ = shared_table
-            .shared_entries()
-            .keys()
-            .into_iter()
-            .map(|hash| {
-                if !self.routing_table.has_node(hash) {
-                    self.routing_table.add_new_node(hash);
-                }
-            })
-            .collect::<Vec<_>>();
+        let signed_bytes = shared_table.signable_bytes(peer_id)?;
+        if !self.is_authentic_advertisement(peer_addr, peer_id, &signed_bytes, signature) {
+            return self.penalise(peer_addr, Misbehaviour::InvalidSignature, quic);
+        }
+        if !shared_table.is_plausible(peer_id) {
+            return self.penalise(peer_addr, Misbehaviour::RoutingLie, quic);
+        }
+        let changed = self
+            .routing_table
+            .apply_shared(peer_id, &shared_table, Instant::now());
+        let _ = self.peer_versions.insert(*peer_id, shared_table.version());
+        if changed {
+            self.routing_table.increment_version();
+            self.share_routing_table(quic, identity).await?;
+        }
+        Ok(())
+    }
 
-        let mut changed = false;
-        let _ = self
+    /// Remove stale routes and report each lost destination.
+    pub fn expire_routes(
+        &mut self,
+        ttl: Duration,
+        hold_down: Duration,
+        sender: &Sender<Event>,
+    ) -> Result<()> {
+        for node_id in self
             .routing_table
-            .entries_mut()
-            .iter_mut()
-            .map(|(dest, (hop_to, count))| {
-                if let Some(new_hop_count) = shared_table.shared_routing_info(dest) {
-                    if new_hop_count + 1 < *count {
-                        changed = true;
-                        let _ = std::mem::replace(hop_to, *peer_id);
-                        let _ = std::mem::replace(count, new_hop_count + 1);
-                    }
-                }
-            })
-            .collect::<Vec<_>>();
+            .expire_routes(Instant::now(), ttl, hold_down)
+        {
+            log::debug!("Route to {:?} expired", node_id);
+            sender.send(Event::RouteLost(node_id))?;
+        }
+        Ok(())
+    }
+
+    /// Apply a signed incremental routing update from a peer.
+    /// A full snapshot is requested instead if the update does not follow
+    /// the last version we received from that peer.
+    pub async fn handle_routing_delta(
+        &mut self,
+        identity: &Identity,
+        peer_addr: &SocketAddr,
+        peer_id: &Hash,
+        delta: RoutingDelta,
+        signature: &[u8],
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        let signed_bytes = delta.signable_bytes(peer_id)?;
+        if !self.is_authentic_advertisement(peer_addr, peer_id, &signed_bytes, signature) {
+            return self.penalise(peer_addr, Misbehaviour::InvalidSignature, quic);
+        }
+        if !delta.is_plausible(peer_id) {
+            return self.penalise(peer_addr, Misbehaviour::RoutingLie, quic);
+        }
+        if self.peer_versions.get(peer_id) != Some(&delta.base_version()) {
+            log::debug!(
+                "Gap in routing updates from {:?}; requesting a snapshot",
+                peer_id
+            );
+            let user_msg_bytes = (
+                Bytes::from("Routing snapshot request"),
+                Bytes::from(peer_addr.to_string()),
+                Bytes::from(bincode::serialize(&Message::RoutingSnapshotRequest {
+                    source: identity.public_id().node_id,
+                })?),
+            );
+            quic.send(user_msg_bytes).await?;
+            return Ok(());
+        }
+        let changed = self
+            .routing_table
+            .apply_delta(peer_id, &delta, Instant::now());
+        let _ = self.peer_versions.insert(*peer_id, delta.version());
         if changed {
             self.routing_table.increment_version();
-            self.share_routing_table(quic, self_id).await?;
+            self.share_routing_table(quic, identity).await?;
         }
         Ok(())
     }
 
-    /// Share routing information with peers
+    /// Send our full routing table to a peer which requested it.
+    pub async fn handle_routing_snapshot_request(
+        &mut self,
+        identity: &Identity,
+        peer_addr: &SocketAddr,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        self.send_routing_snapshot(identity, peer_addr, quic).await
+    }
+
+    /// Share signed routing information with peers.
+    /// Peers which have already received a version of our table
+    /// only get the entries that changed since then.
     pub async fn share_routing_table(
         &mut self,
         quic: &mut QuicConnection,
-        self_id: &Hash,
+        identity: &Identity,
+    ) -> Result<()> {
+        let self_id = identity.public_id().node_id;
+        let version = self.routing_table.version();
+        let peers = self
+            .active_connections()
+            .into_iter()
+            .copied()
+            .collect::<Vec<SocketAddr>>();
+        for socket_addr in peers {
+            let delta = self
+                .shared_versions
+                .get(&socket_addr)
+                .and_then(|since| self.routing_table.delta_since(*since));
+            match delta {
+                Some(delta) if delta.is_empty() => {}
+                Some(delta) => {
+                    let signature = identity
+                        .sign_with_signing_key(&delta.signable_bytes(&self_id)?)
+                        .to_bytes()
+                        .to_vec();
+                    let user_msg_bytes = (
+                        Bytes::from("Routing update"),
+                        Bytes::from(socket_addr.to_string()),
+                        Bytes::from(bincode::serialize(&Message::RoutingDelta {
+                            delta,
+                            source: self_id,
+                            signature,
+                        })?),
+                    );
+                    quic.send(user_msg_bytes).await?;
+                    let _ = self.shared_versions.insert(socket_addr, version);
+                }
+                None => {
+                    self.send_routing_snapshot(identity, &socket_addr, quic)
+                        .await?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn send_routing_snapshot(
+        &mut self,
+        identity: &Identity,
+        socket_addr: &SocketAddr,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        let self_id = identity.public_id().node_id;
+        let shared_routing_table = self.routing_table.get_shared();
+        let signature = identity
+            .sign_with_signing_key(&shared_routing_table.signable_bytes(&self_id)?)
+            .to_bytes()
+            .to_vec();
+        let user_msg_bytes = (
+            Bytes::from("Routing information"),
+            Bytes::from(socket_addr.to_string()),
+            Bytes::from(bincode::serialize(&Message::RoutingTable {
+                shared_routing_table,
+                source: self_id,
+                signature,
+            })?),
+        );
+        quic.send(user_msg_bytes).await?;
+        let _ = self
+            .shared_versions
+            .insert(*socket_addr, self.routing_table.version());
+        Ok(())
+    }
+
+    /// Checks that a routing advertisement comes from the identity
+    /// authenticated on the connection, and that it carries its signature.
+    fn is_authentic_advertisement(
+        &self,
+        peer_addr: &SocketAddr,
+        source: &Hash,
+        signed_bytes: &[u8],
+        signature: &[u8],
+    ) -> bool {
+        let peer_id = match self.peer_id(peer_addr) {
+            Some(peer_id) if peer_id.node_id == *source => peer_id,
+            _ => {
+                log::warn!(
+                    "Peer at {:?} advertised routes on behalf of {:?}",
+                    peer_addr,
+                    source
+                );
+                return false;
+            }
+        };
+        let is_valid = Signature::from_bytes(signature)
+            .map(|signature| {
+                peer_id
+                    .signing_public_key
+                    .verify(signed_bytes, &signature)
+                    .is_ok()
+            })
+            .unwrap_or(false);
+        if !is_valid {
+            log::warn!("Invalid routing signature from peer at {:?}", peer_addr);
+        }
+        is_valid
+    }
+
+    /// Share our own signed record, along with a sample of the records we know,
+    /// with every active connection.
+    pub async fn share_peer_records(
+        &mut self,
+        identity: &Identity,
+        self_addrs: Vec<SocketAddr>,
+        quic: &mut QuicConnection,
     ) -> Result<()> {
-        let routing_table = self.routing_table();
+        let unix_now = unix_timestamp();
+        let own_record = SignedPeerRecord::new(identity, self_addrs, unix_now)?;
         for socket_addr in self.active_connections() {
+            let exclude = self
+                .peer_id(socket_addr)
+                .map_or(own_record.public_id.node_id, |id| id.node_id);
+            let mut records =
+                self.peer_exchange
+                    .sample(&exclude, MAX_RECORDS_PER_MESSAGE - 1, unix_now);
+            records.push(own_record.clone());
             let user_msg_bytes = (
-                Bytes::from("Routing information"),
+                Bytes::from("Peer records"),
                 Bytes::from(socket_addr.to_string()),
-                Bytes::from(bincode::serialize(&Message::RoutingTable {
-                    shared_routing_table: routing_table.get_shared(),
-                    source: *self_id,
-                })?),
+                Bytes::from(bincode::serialize(&Message::PeerRecords(records))?),
+            );
+            quic.send(user_msg_bytes).await?;
+        }
+        Ok(())
+    }
+
+    /// Handle peer records shared by a peer, returning the newly learnt peers.
+    /// Peers which exchange too often, too much, or forge records are penalised.
+    pub fn handle_peer_records(
+        &mut self,
+        peer_addr: &SocketAddr,
+        records: Vec<SignedPeerRecord>,
+        quic: &mut QuicConnection,
+    ) -> Result<Vec<ConnectionInfo>> {
+        match self.peer_exchange.handle_records(
+            peer_addr,
+            records,
+            Instant::now(),
+            unix_timestamp(),
+        ) {
+            Ok(added) => {
+                log::trace!(
+                    "Learnt {} new peer addresses from {:?}",
+                    added.len(),
+                    peer_addr
+                );
+                Ok(added)
+            }
+            Err(violation) => {
+                log::warn!(
+                    "Peer at {:?} violated peer exchange: {:?}",
+                    peer_addr,
+                    violation
+                );
+                let misbehaviour = match violation {
+                    ExchangeViolation::InvalidRecord => Misbehaviour::InvalidSignature,
+                    ExchangeViolation::RateLimited | ExchangeViolation::TooManyRecords => {
+                        Misbehaviour::Spam
+                    }
+                };
+                self.penalise(peer_addr, misbehaviour, quic)?;
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    /// Retrieves the verified connection information learnt through peer exchange
+    pub fn known_peers(&self) -> Vec<ConnectionInfo> {
+        self.peer_exchange.known_peers(unix_timestamp())
+    }
+
+    /// Connect to peers learnt through peer exchange until our slots are full
+    pub async fn connect_to_known_peers(&mut self, quic: &mut QuicEndpoint) -> Result<()> {
+        for info in self.known_peers() {
+            if self.limits.has_target_outbound(self.outbound_count()) {
+                break;
+            }
+            let already_known = self.entries.contains_addr(&info.socket_addr)
+                || self.entries.contains_node(&info.public_id.node_id);
+            if !already_known {
+                let _ = self.connect_to(&info, quic).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Lower the score of a misbehaving peer,
+    /// disconnecting from it once it gets banned.
+    pub fn penalise(
+        &mut self,
+        peer_addr: &SocketAddr,
+        misbehaviour: Misbehaviour,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        if self.record_misbehaviour(peer_addr, None, misbehaviour) {
+            quic.close(Some("Banned for protocol violations".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Lower the score of a peer we may not be connected to,
+    /// forgetting about it once it gets banned.
+    /// Returns `true` if the peer got banned.
+    pub fn record_misbehaviour(
+        &mut self,
+        peer_addr: &SocketAddr,
+        node_id: Option<&Hash>,
+        misbehaviour: Misbehaviour,
+    ) -> bool {
+        let node_id = node_id
+            .copied()
+            .or_else(|| self.peer_id(peer_addr).map(|id| id.node_id));
+        log::warn!("Peer at {:?} misbehaved: {:?}", peer_addr, misbehaviour);
+        if !self
+            .scores
+            .record(peer_addr, node_id.as_ref(), misbehaviour, unix_timestamp())
+        {
+            return false;
+        }
+        log::warn!("Banned peer at {:?} ({:?})", peer_addr, node_id);
+        let _ = self.remove_peer(peer_addr);
+        true
+    }
+
+    /// Checks if a peer is banned, by address or by node ID
+    pub fn is_banned(&self, peer_addr: &SocketAddr, node_id: Option<&Hash>) -> bool {
+        self.scores.is_banned(peer_addr, node_id, unix_timestamp())
+    }
+
+    /// Retrieves the scores of peers and the ban list
+    pub fn peer_scores(&self) -> &PeerScores {
+        &self.scores
+    }
+
+    /// Retrieves a mutable reference to the scores of peers and the ban list
+    pub fn peer_scores_mut(&mut self) -> &mut PeerScores {
+        &mut self.scores
+    }
+
+    /// Forget the connection at an address.
+    /// Once the peer has no connection left, the routes through it are removed.
+    /// Returns the state of the connection and the destinations which became unreachable.
+    fn remove_peer(
+        &mut self,
+        peer_addr: &SocketAddr,
+    ) -> Option<(Option<PublicId>, ConnectionState, Vec<Hash>)> {
+        let _ = self.shared_versions.remove(peer_addr);
+        self.scores.forget_addr(peer_addr);
+        let _ = self.outbound.remove(peer_addr);
+        let _ = self.liveness.remove(peer_addr);
+        self.peer_exchange.remove_peer(peer_addr);
+        self.observed_addrs.forget(peer_addr);
+        let (id, state) = self.entries.remove(peer_addr)?;
+        let mut lost = Vec::new();
+        if let Some(id) = id.filter(|id| !self.entries.contains_node(&id.node_id)) {
+            let _ = self.peer_versions.remove(&id.node_id);
+            lost = self.routing_table.remove_routes_via(&id.node_id);
+        }
+        Some((id, state, lost))
+    }
+
+    /// Answer a liveness probe from a peer.
+    pub async fn handle_ping(
+        &mut self,
+        peer_addr: &SocketAddr,
+        nonce: u64,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        let user_msg_bytes = (
+            Bytes::from("Pong"),
+            Bytes::from(peer_addr.to_string()),
+            Bytes::from(bincode::serialize(&Message::Pong { nonce })?),
+        );
+        quic.send(user_msg_bytes).await?;
+        Ok(())
+    }
+
+    /// Record that a peer was heard from.
+    pub fn observe_activity(&mut self, peer_addr: &SocketAddr) {
+        if let Some(liveness) = self.liveness.get_mut(peer_addr) {
+            liveness.heard(Instant::now());
+        }
+    }
+
+    /// Handle an answer to a keepalive ping.
+    /// Returns the updated round-trip time estimate of the peer.
+    pub fn handle_pong(&mut self, peer_addr: &SocketAddr, nonce: u64) -> Option<Duration> {
+        self.liveness
+            .get_mut(peer_addr)?
+            .pong_received(nonce, Instant::now())
+    }
+
+    /// Retrieves the smoothed round-trip time to a peer
+    pub fn rtt(&self, peer_addr: &SocketAddr) -> Option<Duration> {
+        self.liveness.get(peer_addr)?.rtt()
+    }
+
+    /// Tell a peer the address we see it at, so it can detect whether it is behind a NAT.
+    pub async fn reflect_address(
+        &mut self,
+        peer_addr: &SocketAddr,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        let user_msg_bytes = (
+            Bytes::from("Observed address"),
+            Bytes::from(peer_addr.to_string()),
+            Bytes::from(bincode::serialize(&Message::ObservedAddress(*peer_addr))?),
+        );
+        quic.send(user_msg_bytes).await?;
+        Ok(())
+    }
+
+    /// Record the address a peer sees us at.
+    /// Reports from peers which have not identified themselves are ignored.
+    pub fn handle_observed_address(&mut self, peer_addr: &SocketAddr, observed: SocketAddr) {
+        if self.entries.public_id(peer_addr).is_some() {
+            self.observed_addrs.record(*peer_addr, observed);
+        }
+    }
+
+    /// Retrieves our external address as reflected by our peers
+    pub fn external_addr(&self) -> Option<SocketAddr> {
+        self.observed_addrs.external_addr()
+    }
+
+    /// Checks if we are behind a NAT, judging by the addresses reflected by our peers
+    pub fn is_behind_nat(&self, local_addr: &SocketAddr) -> bool {
+        self.observed_addrs.is_behind_nat(local_addr)
+    }
+
+    /// Ask a rendezvous peer to introduce us to `target`, so we can both punch through our NATs.
+    pub async fn request_introduction(
+        &mut self,
+        self_id: PublicId,
+        target: Hash,
+        rendezvous: &SocketAddr,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        let message = Message::ConnectRequest {
+            source: self_id,
+            target,
+            addrs: Vec::new(),
+        };
+        let user_msg_bytes = (
+            Bytes::from("Connect request"),
+            Bytes::from(rendezvous.to_string()),
+            Bytes::from(bincode::serialize(&message)?),
+        );
+        quic.send(user_msg_bytes).await?;
+        self.introductions
+            .request(target, *rendezvous, Instant::now());
+        Ok(())
+    }
+
+    /// Handle a `ConnectRequest`.
+    /// As a rendezvous peer, introduce the requester and the target to each other.
+    /// As the target of an introduction, return the addresses to dial;
+    /// the other side dials ours at the same time. Introductions are followed only
+    /// if we asked the peer for them, or the peer is a bootstrap or allow-listed one.
+    /// Peers sending too many connect requests are penalised.
+    pub async fn handle_connect_request(
+        &mut self,
+        self_id: &Hash,
+        peer_addr: &SocketAddr,
+        source: PublicId,
+        target: Hash,
+        addrs: Vec<SocketAddr>,
+        quic: &mut QuicConnection,
+    ) -> Result<Vec<ConnectionInfo>> {
+        let requester = match self.entries.public_id(peer_addr) {
+            Some(requester) => requester,
+            None => {
+                self.penalise(peer_addr, Misbehaviour::Spam, quic)?;
+                return Ok(Vec::new());
+            }
+        };
+        let now = Instant::now();
+        if !self.introductions.allow(*peer_addr, now) {
+            self.penalise(peer_addr, Misbehaviour::Spam, quic)?;
+            return Ok(Vec::new());
+        }
+        if target == *self_id {
+            if source.node_id == *self_id || addrs.len() > MAX_ADDRS_PER_RECORD {
+                self.penalise(peer_addr, Misbehaviour::Spam, quic)?;
+                return Ok(Vec::new());
+            }
+            if !self.introductions.take(&source.node_id, peer_addr, now)
+                && !self.important.contains(peer_addr)
+            {
+                log::debug!(
+                    "Ignoring introduction to {:?} we did not ask {:?} for",
+                    source.node_id,
+                    requester.node_id
+                );
+                return Ok(Vec::new());
+            }
+            log::debug!(
+                "Peer {:?} introduced us to {:?} at {:?}",
+                requester.node_id,
+                source.node_id,
+                addrs
+            );
+            return Ok(addrs
+                .into_iter()
+                .filter(|addr| {
+                    !self.entries.contains_addr(addr)
+                        && !self.is_banned(addr, Some(&source.node_id))
+                })
+                .map(|socket_addr| ConnectionInfo {
+                    public_id: source,
+                    socket_addr,
+                })
+                .collect());
+        }
+        if source != requester {
+            self.penalise(peer_addr, Misbehaviour::Spam, quic)?;
+            return Ok(Vec::new());
+        }
+        let info = ConnectionInfo {
+            public_id: source,
+            socket_addr: *peer_addr,
+        };
+        match self
+            .entries
+            .peer(&target)
+            .and_then(|target| nat::introductions(&info, target))
+        {
+            Some(introductions) => {
+                for (socket_addr, message) in introductions {
+                    let user_msg_bytes = (
+                        Bytes::from("Connect request"),
+                        Bytes::from(socket_addr.to_string()),
+                        Bytes::from(bincode::serialize(&message)?),
+                    );
+                    quic.send(user_msg_bytes).await?;
+                }
+            }
+            None => log::debug!(
+                "Cannot introduce {:?} to {:?}: not connected",
+                source.node_id,
+                target
+            ),
+        }
+        Ok(Vec::new())
+    }
+
+    /// Ask a relay for a circuit to `target`.
+    /// Returns the identifier of the circuit; an `Event::CircuitEstablished`
+    /// is emitted once `target` proved its identity through it.
+    pub async fn open_circuit(
+        &mut self,
+        relay_addr: &SocketAddr,
+        target: PublicId,
+        quic: &mut QuicConnection,
+    ) -> Result<u64> {
+        let circuit = rand::thread_rng().gen::<u64>();
+        let _ = self.pending_circuits.insert(circuit, (*relay_addr, target));
+        let message = Message::RelayConnect {
+            circuit,
+            target: target.node_id,
+        };
+        self.send_relay_message(relay_addr, &message, quic).await?;
+        Ok(circuit)
+    }
+
+    /// Handle a request for a circuit, as a relay.
+    /// Both ends are told about the circuit, and start the end-to-end handshake.
+    pub async fn handle_relay_connect(
+        &mut self,
+        peer_addr: &SocketAddr,
+        circuit: u64,
+        target: Hash,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        let requester = self.entries.public_id(peer_addr);
+        let target_end = self.entries.peer(&target).and_then(|peer| {
+            peer.addrs
+                .iter()
+                .find(|(_, state)| **state == ConnectionState::Connected)
+                .map(|(addr, _)| (*addr, peer.public_id))
+        });
+        let opened = match (self.relay.as_mut(), requester, target_end) {
+            (None, _, _) => Err(CircuitError::NotRelaying),
+            (Some(relay), Some(requester), Some((target_addr, target_id))) => relay
+                .open(circuit, *peer_addr, target_addr, Instant::now())
+                .map(|()| (requester, target_addr, target_id)),
+            _ => Err(CircuitError::UnknownPeer),
+        };
+        match opened {
+            Ok((requester, target_addr, target_id)) => {
+                log::debug!(
+                    "Relaying circuit {} between {:?} and {:?}",
+                    circuit,
+                    peer_addr,
+                    target_addr
+                );
+                let to_requester = Message::RelayOpened {
+                    circuit,
+                    peer: target_id,
+                };
+                self.send_relay_message(peer_addr, &to_requester, quic)
+                    .await?;
+                let to_target = Message::RelayOpened {
+                    circuit,
+                    peer: requester,
+                };
+                self.send_relay_message(&target_addr, &to_target, quic)
+                    .await
+            }
+            Err(reason) => {
+                let message = Message::RelayClosed { circuit, reason };
+                self.send_relay_message(peer_addr, &message, quic).await
+            }
+        }
+    }
+
+    /// Handle the notice of a relay that a circuit was opened,
+    /// and challenge the other end to prove its identity.
+    pub async fn handle_relay_opened(
+        &mut self,
+        identity: &Identity,
+        relay_addr: &SocketAddr,
+        circuit: u64,
+        peer: PublicId,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        if self.entries.public_id(relay_addr).is_none() || self.circuits.contains_key(&circuit) {
+            return self.penalise(relay_addr, Misbehaviour::Spam, quic);
+        }
+        let expected = match self.pending_circuits.remove(&circuit) {
+            Some((relay, target)) if relay == *relay_addr => Some(target),
+            _ => None,
+        };
+        let nonce = rand::thread_rng().gen::<u64>();
+        let _ = self.circuits.insert(
+            circuit,
+            CircuitEnd {
+                relay: *relay_addr,
+                announced: peer,
+                expected,
+                nonce,
+                authenticated: None,
+            },
+        );
+        let frame = CircuitFrame::Challenge {
+            public_id: Box::new(identity.public_id()),
+            nonce,
+        };
+        self.send_frame(relay_addr, circuit, &frame, quic).await
+    }
+
+    /// Handle bytes carried through a circuit.
+    /// As a relay, forward them to the other end within the limits of the circuit.
+    /// As an end of the circuit, run the end-to-end handshake and deliver data
+    /// only once the other end proved its identity.
+    pub async fn handle_relay_data(
+        &mut self,
+        identity: &Identity,
+        peer_addr: &SocketAddr,
+        circuit: u64,
+        payload: Vec<u8>,
+        sender: &Sender<Event>,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        if let Some(relay) = self.relay.as_mut() {
+            if let Some(other) = relay.other_end(circuit, peer_addr) {
+                let message = match relay.forward(circuit, peer_addr, payload.len(), Instant::now())
+                {
+                    Ok(other) => {
+                        let message = Message::RelayData { circuit, payload };
+                        return self.send_relay_message(&other, &message, quic).await;
+                    }
+                    Err(reason) => Message::RelayClosed { circuit, reason },
+                };
+                log::debug!("Closing relayed circuit {}: {:?}", circuit, message);
+                self.send_relay_message(peer_addr, &message, quic).await?;
+                return self.send_relay_message(&other, &message, quic).await;
+            }
+        }
+        let end = match self.circuits.get_mut(&circuit) {
+            Some(end) if end.relay == *peer_addr => end,
+            _ => {
+                log::debug!("Data for unknown circuit {} from {:?}", circuit, peer_addr);
+                return Ok(());
+            }
+        };
+        let accepted = match bincode::deserialize::<CircuitFrame>(&payload) {
+            Ok(CircuitFrame::Challenge { public_id, nonce })
+                if end.handle_challenge(&public_id) =>
+            {
+                let signed_bytes = handshake_bytes(circuit, nonce, &identity.public_id().node_id)?;
+                let signature = identity.sign_with_signing_key(&signed_bytes).to_bytes();
+                let frame = CircuitFrame::Response {
+                    signature: signature.to_vec(),
+                };
+                self.send_frame(peer_addr, circuit, &frame, quic).await?;
+                true
+            }
+            Ok(CircuitFrame::Response { signature })
+                if end.handle_response(circuit, &signature) =>
+            {
+                if let Some(peer) = end.authenticated {
+                    log::debug!("Circuit {} established with {:?}", circuit, peer.node_id);
+                    sender.send(Event::CircuitEstablished { circuit, peer })?;
+                }
+                true
+            }
+            Ok(CircuitFrame::Data(message)) => match end.authenticated {
+                Some(peer) => {
+                    sender.send(Event::CircuitMessage {
+                        circuit,
+                        peer,
+                        message,
+                    })?;
+                    true
+                }
+                None => false,
+            },
+            _ => false,
+        };
+        if !accepted {
+            log::warn!("End-to-end handshake failed on circuit {}", circuit);
+            self.close_circuit_with(circuit, CircuitError::HandshakeFailed, sender, quic)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Handle the notice that a circuit was refused or closed.
+    /// As a relay, pass it on to the other end.
+    pub async fn handle_relay_closed(
+        &mut self,
+        peer_addr: &SocketAddr,
+        circuit: u64,
+        reason: CircuitError,
+        sender: &Sender<Event>,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        if let Some(other) = self
+            .relay
+            .as_mut()
+            .and_then(|relay| relay.close(circuit, peer_addr))
+        {
+            let message = Message::RelayClosed { circuit, reason };
+            return self.send_relay_message(&other, &message, quic).await;
+        }
+        let ours = self
+            .circuits
+            .get(&circuit)
+            .is_some_and(|end| end.relay == *peer_addr)
+            || self
+                .pending_circuits
+                .get(&circuit)
+                .is_some_and(|(relay, _)| relay == peer_addr);
+        if ours {
+            let _ = self.circuits.remove(&circuit);
+            let _ = self.pending_circuits.remove(&circuit);
+            log::debug!("Circuit {} closed: {:?}", circuit, reason);
+            sender.send(Event::CircuitClosed { circuit, reason })?;
+        }
+        Ok(())
+    }
+
+    /// Send data to the other end of a circuit, once it proved its identity.
+    pub async fn send_circuit_message(
+        &mut self,
+        circuit: u64,
+        message: Vec<u8>,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        let relay_addr = match self.circuits.get(&circuit) {
+            Some(end) if end.authenticated.is_some() => end.relay,
+            _ => return Err(Error::NoCircuit),
+        };
+        self.send_frame(&relay_addr, circuit, &CircuitFrame::Data(message), quic)
+            .await
+    }
+
+    /// Close one of our circuits.
+    pub async fn close_circuit(
+        &mut self,
+        circuit: u64,
+        sender: &Sender<Event>,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        self.close_circuit_with(circuit, CircuitError::Closed, sender, quic)
+            .await
+    }
+
+    async fn close_circuit_with(
+        &mut self,
+        circuit: u64,
+        reason: CircuitError,
+        sender: &Sender<Event>,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        if let Some(end) = self.circuits.remove(&circuit) {
+            let message = Message::RelayClosed { circuit, reason };
+            self.send_relay_message(&end.relay, &message, quic).await?;
+            sender.send(Event::CircuitClosed { circuit, reason })?;
+        }
+        Ok(())
+    }
+
+    /// Close the relayed circuits which outlived their duration,
+    /// and tell the ends of circuits closed because the other end was lost.
+    /// Should be called periodically when relaying traffic.
+    pub async fn expire_circuits(&mut self, quic: &mut QuicConnection) -> Result<()> {
+        if let Some(relay) = self.relay.as_mut() {
+            for (circuit, ends) in relay.expire(Instant::now()) {
+                for end in ends {
+                    self.relay_notices
+                        .push((end, circuit, CircuitError::Expired));
+                }
+            }
+        }
+        for (peer_addr, circuit, reason) in std::mem::take(&mut self.relay_notices) {
+            let message = Message::RelayClosed { circuit, reason };
+            self.send_relay_message(&peer_addr, &message, quic).await?;
+        }
+        Ok(())
+    }
+
+    /// Close the circuits which went through, or were relayed for, a lost peer.
+    fn close_circuits_through(
+        &mut self,
+        peer_addr: &SocketAddr,
+        sender: &Sender<Event>,
+    ) -> Result<()> {
+        if let Some(relay) = self.relay.as_mut() {
+            for (circuit, other) in relay.close_peer(peer_addr) {
+                self.relay_notices
+                    .push((other, circuit, CircuitError::Closed));
+            }
+        }
+        self.pending_circuits
+            .retain(|_, (relay, _)| relay != peer_addr);
+        let lost = self
+            .circuits
+            .iter()
+            .filter(|(_, end)| end.relay == *peer_addr)
+            .map(|(circuit, _)| *circuit)
+            .collect::<Vec<_>>();
+        for circuit in lost {
+            let _ = self.circuits.remove(&circuit);
+            sender.send(Event::CircuitClosed {
+                circuit,
+                reason: CircuitError::RelayLost,
+            })?;
+        }
+        Ok(())
+    }
+
+    async fn send_frame(
+        &mut self,
+        relay_addr: &SocketAddr,
+        circuit: u64,
+        frame: &CircuitFrame,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        let message = Message::RelayData {
+            circuit,
+            payload: bincode::serialize(frame)?,
+        };
+        self.send_relay_message(relay_addr, &message, quic).await
+    }
+
+    async fn send_relay_message(
+        &mut self,
+        peer_addr: &SocketAddr,
+        message: &Message,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        let user_msg_bytes = (
+            Bytes::from("Relay"),
+            Bytes::from(peer_addr.to_string()),
+            Bytes::from(bincode::serialize(message)?),
+        );
+        quic.send(user_msg_bytes).await?;
+        Ok(())
+    }
+
+    /// Tell our active connections we are leaving the network, then close the connection.
+    pub async fn say_goodbye(&mut self, reason: &str, quic: &mut QuicConnection) -> Result<()> {
+        let peers = self
+            .active_connections()
+            .into_iter()
+            .copied()
+            .collect::<Vec<_>>();
+        let message = Message::Goodbye {
+            reason: reason.to_string(),
+        };
+        for peer_addr in peers {
+            let user_msg_bytes = (
+                Bytes::from("Goodbye"),
+                Bytes::from(peer_addr.to_string()),
+                Bytes::from(bincode::serialize(&message)?),
+            );
+            quic.send(user_msg_bytes).await?;
+            self.disconnect(&peer_addr);
+        }
+        quic.close(Some(reason.to_string()));
+        Ok(())
+    }
+
+    /// Handle a peer leaving the network.
+    /// Its routes are withdrawn at once rather than when they time out.
+    pub fn handle_goodbye(
+        &mut self,
+        peer_addr: &SocketAddr,
+        reason: &str,
+        sender: &Sender<Event>,
+    ) -> Result<()> {
+        self.handle_connection_failure(peer_addr, &format!("Peer left: {}", reason), sender)
+    }
+
+    /// Start tearing down the connection with a peer.
+    /// The peer no longer counts as an active connection, and is forgotten
+    /// once the connection fails or the handshake timeout runs out.
+    pub fn disconnect(&mut self, peer_addr: &SocketAddr) {
+        if let Some(state) = self.entries.state_mut(peer_addr) {
+            *state = ConnectionState::Disconnecting;
+            if let Some(liveness) = self.liveness.get_mut(peer_addr) {
+                liveness.start_closing(Instant::now());
+            }
+        }
+    }
+
+    /// Ping quiet peers, and drop the peers which did not identify themselves
+    /// or did not answer in time. Should be called periodically.
+    pub async fn check_liveness(
+        &mut self,
+        timeouts: &Timeouts,
+        sender: &Sender<Event>,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        let mut idle = Vec::new();
+        let mut quiet = Vec::new();
+        for (peer_addr, _, state) in self.entries.iter() {
+            let liveness = match self.liveness.get(peer_addr) {
+                Some(liveness) => liveness,
+                None => continue,
+            };
+            match state {
+                ConnectionState::Connecting | ConnectionState::Incoming
+                    if liveness.is_older_than(now, timeouts.handshake_timeout) =>
+                {
+                    expired.push((*peer_addr, "Handshake timed out"));
+                }
+                ConnectionState::Connected if liveness.is_idle(now, timeouts.idle_timeout) => {
+                    idle.push(*peer_addr);
+                }
+                ConnectionState::Connected
+                    if liveness.needs_ping(now, timeouts.keepalive_interval) =>
+                {
+                    quiet.push(*peer_addr);
+                }
+                ConnectionState::Disconnecting
+                    if liveness.is_closing_for(now, timeouts.handshake_timeout) =>
+                {
+                    expired.push((*peer_addr, "Disconnected"));
+                }
+                _ => {}
+            }
+        }
+        for (peer_addr, reason) in expired {
+            self.handle_connection_failure(&peer_addr, reason, sender)?;
+        }
+        for peer_addr in idle {
+            let node_id = self.peer_id(&peer_addr).map(|id| id.node_id);
+            self.handle_connection_failure(&peer_addr, "Idle timeout", sender)?;
+            let _ = self.record_misbehaviour(&peer_addr, node_id.as_ref(), Misbehaviour::Timeout);
+        }
+        for peer_addr in quiet {
+            let nonce = rand::thread_rng().gen::<u64>();
+            if let Some(liveness) = self.liveness.get_mut(&peer_addr) {
+                liveness.ping_sent(nonce, now);
+            }
+            let user_msg_bytes = (
+                Bytes::from("Ping"),
+                Bytes::from(peer_addr.to_string()),
+                Bytes::from(bincode::serialize(&Message::Ping { nonce })?),
             );
             quic.send(user_msg_bytes).await?;
         }
@@ -94,97 +1122,167 @@ impl Connection {
     }
 
     /// Handle a node-identification message from a peer.
-    /// Returns `true` if an agent should be deployed.
+    /// Returns whether the connection was established by it, and whether an agent
+    /// should be deployed.
     pub async fn handle_peer_identification(
         &mut self,
-        self_id: &PublicId,
+        identity: &Identity,
         peer: &mut QuicEndpoint,
         peer_id: &PublicId,
         sender: &Sender<Event>,
         quic: &mut QuicConnection,
-    ) -> Result<bool> {
+    ) -> Result<Identified> {
         let peer_addr = peer.local_addr();
         log::debug!(
             "Peer at {:?} has identified itself as {:?}",
-            &peer_addr,
-            &peer_id
+            peer_addr,
+            peer_id
         );
-        let mut connected = false;
-        if let Entry::Occupied(mut entry) = self.entries.entry(peer_addr) {
-            let (id, state) = entry.get_mut();
-            if id.is_none() {
-                let _ = std::mem::replace(id, Some(*peer_id));
-                let _ = std::mem::replace(state, ConnectionState::Connected);
-
-                sender.send(Event::ConnectedTo(*peer_id))?;
-                self.routing_table.add_direct_connection(&peer_id.node_id);
-                self.routing_table.increment_version();
-                connected = true;
-
-                log::debug!("Successfully connected with peer at {:?}", peer_addr);
-                log::debug!("Our connections: {:?}", &self.entries);
-            }
+        if !peer_id.has_valid_node_id() {
+            log::warn!(
+                "Peer at {:?} claimed a node ID not derived from its key",
+                peer_addr
+            );
+            self.penalise(&peer_addr, Misbehaviour::InvalidSignature, quic)?;
+            return Ok(Identified::default());
         }
-        if connected && !self.is_bootstrapped() {
+        if self.is_banned(&peer_addr, Some(&peer_id.node_id)) {
+            log::debug!("Disconnecting from banned node {:?}", peer_id.node_id);
+            quic.close(Some("Banned".to_string()));
+            let _ = self.remove_peer(&peer_addr);
+            return Ok(Identified::default());
+        }
+        let mut identified = Identified {
+            outbound: self.outbound.contains(&peer_addr),
+            ..Default::default()
+        };
+        if self.entries.contains_addr(&peer_addr) && self.entries.public_id(&peer_addr).is_none() {
+            identified.established =
+                self.establish(&identity.public_id().node_id, peer_addr, *peer_id, sender)?;
+        }
+        if identified.established && !self.is_bootstrapped() {
             self.set_bootstrapped();
-            self.share_routing_table(quic, &self_id.node_id).await?;
-            Ok(true)
-        } else {
-            Ok(false)
+            self.share_routing_table(quic, identity).await?;
+            identified.deploy_agent = true;
         }
+        Ok(identified)
+    }
+
+    /// Mark the connection at `peer_addr` as established with `public_id`.
+    /// If the peer was already connected at another address, only one of the
+    /// two connections is kept, following a rule both ends agree on.
+    /// Returns `true` if the peer was not connected before.
+    fn establish(
+        &mut self,
+        self_id: &Hash,
+        peer_addr: SocketAddr,
+        public_id: PublicId,
+        sender: &Sender<Event>,
+    ) -> Result<bool> {
+        let node_id = public_id.node_id;
+        let existing = self.entries.peer(&node_id).and_then(|peer| {
+            peer.addrs
+                .iter()
+                .find(|(addr, state)| **addr != peer_addr && **state == ConnectionState::Connected)
+                .map(|(addr, _)| *addr)
+        });
+        let _ = self.entries.identify(&peer_addr, public_id);
+        if let Some(state) = self.entries.state_mut(&peer_addr) {
+            *state = ConnectionState::Connected;
+        }
+        if self.reconnect.cancel(&peer_addr).is_some() {
+            log::info!("Reconnected to peer at {:?}", peer_addr);
+        }
+        if let Some(existing) = existing {
+            let duplicate = if keeps_new_connection(
+                self_id,
+                &node_id,
+                self.outbound.contains(&existing),
+                self.outbound.contains(&peer_addr),
+            ) {
+                existing
+            } else {
+                peer_addr
+            };
+            log::debug!(
+                "Duplicate connection with {:?}; dropping the one at {:?}",
+                node_id,
+                duplicate
+            );
+            self.disconnect(&duplicate);
+            return Ok(false);
+        }
+        sender.send(Event::ConnectedTo(public_id))?;
+        self.routing_table.add_direct_connection(&node_id);
+        self.routing_table.increment_version();
+        log::debug!("Successfully connected with peer at {:?}", peer_addr);
+        log::debug!("Our connections: {:?}", self.entries);
+        Ok(true)
     }
 
     /// Connect to a peer.
     /// Used when both a peer's public identity and socket address are known.
+    /// Returns `false` if the connection was not started, as the peer is banned
+    /// or no outbound slot is left for it.
     pub async fn connect_to(
         &mut self,
         info: &ConnectionInfo,
         quic: &mut QuicEndpoint,
-    ) -> Result<()> {
+    ) -> Result<bool> {
         log::trace!("Connecting to: {:?}", info);
-        let _ = self.entries.insert(
+        if !self.reserve_outbound(&info.socket_addr, Some(&info.public_id.node_id)) {
+            return Ok(false);
+        }
+        self.entries.insert(
             info.socket_addr,
-            (Some(info.public_id), ConnectionState::Connecting),
+            Some(info.public_id),
+            ConnectionState::Connecting,
         );
+        let _ = self
+            .liveness
+            .insert(info.socket_addr, Liveness::new(Instant::now()));
         let _ = quic.connect_to(&info.socket_addr).await?;
-        Ok(())
+        Ok(true)
     }
 
     /// Handle a successful incoming connection.
     /// Returns `true` if an agent is to be deployed.
     pub async fn handle_successful_connection(
         &mut self,
-        self_id: &PublicId,
+        identity: &Identity,
         peer: &mut QuicEndpoint,
         sender: &Sender<Event>,
         quic: &mut QuicConnection,
     ) -> Result<bool> {
         let peer_addr = peer.local_addr();
+        let self_id = identity.public_id();
         let mut connected = false;
-        if let Some((public_identity, state)) = self.entries.get_mut(&peer_addr) {
+        if self.is_banned(&peer_addr, None) {
+            log::debug!("Refusing connection from banned peer at {:?}", peer_addr);
+            quic.close(Some("Banned".to_string()));
+            return Ok(false);
+        }
+        if self.entries.contains_addr(&peer_addr) {
             let user_msg_bytes = (
                 Bytes::from("Public identity"),
                 Bytes::from(peer_addr.to_string()),
-                Bytes::from(bincode::serialize(&Message::Identification(*self_id))?),
+                Bytes::from(bincode::serialize(&Message::Identification(self_id))?),
             );
             quic.send(user_msg_bytes).await?;
+            self.reflect_address(&peer_addr, quic).await?;
 
-            if let Some(id) = public_identity {
-                let _ = std::mem::replace(state, ConnectionState::Connected);
-
-                sender.send(Event::ConnectedTo(*id))?;
-
-                self.routing_table.add_direct_connection(&id.node_id);
-                self.routing_table.increment_version();
-                connected = true;
-                log::debug!("Successfully connected with peer at {:?}", peer_addr);
-                log::debug!("Our connections: {:?}", &self.entries);
+            if let Some(id) = self.entries.public_id(&peer_addr) {
+                connected = self.establish(&self_id.node_id, peer_addr, id, sender)?;
             } else {
                 log::debug!("Waiting to identify peer at {:?}", peer_addr);
             }
-        } else if self.entries.len() == MAX_CONNECTION_LEN {
-            let connections = self.entries.keys().copied().collect::<Vec<SocketAddr>>();
-            log::warn!("Too many connections! Disconnecting from {:?}", &peer_addr);
+        } else if !self
+            .limits
+            .accepts_inbound(self.inbound_count(), &peer_addr)
+            && !self.evict_inbound(&peer_addr)
+        {
+            let connections = self.entries.addrs().copied().collect::<Vec<SocketAddr>>();
+            log::warn!("Too many connections! Disconnecting from {:?}", peer_addr);
             let user_msg_bytes = (
                 Bytes::from("Contacts"),
                 Bytes::from(peer_addr.to_string()),
@@ -193,60 +1291,180 @@ impl Connection {
             quic.send_with(user_msg_bytes, 1).await?;
             return Ok(false);
         } else {
+            self.entries
+                .insert(peer_addr, None, ConnectionState::Incoming);
             let _ = self
-                .entries
-                .insert(peer_addr, (None, ConnectionState::Incoming));
+                .liveness
+                .insert(peer_addr, Liveness::new(Instant::now()));
             let user_msg_bytes = (
                 Bytes::from("Public identity"),
                 Bytes::from(peer_addr.to_string()),
-                Bytes::from(bincode::serialize(&Message::Identification(*self_id))?),
+                Bytes::from(bincode::serialize(&Message::Identification(self_id))?),
             );
             quic.send(user_msg_bytes).await?;
+            self.reflect_address(&peer_addr, quic).await?;
         }
         if connected && !self.is_bootstrapped() {
             self.set_bootstrapped();
-            self.share_routing_table(quic, &self_id.node_id).await?;
+            self.share_routing_table(quic, identity).await?;
             Ok(true)
         } else {
-            log::trace!("Our connections: {:?}", &self.entries);
+            log::trace!("Our connections: {:?}", self.entries);
             Ok(false)
         }
     }
 
     /// Disseminate appropriate information on connection failure.
+    /// The routes through the peer are removed, and reported as lost.
+    /// Lost bootstrap nodes and allow-listed peers are scheduled for reconnection.
     pub fn handle_connection_failure(
         &mut self,
-        quic: &mut QuicEndpoint,
+        peer_addr: &SocketAddr,
         err_msg: &str,
+        sender: &Sender<Event>,
     ) -> Result<()> {
-        let peer_addr = quic.local_addr();
         log::info!(
             "Lost connection with peer at {:?} due to {}",
-            &peer_addr,
+            peer_addr,
             err_msg
         );
-        if let Some((id, _state)) = self.entries.remove(&peer_addr) {
-            log::info!(
-                "Disconnected from peer at {:?} with ID {:?}",
-                &peer_addr,
-                id
-            );
+        let mut public_id = None;
+        if let Some((id, state, lost)) = self.remove_peer(peer_addr) {
+            log::info!("Disconnected from peer at {:?} with ID {:?}", peer_addr, id);
+            self.close_circuits_through(peer_addr, sender)?;
+            for node_id in lost {
+                sender.send(Event::RouteLost(node_id))?;
+            }
+            if matches!(
+                state,
+                ConnectionState::Connected | ConnectionState::Disconnecting
+            ) {
+                sender.send(Event::Disconnected {
+                    socket_addr: *peer_addr,
+                    peer: id,
+                    reason: err_msg.to_string(),
+                })?;
+            }
+            public_id = id;
         } else {
             log::warn!(
                 "Connection with peer at {:?} was dropped before the operation",
-                &peer_addr
+                peer_addr
+            );
+        }
+        if !self.record_reconnect_failure(peer_addr, err_msg, sender)?
+            && self.important.contains(peer_addr)
+        {
+            self.reconnect.schedule(
+                *peer_addr,
+                public_id,
+                Instant::now(),
+                &mut rand::thread_rng(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Record that the latest reconnection attempt to a peer failed.
+    /// Returns `false` if no attempt was made to reach the peer.
+    fn record_reconnect_failure(
+        &mut self,
+        peer_addr: &SocketAddr,
+        err_msg: &str,
+        sender: &Sender<Event>,
+    ) -> Result<bool> {
+        let failure = match self.reconnect.record_failure(peer_addr) {
+            Some(failure) => failure,
+            None => return Ok(false),
+        };
+        sender.send(Event::ConnectionFailure {
+            peer: *peer_addr,
+            attempt: failure.attempt,
+            err: err_msg.to_string(),
+        })?;
+        if failure.gave_up {
+            log::warn!("Giving up on reconnecting to {:?}", peer_addr);
+        }
+        Ok(true)
+    }
+
+    /// Retrieves the reconnection attempts which are due, taking an outbound slot for each.
+    /// Attempts to peers we are connected or connecting to again are cleared, and those
+    /// to peers which are banned or for which no slot is left are recorded as failed.
+    fn due_reconnections(
+        &mut self,
+        now: Instant,
+        sender: &Sender<Event>,
+    ) -> Result<Vec<ReconnectAttempt>> {
+        let mut attempts = Vec::new();
+        for attempt in self.reconnect.due(now, &mut rand::thread_rng()) {
+            let peer_addr = attempt.socket_addr;
+            if self.entries.contains_addr(&peer_addr) {
+                let _ = self.reconnect.cancel(&peer_addr);
+                continue;
+            }
+            let node_id = attempt.public_id.map(|public_id| public_id.node_id);
+            if !self.reserve_outbound(&peer_addr, node_id.as_ref()) {
+                let _ =
+                    self.record_reconnect_failure(&peer_addr, "Not allowed to connect", sender)?;
+                continue;
+            }
+            attempts.push(attempt);
+        }
+        Ok(attempts)
+    }
+
+    /// Make the reconnection attempts which are due.
+    /// Should be called periodically.
+    pub async fn reconnect(
+        &mut self,
+        sender: &Sender<Event>,
+        quic: &mut QuicEndpoint,
+    ) -> Result<()> {
+        for attempt in self.due_reconnections(Instant::now(), sender)? {
+            log::debug!(
+                "Reconnection attempt {} to {:?}",
+                attempt.attempt,
+                attempt.socket_addr
             );
+            let result = match attempt.public_id {
+                Some(public_id) => {
+                    let info = ConnectionInfo {
+                        public_id,
+                        socket_addr: attempt.socket_addr,
+                    };
+                    self.connect_to(&info, quic).await.map(|_| ())
+                }
+                None => self.bootstrap_with(&attempt.socket_addr, quic).await,
+            };
+            if let Err(err) = result {
+                self.handle_connection_failure(&attempt.socket_addr, &err.to_string(), sender)?;
+            }
         }
         Ok(())
     }
 
-    /// Bootstrap to the network using all our contacts
+    /// Reconnect to a peer whenever the connection with it is lost.
+    pub fn mark_important(&mut self, socket_addr: SocketAddr) {
+        let _ = self.important.insert(socket_addr);
+    }
+
+    /// Retrieves the reconnection scheduler
+    pub fn reconnect_scheduler(&self) -> &ReconnectScheduler {
+        &self.reconnect
+    }
+
+    /// Bootstrap to the network using all our contacts.
+    /// Nodes from network groups we are least connected to are tried first.
     pub async fn bootstrap(&mut self, nodes: &[SocketAddr], quic: &mut QuicEndpoint) -> Result<()> {
-        for node in nodes {
-            if self.entries.len() == MAX_CONNECTION_LEN {
+        let counts = netgroup::group_counts(&self.outbound);
+        let mut nodes = nodes.to_vec();
+        nodes.sort_by_key(|node| counts.get(&netgroup(&node.ip())).copied().unwrap_or(0));
+        for node in &nodes {
+            if self.limits.has_target_outbound(self.outbound_count()) {
                 break;
             }
-            if !self.entries.contains_key(node) {
+            if !self.entries.contains_addr(node) {
                 self.bootstrap_with(node, quic).await?;
             }
         }
@@ -260,13 +1478,90 @@ impl Connection {
         socket_addr: &SocketAddr,
         quic: &mut QuicEndpoint,
     ) -> Result<()> {
+        if !self.reserve_outbound(socket_addr, None) {
+            return Ok(());
+        }
+        self.entries
+            .insert(*socket_addr, None, ConnectionState::Connecting);
         let _ = self
-            .entries
-            .insert(*socket_addr, (None, ConnectionState::Connecting));
+            .liveness
+            .insert(*socket_addr, Liveness::new(Instant::now()));
         let _ = quic.connect_to(socket_addr).await?;
         Ok(())
     }
 
+    /// Take an outbound slot for a connection to `socket_addr`.
+    /// Returns `false` if the peer is banned or all outbound slots it may use are taken.
+    fn reserve_outbound(&mut self, socket_addr: &SocketAddr, node_id: Option<&Hash>) -> bool {
+        if self.is_banned(socket_addr, node_id) {
+            log::debug!("Not connecting to banned peer at {:?}", socket_addr);
+            return false;
+        }
+        if self.outbound.contains(socket_addr) {
+            return true;
+        }
+        if !self
+            .limits
+            .accepts_outbound(self.outbound_count(), socket_addr)
+        {
+            log::debug!("No outbound slot left for {:?}", socket_addr);
+            return false;
+        }
+        let group = netgroup(&socket_addr.ip());
+        let in_group = self
+            .outbound
+            .iter()
+            .filter(|addr| netgroup(&addr.ip()) == group)
+            .count();
+        if in_group >= self.limits.max_per_netgroup && !self.limits.allow_list.contains(socket_addr)
+        {
+            log::debug!("Too many outbound connections to {:?}", group);
+            return false;
+        }
+        let _ = self.outbound.insert(*socket_addr);
+        true
+    }
+
+    /// Make room for an inbound connection from `newcomer` by dropping
+    /// an inbound peer from the most over-represented network group.
+    /// Allow-listed peers are never evicted.
+    fn evict_inbound(&mut self, newcomer: &SocketAddr) -> bool {
+        let inbound = self
+            .entries
+            .addrs()
+            .filter(|addr| !self.outbound.contains(addr) && !self.limits.allow_list.contains(addr))
+            .map(|addr| (*addr, self.scores.addr_score(addr)))
+            .collect::<Vec<_>>();
+        match netgroup::eviction_candidate(&inbound, newcomer) {
+            Some(evicted) => {
+                log::info!("Evicting inbound peer at {:?} for {:?}", evicted, newcomer);
+                let _ = self.remove_peer(&evicted);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Retrieves the connection limits
+    pub fn limits(&self) -> &ConnectionLimits {
+        &self.limits
+    }
+
+    /// Number of connections we initiated
+    pub fn outbound_count(&self) -> usize {
+        self.outbound.len()
+    }
+
+    /// Number of connections initiated by peers.
+    /// Outbound slots are taken before the connection exists, so they are not
+    /// subtracted from the connections but told apart from them.
+    pub fn inbound_count(&self) -> usize {
+        self.entries
+            .addrs()
+            .filter(|addr| !self.outbound.contains(addr))
+            .count()
+    }
+
     /// Checks if a node is bootstrapped to the network
     pub fn is_bootstrapped(&self) -> bool {
         self.is_bootstrapped
@@ -287,12 +1582,38 @@ impl Connection {
         &self.entries
     }
 
+    /// Retrieves the public identity of the peer at a given address, if known
+    pub fn peer_id(&self, socket_addr: &SocketAddr) -> Option<PublicId> {
+        self.entries.public_id(socket_addr)
+    }
+
+    /// Retrieves the connection information of the peer at a given address,
+    /// once it identified itself on the connection.
+    pub fn identified_info(&self, socket_addr: &SocketAddr) -> Option<ConnectionInfo> {
+        match self.entries.state(socket_addr) {
+            Some(ConnectionState::Connected) => Some(ConnectionInfo {
+                public_id: self.peer_id(socket_addr)?,
+                socket_addr: *socket_addr,
+            }),
+            _ => None,
+        }
+    }
+
     /// Returns the map of active connections
     pub fn active_connections(&self) -> Vec<&SocketAddr> {
         self.entries
             .iter()
-            .filter(|(_, (_, state))| state == &ConnectionState::Connected)
-            .map(|(socket_addr, _)| socket_addr)
+            .filter(|(_, _, state)| **state == ConnectionState::Connected)
+            .map(|(socket_addr, _, _)| socket_addr)
+            .collect::<Vec<_>>()
+    }
+
+    /// Retrieves the active connections along with the node ID of each peer, if known
+    pub fn active_peers(&self) -> Vec<(&SocketAddr, Option<Hash>)> {
+        self.entries
+            .iter()
+            .filter(|(_, _, state)| **state == ConnectionState::Connected)
+            .map(|(socket_addr, public_id, _)| (socket_addr, public_id.map(|id| id.node_id)))
             .collect::<Vec<_>>()
     }
 }
@@ -302,3 +1623,92 @@ impl Default for Connection {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use peer_score::Ban;
+    use reconnect::DEFAULT_MAX_RECONNECT_DELAY;
+
+    /// Connection with a reconnection to `peer` due at the returned instant
+    fn scheduled(limits: ConnectionLimits, peer: SocketAddr) -> (Connection, Instant) {
+        let mut connection = Connection::with_parts(limits, Default::default(), Backoff::default());
+        let now = Instant::now();
+        connection
+            .reconnect
+            .schedule(peer, None, now, &mut rand::thread_rng());
+        (connection, now + DEFAULT_MAX_RECONNECT_DELAY)
+    }
+
+    #[test]
+    fn test_reconnection_to_connected_peer_is_cleared() {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let peer = SocketAddr::from(([10, 0, 0, 1], 9000));
+        let (mut connection, due) = scheduled(Default::default(), peer);
+        connection
+            .entries
+            .insert(peer, None, ConnectionState::Connecting);
+        assert!(connection
+            .due_reconnections(due, &tx)
+            .is_ok_and(|attempts| attempts.is_empty()));
+        assert!(connection.reconnect_scheduler().state(&peer).is_none());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_reconnection_to_banned_peer_fails() {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let peer = SocketAddr::from(([10, 0, 0, 1], 9000));
+        let (mut connection, due) = scheduled(Default::default(), peer);
+        let ban = Ban {
+            until: u64::MAX,
+            reason: Misbehaviour::Spam,
+        };
+        connection.peer_scores_mut().bans_mut().ban_addr(peer, ban);
+        assert!(connection
+            .due_reconnections(due, &tx)
+            .is_ok_and(|attempts| attempts.is_empty()));
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(Event::ConnectionFailure { attempt: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_reconnection_without_slot_fails() {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let peer = SocketAddr::from(([10, 0, 0, 1], 9000));
+        let limits = ConnectionLimits {
+            max_outbound: 0,
+            ..Default::default()
+        };
+        let (mut connection, due) = scheduled(limits, peer);
+        assert!(connection
+            .due_reconnections(due, &tx)
+            .is_ok_and(|attempts| attempts.is_empty()));
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(Event::ConnectionFailure { attempt: 1, .. })
+        ));
+        assert!(connection.outbound.is_empty());
+    }
+
+    #[test]
+    fn test_reserved_slots_are_not_inbound() {
+        let (tx, _rx) = crossbeam_channel::unbounded();
+        let peer = SocketAddr::from(([10, 0, 0, 1], 9000));
+        let (mut connection, due) = scheduled(Default::default(), peer);
+        assert!(connection
+            .due_reconnections(due, &tx)
+            .is_ok_and(|attempts| attempts.len() == 1));
+        assert_eq!(
+            (connection.outbound_count(), connection.inbound_count()),
+            (1, 0)
+        );
+        let inbound = SocketAddr::from(([10, 0, 0, 2], 9000));
+        connection
+            .entries
+            .insert(inbound, None, ConnectionState::Connecting);
+        assert_eq!(connection.inbound_count(), 1);
+    }
+}
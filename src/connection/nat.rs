@@ -0,0 +1,363 @@
+use super::{
+    connection_types::{ConnectionInfo, ConnectionState, PeerConnections},
+    peer_exchange::MAX_ADDRS_PER_RECORD,
+};
+use crate::{crypto::hash::Hash, Message};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// Time during which an introduction we asked a rendezvous peer for is awaited
+pub const INTRODUCTION_TIMEOUT: Duration = Duration::from_secs(30);
+/// Maximum number of connect requests a peer may send within `INTRODUCTION_WINDOW`
+pub const MAX_INTRODUCTIONS_PER_PEER: usize = 4;
+/// Time over which the connect requests of a peer are counted
+pub const INTRODUCTION_WINDOW: Duration = Duration::from_secs(60);
+
+/// Our external addresses, as reflected by the peers we are connected to
+#[derive(Debug, Clone, Default)]
+pub struct ObservedAddrs {
+    reports: HashMap<SocketAddr, SocketAddr>,
+}
+
+impl ObservedAddrs {
+    /// Creates a new, empty `ObservedAddrs`.
+    pub fn new() -> Self {
+        Self {
+            reports: Default::default(),
+        }
+    }
+
+    /// Record the address a peer sees us at, replacing its previous report.
+    pub fn record(&mut self, reporter: SocketAddr, observed: SocketAddr) {
+        let _ = self.reports.insert(reporter, observed);
+    }
+
+    /// Forget the report of a peer we are no longer connected to.
+    pub fn forget(&mut self, reporter: &SocketAddr) {
+        let _ = self.reports.remove(reporter);
+    }
+
+    /// The address reported by the most peers, if any.
+    /// A single peer cannot make us believe we moved unless it is our only peer.
+    pub fn external_addr(&self) -> Option<SocketAddr> {
+        let mut votes = HashMap::<SocketAddr, usize>::new();
+        for observed in self.reports.values() {
+            *votes.entry(*observed).or_default() += 1;
+        }
+        votes
+            .into_iter()
+            .max_by(|(a, a_votes), (b, b_votes)| a_votes.cmp(b_votes).then(b.cmp(a)))
+            .map(|(addr, _)| addr)
+    }
+
+    /// Checks if we are behind a NAT: our peers see us at an address other than our own.
+    pub fn is_behind_nat(&self, local_addr: &SocketAddr) -> bool {
+        self.external_addr()
+            .is_some_and(|external| external != *local_addr)
+    }
+}
+
+/// The introductions we asked rendezvous peers for, and the connect requests
+/// each peer sent recently. Each connect request we act on makes us dial or send
+/// messages, so only solicited ones are followed and every peer is rate-limited.
+#[derive(Debug, Clone, Default)]
+pub struct Introductions {
+    pending: HashMap<Hash, (SocketAddr, Instant)>,
+    recent: HashMap<SocketAddr, Vec<Instant>>,
+}
+
+impl Introductions {
+    /// Creates a new, empty `Introductions`.
+    pub fn new() -> Self {
+        Self {
+            pending: Default::default(),
+            recent: Default::default(),
+        }
+    }
+
+    /// Record that we asked `rendezvous` to introduce us to `target`.
+    pub fn request(&mut self, target: Hash, rendezvous: SocketAddr, now: Instant) {
+        self.pending.retain(|_, (_, requested_at)| {
+            now.saturating_duration_since(*requested_at) < INTRODUCTION_TIMEOUT
+        });
+        let _ = self.pending.insert(target, (rendezvous, now));
+    }
+
+    /// Take the introduction to `target` we asked `rendezvous` for.
+    /// Returns false if we did not ask for it, or no longer await it.
+    pub fn take(&mut self, target: &Hash, rendezvous: &SocketAddr, now: Instant) -> bool {
+        match self.pending.get(target) {
+            Some((asked, requested_at))
+                if asked == rendezvous
+                    && now.saturating_duration_since(*requested_at) < INTRODUCTION_TIMEOUT =>
+            {
+                let _ = self.pending.remove(target);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Count a connect request from a peer.
+    /// Returns false if the peer sent too many within the window.
+    pub fn allow(&mut self, peer: SocketAddr, now: Instant) -> bool {
+        self.recent.retain(|_, sent| {
+            sent.retain(|sent_at| now.saturating_duration_since(*sent_at) < INTRODUCTION_WINDOW);
+            !sent.is_empty()
+        });
+        let sent = self.recent.entry(peer).or_default();
+        if sent.len() >= MAX_INTRODUCTIONS_PER_PEER {
+            return false;
+        }
+        sent.push(now);
+        true
+    }
+}
+
+/// Messages a rendezvous peer sends to introduce `source` and `target` to each other.
+/// The target learns the address `source` was observed at, and the source the
+/// addresses of the target; both sides dial each other on receipt, which opens
+/// a hole in both NATs. Returns `None` if the target is not connected.
+pub fn introductions(
+    source: &ConnectionInfo,
+    target: &PeerConnections,
+) -> Option<Vec<(SocketAddr, Message)>> {
+    let target_addrs = target
+        .addrs
+        .iter()
+        .filter(|(_, state)| **state == ConnectionState::Connected)
+        .map(|(addr, _)| *addr)
+        .take(MAX_ADDRS_PER_RECORD)
+        .collect::<Vec<_>>();
+    let target_addr = *target_addrs.first()?;
+    Some(vec![
+        (
+            target_addr,
+            Message::ConnectRequest {
+                source: source.public_id,
+                target: target.public_id.node_id,
+                addrs: vec![source.socket_addr],
+            },
+        ),
+        (
+            source.socket_addr,
+            Message::ConnectRequest {
+                source: target.public_id,
+                target: source.public_id.node_id,
+                addrs: target_addrs,
+            },
+        ),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        connection::connection_types::ConnectionMap, crypto::hash::Hash,
+        test_utils::connection_info,
+    };
+    use std::collections::HashSet;
+
+    /// A NAT with endpoint-independent mapping and address-dependent filtering:
+    /// every internal address keeps the same external port, but packets are let in
+    /// only from hosts the internal address sent a packet to.
+    struct Nat {
+        external_ip: [u8; 4],
+        mappings: HashMap<SocketAddr, SocketAddr>,
+        contacted: HashSet<(SocketAddr, SocketAddr)>,
+    }
+
+    impl Nat {
+        fn new(external_ip: [u8; 4]) -> Self {
+            Self {
+                external_ip,
+                mappings: HashMap::new(),
+                contacted: HashSet::new(),
+            }
+        }
+
+        fn outbound(&mut self, internal: SocketAddr, remote: SocketAddr) -> SocketAddr {
+            let port = 40000 + self.mappings.len() as u16;
+            let external_ip = self.external_ip;
+            let external = *self
+                .mappings
+                .entry(internal)
+                .or_insert_with(|| SocketAddr::from((external_ip, port)));
+            let _ = self.contacted.insert((internal, remote));
+            external
+        }
+
+        fn inbound(&self, external: &SocketAddr, remote: &SocketAddr) -> Option<SocketAddr> {
+            self.mappings
+                .iter()
+                .find(|(_, mapped)| *mapped == external)
+                .map(|(internal, _)| *internal)
+                .filter(|internal| self.contacted.contains(&(*internal, *remote)))
+        }
+    }
+
+    /// In-process transport: hosts are either public or behind one of the NATs.
+    struct SimNet {
+        nats: Vec<Nat>,
+        behind: HashMap<SocketAddr, usize>,
+    }
+
+    impl SimNet {
+        /// Send a packet from a host to an address.
+        /// Returns the source address seen by the receiver if the packet got through.
+        fn send(&mut self, from: SocketAddr, to: SocketAddr) -> Option<SocketAddr> {
+            let source = match self.behind.get(&from) {
+                Some(nat) => self.nats[*nat].outbound(from, to),
+                None => from,
+            };
+            match self
+                .nats
+                .iter()
+                .find(|nat| nat.external_ip == ip_bytes(&to))
+            {
+                Some(nat) => nat.inbound(&to, &source).map(|_| source),
+                None => Some(source),
+            }
+        }
+    }
+
+    fn ip_bytes(addr: &SocketAddr) -> [u8; 4] {
+        match addr {
+            SocketAddr::V4(addr) => addr.ip().octets(),
+            SocketAddr::V6(_) => [0; 4],
+        }
+    }
+
+    fn dial_targets(message: &Message) -> Vec<SocketAddr> {
+        match message {
+            Message::ConnectRequest { addrs, .. } => addrs.clone(),
+            _ => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_external_addr_by_majority() {
+        let local = SocketAddr::from(([192, 168, 1, 2], 9000));
+        let external = SocketAddr::from(([203, 0, 113, 7], 40000));
+        let mut observed = ObservedAddrs::new();
+        assert!(!observed.is_behind_nat(&local));
+
+        observed.record(SocketAddr::from(([10, 0, 0, 1], 9000)), external);
+        observed.record(SocketAddr::from(([10, 0, 0, 2], 9000)), external);
+        observed.record(SocketAddr::from(([10, 0, 0, 3], 9000)), local);
+        assert_eq!(observed.external_addr(), Some(external));
+        assert!(observed.is_behind_nat(&local));
+
+        observed.forget(&SocketAddr::from(([10, 0, 0, 1], 9000)));
+        observed.forget(&SocketAddr::from(([10, 0, 0, 2], 9000)));
+        assert!(!observed.is_behind_nat(&local));
+    }
+
+    #[test]
+    fn test_introductions() {
+        let now = Instant::now();
+        let rendezvous = SocketAddr::from(([10, 0, 0, 1], 9000));
+        let other = SocketAddr::from(([10, 0, 0, 2], 9000));
+        let target = Hash::random();
+        let mut introductions = Introductions::new();
+
+        // Only the introduction we asked for, through the peer we asked, is followed, once.
+        assert!(!introductions.take(&target, &rendezvous, now));
+        introductions.request(target, rendezvous, now);
+        assert!(!introductions.take(&Hash::random(), &rendezvous, now));
+        assert!(!introductions.take(&target, &other, now));
+        assert!(introductions.take(&target, &rendezvous, now));
+        assert!(!introductions.take(&target, &rendezvous, now));
+        introductions.request(target, rendezvous, now);
+        assert!(!introductions.take(&target, &rendezvous, now + INTRODUCTION_TIMEOUT));
+
+        // Each peer may send a few connect requests per window.
+        for _ in 0..MAX_INTRODUCTIONS_PER_PEER {
+            assert!(introductions.allow(rendezvous, now));
+        }
+        assert!(!introductions.allow(rendezvous, now));
+        assert!(introductions.allow(other, now));
+        assert!(introductions.allow(rendezvous, now + INTRODUCTION_WINDOW));
+    }
+
+    #[test]
+    fn test_hole_punch_through_rendezvous() {
+        let relay = SocketAddr::from(([198, 51, 100, 1], 9000));
+        let alice = SocketAddr::from(([192, 168, 1, 2], 9000));
+        let bob = SocketAddr::from(([192, 168, 1, 2], 9001));
+        let mut net = SimNet {
+            nats: vec![Nat::new([203, 0, 113, 1]), Nat::new([203, 0, 113, 2])],
+            behind: [(alice, 0), (bob, 1)].into_iter().collect(),
+        };
+
+        // Both sides reach the rendezvous peer, which reflects their external addresses.
+        let mut relay_entries = ConnectionMap::new();
+        let alice_info = connection_info(Hash::random(), 0);
+        let bob_info = connection_info(Hash::random(), 0);
+        let mut alice_observed = ObservedAddrs::new();
+        for (host, info) in [(alice, &alice_info), (bob, &bob_info)] {
+            let seen = net.send(host, relay);
+            assert!(seen.is_some_and(|seen| seen != host));
+            let seen = seen.unwrap_or(host);
+            relay_entries.insert(seen, Some(info.public_id), ConnectionState::Connected);
+            if host == alice {
+                alice_observed.record(relay, seen);
+            }
+        }
+        assert!(alice_observed.is_behind_nat(&alice));
+        let alice_external = alice_observed.external_addr().unwrap_or(alice);
+
+        // Dialling the other side directly is filtered by its NAT.
+        let bob_external = net.nats[1].mappings[&bob];
+        assert_eq!(net.send(alice, bob_external), None);
+
+        // The rendezvous peer introduces them to each other.
+        let source = ConnectionInfo {
+            public_id: alice_info.public_id,
+            socket_addr: alice_external,
+        };
+        let target = relay_entries.peer(&bob_info.public_id.node_id);
+        let intros = target.and_then(|target| introductions(&source, target));
+        let intros = intros.unwrap_or_default();
+        assert_eq!(intros.len(), 2);
+        let bob_dials = dial_targets(&intros[0].1);
+        let alice_dials = dial_targets(&intros[1].1);
+        assert_eq!(
+            (intros[0].0, bob_dials.clone()),
+            (bob_external, vec![alice_external])
+        );
+        assert_eq!(
+            (intros[1].0, alice_dials.clone()),
+            (alice_external, vec![bob_external])
+        );
+
+        // Both sides dial at the same time: the first packets open both NATs,
+        // so the retries get through in both directions.
+        let mut reached = (false, false);
+        for _ in 0..2 {
+            reached.0 |= alice_dials
+                .iter()
+                .any(|addr| net.send(alice, *addr).is_some());
+            reached.1 |= bob_dials.iter().any(|addr| net.send(bob, *addr).is_some());
+        }
+        assert_eq!(reached, (true, true));
+    }
+
+    #[test]
+    fn test_no_introduction_to_unknown_peer() {
+        let info = connection_info(Hash::random(), 9000);
+        let other = connection_info(Hash::random(), 9001);
+        let mut entries = ConnectionMap::new();
+        entries.insert(
+            other.socket_addr,
+            Some(other.public_id),
+            ConnectionState::Connecting,
+        );
+        let target = entries.peer(&other.public_id.node_id);
+        assert!(target.is_some_and(|target| introductions(&info, target).is_none()));
+    }
+}
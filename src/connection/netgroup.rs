@@ -0,0 +1,123 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+};
+
+/// Coarse network group of an address: /16 for IPv4, /32 for IPv6.
+/// Addresses in the same group are likely controlled by the same operator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum NetGroup {
+    /// First two octets of an IPv4 address
+    V4([u8; 2]),
+    /// First four octets of an IPv6 address
+    V6([u8; 4]),
+}
+
+impl NetGroup {
+    /// Bytes identifying the group
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Self::V4(prefix) => prefix,
+            Self::V6(prefix) => prefix,
+        }
+    }
+}
+
+/// Compute the network group of an address.
+/// IPv4-mapped IPv6 addresses are grouped with their IPv4 counterparts.
+pub fn netgroup(ip: &IpAddr) -> NetGroup {
+    match ip.to_canonical() {
+        IpAddr::V4(v4) => {
+            let octets = v4.octets();
+            NetGroup::V4([octets[0], octets[1]])
+        }
+        IpAddr::V6(v6) => {
+            let octets = v6.octets();
+            NetGroup::V6([octets[0], octets[1], octets[2], octets[3]])
+        }
+    }
+}
+
+/// Count the addresses falling into each network group
+pub fn group_counts<'a, I>(addrs: I) -> HashMap<NetGroup, usize>
+where
+    I: IntoIterator<Item = &'a SocketAddr>,
+{
+    let mut counts = HashMap::new();
+    for addr in addrs {
+        *counts.entry(netgroup(&addr.ip())).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// Pick the inbound peer to evict to make room for `newcomer`.
+/// The peer comes from the most over-represented group, and has the lowest
+/// reputation within it. Nobody is evicted if that group holds a single peer,
+/// or if the newcomer belongs to it.
+pub fn eviction_candidate(
+    peers: &[(SocketAddr, i64)],
+    newcomer: &SocketAddr,
+) -> Option<SocketAddr> {
+    let (group, count) = group_counts(peers.iter().map(|(addr, _)| addr))
+        .into_iter()
+        .max_by_key(|(group, count)| (*count, std::cmp::Reverse(*group)))?;
+    if count < 2 || group == netgroup(&newcomer.ip()) {
+        return None;
+    }
+    peers
+        .iter()
+        .filter(|(addr, _)| netgroup(&addr.ip()) == group)
+        .min_by_key(|(addr, reputation)| (*reputation, *addr))
+        .map(|(addr, _)| *addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn test_netgroup_prefixes() {
+        let a = IpAddr::from([203, 0, 113, 7]);
+        let b = IpAddr::from([203, 0, 200, 9]);
+        let c = IpAddr::from([203, 1, 113, 7]);
+        assert_eq!(netgroup(&a), netgroup(&b));
+        assert_ne!(netgroup(&a), netgroup(&c));
+
+        let d = IpAddr::from([0x2001, 0xdb8, 1, 0, 0, 0, 0, 1]);
+        let e = IpAddr::from([0x2001, 0xdb8, 0xffff, 0, 0, 0, 0, 1]);
+        let f = IpAddr::from([0x2001, 0xdb9, 1, 0, 0, 0, 0, 1]);
+        assert_eq!(netgroup(&d), netgroup(&e));
+        assert_ne!(netgroup(&d), netgroup(&f));
+    }
+
+    #[test]
+    fn test_mapped_addresses() {
+        let v4 = Ipv4Addr::new(198, 51, 100, 1);
+        let mapped: Ipv6Addr = v4.to_ipv6_mapped();
+        assert_eq!(netgroup(&IpAddr::V4(v4)), netgroup(&IpAddr::V6(mapped)));
+        assert_eq!(netgroup(&IpAddr::V4(v4)).as_bytes(), &[198, 51]);
+    }
+
+    #[test]
+    fn test_evicts_from_largest_group() {
+        let peer = |ip: [u8; 4], reputation| (SocketAddr::from((ip, 9000)), reputation);
+        let peers = vec![
+            peer([10, 1, 0, 1], 0),
+            peer([10, 1, 0, 2], -20),
+            peer([10, 1, 0, 3], 0),
+            peer([10, 2, 0, 1], -50),
+            peer([10, 2, 0, 2], 0),
+            peer([10, 3, 0, 1], -90),
+        ];
+
+        let newcomer = SocketAddr::from(([10, 4, 0, 1], 9000));
+        assert_eq!(eviction_candidate(&peers, &newcomer), Some(peers[1].0));
+
+        let crowded = SocketAddr::from(([10, 1, 0, 9], 9000));
+        assert_eq!(eviction_candidate(&peers, &crowded), None);
+
+        let diverse = vec![peer([10, 1, 0, 1], 0), peer([10, 2, 0, 1], 0)];
+        assert_eq!(eviction_candidate(&diverse, &newcomer), None);
+    }
+}
@@ -0,0 +1,286 @@
+use super::connection_types::ConnectionInfo;
+use crate::{crypto::hash::Hash, Identity, PublicId, Result};
+use ed25519_dalek::{Signature, Verifier};
+use rand::seq::IteratorRandom;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Maximum number of records carried by a single peer-exchange message
+pub const MAX_RECORDS_PER_MESSAGE: usize = 32;
+/// Maximum number of addresses a single record may advertise
+pub const MAX_ADDRS_PER_RECORD: usize = 4;
+
+const MAX_KNOWN_RECORDS: usize = 1024;
+const MAX_RECORD_AGE_SECS: u64 = 60 * 60;
+const MAX_CLOCK_SKEW_SECS: u64 = 5 * 60;
+
+/// Minimum time between two peer exchanges accepted from the same peer
+pub const MIN_EXCHANGE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Addresses of a node, signed by the node itself
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedPeerRecord {
+    /// Public identity of the node
+    pub public_id: PublicId,
+    /// Addresses the node can be reached at
+    pub addrs: Vec<SocketAddr>,
+    /// Seconds since the UNIX epoch at which the record was signed
+    pub timestamp: u64,
+    /// Signature of the node over the other fields
+    pub signature: Vec<u8>,
+}
+
+impl SignedPeerRecord {
+    /// Creates a new `SignedPeerRecord` for our own addresses.
+    pub fn new(identity: &Identity, addrs: Vec<SocketAddr>, timestamp: u64) -> Result<Self> {
+        let public_id = identity.public_id();
+        let signature = identity
+            .sign_with_signing_key(&signable_bytes(&public_id, &addrs, timestamp)?)
+            .to_bytes()
+            .to_vec();
+        Ok(Self {
+            public_id,
+            addrs,
+            timestamp,
+            signature,
+        })
+    }
+
+    /// Checks the signature of the record against the key it advertises,
+    /// and that the node ID is derived from that key.
+    pub fn verify(&self) -> bool {
+        if !self.public_id.has_valid_node_id() {
+            return false;
+        }
+        let signed_bytes = match signable_bytes(&self.public_id, &self.addrs, self.timestamp) {
+            Ok(bytes) => bytes,
+            Err(_) => return false,
+        };
+        Signature::from_bytes(&self.signature)
+            .map(|signature| {
+                self.public_id
+                    .signing_public_key
+                    .verify(&signed_bytes, &signature)
+                    .is_ok()
+            })
+            .unwrap_or(false)
+    }
+
+    /// Checks that the record is neither too old nor from the future.
+    pub fn is_fresh(&self, now: u64) -> bool {
+        self.timestamp <= now.saturating_add(MAX_CLOCK_SKEW_SECS)
+            && now.saturating_sub(self.timestamp) <= MAX_RECORD_AGE_SECS
+    }
+
+    /// Connection information for every advertised address.
+    pub fn connection_infos(&self) -> impl Iterator<Item = ConnectionInfo> + '_ {
+        self.addrs.iter().map(|socket_addr| ConnectionInfo {
+            public_id: self.public_id,
+            socket_addr: *socket_addr,
+        })
+    }
+}
+
+fn signable_bytes(public_id: &PublicId, addrs: &[SocketAddr], timestamp: u64) -> Result<Vec<u8>> {
+    Ok(bincode::serialize(&(public_id, addrs, timestamp))?)
+}
+
+/// Seconds since the UNIX epoch
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// Reason for rejecting a peer-exchange message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExchangeViolation {
+    /// The peer exchanged records too often
+    RateLimited,
+    /// The message carried more than `MAX_RECORDS_PER_MESSAGE` records
+    TooManyRecords,
+    /// A record carried an invalid signature or too many addresses
+    InvalidRecord,
+}
+
+/// Verified peer records learnt through peer exchange
+#[derive(Debug, Clone, Default)]
+pub struct PeerExchange {
+    records: HashMap<Hash, SignedPeerRecord>,
+    last_received: HashMap<SocketAddr, Instant>,
+}
+
+impl PeerExchange {
+    /// Creates a new `PeerExchange`.
+    pub fn new() -> Self {
+        Self {
+            records: Default::default(),
+            last_received: Default::default(),
+        }
+    }
+
+    /// Process the records sent by a peer.
+    /// Stale records are silently skipped; the connection information
+    /// of the new records is returned.
+    pub fn handle_records(
+        &mut self,
+        peer_addr: &SocketAddr,
+        records: Vec<SignedPeerRecord>,
+        now: Instant,
+        unix_now: u64,
+    ) -> std::result::Result<Vec<ConnectionInfo>, ExchangeViolation> {
+        if let Some(last) = self.last_received.get(peer_addr) {
+            if now.saturating_duration_since(*last) < MIN_EXCHANGE_INTERVAL {
+                return Err(ExchangeViolation::RateLimited);
+            }
+        }
+        let _ = self.last_received.insert(*peer_addr, now);
+        if records.len() > MAX_RECORDS_PER_MESSAGE {
+            return Err(ExchangeViolation::TooManyRecords);
+        }
+        let mut added = Vec::new();
+        for record in records {
+            if record.addrs.is_empty()
+                || record.addrs.len() > MAX_ADDRS_PER_RECORD
+                || !record.verify()
+            {
+                return Err(ExchangeViolation::InvalidRecord);
+            }
+            if record.is_fresh(unix_now) {
+                let infos = record.connection_infos().collect::<Vec<_>>();
+                if self.insert(record) {
+                    added.extend(infos);
+                }
+            }
+        }
+        Ok(added)
+    }
+
+    /// Store a verified record, keeping only the newest one per node.
+    /// Returns `true` if the record was new.
+    fn insert(&mut self, record: SignedPeerRecord) -> bool {
+        let node_id = record.public_id.node_id;
+        match self.records.get(&node_id) {
+            Some(known) if known.timestamp >= record.timestamp => return false,
+            None if self.records.len() >= MAX_KNOWN_RECORDS => {
+                let oldest = self
+                    .records
+                    .iter()
+                    .min_by_key(|(_, record)| record.timestamp)
+                    .map(|(node_id, _)| *node_id);
+                if let Some(oldest) = oldest {
+                    let _ = self.records.remove(&oldest);
+                }
+            }
+            _ => {}
+        }
+        let _ = self.records.insert(node_id, record);
+        true
+    }
+
+    /// Pick a random sample of fresh records to share with a peer.
+    pub fn sample(&self, exclude: &Hash, count: usize, unix_now: u64) -> Vec<SignedPeerRecord> {
+        self.records
+            .values()
+            .filter(|record| record.public_id.node_id != *exclude && record.is_fresh(unix_now))
+            .cloned()
+            .choose_multiple(&mut rand::thread_rng(), count)
+    }
+
+    /// Connection information of every node with a fresh record.
+    pub fn known_peers(&self, unix_now: u64) -> Vec<ConnectionInfo> {
+        self.records
+            .values()
+            .filter(|record| record.is_fresh(unix_now))
+            .flat_map(|record| record.connection_infos())
+            .collect()
+    }
+
+    /// Forget about a peer we are no longer connected to.
+    pub fn remove_peer(&mut self, peer_addr: &SocketAddr) {
+        let _ = self.last_received.remove(peer_addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::connection_info;
+
+    fn unsigned_record(timestamp: u64) -> SignedPeerRecord {
+        let info = connection_info(Hash::random(), 9000);
+        SignedPeerRecord {
+            public_id: info.public_id,
+            addrs: vec![info.socket_addr],
+            timestamp,
+            signature: vec![0; 64],
+        }
+    }
+
+    #[test]
+    fn test_record_freshness() {
+        let now = unix_timestamp();
+        assert!(unsigned_record(now).is_fresh(now));
+        assert!(!unsigned_record(now - MAX_RECORD_AGE_SECS - 1).is_fresh(now));
+        assert!(!unsigned_record(now + MAX_CLOCK_SKEW_SECS + 1).is_fresh(now));
+    }
+
+    #[test]
+    fn test_rejects_forged_records() {
+        let peer = SocketAddr::from(([127, 0, 0, 1], 9001));
+        let mut pex = PeerExchange::new();
+        let record = unsigned_record(unix_timestamp());
+        assert!(!record.verify());
+        assert_eq!(
+            pex.handle_records(&peer, vec![record], Instant::now(), unix_timestamp()),
+            Err(ExchangeViolation::InvalidRecord)
+        );
+
+        // Records are valid only if signed by the key the node ID is derived from.
+        let signed = SignedPeerRecord::new(&Identity::new(), vec![peer], unix_timestamp())
+            .expect("Failed to sign a record");
+        assert!(signed.verify());
+    }
+
+    #[test]
+    fn test_rate_limit_and_cap() {
+        let peer = SocketAddr::from(([127, 0, 0, 1], 9001));
+        let now = Instant::now();
+        let mut pex = PeerExchange::new();
+        assert_eq!(
+            pex.handle_records(&peer, Vec::new(), now, 0),
+            Ok(Vec::new())
+        );
+        assert_eq!(
+            pex.handle_records(&peer, Vec::new(), now, 0),
+            Err(ExchangeViolation::RateLimited)
+        );
+
+        let later = now + MIN_EXCHANGE_INTERVAL;
+        let records = (0..=MAX_RECORDS_PER_MESSAGE)
+            .map(|_| unsigned_record(0))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pex.handle_records(&peer, records, later, 0),
+            Err(ExchangeViolation::TooManyRecords)
+        );
+    }
+
+    #[test]
+    fn test_keeps_newest_record() {
+        let mut pex = PeerExchange::new();
+        let record = unsigned_record(100);
+        let mut newer = record.clone();
+        newer.timestamp = 200;
+
+        assert!(pex.insert(newer.clone()));
+        assert!(!pex.insert(record));
+        assert_eq!(pex.known_peers(200).len(), 1);
+        assert_eq!(pex.sample(&Hash::random(), 8, 200), vec![newer]);
+    }
+}
@@ -0,0 +1,364 @@
+use crate::{crypto::hash::Hash, persistence, Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs, net::SocketAddr, path::Path, time::Duration};
+
+/// Default score under which a peer is banned
+pub const DEFAULT_BAN_THRESHOLD: i64 = -100;
+/// Default duration of a ban
+pub const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Kinds of misbehaviour lowering the score of a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Misbehaviour {
+    /// A signature did not match the key it was attributed to
+    InvalidSignature,
+    /// A message could not be decoded
+    UndecodableMessage,
+    /// Routing information was forged or implausible
+    RoutingLie,
+    /// Messages were unsolicited, too frequent or too large
+    Spam,
+    /// A request was left unanswered
+    Timeout,
+    /// A block or transaction failed validation
+    InvalidObject,
+}
+
+/// Amount by which each kind of misbehaviour lowers the score of a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScoreWeights {
+    /// Weight of an invalid signature
+    pub invalid_signature: i64,
+    /// Weight of an undecodable message
+    pub undecodable_message: i64,
+    /// Weight of a routing lie
+    pub routing_lie: i64,
+    /// Weight of spam
+    pub spam: i64,
+    /// Weight of a timeout
+    pub timeout: i64,
+    /// Weight of an invalid block or transaction
+    pub invalid_object: i64,
+}
+
+impl ScoreWeights {
+    /// Retrieves the weight of a kind of misbehaviour
+    pub fn weight(&self, misbehaviour: Misbehaviour) -> i64 {
+        match misbehaviour {
+            Misbehaviour::InvalidSignature => self.invalid_signature,
+            Misbehaviour::UndecodableMessage => self.undecodable_message,
+            Misbehaviour::RoutingLie => self.routing_lie,
+            Misbehaviour::Spam => self.spam,
+            Misbehaviour::Timeout => self.timeout,
+            Misbehaviour::InvalidObject => self.invalid_object,
+        }
+    }
+}
+
+impl Default for ScoreWeights {
+    fn default() -> Self {
+        Self {
+            invalid_signature: 50,
+            undecodable_message: 20,
+            routing_lie: 25,
+            spam: 10,
+            timeout: 5,
+            invalid_object: 25,
+        }
+    }
+}
+
+/// How peers are scored and banned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScoreConfig {
+    /// Weight of each kind of misbehaviour
+    pub weights: ScoreWeights,
+    /// Score at or under which a peer is banned
+    pub ban_threshold: i64,
+    /// How long a ban lasts
+    pub ban_duration: Duration,
+}
+
+impl Default for ScoreConfig {
+    fn default() -> Self {
+        Self {
+            weights: Default::default(),
+            ban_threshold: DEFAULT_BAN_THRESHOLD,
+            ban_duration: DEFAULT_BAN_DURATION,
+        }
+    }
+}
+
+/// Score of a peer.
+/// Starts at zero and is lowered by every misbehaviour.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerScore {
+    score: i64,
+}
+
+impl PeerScore {
+    /// Retrieves the current score
+    pub fn value(&self) -> i64 {
+        self.score
+    }
+
+    /// Lower the score by `weight`, returning the new score.
+    pub fn penalise(&mut self, weight: i64) -> i64 {
+        self.score = self.score.saturating_sub(weight);
+        self.score
+    }
+}
+
+/// A ban on a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ban {
+    /// Seconds since the UNIX epoch at which the ban is lifted
+    pub until: u64,
+    /// Misbehaviour which got the peer banned
+    pub reason: Misbehaviour,
+}
+
+/// Banned node IDs and addresses
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BanList {
+    nodes: HashMap<Hash, Ban>,
+    addrs: HashMap<SocketAddr, Ban>,
+}
+
+impl BanList {
+    /// Creates a new, empty `BanList`.
+    pub fn new() -> Self {
+        Self {
+            nodes: Default::default(),
+            addrs: Default::default(),
+        }
+    }
+
+    /// Load a `BanList` from a file, or create an empty one if it does not exist.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::new());
+        }
+        Ok(bincode::deserialize(&fs::read(path)?)?)
+    }
+
+    /// Atomically write the `BanList` to a file.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        persistence::save(self, path)
+    }
+
+    /// Ban a node ID
+    pub fn ban_node(&mut self, node_id: Hash, ban: Ban) {
+        let _ = self.nodes.insert(node_id, ban);
+    }
+
+    /// Ban a socket address
+    pub fn ban_addr(&mut self, socket_addr: SocketAddr, ban: Ban) {
+        let _ = self.addrs.insert(socket_addr, ban);
+    }
+
+    /// Lift the ban on a node ID, returning it if there was one.
+    pub fn unban_node(&mut self, node_id: &Hash) -> Option<Ban> {
+        self.nodes.remove(node_id)
+    }
+
+    /// Lift the ban on a socket address, returning it if there was one.
+    pub fn unban_addr(&mut self, socket_addr: &SocketAddr) -> Option<Ban> {
+        self.addrs.remove(socket_addr)
+    }
+
+    /// Checks if a node ID is banned at time `now`
+    pub fn is_node_banned(&self, node_id: &Hash, now: u64) -> bool {
+        self.nodes.get(node_id).is_some_and(|ban| ban.until > now)
+    }
+
+    /// Checks if a socket address is banned at time `now`
+    pub fn is_addr_banned(&self, socket_addr: &SocketAddr, now: u64) -> bool {
+        self.addrs
+            .get(socket_addr)
+            .is_some_and(|ban| ban.until > now)
+    }
+
+    /// Banned node IDs, including expired bans not yet removed
+    pub fn banned_nodes(&self) -> impl Iterator<Item = (&Hash, &Ban)> {
+        self.nodes.iter()
+    }
+
+    /// Banned socket addresses, including expired bans not yet removed
+    pub fn banned_addrs(&self) -> impl Iterator<Item = (&SocketAddr, &Ban)> {
+        self.addrs.iter()
+    }
+
+    /// Remove the bans which have run out.
+    pub fn expire(&mut self, now: u64) {
+        self.nodes.retain(|_, ban| ban.until > now);
+        self.addrs.retain(|_, ban| ban.until > now);
+    }
+}
+
+/// Scores of peers, by node ID and by socket address, and the resulting bans
+#[derive(Debug, Clone, Default)]
+pub struct PeerScores {
+    config: ScoreConfig,
+    by_node: HashMap<Hash, PeerScore>,
+    by_addr: HashMap<SocketAddr, PeerScore>,
+    bans: BanList,
+}
+
+impl PeerScores {
+    /// Creates a new `PeerScores` enforcing previously issued bans.
+    pub fn new(config: ScoreConfig, bans: BanList) -> Self {
+        Self {
+            config,
+            by_node: Default::default(),
+            by_addr: Default::default(),
+            bans,
+        }
+    }
+
+    /// Lower the score of a peer for a misbehaviour.
+    /// If either its address or its node ID drops to the threshold, both are banned.
+    /// Returns `true` if the peer got banned.
+    pub fn record(
+        &mut self,
+        socket_addr: &SocketAddr,
+        node_id: Option<&Hash>,
+        misbehaviour: Misbehaviour,
+        now: u64,
+    ) -> bool {
+        let weight = self.config.weights.weight(misbehaviour);
+        let mut lowest = self
+            .by_addr
+            .entry(*socket_addr)
+            .or_default()
+            .penalise(weight);
+        if let Some(node_id) = node_id {
+            lowest = lowest.min(self.by_node.entry(*node_id).or_default().penalise(weight));
+        }
+        if lowest > self.config.ban_threshold {
+            return false;
+        }
+        let ban = Ban {
+            until: now.saturating_add(self.config.ban_duration.as_secs()),
+            reason: misbehaviour,
+        };
+        self.bans.ban_addr(*socket_addr, ban);
+        let _ = self.by_addr.remove(socket_addr);
+        if let Some(node_id) = node_id {
+            self.bans.ban_node(*node_id, ban);
+            let _ = self.by_node.remove(node_id);
+        }
+        true
+    }
+
+    /// Retrieves the score of a socket address
+    pub fn addr_score(&self, socket_addr: &SocketAddr) -> i64 {
+        self.by_addr
+            .get(socket_addr)
+            .map(PeerScore::value)
+            .unwrap_or(0)
+    }
+
+    /// Retrieves the score of a node ID
+    pub fn node_score(&self, node_id: &Hash) -> i64 {
+        self.by_node.get(node_id).map(PeerScore::value).unwrap_or(0)
+    }
+
+    /// Checks if a peer is banned, by address or by node ID
+    pub fn is_banned(&self, socket_addr: &SocketAddr, node_id: Option<&Hash>, now: u64) -> bool {
+        self.bans.is_addr_banned(socket_addr, now)
+            || node_id.is_some_and(|node_id| self.bans.is_node_banned(node_id, now))
+    }
+
+    /// Forget the score of an address we are no longer connected to.
+    /// Scores by node ID are kept, so reconnecting does not reset them.
+    pub fn forget_addr(&mut self, socket_addr: &SocketAddr) {
+        let _ = self.by_addr.remove(socket_addr);
+    }
+
+    /// Retrieves the ban list
+    pub fn bans(&self) -> &BanList {
+        &self.bans
+    }
+
+    /// Retrieves a mutable reference to the ban list
+    pub fn bans_mut(&mut self) -> &mut BanList {
+        &mut self.bans
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bans_after_threshold() {
+        let addr = SocketAddr::from(([10, 0, 0, 1], 9000));
+        let node_id = Hash::random();
+        let mut scores = PeerScores::new(ScoreConfig::default(), BanList::new());
+
+        assert!(!scores.record(&addr, Some(&node_id), Misbehaviour::RoutingLie, 0));
+        assert!(!scores.record(&addr, None, Misbehaviour::InvalidSignature, 0));
+        assert_eq!(scores.addr_score(&addr), -75);
+        assert_eq!(scores.node_score(&node_id), -25);
+
+        assert!(scores.record(&addr, Some(&node_id), Misbehaviour::RoutingLie, 0));
+        assert!(scores.is_banned(&addr, None, 1));
+        assert!(scores.is_banned(&SocketAddr::from(([10, 0, 0, 2], 9000)), Some(&node_id), 1));
+
+        let until = DEFAULT_BAN_DURATION.as_secs();
+        assert!(!scores.is_banned(&addr, Some(&node_id), until));
+        scores.bans_mut().expire(until);
+        assert_eq!(scores.bans().banned_nodes().count(), 0);
+    }
+
+    #[test]
+    fn test_configurable_weights() {
+        let addr = SocketAddr::from(([10, 0, 0, 1], 9000));
+        let config = ScoreConfig {
+            weights: ScoreWeights {
+                timeout: 0,
+                ..Default::default()
+            },
+            ban_threshold: -10,
+            ban_duration: Duration::from_secs(60),
+        };
+        let mut scores = PeerScores::new(config, BanList::new());
+        for _ in 0..100 {
+            assert!(!scores.record(&addr, None, Misbehaviour::Timeout, 0));
+        }
+        assert!(scores.record(&addr, None, Misbehaviour::Spam, 0));
+        assert_eq!(
+            scores.bans().banned_addrs().next(),
+            Some((
+                &addr,
+                &Ban {
+                    until: 60,
+                    reason: Misbehaviour::Spam,
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_ban_list_persistence() {
+        let path = std::env::temp_dir().join(format!("ban_list_{}", Hash::random()));
+        let node_id = Hash::random();
+        let mut bans = BanList::new();
+        bans.ban_node(
+            node_id,
+            Ban {
+                until: 100,
+                reason: Misbehaviour::UndecodableMessage,
+            },
+        );
+        assert!(bans.save(&path).is_ok());
+
+        let loaded = BanList::load(&path);
+        assert!(loaded.is_ok());
+        let loaded = loaded.unwrap_or_default();
+        assert!(loaded.is_node_banned(&node_id, 99));
+        assert!(!loaded.is_node_banned(&node_id, 100));
+        let _ = fs::remove_file(path);
+    }
+}
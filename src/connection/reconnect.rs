@@ -0,0 +1,243 @@
+use crate::PublicId;
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// Default delay before the first reconnection attempt
+pub const DEFAULT_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Default upper bound on the delay between two reconnection attempts
+pub const DEFAULT_MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5 * 60);
+/// Default number of reconnection attempts before giving up on a peer
+pub const DEFAULT_MAX_RECONNECT_ATTEMPTS: u32 = 10;
+
+/// Exponential backoff between reconnection attempts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    /// Delay before the first attempt, doubled after every failure
+    pub base_delay: Duration,
+    /// Upper bound on the delay between two attempts
+    pub max_delay: Duration,
+    /// Number of attempts before giving up
+    pub max_attempts: u32,
+}
+
+impl Backoff {
+    /// Delay before attempt number `attempt`, counting from zero.
+    /// The delay is drawn uniformly from the upper half of the exponential
+    /// window, so that peers which dropped together do not retry together.
+    pub fn delay<R: Rng>(&self, attempt: u32, rng: &mut R) -> Duration {
+        let window = self
+            .base_delay
+            .checked_mul(2u32.saturating_pow(attempt))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        let half = window / 2;
+        half + window.saturating_sub(half).mul_f64(rng.gen::<f64>())
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            base_delay: DEFAULT_RECONNECT_DELAY,
+            max_delay: DEFAULT_MAX_RECONNECT_DELAY,
+            max_attempts: DEFAULT_MAX_RECONNECT_ATTEMPTS,
+        }
+    }
+}
+
+/// Reconnection state of a single peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectState {
+    /// Public identity of the peer, if it identified itself before
+    pub public_id: Option<PublicId>,
+    /// Number of attempts made so far
+    pub attempts: u32,
+    /// When the next attempt is due
+    pub next_attempt: Instant,
+}
+
+/// A reconnection attempt which is due
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectAttempt {
+    /// Address of the peer
+    pub socket_addr: SocketAddr,
+    /// Public identity of the peer, if known
+    pub public_id: Option<PublicId>,
+    /// Number of this attempt, counting from one
+    pub attempt: u32,
+}
+
+/// Outcome of a failed reconnection attempt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FailedAttempt {
+    /// Number of the attempt which failed, counting from one
+    pub attempt: u32,
+    /// Whether the scheduler gave up on the peer
+    pub gave_up: bool,
+}
+
+/// Schedules reconnections to important peers.
+#[derive(Debug, Clone, Default)]
+pub struct ReconnectScheduler {
+    backoff: Backoff,
+    peers: HashMap<SocketAddr, ReconnectState>,
+}
+
+impl ReconnectScheduler {
+    /// Creates a new `ReconnectScheduler`.
+    pub fn new(backoff: Backoff) -> Self {
+        Self {
+            backoff,
+            peers: Default::default(),
+        }
+    }
+
+    /// Schedule reconnections to a peer we lost our connection to.
+    /// Does nothing if the peer is already scheduled.
+    pub fn schedule<R: Rng>(
+        &mut self,
+        socket_addr: SocketAddr,
+        public_id: Option<PublicId>,
+        now: Instant,
+        rng: &mut R,
+    ) {
+        if self.backoff.max_attempts == 0 || self.peers.contains_key(&socket_addr) {
+            return;
+        }
+        let _ = self.peers.insert(
+            socket_addr,
+            ReconnectState {
+                public_id,
+                attempts: 0,
+                next_attempt: now + self.backoff.delay(0, rng),
+            },
+        );
+    }
+
+    /// Retrieves the attempts which are due, and schedules the following ones.
+    pub fn due<R: Rng>(&mut self, now: Instant, rng: &mut R) -> Vec<ReconnectAttempt> {
+        let mut attempts = Vec::new();
+        for (socket_addr, state) in &mut self.peers {
+            if state.next_attempt > now || state.attempts >= self.backoff.max_attempts {
+                continue;
+            }
+            state.attempts += 1;
+            state.next_attempt = now + self.backoff.delay(state.attempts, rng);
+            attempts.push(ReconnectAttempt {
+                socket_addr: *socket_addr,
+                public_id: state.public_id,
+                attempt: state.attempts,
+            });
+        }
+        attempts
+    }
+
+    /// Record that the latest attempt to reach a peer failed.
+    /// The peer is forgotten once it failed its last attempt.
+    /// Returns `None` if no attempt was made to reach the peer.
+    pub fn record_failure(&mut self, socket_addr: &SocketAddr) -> Option<FailedAttempt> {
+        let attempts = self.peers.get(socket_addr)?.attempts;
+        if attempts == 0 {
+            return None;
+        }
+        let gave_up = attempts >= self.backoff.max_attempts;
+        if gave_up {
+            let _ = self.peers.remove(socket_addr);
+        }
+        Some(FailedAttempt {
+            attempt: attempts,
+            gave_up,
+        })
+    }
+
+    /// Retrieves the reconnection state of a peer
+    pub fn state(&self, socket_addr: &SocketAddr) -> Option<&ReconnectState> {
+        self.peers.get(socket_addr)
+    }
+
+    /// Stop reconnecting to a peer, e.g. because we reconnected.
+    /// Returns its state if it was scheduled.
+    pub fn cancel(&mut self, socket_addr: &SocketAddr) -> Option<ReconnectState> {
+        self.peers.remove(socket_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn test_backoff_grows_with_jitter() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let backoff = Backoff {
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            max_attempts: 10,
+        };
+        for attempt in 0..10 {
+            let window = Duration::from_secs(1 << attempt).min(backoff.max_delay);
+            let delay = backoff.delay(attempt, &mut rng);
+            assert!(delay >= window / 2 && delay <= window);
+        }
+        assert!(backoff.delay(u32::MAX, &mut rng) <= backoff.max_delay);
+    }
+
+    #[test]
+    fn test_attempts_follow_mock_clock() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let peer = SocketAddr::from(([10, 0, 0, 1], 9000));
+        let backoff = Backoff {
+            base_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(8),
+            max_attempts: 3,
+        };
+        let start = Instant::now();
+        let mut clock = start;
+        let mut scheduler = ReconnectScheduler::new(backoff);
+        scheduler.schedule(peer, None, clock, &mut rng);
+        assert!(scheduler.due(clock, &mut rng).is_empty());
+
+        assert_eq!(scheduler.record_failure(&peer), None);
+
+        let mut made = Vec::new();
+        while made.len() < 3 {
+            clock += Duration::from_millis(500);
+            for attempt in scheduler.due(clock, &mut rng) {
+                made.push((attempt.attempt, clock));
+                let failure = scheduler.record_failure(&peer);
+                assert_eq!(
+                    failure.map(|failure| (failure.attempt, failure.gave_up)),
+                    Some((attempt.attempt, attempt.attempt == 3))
+                );
+            }
+        }
+        assert_eq!(
+            made.iter().map(|(n, _)| *n).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert!(made[0].1 >= start + Duration::from_secs(1));
+        assert!(made[1].1 - made[0].1 >= Duration::from_secs(2));
+        assert!(scheduler.state(&peer).is_none());
+        assert!(scheduler
+            .due(clock + Duration::from_secs(60), &mut rng)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_cancel_on_recovery() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let peer = SocketAddr::from(([10, 0, 0, 1], 9000));
+        let now = Instant::now();
+        let mut scheduler = ReconnectScheduler::default();
+        scheduler.schedule(peer, None, now, &mut rng);
+        assert!(scheduler.cancel(&peer).is_some());
+        assert!(scheduler
+            .due(now + DEFAULT_MAX_RECONNECT_DELAY, &mut rng)
+            .is_empty());
+    }
+}
@@ -0,0 +1,419 @@
+use crate::{crypto::hash::Hash, PublicId, Result};
+use ed25519_dalek::{Signature, Verifier};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// Default maximum number of circuits a relay serves at once
+pub const DEFAULT_MAX_CIRCUITS: usize = 16;
+/// Default number of bytes per second a single circuit may carry
+pub const DEFAULT_CIRCUIT_BANDWIDTH: u64 = 64 * 1024;
+/// Default lifetime of a circuit
+pub const DEFAULT_CIRCUIT_DURATION: Duration = Duration::from_secs(2 * 60);
+
+/// Limits of a relay, for the circuits it serves
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelayLimits {
+    /// Maximum number of circuits served at once
+    pub max_circuits: usize,
+    /// Number of bytes per second a single circuit may carry, with bursts of up to one second
+    pub bandwidth: u64,
+    /// Time after which a circuit is closed
+    pub max_duration: Duration,
+}
+
+impl Default for RelayLimits {
+    fn default() -> Self {
+        Self {
+            max_circuits: DEFAULT_MAX_CIRCUITS,
+            bandwidth: DEFAULT_CIRCUIT_BANDWIDTH,
+            max_duration: DEFAULT_CIRCUIT_DURATION,
+        }
+    }
+}
+
+/// Reason for refusing or closing a circuit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CircuitError {
+    /// The node does not relay traffic
+    NotRelaying,
+    /// The relay serves as many circuits as it can
+    TooManyCircuits,
+    /// The relay is not connected to the target, or the requester did not identify itself
+    UnknownPeer,
+    /// The circuit does not exist, or the peer is not one of its ends
+    UnknownCircuit,
+    /// The circuit carried more than its bandwidth
+    BandwidthExceeded,
+    /// The circuit outlived its maximum duration
+    Expired,
+    /// The other end failed the end-to-end handshake
+    HandshakeFailed,
+    /// The connection to the relay was lost
+    RelayLost,
+    /// One of the ends closed the circuit
+    Closed,
+}
+
+/// Frames exchanged end to end through a circuit, opaque to the relay
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CircuitFrame {
+    /// Claimed identity of the sender, and a nonce for the other end to sign
+    Challenge {
+        /// Public identity of the sender
+        public_id: Box<PublicId>,
+        /// Nonce to be signed by the other end
+        nonce: u64,
+    },
+    /// Signature over the nonce of the other end, proving the identity of the sender
+    Response {
+        /// Signature of the sender
+        signature: Vec<u8>,
+    },
+    /// Application data, only accepted once both ends proved their identity
+    Data(Vec<u8>),
+}
+
+/// Bytes a peer signs to prove its identity on a circuit.
+/// Binding the circuit and the signer prevents replaying the signature elsewhere.
+pub fn handshake_bytes(circuit: u64, nonce: u64, signer: &Hash) -> Result<Vec<u8>> {
+    Ok(bincode::serialize(&(circuit, nonce, signer))?)
+}
+
+struct Circuit {
+    ends: [SocketAddr; 2],
+    opened_at: Instant,
+    allowance: u64,
+    refilled_at: Instant,
+}
+
+impl Circuit {
+    fn other_end(&self, end: &SocketAddr) -> SocketAddr {
+        if self.ends[0] == *end {
+            self.ends[1]
+        } else {
+            self.ends[0]
+        }
+    }
+}
+
+/// Circuits served by a relay, forwarding bytes between two peers which are both
+/// connected to it.
+pub struct RelayService {
+    limits: RelayLimits,
+    circuits: HashMap<u64, Circuit>,
+}
+
+impl RelayService {
+    /// Creates a new `RelayService`.
+    pub fn new(limits: RelayLimits) -> Self {
+        Self {
+            limits,
+            circuits: Default::default(),
+        }
+    }
+
+    /// Retrieves the limits of the relay
+    pub fn limits(&self) -> &RelayLimits {
+        &self.limits
+    }
+
+    /// Number of circuits being served
+    pub fn len(&self) -> usize {
+        self.circuits.len()
+    }
+
+    /// Checks if no circuit is being served
+    pub fn is_empty(&self) -> bool {
+        self.circuits.is_empty()
+    }
+
+    /// Open a circuit between `source` and `target`.
+    pub fn open(
+        &mut self,
+        circuit: u64,
+        source: SocketAddr,
+        target: SocketAddr,
+        now: Instant,
+    ) -> std::result::Result<(), CircuitError> {
+        if self.circuits.contains_key(&circuit) {
+            return Err(CircuitError::UnknownCircuit);
+        }
+        if self.circuits.len() >= self.limits.max_circuits {
+            return Err(CircuitError::TooManyCircuits);
+        }
+        let _ = self.circuits.insert(
+            circuit,
+            Circuit {
+                ends: [source, target],
+                opened_at: now,
+                allowance: self.limits.bandwidth,
+                refilled_at: now,
+            },
+        );
+        Ok(())
+    }
+
+    /// Account for `len` bytes sent by `from` on a circuit.
+    /// Returns the other end, to which the bytes should be forwarded.
+    /// Circuits which outlived their duration or exceeded their bandwidth are closed.
+    pub fn forward(
+        &mut self,
+        circuit: u64,
+        from: &SocketAddr,
+        len: usize,
+        now: Instant,
+    ) -> std::result::Result<SocketAddr, CircuitError> {
+        let limits = self.limits;
+        let entry = match self.circuits.get_mut(&circuit) {
+            Some(entry) if entry.ends.contains(from) => entry,
+            _ => return Err(CircuitError::UnknownCircuit),
+        };
+        if now.saturating_duration_since(entry.opened_at) > limits.max_duration {
+            let _ = self.circuits.remove(&circuit);
+            return Err(CircuitError::Expired);
+        }
+        let elapsed = now.saturating_duration_since(entry.refilled_at);
+        let refill = (limits.bandwidth as f64 * elapsed.as_secs_f64()) as u64;
+        entry.allowance = entry.allowance.saturating_add(refill).min(limits.bandwidth);
+        entry.refilled_at = now;
+        match entry.allowance.checked_sub(len as u64) {
+            Some(allowance) => {
+                entry.allowance = allowance;
+                Ok(entry.other_end(from))
+            }
+            None => {
+                let _ = self.circuits.remove(&circuit);
+                Err(CircuitError::BandwidthExceeded)
+            }
+        }
+    }
+
+    /// Retrieves the other end of a circuit `end` is one of the ends of
+    pub fn other_end(&self, circuit: u64, end: &SocketAddr) -> Option<SocketAddr> {
+        self.circuits
+            .get(&circuit)
+            .filter(|entry| entry.ends.contains(end))
+            .map(|entry| entry.other_end(end))
+    }
+
+    /// Close a circuit at the request of one of its ends.
+    /// Returns the other end, which should be told.
+    pub fn close(&mut self, circuit: u64, from: &SocketAddr) -> Option<SocketAddr> {
+        let other = self.other_end(circuit, from)?;
+        let _ = self.circuits.remove(&circuit);
+        Some(other)
+    }
+
+    /// Close the circuits a peer we lost our connection to was an end of.
+    /// Returns the circuits along with their other end.
+    pub fn close_peer(&mut self, peer_addr: &SocketAddr) -> Vec<(u64, SocketAddr)> {
+        let closed = self
+            .circuits
+            .iter()
+            .filter(|(_, entry)| entry.ends.contains(peer_addr))
+            .map(|(circuit, entry)| (*circuit, entry.other_end(peer_addr)))
+            .collect::<Vec<_>>();
+        for (circuit, _) in &closed {
+            let _ = self.circuits.remove(circuit);
+        }
+        closed
+    }
+
+    /// Close the circuits which outlived their duration.
+    /// Returns the circuits along with both their ends.
+    pub fn expire(&mut self, now: Instant) -> Vec<(u64, [SocketAddr; 2])> {
+        let max_duration = self.limits.max_duration;
+        let expired = self
+            .circuits
+            .iter()
+            .filter(|(_, entry)| now.saturating_duration_since(entry.opened_at) > max_duration)
+            .map(|(circuit, entry)| (*circuit, entry.ends))
+            .collect::<Vec<_>>();
+        for (circuit, _) in &expired {
+            let _ = self.circuits.remove(circuit);
+        }
+        expired
+    }
+}
+
+/// Our end of a circuit through a relay
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CircuitEnd {
+    /// Address of the relay
+    pub relay: SocketAddr,
+    /// Identity announced by the relay for the other end
+    pub announced: PublicId,
+    /// Identity the other end must prove, if we opened the circuit
+    pub expected: Option<PublicId>,
+    /// Nonce the other end must sign
+    pub nonce: u64,
+    /// Identity proven by the other end, once it answered our challenge
+    pub authenticated: Option<PublicId>,
+}
+
+impl CircuitEnd {
+    /// Identity the other end must prove: the one we asked for if we opened the circuit,
+    /// or else the one announced by the relay. Either must have its node ID derived
+    /// from its signing key, so the relay cannot claim the node ID of another node
+    /// along with a key of its own.
+    fn peer(&self) -> Option<PublicId> {
+        Some(self.expected.unwrap_or(self.announced)).filter(PublicId::has_valid_node_id)
+    }
+
+    /// Handle the challenge of the other end.
+    /// Returns `false` if it claims an identity other than the expected one.
+    pub fn handle_challenge(&mut self, public_id: &PublicId) -> bool {
+        self.peer() == Some(*public_id) && self.authenticated.is_none()
+    }
+
+    /// Handle the response of the other end to our challenge.
+    /// Returns `true` if its signature proves the expected identity.
+    pub fn handle_response(&mut self, circuit: u64, signature: &[u8]) -> bool {
+        let peer = match self.peer() {
+            Some(peer) => peer,
+            None => return false,
+        };
+        let signed_bytes = match handshake_bytes(circuit, self.nonce, &peer.node_id) {
+            Ok(bytes) => bytes,
+            Err(_) => return false,
+        };
+        let valid = Signature::from_bytes(signature)
+            .map(|signature| {
+                peer.signing_public_key
+                    .verify(&signed_bytes, &signature)
+                    .is_ok()
+            })
+            .unwrap_or(false);
+        if valid {
+            self.authenticated = Some(peer);
+        }
+        valid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_utils::connection_info, Identity};
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_circuit_limits() {
+        let limits = RelayLimits {
+            max_circuits: 1,
+            bandwidth: 1000,
+            max_duration: Duration::from_secs(60),
+        };
+        let now = Instant::now();
+        let mut relay = RelayService::new(limits);
+        assert_eq!(relay.open(1, addr(1), addr(2), now), Ok(()));
+        assert_eq!(
+            relay.open(2, addr(3), addr(4), now),
+            Err(CircuitError::TooManyCircuits)
+        );
+
+        assert_eq!(relay.forward(1, &addr(1), 600, now), Ok(addr(2)));
+        assert_eq!(relay.forward(1, &addr(2), 400, now), Ok(addr(1)));
+        assert_eq!(
+            relay.forward(1, &addr(3), 1, now),
+            Err(CircuitError::UnknownCircuit)
+        );
+        let later = now + Duration::from_millis(500);
+        assert_eq!(relay.forward(1, &addr(1), 500, later), Ok(addr(2)));
+        assert_eq!(
+            relay.forward(1, &addr(1), 1, later),
+            Err(CircuitError::BandwidthExceeded)
+        );
+        assert!(relay.is_empty());
+
+        assert_eq!(relay.open(3, addr(1), addr(2), now), Ok(()));
+        let expiry = now + limits.max_duration + Duration::from_secs(1);
+        assert_eq!(relay.expire(expiry), vec![(3, [addr(1), addr(2)])]);
+        assert_eq!(relay.close(3, &addr(1)), None);
+    }
+
+    #[test]
+    fn test_close_peer() {
+        let now = Instant::now();
+        let mut relay = RelayService::new(RelayLimits::default());
+        assert_eq!(relay.open(1, addr(1), addr(2), now), Ok(()));
+        assert_eq!(relay.open(2, addr(3), addr(1), now), Ok(()));
+        assert_eq!(relay.open(3, addr(3), addr(4), now), Ok(()));
+        let mut closed = relay.close_peer(&addr(1));
+        closed.sort_unstable();
+        assert_eq!(closed, vec![(1, addr(2)), (2, addr(3))]);
+        assert_eq!(relay.close(3, &addr(4)), Some(addr(3)));
+        assert!(relay.is_empty());
+    }
+
+    fn sign(identity: &Identity, circuit: u64, nonce: u64) -> Vec<u8> {
+        let bytes =
+            handshake_bytes(circuit, nonce, &identity.public_id().node_id).unwrap_or_default();
+        identity.sign_with_signing_key(&bytes).to_bytes().to_vec()
+    }
+
+    #[test]
+    fn test_relay_cannot_impersonate() {
+        let target = Identity::new();
+        let target_id = target.public_id();
+
+        // The relay announces an identity of its own for the node we asked for.
+        let forged = connection_info(target_id.node_id, 9000).public_id;
+        let mut end = CircuitEnd {
+            relay: addr(1),
+            announced: forged,
+            expected: Some(target_id),
+            nonce: 42,
+            authenticated: None,
+        };
+        assert!(!end.handle_challenge(&forged));
+        assert!(end.handle_challenge(&target_id));
+
+        // Signatures over another nonce or another circuit are rejected.
+        assert!(!end.handle_response(5, &sign(&target, 5, 41)));
+        assert!(!end.handle_response(5, &sign(&target, 6, 42)));
+        assert!(end.handle_response(5, &sign(&target, 5, 42)));
+        assert_eq!(end.authenticated, Some(target_id));
+    }
+
+    #[test]
+    fn test_relay_cannot_impersonate_requester() {
+        let requester = Identity::new().public_id();
+        let relay = Identity::new();
+
+        // The target did not ask for the circuit, so it only knows the identity the
+        // relay announces. The relay claims the node ID of the requester with its own
+        // key, and answers the challenge itself.
+        let forged = PublicId {
+            signing_public_key: relay.public_id().signing_public_key,
+            ..requester
+        };
+        let mut end = CircuitEnd {
+            relay: addr(1),
+            announced: forged,
+            expected: None,
+            nonce: 42,
+            authenticated: None,
+        };
+        assert!(!end.handle_challenge(&forged));
+        assert!(!end.handle_response(5, &sign(&relay, 5, 42)));
+        assert_eq!(end.authenticated, None);
+
+        // The relay can only announce a node ID matching its key, which is its own.
+        let genuine = Identity::new();
+        let mut end = CircuitEnd {
+            announced: genuine.public_id(),
+            ..end
+        };
+        assert!(end.handle_challenge(&genuine.public_id()));
+        assert!(end.handle_response(5, &sign(&genuine, 5, 42)));
+        assert_eq!(end.authenticated, Some(genuine.public_id()));
+    }
+}
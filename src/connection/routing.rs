@@ -1,18 +1,67 @@
-use crate::crypto::hash::Hash;
+use crate::{crypto::hash::Hash, Result};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+const MAX_WITHDRAWN_LOG: usize = 1024;
+
+/// Largest hop count a peer may plausibly advertise for a reachable node
+pub const MAX_ADVERTISED_HOPS: usize = 64;
 
 /// Representation of a routing table
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct RoutingTable {
-    entries: HashMap<Hash, (Hash, usize)>,
+    entries: HashMap<Hash, RouteEntry>,
+    #[serde(skip)]
+    held_down: HashMap<Hash, Instant>,
+    #[serde(skip)]
+    withdrawn: VecDeque<(Hash, usize)>,
+    #[serde(skip)]
+    delta_floor: usize,
     version: usize,
 }
 
+/// Routing information for a single destination
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RouteEntry {
+    /// Next hop towards the destination
+    pub next_hop: Hash,
+    /// Number of hops to the destination
+    pub hops: usize,
+    /// Version of the shared routing table this entry came from
+    pub source_version: usize,
+    /// Version of our own routing table in which this entry last changed
+    pub changed_in: usize,
+    /// When this entry was last refreshed
+    #[serde(skip, default = "Instant::now")]
+    pub last_refreshed: Instant,
+}
+
+impl RouteEntry {
+    /// Creates a new `RouteEntry`, refreshed at `now`.
+    pub fn new(next_hop: Hash, hops: usize, source_version: usize, now: Instant) -> Self {
+        Self {
+            next_hop,
+            hops,
+            source_version,
+            changed_in: 0,
+            last_refreshed: now,
+        }
+    }
+
+    /// Checks if this entry has not been refreshed within `ttl`.
+    pub fn is_stale(&self, now: Instant, ttl: Duration) -> bool {
+        now.saturating_duration_since(self.last_refreshed) > ttl
+    }
+}
+
 /// Representation of a shared routing table
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct SharedRoutingTable {
     entries: HashMap<Hash, usize>,
+    version: usize,
 }
 
 impl SharedRoutingTable {
@@ -25,6 +74,100 @@ impl SharedRoutingTable {
     pub fn shared_routing_info(&self, node_id: &Hash) -> Option<usize> {
         self.entries.get(node_id).copied()
     }
+
+    /// Retrieve version number of the routing table this was shared from.
+    pub fn version(&self) -> usize {
+        self.version
+    }
+
+    /// Canonical byte representation signed by the advertiser.
+    pub fn signable_bytes(&self, source: &Hash) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(&(
+            source,
+            self.version,
+            sorted_entries(&self.entries),
+        ))?)
+    }
+
+    /// Checks the advertised hop counts against sanity limits.
+    pub fn is_plausible(&self, source: &Hash) -> bool {
+        are_plausible_claims(source, &self.entries)
+    }
+}
+
+/// Incremental routing update carrying only the entries
+/// which changed between `base_version` and `version`
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct RoutingDelta {
+    base_version: usize,
+    version: usize,
+    updated: HashMap<Hash, usize>,
+    withdrawn: Vec<Hash>,
+}
+
+impl RoutingDelta {
+    /// Retrieve the version this delta applies on top of.
+    pub fn base_version(&self) -> usize {
+        self.base_version
+    }
+
+    /// Retrieve the version of the routing table after this delta.
+    pub fn version(&self) -> usize {
+        self.version
+    }
+
+    /// Retrieve the added or changed entries.
+    pub fn updated(&self) -> &HashMap<Hash, usize> {
+        &self.updated
+    }
+
+    /// Retrieve the destinations which are no longer reachable.
+    pub fn withdrawn(&self) -> &[Hash] {
+        &self.withdrawn
+    }
+
+    /// Checks if the delta carries no changes.
+    pub fn is_empty(&self) -> bool {
+        self.updated.is_empty() && self.withdrawn.is_empty()
+    }
+
+    /// Canonical byte representation signed by the advertiser.
+    pub fn signable_bytes(&self, source: &Hash) -> Result<Vec<u8>> {
+        let mut withdrawn = self.withdrawn.clone();
+        withdrawn.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+        Ok(bincode::serialize(&(
+            source,
+            self.base_version,
+            self.version,
+            sorted_entries(&self.updated),
+            withdrawn,
+        ))?)
+    }
+
+    /// Checks the advertised hop counts against sanity limits.
+    pub fn is_plausible(&self, source: &Hash) -> bool {
+        are_plausible_claims(source, &self.updated)
+    }
+}
+
+fn sorted_entries(entries: &HashMap<Hash, usize>) -> Vec<(Hash, usize)> {
+    let mut sorted = entries
+        .iter()
+        .map(|(node_id, hops)| (*node_id, *hops))
+        .collect::<Vec<(Hash, usize)>>();
+    sorted.sort_by(|(a, _), (b, _)| a.as_ref().cmp(b.as_ref()));
+    sorted
+}
+
+/// Only the advertiser itself can be zero hops away,
+/// and no reachable node can be further than `MAX_ADVERTISED_HOPS`.
+/// `usize::MAX` marks a node whose route is not yet known.
+fn are_plausible_claims(source: &Hash, entries: &HashMap<Hash, usize>) -> bool {
+    entries.iter().all(|(dest, hops)| match *hops {
+        0 => dest == source,
+        usize::MAX => true,
+        hops => hops <= MAX_ADVERTISED_HOPS,
+    })
 }
 
 impl RoutingTable {
@@ -32,6 +175,9 @@ impl RoutingTable {
     pub fn new() -> Self {
         Self {
             entries: HashMap::new(),
+            held_down: HashMap::new(),
+            withdrawn: VecDeque::new(),
+            delta_floor: 0,
             version: 0,
         }
     }
@@ -41,23 +187,55 @@ impl RoutingTable {
         let entries = self
             .entries
             .iter()
-            .map(|(node_id, (_intermediate, hops))| (*node_id, *hops))
+            .map(|(node_id, entry)| (*node_id, entry.hops))
             .collect::<HashMap<Hash, usize>>();
-        SharedRoutingTable { entries }
+        SharedRoutingTable {
+            entries,
+            version: self.version,
+        }
+    }
+
+    /// Retrieve the entries which changed since version `since`.
+    /// Returns `None` if that version is too old to be reconstructed,
+    /// in which case a full snapshot must be shared instead.
+    pub fn delta_since(&self, since: usize) -> Option<RoutingDelta> {
+        if since < self.delta_floor || since > self.version {
+            return None;
+        }
+        let updated = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.changed_in > since)
+            .map(|(node_id, entry)| (*node_id, entry.hops))
+            .collect::<HashMap<Hash, usize>>();
+        let withdrawn = self
+            .withdrawn
+            .iter()
+            .filter(|(node_id, removed_in)| *removed_in > since && !updated.contains_key(node_id))
+            .map(|(node_id, _)| *node_id)
+            .collect::<Vec<Hash>>();
+        Some(RoutingDelta {
+            base_version: since,
+            version: self.version,
+            updated,
+            withdrawn,
+        })
     }
 
     /// Retrieve routing information for a node.
     pub fn get_routing_info(&self, node_id: &Hash) -> Option<(Hash, usize)> {
-        self.entries.get(node_id).copied()
+        self.entries
+            .get(node_id)
+            .map(|entry| (entry.next_hop, entry.hops))
     }
 
     /// Get all routing information for all nodes.
-    pub fn entries(&self) -> &HashMap<Hash, (Hash, usize)> {
+    pub fn entries(&self) -> &HashMap<Hash, RouteEntry> {
         &self.entries
     }
 
     /// Get a mutable reference to routing information for all nodes.
-    pub fn entries_mut(&mut self) -> &mut HashMap<Hash, (Hash, usize)> {
+    pub fn entries_mut(&mut self) -> &mut HashMap<Hash, RouteEntry> {
         &mut self.entries
     }
 
@@ -69,12 +247,158 @@ impl RoutingTable {
     /// Insert a new routing info for a node,
     /// while generating a random one.
     pub fn add_new_node(&mut self, node_id: &Hash) {
-        let _ = self.entries.insert(*node_id, (Hash::random(), usize::MAX));
+        let mut entry = RouteEntry::new(Hash::random(), usize::MAX, 0, Instant::now());
+        entry.changed_in = self.pending_version();
+        let _ = self.entries.insert(*node_id, entry);
     }
 
-    /// Insert a new routing info for a node as a direct connection.
+    /// Insert a new routing info for a node as a direct connection, one hop away.
     pub fn add_direct_connection(&mut self, node_id: &Hash) {
-        let _ = self.entries.insert(*node_id, (*node_id, usize::MAX));
+        let _ = self.held_down.remove(node_id);
+        let mut entry = RouteEntry::new(*node_id, 1, self.version, Instant::now());
+        entry.changed_in = self.pending_version();
+        let _ = self.entries.insert(*node_id, entry);
+    }
+
+    /// Merge routing information shared by a directly connected peer.
+    /// Routes through `peer_id` are refreshed, shorter routes replace longer ones,
+    /// and destinations in hold-down or the peer cannot reach are left alone.
+    /// Returns `true` if any route changed.
+    pub fn apply_shared(
+        &mut self,
+        peer_id: &Hash,
+        shared_table: &SharedRoutingTable,
+        now: Instant,
+    ) -> bool {
+        let mut changed = false;
+        for (dest, hops) in shared_table.shared_entries() {
+            changed |= self.merge_route(peer_id, dest, *hops, shared_table.version(), now);
+        }
+        changed
+    }
+
+    /// Merge an incremental update from a directly connected peer.
+    /// Withdrawn destinations are only removed if routed through `peer_id`.
+    /// Returns `true` if any route changed.
+    pub fn apply_delta(&mut self, peer_id: &Hash, delta: &RoutingDelta, now: Instant) -> bool {
+        let mut changed = false;
+        for (dest, hops) in delta.updated() {
+            changed |= self.merge_route(peer_id, dest, *hops, delta.version(), now);
+        }
+        for dest in delta.withdrawn() {
+            let routed_via_peer = self
+                .entries
+                .get(dest)
+                .is_some_and(|entry| entry.next_hop == *peer_id && entry.next_hop != *dest);
+            if routed_via_peer {
+                self.remove_route(dest);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn merge_route(
+        &mut self,
+        peer_id: &Hash,
+        dest: &Hash,
+        hops: usize,
+        source_version: usize,
+        now: Instant,
+    ) -> bool {
+        if self.is_held_down(dest, now) {
+            return false;
+        }
+        let pending_version = self.pending_version();
+        let hops = hops.saturating_add(1);
+        if hops == usize::MAX && !self.entries.contains_key(dest) {
+            // No route is learnt from a peer which does not know one either
+            return false;
+        }
+        let entry = self
+            .entries
+            .entry(*dest)
+            .or_insert_with(|| RouteEntry::new(*peer_id, usize::MAX, source_version, now));
+        if entry.next_hop == *dest {
+            // Direct connections are not learnt from peers
+            return false;
+        }
+        let mut changed = false;
+        if hops < entry.hops || (entry.next_hop == *peer_id && hops != entry.hops) {
+            changed = true;
+            entry.next_hop = *peer_id;
+            entry.hops = hops;
+            entry.changed_in = pending_version;
+        }
+        if entry.next_hop == *peer_id {
+            entry.source_version = source_version;
+            entry.last_refreshed = now;
+        }
+        changed
+    }
+
+    fn remove_route(&mut self, dest: &Hash) {
+        let _ = self.entries.remove(dest);
+        self.withdrawn.push_back((*dest, self.pending_version()));
+        while self.withdrawn.len() > MAX_WITHDRAWN_LOG {
+            if let Some((_, removed_in)) = self.withdrawn.pop_front() {
+                self.delta_floor = removed_in;
+            }
+        }
+    }
+
+    fn pending_version(&self) -> usize {
+        self.version + 1
+    }
+
+    /// Remove routes which have not been refreshed within `ttl`,
+    /// placing their destinations in hold-down for `hold_down`.
+    /// Direct connections never expire this way.
+    /// Returns the destinations that were lost.
+    pub fn expire_routes(&mut self, now: Instant, ttl: Duration, hold_down: Duration) -> Vec<Hash> {
+        self.held_down.retain(|_, until| *until > now);
+
+        let lost = self
+            .entries
+            .iter()
+            .filter(|(dest, entry)| entry.next_hop != **dest && entry.is_stale(now, ttl))
+            .map(|(dest, _)| *dest)
+            .collect::<Vec<Hash>>();
+        for dest in &lost {
+            self.remove_route(dest);
+            let _ = self.held_down.insert(*dest, now + hold_down);
+        }
+        if !lost.is_empty() {
+            self.increment_version();
+        }
+        lost
+    }
+
+    /// Remove every route through a peer we lost our connection to,
+    /// including the direct route to it.
+    /// Returns the destinations which became unreachable.
+    pub fn remove_routes_via(&mut self, peer_id: &Hash) -> Vec<Hash> {
+        let lost = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.next_hop == *peer_id)
+            .map(|(dest, _)| *dest)
+            .collect::<Vec<Hash>>();
+        for dest in &lost {
+            self.remove_route(dest);
+        }
+        if !lost.is_empty() {
+            self.increment_version();
+        }
+        lost
+    }
+
+    /// Checks if a destination is in hold-down,
+    /// during which advertisements for it are ignored.
+    pub fn is_held_down(&self, node_id: &Hash, now: Instant) -> bool {
+        self.held_down
+            .get(node_id)
+            .is_some_and(|until| *until > now)
     }
 
     /// Bump version number of the routing table.
@@ -87,3 +411,242 @@ impl RoutingTable {
         self.version
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shared(entries: &[(Hash, usize)], version: usize) -> SharedRoutingTable {
+        SharedRoutingTable {
+            entries: entries.iter().copied().collect(),
+            version,
+        }
+    }
+
+    #[test]
+    fn test_apply_shared_refreshes_routes() {
+        let peer = Hash::random();
+        let dest = Hash::random();
+        let start = Instant::now();
+        let mut table = RoutingTable::new();
+        table.add_direct_connection(&peer);
+
+        assert!(table.apply_shared(&peer, &shared(&[(dest, 1)], 4), start));
+        assert_eq!(table.get_routing_info(&dest), Some((peer, 2)));
+
+        let later = start + Duration::from_secs(10);
+        assert!(!table.apply_shared(&peer, &shared(&[(dest, 1)], 5), later));
+        let entry = table.entries()[&dest];
+        assert_eq!(entry.source_version, 5);
+        assert_eq!(entry.last_refreshed, later);
+    }
+
+    #[test]
+    fn test_direct_connections_are_advertised() {
+        let (node, peer, unknown) = (Hash::random(), Hash::random(), Hash::random());
+        let now = Instant::now();
+        let mut table = RoutingTable::new();
+        table.add_direct_connection(&peer);
+        assert_eq!(table.get_routing_info(&peer), Some((peer, 1)));
+        assert!(table.get_shared().is_plausible(&node));
+
+        let mut neighbour = RoutingTable::new();
+        neighbour.add_direct_connection(&node);
+        assert!(neighbour.apply_shared(&node, &table.get_shared(), now));
+        assert_eq!(neighbour.get_routing_info(&peer), Some((node, 2)));
+
+        // Destinations the peer cannot reach are not added.
+        assert!(!neighbour.apply_shared(&node, &shared(&[(unknown, usize::MAX)], 1), now));
+        assert!(!neighbour.has_node(&unknown));
+    }
+
+    #[test]
+    fn test_stale_routes_expire() {
+        let peer = Hash::random();
+        let dest = Hash::random();
+        let start = Instant::now();
+        let ttl = Duration::from_secs(30);
+        let hold_down = Duration::from_secs(60);
+        let mut table = RoutingTable::new();
+        table.add_direct_connection(&peer);
+        let _ = table.apply_shared(&peer, &shared(&[(dest, 1)], 1), start);
+
+        assert!(table
+            .expire_routes(start + Duration::from_secs(10), ttl, hold_down)
+            .is_empty());
+        let lost = table.expire_routes(start + Duration::from_secs(31), ttl, hold_down);
+        assert_eq!(lost, vec![dest]);
+        assert!(!table.has_node(&dest));
+        assert!(table.has_node(&peer));
+    }
+
+    #[test]
+    fn test_routes_via_lost_peer_are_removed() {
+        let peer = Hash::random();
+        let other = Hash::random();
+        let dest = Hash::random();
+        let now = Instant::now();
+        let mut table = RoutingTable::new();
+        table.add_direct_connection(&peer);
+        table.add_direct_connection(&other);
+        let _ = table.apply_shared(&peer, &shared(&[(dest, 1)], 1), now);
+
+        let version = table.version();
+        let lost = table.remove_routes_via(&peer);
+        assert_eq!(lost.len(), 2);
+        assert!(lost.contains(&peer) && lost.contains(&dest));
+        assert!(table.has_node(&other));
+        assert!(table.version() > version);
+        assert!(table
+            .delta_since(version)
+            .is_some_and(|delta| delta.withdrawn().len() == 2));
+    }
+
+    #[test]
+    fn test_hold_down_ignores_advertisements() {
+        let peer = Hash::random();
+        let dest = Hash::random();
+        let start = Instant::now();
+        let ttl = Duration::from_secs(30);
+        let hold_down = Duration::from_secs(60);
+        let mut table = RoutingTable::new();
+        table.add_direct_connection(&peer);
+        let _ = table.apply_shared(&peer, &shared(&[(dest, 1)], 1), start);
+
+        let expired_at = start + Duration::from_secs(31);
+        let _ = table.expire_routes(expired_at, ttl, hold_down);
+        assert!(table.is_held_down(&dest, expired_at));
+        assert!(!table.apply_shared(&peer, &shared(&[(dest, 1)], 2), expired_at));
+        assert!(!table.has_node(&dest));
+
+        let released_at = expired_at + hold_down + Duration::from_secs(1);
+        let _ = table.expire_routes(released_at, ttl, hold_down);
+        assert!(!table.is_held_down(&dest, released_at));
+        assert!(table.apply_shared(&peer, &shared(&[(dest, 1)], 3), released_at));
+        assert_eq!(table.get_routing_info(&dest), Some((peer, 2)));
+    }
+
+    #[test]
+    fn test_delta_carries_only_changes() {
+        let peer = Hash::random();
+        let old_dest = Hash::random();
+        let new_dest = Hash::random();
+        let now = Instant::now();
+        let mut table = RoutingTable::new();
+        table.add_direct_connection(&peer);
+        let _ = table.apply_shared(&peer, &shared(&[(old_dest, 1)], 1), now);
+        table.increment_version();
+        let since = table.version();
+
+        let _ = table.apply_shared(&peer, &shared(&[(old_dest, 1), (new_dest, 2)], 2), now);
+        table.increment_version();
+        let delta = table.delta_since(since);
+        assert!(delta.is_some());
+        let delta = delta.unwrap_or_default();
+        assert_eq!(delta.base_version(), since);
+        assert_eq!(delta.version(), table.version());
+        assert_eq!(delta.updated().len(), 1);
+        assert_eq!(delta.updated().get(&new_dest), Some(&3));
+        assert!(delta.withdrawn().is_empty());
+    }
+
+    #[test]
+    fn test_delta_propagates_withdrawals() {
+        let peer = Hash::random();
+        let dest = Hash::random();
+        let now = Instant::now();
+        let mut upstream = RoutingTable::new();
+        let _ = upstream.apply_shared(&peer, &shared(&[(dest, 0)], 1), now);
+        upstream.increment_version();
+        let since = upstream.version();
+        let _ = upstream.expire_routes(
+            now + Duration::from_secs(2),
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+        );
+
+        let delta = upstream.delta_since(since).unwrap_or_default();
+        assert_eq!(delta.withdrawn(), &[dest]);
+
+        let upstream_id = Hash::random();
+        let mut downstream = RoutingTable::new();
+        downstream.add_direct_connection(&upstream_id);
+        let _ = downstream.apply_shared(&upstream_id, &shared(&[(dest, 1)], since), now);
+        assert!(downstream.apply_delta(&upstream_id, &delta, now));
+        assert!(!downstream.has_node(&dest));
+    }
+
+    #[test]
+    fn test_delta_unavailable_after_log_is_pruned() {
+        let peer = Hash::random();
+        let now = Instant::now();
+        let mut table = RoutingTable::new();
+        let dests = (0..=MAX_WITHDRAWN_LOG)
+            .map(|_| Hash::random())
+            .collect::<Vec<Hash>>();
+        let advertised = dests.iter().map(|dest| (*dest, 1)).collect::<Vec<_>>();
+        let _ = table.apply_shared(&peer, &shared(&advertised, 1), now);
+        table.increment_version();
+        let since = table.version();
+
+        for dest in &dests {
+            table.remove_route(dest);
+            table.increment_version();
+        }
+        assert!(table.delta_since(since).is_none());
+        assert!(table.delta_since(table.version()).is_some());
+    }
+
+    #[test]
+    fn test_delta_bandwidth_with_1000_nodes() {
+        let peer = Hash::random();
+        let now = Instant::now();
+        let mut table = RoutingTable::new();
+        table.add_direct_connection(&peer);
+        let advertised = (0..1000)
+            .map(|hops| (Hash::random(), hops))
+            .collect::<Vec<_>>();
+        let _ = table.apply_shared(&peer, &shared(&advertised, 1), now);
+        table.increment_version();
+        let since = table.version();
+
+        let _ = table.apply_shared(&peer, &shared(&[(Hash::random(), 0)], 2), now);
+        table.increment_version();
+
+        let full = bincode::serialize(&table.get_shared()).unwrap_or_default();
+        let delta =
+            bincode::serialize(&table.delta_since(since).unwrap_or_default()).unwrap_or_default();
+        assert!(!delta.is_empty());
+        assert!(delta.len() * 100 < full.len());
+    }
+
+    #[test]
+    fn test_signable_bytes_are_canonical() {
+        let source = Hash::random();
+        let advertised = (0..32)
+            .map(|hops| (Hash::random(), hops))
+            .collect::<Vec<_>>();
+        let table = shared(&advertised, 7);
+        let decoded = bincode::serialize(&table)
+            .and_then(|bytes| bincode::deserialize::<SharedRoutingTable>(&bytes))
+            .unwrap_or_default();
+        assert_eq!(
+            table.signable_bytes(&source).ok(),
+            decoded.signable_bytes(&source).ok()
+        );
+        assert_ne!(
+            table.signable_bytes(&source).ok(),
+            table.signable_bytes(&Hash::random()).ok()
+        );
+    }
+
+    #[test]
+    fn test_implausible_hop_counts() {
+        let source = Hash::random();
+        let other = Hash::random();
+        assert!(shared(&[(source, 0), (other, 3)], 1).is_plausible(&source));
+        assert!(shared(&[(other, usize::MAX)], 1).is_plausible(&source));
+        assert!(!shared(&[(other, 0)], 1).is_plausible(&source));
+        assert!(!shared(&[(other, MAX_ADVERTISED_HOPS + 1)], 1).is_plausible(&source));
+    }
+}
@@ -36,6 +36,20 @@ impl Hash {
         Self(blake3::hash(buf.as_bytes()))
     }
 
+    /// Creates a `Hash` directly from its raw bytes, without hashing them
+    pub fn from_raw(bytes: [u8; blake3::OUT_LEN]) -> Self {
+        Self(blake3::Hash::from(bytes))
+    }
+
+    /// Bitwise XOR of two `Hash`es
+    pub fn xor(&self, other: &Hash) -> [u8; blake3::OUT_LEN] {
+        let mut out = [0; blake3::OUT_LEN];
+        for (i, byte) in out.iter_mut().enumerate() {
+            *byte = self.0.as_bytes()[i] ^ other.0.as_bytes()[i];
+        }
+        out
+    }
+
     /// Convert a `Hash` to a `Vec<u8>`
     pub fn to_vec(&self) -> Vec<u8> {
         self.0.as_bytes().to_vec()
@@ -94,11 +108,13 @@ impl<'de> Visitor<'de> for HashVisitor {
         formatter.write_str("a hash byte array")
     }
 
-    fn visit_borrowed_bytes<E>(self, v: &'de [u8]) -> Result<Self::Value, E>
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
     where
         E: serde::de::Error,
     {
-        Ok(Hash::from_bytes(v))
+        let bytes =
+            <[u8; blake3::OUT_LEN]>::try_from(v).map_err(|_| E::invalid_length(v.len(), &self))?;
+        Ok(Hash::from_raw(bytes))
     }
 
     fn visit_borrowed_str<E>(self, v: &'de str) -> Result<Self::Value, E>
@@ -117,3 +133,26 @@ impl<'de> Deserialize<'de> for Hash {
         deserializer.deserialize_bytes(HashVisitor)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_serde() {
+        let hash = Hash::random();
+        let hash_ser = bincode::serialize(&hash);
+        assert!(hash_ser.is_ok());
+
+        let hash_de = bincode::deserialize::<Hash>(&hash_ser.unwrap_or_default());
+        assert!(hash_de.is_ok());
+        assert_eq!(hash, hash_de.unwrap_or_else(|_| Hash::random()));
+    }
+
+    #[test]
+    fn test_hash_rejects_wrong_length() {
+        let hash_ser = bincode::serialize(&[0u8; 16][..]);
+        assert!(hash_ser.is_ok());
+        assert!(bincode::deserialize::<Hash>(&hash_ser.unwrap_or_default()).is_err());
+    }
+}
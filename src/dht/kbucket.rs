@@ -0,0 +1,335 @@
+use crate::{connection::connection_types::ConnectionInfo, crypto::hash::Hash};
+use rand::{thread_rng, Rng};
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// Number of bits in a node ID, and thus the number of buckets
+pub const ID_BITS: usize = 256;
+
+/// Distance between two node IDs under the XOR metric
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Distance([u8; 32]);
+
+impl Distance {
+    /// Computes the XOR distance between two node IDs.
+    pub fn between(a: &Hash, b: &Hash) -> Self {
+        Self(a.xor(b))
+    }
+
+    /// Index of the bucket a node at this distance belongs in,
+    /// i.e. the position of the highest set bit.
+    /// Returns `None` for a zero distance.
+    pub fn bucket_index(&self) -> Option<usize> {
+        let leading_zeros = self
+            .0
+            .iter()
+            .position(|byte| *byte != 0)
+            .map(|i| i * 8 + self.0[i].leading_zeros() as usize)?;
+        Some(ID_BITS - 1 - leading_zeros)
+    }
+}
+
+/// A node known to the DHT
+#[derive(Debug, Clone, Copy)]
+pub struct BucketEntry {
+    /// How to reach the node
+    pub info: ConnectionInfo,
+    /// When the node was last heard from
+    pub last_seen: Instant,
+}
+
+/// Outcome of inserting a node into the table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertOutcome {
+    /// The node was added to its bucket
+    Inserted,
+    /// The node was already known, and has been moved to the tail of its bucket
+    Updated,
+    /// The bucket is full; the least-recently seen node should be pinged
+    /// and evicted if it does not answer
+    Full {
+        /// ID of the least-recently seen node of the bucket
+        node_id: Hash,
+        /// Address of the least-recently seen node of the bucket
+        socket_addr: SocketAddr,
+    },
+    /// The node is known at another address or with other keys; the known one
+    /// should be pinged and replaced if it does not answer
+    Conflict {
+        /// Address the node is known at
+        socket_addr: SocketAddr,
+    },
+    /// The node is ourselves
+    Local,
+}
+
+/// A single k-bucket, ordered from least- to most-recently seen
+#[derive(Debug, Clone)]
+struct KBucket {
+    nodes: VecDeque<BucketEntry>,
+    last_refreshed: Instant,
+}
+
+/// Kademlia routing table of k-buckets over the XOR metric
+#[derive(Debug, Clone)]
+pub struct KBucketTable {
+    local_id: Hash,
+    bucket_size: usize,
+    buckets: Vec<KBucket>,
+}
+
+impl KBucketTable {
+    /// Creates a new `KBucketTable` around `local_id`.
+    pub fn new(local_id: Hash, bucket_size: usize, now: Instant) -> Self {
+        let buckets = (0..ID_BITS)
+            .map(|_| KBucket {
+                nodes: VecDeque::with_capacity(bucket_size),
+                last_refreshed: now,
+            })
+            .collect();
+        Self {
+            local_id,
+            bucket_size,
+            buckets,
+        }
+    }
+
+    /// Retrieves the ID the table is centred on.
+    pub fn local_id(&self) -> &Hash {
+        &self.local_id
+    }
+
+    /// Record that a node has been heard from.
+    /// A known node is never moved to another address or keys here; that takes
+    /// the known one failing to answer a ping.
+    pub fn insert(&mut self, info: ConnectionInfo, now: Instant) -> InsertOutcome {
+        let index = match Distance::between(&self.local_id, &info.public_id.node_id).bucket_index()
+        {
+            Some(index) => index,
+            None => return InsertOutcome::Local,
+        };
+        let bucket_size = self.bucket_size;
+        let bucket = &mut self.buckets[index];
+        bucket.last_refreshed = now;
+        if let Some(pos) = bucket
+            .nodes
+            .iter()
+            .position(|entry| entry.info.public_id.node_id == info.public_id.node_id)
+        {
+            if bucket.nodes[pos].info != info {
+                return InsertOutcome::Conflict {
+                    socket_addr: bucket.nodes[pos].info.socket_addr,
+                };
+            }
+            let _ = bucket.nodes.remove(pos);
+            bucket.nodes.push_back(BucketEntry {
+                info,
+                last_seen: now,
+            });
+            return InsertOutcome::Updated;
+        }
+        if bucket.nodes.len() < bucket_size {
+            bucket.nodes.push_back(BucketEntry {
+                info,
+                last_seen: now,
+            });
+            return InsertOutcome::Inserted;
+        }
+        match bucket.nodes.front() {
+            Some(entry) => InsertOutcome::Full {
+                node_id: entry.info.public_id.node_id,
+                socket_addr: entry.info.socket_addr,
+            },
+            None => InsertOutcome::Inserted,
+        }
+    }
+
+    /// Remove a node from the table.
+    pub fn remove(&mut self, node_id: &Hash) -> Option<BucketEntry> {
+        let index = Distance::between(&self.local_id, node_id).bucket_index()?;
+        let bucket = &mut self.buckets[index];
+        let pos = bucket
+            .nodes
+            .iter()
+            .position(|entry| entry.info.public_id.node_id == *node_id)?;
+        bucket.nodes.remove(pos)
+    }
+
+    /// Checks if the table contains a node.
+    pub fn contains(&self, node_id: &Hash) -> bool {
+        Distance::between(&self.local_id, node_id)
+            .bucket_index()
+            .is_some_and(|index| {
+                self.buckets[index]
+                    .nodes
+                    .iter()
+                    .any(|entry| entry.info.public_id.node_id == *node_id)
+            })
+    }
+
+    /// Retrieves up to `count` known nodes closest to `target`.
+    pub fn closest(&self, target: &Hash, count: usize) -> Vec<ConnectionInfo> {
+        let mut nodes = self
+            .buckets
+            .iter()
+            .flat_map(|bucket| bucket.nodes.iter())
+            .map(|entry| {
+                (
+                    Distance::between(target, &entry.info.public_id.node_id),
+                    entry.info,
+                )
+            })
+            .collect::<Vec<_>>();
+        nodes.sort_by_key(|(distance, _)| *distance);
+        nodes
+            .into_iter()
+            .take(count)
+            .map(|(_, info)| info)
+            .collect()
+    }
+
+    /// Total number of nodes in the table.
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.nodes.len()).sum()
+    }
+
+    /// Checks if the table is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Indices of the non-empty buckets which have not been refreshed within `interval`.
+    pub fn stale_buckets(&self, now: Instant, interval: Duration) -> Vec<usize> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, bucket)| {
+                !bucket.nodes.is_empty()
+                    && now.saturating_duration_since(bucket.last_refreshed) > interval
+            })
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Mark a bucket as refreshed.
+    pub fn mark_refreshed(&mut self, index: usize, now: Instant) {
+        if let Some(bucket) = self.buckets.get_mut(index) {
+            bucket.last_refreshed = now;
+        }
+    }
+
+    /// Generates a random ID which falls into the bucket at `index`.
+    pub fn random_id_in_bucket(&self, index: usize) -> Hash {
+        let mut distance = [0u8; 32];
+        thread_rng().fill(&mut distance);
+        let byte = (ID_BITS - 1 - index) / 8;
+        let bit = 7 - (ID_BITS - 1 - index) % 8;
+        for b in distance.iter_mut().take(byte) {
+            *b = 0;
+        }
+        distance[byte] &= (1u8 << bit) - 1;
+        distance[byte] |= 1 << bit;
+        Hash::from_raw(Hash::from_raw(distance).xor(&self.local_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::connection_info;
+
+    #[test]
+    fn test_bucket_index() {
+        let local = Hash::random();
+        assert_eq!(Distance::between(&local, &local).bucket_index(), None);
+
+        let mut raw = [0u8; 32];
+        raw[31] = 1;
+        let near = Hash::from_raw(Hash::from_raw(raw).xor(&local));
+        assert_eq!(Distance::between(&local, &near).bucket_index(), Some(0));
+
+        let mut raw = [0u8; 32];
+        raw[0] = 0x80;
+        let far = Hash::from_raw(Hash::from_raw(raw).xor(&local));
+        assert_eq!(
+            Distance::between(&local, &far).bucket_index(),
+            Some(ID_BITS - 1)
+        );
+    }
+
+    #[test]
+    fn test_random_id_in_bucket() {
+        let now = Instant::now();
+        let table = KBucketTable::new(Hash::random(), 20, now);
+        for index in [0, 7, 8, 100, 255] {
+            let id = table.random_id_in_bucket(index);
+            assert_eq!(
+                Distance::between(table.local_id(), &id).bucket_index(),
+                Some(index)
+            );
+        }
+    }
+
+    #[test]
+    fn test_full_bucket_reports_least_recent() {
+        let now = Instant::now();
+        let mut table = KBucketTable::new(Hash::random(), 2, now);
+        let ids = (0..3)
+            .map(|_| table.random_id_in_bucket(200))
+            .collect::<Vec<Hash>>();
+        let infos = ids
+            .iter()
+            .enumerate()
+            .map(|(port, id)| connection_info(*id, port as u16))
+            .collect::<Vec<_>>();
+
+        assert_eq!(table.insert(infos[0], now), InsertOutcome::Inserted);
+        assert_eq!(table.insert(infos[1], now), InsertOutcome::Inserted);
+        assert_eq!(table.insert(infos[0], now), InsertOutcome::Updated);
+        assert_eq!(
+            table.insert(infos[2], now),
+            InsertOutcome::Full {
+                node_id: ids[1],
+                socket_addr: infos[1].socket_addr,
+            }
+        );
+        assert!(table.remove(&ids[1]).is_some());
+        assert_eq!(table.insert(infos[2], now), InsertOutcome::Inserted);
+        assert_eq!(table.len(), 2);
+
+        // A known node claimed at another address is not redirected.
+        let impostor = connection_info(ids[0], 99);
+        assert_eq!(
+            table.insert(impostor, now),
+            InsertOutcome::Conflict {
+                socket_addr: infos[0].socket_addr,
+            }
+        );
+        assert_eq!(
+            table
+                .closest(&ids[0], 1)
+                .first()
+                .map(|info| info.socket_addr),
+            Some(infos[0].socket_addr)
+        );
+    }
+
+    #[test]
+    fn test_closest_sorted_by_distance() {
+        let now = Instant::now();
+        let mut table = KBucketTable::new(Hash::random(), 20, now);
+        for port in 0..50 {
+            let _ = table.insert(connection_info(Hash::random(), port), now);
+        }
+        let target = Hash::random();
+        let closest = table.closest(&target, 10);
+        assert_eq!(closest.len(), 10);
+        assert!(closest.windows(2).all(|pair| {
+            Distance::between(&target, &pair[0].public_id.node_id)
+                <= Distance::between(&target, &pair[1].public_id.node_id)
+        }));
+    }
+}
@@ -0,0 +1,249 @@
+use super::kbucket::Distance;
+use crate::{connection::connection_types::ConnectionInfo, crypto::hash::Hash};
+use std::time::{Duration, Instant};
+
+/// Progress of a single candidate in a lookup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum QueryState {
+    NotQueried,
+    InFlight(Instant),
+    Responded,
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+struct Candidate {
+    distance: Distance,
+    info: ConnectionInfo,
+    state: QueryState,
+}
+
+/// An iterative Kademlia node lookup.
+/// Keeps at most `alpha` queries in flight, and finishes once
+/// the `k` closest candidates have all answered or failed.
+#[derive(Debug, Clone)]
+pub struct Lookup {
+    target: Hash,
+    alpha: usize,
+    k: usize,
+    candidates: Vec<Candidate>,
+}
+
+impl Lookup {
+    /// Creates a new `Lookup` for `target`, seeded with known nodes.
+    pub fn new<I>(target: Hash, alpha: usize, k: usize, seeds: I) -> Self
+    where
+        I: IntoIterator<Item = ConnectionInfo>,
+    {
+        let mut lookup = Self {
+            target,
+            alpha,
+            k,
+            candidates: Vec::new(),
+        };
+        lookup.add_candidates(seeds);
+        lookup
+    }
+
+    /// Retrieves the lookup target.
+    pub fn target(&self) -> &Hash {
+        &self.target
+    }
+
+    /// Pick the next nodes to query, marking them as in flight.
+    pub fn next_queries(&mut self, now: Instant) -> Vec<ConnectionInfo> {
+        let in_flight = self
+            .candidates
+            .iter()
+            .filter(|candidate| matches!(candidate.state, QueryState::InFlight(_)))
+            .count();
+        let mut queries = Vec::new();
+        for candidate in self.candidates.iter_mut().take(self.k) {
+            if in_flight + queries.len() >= self.alpha {
+                break;
+            }
+            if candidate.state == QueryState::NotQueried {
+                candidate.state = QueryState::InFlight(now);
+                queries.push(candidate.info);
+            }
+        }
+        queries
+    }
+
+    /// Record the nodes returned by a queried node.
+    /// Returns `false`, ignoring the nodes, if the node has no query in flight.
+    pub fn on_response<I>(&mut self, from: &Hash, nodes: I) -> bool
+    where
+        I: IntoIterator<Item = ConnectionInfo>,
+    {
+        let queried = self.candidates.iter().any(|candidate| {
+            candidate.info.public_id.node_id == *from
+                && matches!(candidate.state, QueryState::InFlight(_))
+        });
+        if queried {
+            self.set_state(from, QueryState::Responded);
+            self.add_candidates(nodes);
+        }
+        queried
+    }
+
+    /// Record that a queried node did not answer.
+    pub fn on_failure(&mut self, from: &Hash) {
+        self.set_state(from, QueryState::Failed);
+    }
+
+    /// Fail every query which has been in flight for longer than `timeout`.
+    /// Returns the nodes which timed out.
+    pub fn expire_queries(&mut self, now: Instant, timeout: Duration) -> Vec<ConnectionInfo> {
+        let mut expired = Vec::new();
+        for candidate in &mut self.candidates {
+            if let QueryState::InFlight(sent_at) = candidate.state {
+                if now.saturating_duration_since(sent_at) > timeout {
+                    candidate.state = QueryState::Failed;
+                    expired.push(candidate.info);
+                }
+            }
+        }
+        expired
+    }
+
+    /// Checks if the `k` closest candidates have all answered or failed.
+    pub fn is_finished(&self) -> bool {
+        self.candidates
+            .iter()
+            .filter(|candidate| candidate.state != QueryState::Failed)
+            .take(self.k)
+            .all(|candidate| candidate.state == QueryState::Responded)
+    }
+
+    /// Retrieves the `k` closest nodes which answered.
+    pub fn closest(&self) -> Vec<ConnectionInfo> {
+        self.candidates
+            .iter()
+            .filter(|candidate| candidate.state == QueryState::Responded)
+            .take(self.k)
+            .map(|candidate| candidate.info)
+            .collect()
+    }
+
+    fn set_state(&mut self, node_id: &Hash, state: QueryState) {
+        if let Some(candidate) = self
+            .candidates
+            .iter_mut()
+            .find(|candidate| candidate.info.public_id.node_id == *node_id)
+        {
+            candidate.state = state;
+        }
+    }
+
+    fn add_candidates<I>(&mut self, nodes: I)
+    where
+        I: IntoIterator<Item = ConnectionInfo>,
+    {
+        for info in nodes {
+            let node_id = info.public_id.node_id;
+            if self
+                .candidates
+                .iter()
+                .any(|candidate| candidate.info.public_id.node_id == node_id)
+            {
+                continue;
+            }
+            let distance = Distance::between(&self.target, &node_id);
+            let pos = self
+                .candidates
+                .partition_point(|candidate| candidate.distance < distance);
+            self.candidates.insert(
+                pos,
+                Candidate {
+                    distance,
+                    info,
+                    state: QueryState::NotQueried,
+                },
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dht::kbucket::KBucketTable, test_utils::connection_info};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_respects_alpha() {
+        let now = Instant::now();
+        let seeds = (0..10).map(|port| connection_info(Hash::random(), port));
+        let mut lookup = Lookup::new(Hash::random(), 3, 20, seeds);
+
+        let first = lookup.next_queries(now);
+        assert_eq!(first.len(), 3);
+        assert!(lookup.next_queries(now).is_empty());
+
+        assert!(lookup.on_response(&first[0].public_id.node_id, Vec::new()));
+        assert_eq!(lookup.next_queries(now).len(), 1);
+
+        let expired = lookup.expire_queries(now + Duration::from_secs(10), Duration::from_secs(5));
+        assert_eq!(expired.len(), 3);
+        assert_eq!(lookup.next_queries(now).len(), 3);
+    }
+
+    #[test]
+    fn test_ignores_unsolicited_responses() {
+        let now = Instant::now();
+        let seeds = (0..2)
+            .map(|port| connection_info(Hash::random(), port))
+            .collect::<Vec<_>>();
+        let mut lookup = Lookup::new(Hash::random(), 1, 20, seeds.iter().copied());
+        let queried = lookup.next_queries(now);
+        let unqueried = if queried == seeds[..1] {
+            seeds[1].public_id.node_id
+        } else {
+            seeds[0].public_id.node_id
+        };
+        let injected = connection_info(Hash::random(), 9000);
+
+        assert!(!lookup.on_response(&Hash::random(), vec![injected]));
+        assert!(!lookup.on_response(&unqueried, vec![injected]));
+        assert!(lookup.on_response(&queried[0].public_id.node_id, Vec::new()));
+        assert!(!lookup.on_response(&queried[0].public_id.node_id, vec![injected]));
+        assert_eq!(lookup.next_queries(now).len(), 1);
+        assert_eq!(lookup.closest(), queried);
+    }
+
+    #[test]
+    fn test_converges_on_closest_nodes() {
+        let now = Instant::now();
+        let k = 8;
+        let network = (0..200)
+            .map(|port| connection_info(Hash::random(), port))
+            .collect::<Vec<_>>();
+        let tables = network
+            .iter()
+            .map(|info| {
+                let mut table = KBucketTable::new(info.public_id.node_id, k, now);
+                for peer in &network {
+                    let _ = table.insert(*peer, now);
+                }
+                (info.public_id.node_id, table)
+            })
+            .collect::<HashMap<_, _>>();
+
+        let target = Hash::random();
+        let mut lookup = Lookup::new(target, 3, k, network.iter().take(3).copied());
+        while !lookup.is_finished() {
+            let queries = lookup.next_queries(now);
+            assert!(!queries.is_empty());
+            for info in queries {
+                let node_id = info.public_id.node_id;
+                assert!(lookup.on_response(&node_id, tables[&node_id].closest(&target, k)));
+            }
+        }
+
+        let mut expected = network.clone();
+        expected.sort_by_key(|info| Distance::between(&target, &info.public_id.node_id));
+        expected.truncate(k);
+        assert_eq!(lookup.closest(), expected);
+    }
+}
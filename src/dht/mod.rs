@@ -0,0 +1,278 @@
+use crate::{connection::connection_types::ConnectionInfo, crypto::hash::Hash, Message, Result};
+use bytes::Bytes;
+use kbucket::{InsertOutcome, KBucketTable};
+use lookup::Lookup;
+use qp2p::Connection as QuicConnection;
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// Kademlia k-buckets over the XOR metric
+pub mod kbucket;
+/// Iterative node lookups
+pub mod lookup;
+
+/// Maximum number of nodes per bucket, and number of nodes returned by a lookup
+pub const K: usize = 20;
+/// Number of lookup queries kept in flight at once
+pub const ALPHA: usize = 3;
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+const BUCKET_REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Clone, Copy)]
+struct PendingPing {
+    least_recent: Hash,
+    candidate: ConnectionInfo,
+    sent_at: Instant,
+}
+
+/// Kademlia-style peer discovery keyed by `node_id`
+#[derive(Debug, Clone)]
+pub struct Dht {
+    table: KBucketTable,
+    lookups: HashMap<Hash, Lookup>,
+    pending_pings: HashMap<u64, PendingPing>,
+}
+
+impl Dht {
+    /// Creates a new `Dht` centred on our own node ID.
+    pub fn new(self_id: Hash) -> Self {
+        Self {
+            table: KBucketTable::new(self_id, K, Instant::now()),
+            lookups: Default::default(),
+            pending_pings: Default::default(),
+        }
+    }
+
+    /// Retrieves the k-bucket table.
+    pub fn table(&self) -> &KBucketTable {
+        &self.table
+    }
+
+    /// Record that a node has been heard from.
+    /// If its bucket is full, the least-recently seen node is pinged,
+    /// and replaced by this one if it does not answer in time. Likewise, a node
+    /// claimed at a new address or with new keys replaces the known one only if
+    /// that one does not answer.
+    pub async fn observe(&mut self, info: ConnectionInfo, quic: &mut QuicConnection) -> Result<()> {
+        let (node_id, socket_addr) = match self.table.insert(info, Instant::now()) {
+            InsertOutcome::Full {
+                node_id,
+                socket_addr,
+            } => (node_id, socket_addr),
+            InsertOutcome::Conflict { socket_addr } => (info.public_id.node_id, socket_addr),
+            _ => return Ok(()),
+        };
+        let already_pinging = self
+            .pending_pings
+            .values()
+            .any(|ping| ping.least_recent == node_id);
+        if already_pinging {
+            return Ok(());
+        }
+        let nonce = rand::thread_rng().gen::<u64>();
+        let _ = self.pending_pings.insert(
+            nonce,
+            PendingPing {
+                least_recent: node_id,
+                candidate: info,
+                sent_at: Instant::now(),
+            },
+        );
+        send("Ping", &socket_addr, &Message::Ping { nonce }, quic).await
+    }
+
+    /// Handle an answer to a ping, keeping the node that answered.
+    pub fn handle_pong(&mut self, nonce: u64) {
+        if let Some(ping) = self.pending_pings.remove(&nonce) {
+            if let Some(entry) = self.table.remove(&ping.least_recent) {
+                let _ = self.table.insert(entry.info, Instant::now());
+            }
+        }
+    }
+
+    /// Answer a FIND_NODE request with the closest nodes we know of.
+    pub async fn handle_find_node(
+        &mut self,
+        self_info: &ConnectionInfo,
+        sender: ConnectionInfo,
+        target: Hash,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        let nodes = self
+            .table
+            .closest(&target, K)
+            .into_iter()
+            .filter(|info| info.public_id.node_id != sender.public_id.node_id)
+            .collect();
+        send(
+            "Nodes",
+            &sender.socket_addr,
+            &Message::Nodes {
+                target,
+                sender: *self_info,
+                nodes,
+            },
+            quic,
+        )
+        .await?;
+        self.observe(sender, quic).await
+    }
+
+    /// Feed the answer to a FIND_NODE request into its lookup,
+    /// and send out the next round of queries.
+    /// Answers from nodes which were not queried are ignored.
+    pub async fn handle_nodes(
+        &mut self,
+        self_info: &ConnectionInfo,
+        sender: ConnectionInfo,
+        target: Hash,
+        nodes: Vec<ConnectionInfo>,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        let self_id = *self.table.local_id();
+        let accepted = self.lookups.get_mut(&target).is_some_and(|lookup| {
+            lookup.on_response(
+                &sender.public_id.node_id,
+                nodes
+                    .into_iter()
+                    .filter(|info| info.public_id.node_id != self_id),
+            )
+        });
+        if !accepted {
+            log::debug!("Ignoring unsolicited nodes from {:?}", sender);
+            return Ok(());
+        }
+        self.observe(sender, quic).await?;
+        self.advance_lookup(self_info, &target, quic).await
+    }
+
+    /// Start an iterative lookup for the nodes closest to `target`.
+    pub async fn start_lookup(
+        &mut self,
+        self_info: &ConnectionInfo,
+        target: Hash,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        let seeds = self.table.closest(&target, K);
+        let _ = self
+            .lookups
+            .insert(target, Lookup::new(target, ALPHA, K, seeds));
+        self.advance_lookup(self_info, &target, quic).await
+    }
+
+    /// Look up our own ID, to populate the buckets nearest to us.
+    pub async fn self_lookup(
+        &mut self,
+        self_info: &ConnectionInfo,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        let self_id = *self.table.local_id();
+        self.start_lookup(self_info, self_id, quic).await
+    }
+
+    /// Look up a random ID in every bucket that has not been refreshed recently,
+    /// and time out unanswered queries and pings.
+    /// Returns the nodes which did not answer in time.
+    pub async fn refresh(
+        &mut self,
+        self_info: &ConnectionInfo,
+        quic: &mut QuicConnection,
+    ) -> Result<Vec<ConnectionInfo>> {
+        let now = Instant::now();
+        let mut timed_out = self.expire_pings(now);
+        let targets = self.lookups.keys().copied().collect::<Vec<Hash>>();
+        for target in targets {
+            if let Some(lookup) = self.lookups.get_mut(&target) {
+                for info in lookup.expire_queries(now, QUERY_TIMEOUT) {
+                    log::debug!("Lookup query to {:?} timed out", info);
+                    let _ = self.table.remove(&info.public_id.node_id);
+                    timed_out.push(info);
+                }
+            }
+            self.advance_lookup(self_info, &target, quic).await?;
+        }
+        if self.table.is_empty() {
+            return Ok(timed_out);
+        }
+        for index in self.table.stale_buckets(now, BUCKET_REFRESH_INTERVAL) {
+            self.table.mark_refreshed(index, now);
+            let target = self.table.random_id_in_bucket(index);
+            self.start_lookup(self_info, target, quic).await?;
+        }
+        Ok(timed_out)
+    }
+
+    fn expire_pings(&mut self, now: Instant) -> Vec<ConnectionInfo> {
+        let expired = self
+            .pending_pings
+            .iter()
+            .filter(|(_, ping)| now.saturating_duration_since(ping.sent_at) > QUERY_TIMEOUT)
+            .map(|(nonce, _)| *nonce)
+            .collect::<Vec<u64>>();
+        let mut evicted = Vec::new();
+        for nonce in expired {
+            if let Some(ping) = self.pending_pings.remove(&nonce) {
+                log::debug!("Evicting unresponsive node {:?}", ping.least_recent);
+                if let Some(entry) = self.table.remove(&ping.least_recent) {
+                    evicted.push(entry.info);
+                }
+                let _ = self.table.insert(ping.candidate, now);
+            }
+        }
+        evicted
+    }
+
+    async fn advance_lookup(
+        &mut self,
+        self_info: &ConnectionInfo,
+        target: &Hash,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        let lookup = match self.lookups.get_mut(target) {
+            Some(lookup) => lookup,
+            None => return Ok(()),
+        };
+        if lookup.is_finished() {
+            log::debug!(
+                "Lookup for {:?} finished with {} nodes",
+                target,
+                lookup.closest().len()
+            );
+            let _ = self.lookups.remove(target);
+            return Ok(());
+        }
+        for info in lookup.next_queries(Instant::now()) {
+            send(
+                "Find node",
+                &info.socket_addr,
+                &Message::FindNode {
+                    target: *target,
+                    sender: *self_info,
+                },
+                quic,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+async fn send(
+    header: &'static str,
+    socket_addr: &SocketAddr,
+    msg: &Message,
+    quic: &mut QuicConnection,
+) -> Result<()> {
+    let user_msg_bytes = (
+        Bytes::from(header),
+        Bytes::from(socket_addr.to_string()),
+        Bytes::from(bincode::serialize(msg)?),
+    );
+    quic.send(user_msg_bytes).await?;
+    Ok(())
+}
@@ -3,6 +3,10 @@ use crate::Event;
 /// All error types
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
+    /// Errors when erasure-coding payloads
+    #[error("{0}")]
+    ErasureCodingError(reed_solomon_erasure::Error),
+
     /// Errors associated with BLS private keys, public keys, and signatures
     #[error("{0}")]
     BlsThresholdCryptoError(blsttc::Error),
@@ -17,15 +21,35 @@ pub enum Error {
 
     /// Message not sent because the channel is disconnected
     #[error("{0}")]
-    CrossbeamSendError(crossbeam_channel::SendError<Event>),
+    CrossbeamSendError(Box<crossbeam_channel::SendError<Event>>),
 
     /// Serialization errors
     #[error("{0}")]
     BincodeSerializeError(bincode::Error),
 
+    /// Errors when reading or writing local files
+    #[error("{0}")]
+    IoError(std::io::Error),
+
     /// No routing information found for this node
     #[error("No routing information found for this node")]
     NoRoutingInformation,
+
+    /// No circuit whose other end proved its identity
+    #[error("No authenticated circuit with this identifier")]
+    NoCircuit,
+
+    /// A block rebuilt from the transactions of its sender does not match its ID
+    #[error("Block {0:?} does not match its ID")]
+    InvalidBlock(crate::crypto::hash::Hash),
+
+    /// A chunk does not belong to the payload it claims, or does not fit its layout
+    #[error("Chunk of {0:?} does not match its root")]
+    InvalidChunk(crate::crypto::hash::Hash),
+
+    /// Payloads cannot be split into this number of chunks
+    #[error("Invalid number of chunks")]
+    InvalidChunkLayout,
 }
 
 impl From<blsttc::Error> for Error {
@@ -34,6 +58,12 @@ impl From<blsttc::Error> for Error {
     }
 }
 
+impl From<reed_solomon_erasure::Error> for Error {
+    fn from(value: reed_solomon_erasure::Error) -> Self {
+        Error::ErasureCodingError(value)
+    }
+}
+
 impl From<bincode::Error> for Error {
     fn from(value: bincode::Error) -> Self {
         Error::BincodeSerializeError(value)
@@ -54,6 +84,12 @@ impl From<qp2p::ConnectionError> for Error {
 
 impl From<crossbeam_channel::SendError<Event>> for Error {
     fn from(value: crossbeam_channel::SendError<Event>) -> Self {
-        Error::CrossbeamSendError(value)
+        Error::CrossbeamSendError(Box::new(value))
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Error::IoError(value)
     }
 }
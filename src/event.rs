@@ -1,5 +1,7 @@
-use crate::PublicId;
-use qp2p::Endpoint as QuicEndpoint;
+use crate::{
+    connection::relay::CircuitError, crypto::hash::Hash, gossip::inventory::ObjectKind, PublicId,
+};
+use std::net::SocketAddr;
 
 /// Types of peer-to-peer events
 #[derive(Debug)]
@@ -28,9 +30,81 @@ pub enum Event {
 
     /// Events regarding a failed connection
     ConnectionFailure {
-        /// Desired endpoint
-        peer: QuicEndpoint,
+        /// Address of the desired peer
+        peer: SocketAddr,
+        /// Number of the reconnection attempt which failed
+        attempt: u32,
         /// Error
         err: String,
     },
+
+    /// Events regarding the end of a connection
+    Disconnected {
+        /// Address of the peer
+        socket_addr: SocketAddr,
+        /// Public identity of the peer, if it identified itself
+        peer: Option<PublicId>,
+        /// Reason for the disconnection
+        reason: String,
+    },
+
+    /// Events regarding messages whose agent ran out of hops before reaching their recipient
+    ExpiredMessages(Vec<(PublicId, Hash)>),
+
+    /// Events regarding the receipt of a new broadcast
+    NewBroadcast {
+        /// ID of the broadcast
+        id: Hash,
+        /// Number of hops the broadcast made to reach us
+        hops: u32,
+        /// Payload
+        payload: Vec<u8>,
+    },
+
+    /// Events regarding the receipt of an object we requested
+    NewObject {
+        /// Kind of object
+        kind: ObjectKind,
+        /// Hash of the content of the object
+        id: Hash,
+        /// Content of the object
+        payload: Vec<u8>,
+    },
+
+    /// Events regarding a payload rebuilt from its erasure-coded chunks
+    NewChunkedBroadcast {
+        /// Merkle root of the chunks, which identifies the payload
+        root: Hash,
+        /// Content of the payload
+        payload: Vec<u8>,
+    },
+
+    /// Events regarding the expiry of a route to a node
+    RouteLost(Hash),
+
+    /// Events regarding a relayed connection whose other end proved its identity
+    CircuitEstablished {
+        /// Circuit identifier
+        circuit: u64,
+        /// Public identity of the other end
+        peer: PublicId,
+    },
+
+    /// Events regarding the receipt of data through a relayed connection
+    CircuitMessage {
+        /// Circuit identifier
+        circuit: u64,
+        /// Public identity of the other end
+        peer: PublicId,
+        /// Payload
+        message: Vec<u8>,
+    },
+
+    /// Events regarding the end of a relayed connection
+    CircuitClosed {
+        /// Circuit identifier
+        circuit: u64,
+        /// Reason for closing the circuit
+        reason: CircuitError,
+    },
 }
@@ -0,0 +1,671 @@
+use super::{Outgoing, DEFAULT_REQUEST_TIMEOUT};
+use crate::{crypto::hash::Hash, error::Error, Message, Result};
+use serde::{Deserialize, Serialize};
+use siphasher::sip::SipHasher24;
+use std::{
+    collections::HashMap,
+    hash::Hasher,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// Default time during which blocks are kept to answer requests for their transactions
+pub const DEFAULT_BLOCK_RETENTION: Duration = Duration::from_secs(10 * 60);
+
+/// Maximum number of other peers whose compact block is kept while a block is rebuilt
+pub const MAX_BLOCK_ANNOUNCERS: usize = 8;
+
+/// Short IDs keep the low 48 bits of the SipHash of a transaction ID
+const SHORT_ID_MASK: u64 = 0xffff_ffff_ffff;
+
+/// Short ID of a transaction within a compact block
+pub type ShortId = u64;
+
+/// A block: an opaque header followed by its transactions, in order
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Block {
+    /// Header, as encoded by the application
+    pub header: Vec<u8>,
+    /// Encoded transactions
+    pub transactions: Vec<Vec<u8>>,
+}
+
+impl Block {
+    /// Encodes the block
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    /// Decodes a block
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+
+    /// ID of the block: the hash of its encoding, as for other inventory objects
+    pub fn id(&self) -> Result<Hash> {
+        Ok(Hash::from_bytes(&self.to_bytes()?))
+    }
+}
+
+/// A block announced with short IDs in place of the transactions the receiver
+/// probably has already
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompactBlock {
+    /// ID of the full block
+    pub block_id: Hash,
+    /// Header of the block
+    pub header: Vec<u8>,
+    /// Salt of the short IDs, so collisions cannot be planned across blocks
+    pub nonce: u64,
+    /// Short IDs of the transactions which are not prefilled, in block order
+    pub short_ids: Vec<ShortId>,
+    /// Transactions sent in full, with their index in the block
+    pub prefilled: Vec<(u32, Vec<u8>)>,
+}
+
+impl CompactBlock {
+    /// Creates a compact block sending the transactions at `prefill` in full,
+    /// typically those the receivers cannot have yet.
+    pub fn new(block: &Block, nonce: u64, prefill: &[usize]) -> Result<Self> {
+        let mut compact = Self {
+            block_id: block.id()?,
+            header: block.header.clone(),
+            nonce,
+            short_ids: Vec::new(),
+            prefilled: Vec::new(),
+        };
+        for (index, transaction) in block.transactions.iter().enumerate() {
+            if prefill.contains(&index) {
+                compact.prefilled.push((index as u32, transaction.clone()));
+            } else {
+                let short_id = compact.short_id(&Hash::from_bytes(transaction));
+                compact.short_ids.push(short_id);
+            }
+        }
+        Ok(compact)
+    }
+
+    /// Number of transactions in the block
+    pub fn len(&self) -> usize {
+        self.short_ids.len() + self.prefilled.len()
+    }
+
+    /// Checks if the block has no transaction
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Short ID of a transaction in this block: SipHash-2-4 of its ID,
+    /// keyed by the hash of the header and nonce
+    pub fn short_id(&self, transaction_id: &Hash) -> ShortId {
+        let key = Hash::from_byte_arrays(&[&self.header, &self.nonce.to_be_bytes()]);
+        let mut k0 = [0; 8];
+        let mut k1 = [0; 8];
+        k0.copy_from_slice(&key.as_ref()[..8]);
+        k1.copy_from_slice(&key.as_ref()[8..16]);
+        let mut hasher = SipHasher24::new_with_keys(u64::from_le_bytes(k0), u64::from_le_bytes(k1));
+        hasher.write(transaction_id.as_ref());
+        hasher.finish() & SHORT_ID_MASK
+    }
+}
+
+/// A compact block being rebuilt
+#[derive(Debug, Clone)]
+pub struct PartialBlock {
+    compact: CompactBlock,
+    slots: Vec<Option<Vec<u8>>>,
+    fell_back: bool,
+}
+
+impl PartialBlock {
+    /// Rebuild a compact block from the transactions we have, given with their IDs.
+    /// Short IDs matching several transactions, or shared by several transactions
+    /// of the block, are left missing.
+    pub fn new<'a, I>(compact: CompactBlock, pool: I) -> Self
+    where
+        I: IntoIterator<Item = (&'a Hash, &'a Vec<u8>)>,
+    {
+        let mut slots = vec![None; compact.len()];
+        for (index, transaction) in &compact.prefilled {
+            if let Some(slot) = slots.get_mut(*index as usize) {
+                *slot = Some(transaction.clone());
+            }
+        }
+        let free = slots
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.is_none())
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        let mut by_short_id = HashMap::<ShortId, Vec<usize>>::new();
+        for (short_id, index) in compact.short_ids.iter().zip(free) {
+            by_short_id.entry(*short_id).or_default().push(index);
+        }
+        let mut matches = HashMap::<ShortId, Vec<&Vec<u8>>>::new();
+        for (id, transaction) in pool {
+            let short_id = compact.short_id(id);
+            if by_short_id.contains_key(&short_id) {
+                matches.entry(short_id).or_default().push(transaction);
+            }
+        }
+        for (short_id, indexes) in &by_short_id {
+            match (indexes.as_slice(), matches.get(short_id).map(Vec::as_slice)) {
+                ([index], Some([transaction])) => slots[*index] = Some((*transaction).clone()),
+                (_, None) => {}
+                _ => log::debug!("Short ID collision in block {:?}", compact.block_id),
+            }
+        }
+        Self {
+            compact,
+            slots,
+            fell_back: false,
+        }
+    }
+
+    /// ID of the block
+    pub fn block_id(&self) -> &Hash {
+        &self.compact.block_id
+    }
+
+    /// Indexes of the transactions still missing
+    pub fn missing(&self) -> Vec<u32> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.is_none())
+            .map(|(index, _)| index as u32)
+            .collect()
+    }
+
+    /// Fill in transactions received for the given indexes.
+    pub fn fill(&mut self, indexes: &[u32], transactions: Vec<Vec<u8>>) {
+        for (index, transaction) in indexes.iter().zip(transactions) {
+            if let Some(slot) = self.slots.get_mut(*index as usize) {
+                *slot = Some(transaction);
+            }
+        }
+    }
+
+    /// Retrieves the block once every transaction is in and it matches its ID.
+    /// If it does not, a short ID matched the wrong transaction: the transactions
+    /// which were not prefilled are dropped, to be requested again, and `None` is
+    /// returned. A block which still does not match after that, or which has
+    /// nothing to request again, is invalid.
+    pub fn complete(&mut self) -> Result<Option<Block>> {
+        if self.slots.iter().any(Option::is_none) {
+            return Ok(None);
+        }
+        let block = Block {
+            header: self.compact.header.clone(),
+            transactions: self.slots.iter().flatten().cloned().collect(),
+        };
+        if block.id()? == self.compact.block_id {
+            return Ok(Some(block));
+        }
+        if self.fell_back {
+            return Err(Error::InvalidBlock(self.compact.block_id));
+        }
+        log::debug!(
+            "Block {:?} rebuilt wrong, refetching",
+            self.compact.block_id
+        );
+        self.fell_back = true;
+        let prefilled = self
+            .compact
+            .prefilled
+            .iter()
+            .map(|(index, _)| *index as usize)
+            .collect::<Vec<_>>();
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            if !prefilled.contains(&index) {
+                *slot = None;
+            }
+        }
+        if self.missing().is_empty() {
+            return Err(Error::InvalidBlock(self.compact.block_id));
+        }
+        Ok(None)
+    }
+}
+
+/// Outcome of receiving part of a compact block
+#[derive(Debug)]
+pub enum Reconstruction {
+    /// The block is complete
+    Complete(Block),
+    /// Transactions are missing; the messages requesting them are to be sent
+    Incomplete(Outgoing),
+}
+
+/// A block being rebuilt from the compact block of a peer, along with the
+/// compact blocks other peers announced it with
+#[derive(Debug, Clone)]
+struct Rebuild {
+    peer: SocketAddr,
+    partial: PartialBlock,
+    deadline: Instant,
+    started_at: Instant,
+    announcers: Vec<(SocketAddr, CompactBlock)>,
+}
+
+/// Relay of blocks as compact blocks.
+/// Keeps the blocks we relayed, to answer requests for their transactions,
+/// and the blocks being rebuilt. A block whose sender does not send the missing
+/// transactions in time, or sends an invalid block, is rebuilt from the compact
+/// block of the next peer which announced it.
+#[derive(Debug, Clone)]
+pub struct CompactRelay {
+    retention: Duration,
+    request_timeout: Duration,
+    blocks: HashMap<Hash, (Block, Instant)>,
+    partial: HashMap<Hash, Rebuild>,
+}
+
+impl CompactRelay {
+    /// Creates a new `CompactRelay` keeping blocks for `retention`, and waiting
+    /// `request_timeout` for missing transactions before turning to another peer.
+    pub fn new(retention: Duration, request_timeout: Duration) -> Self {
+        Self {
+            retention,
+            request_timeout,
+            blocks: Default::default(),
+            partial: Default::default(),
+        }
+    }
+
+    /// Keep a block to answer requests for its transactions, and make a compact block
+    /// of it, sending the transactions at `prefill` in full.
+    pub fn relay(
+        &mut self,
+        block: Block,
+        nonce: u64,
+        prefill: &[usize],
+        now: Instant,
+    ) -> Result<CompactBlock> {
+        self.expire(now);
+        let compact = CompactBlock::new(&block, nonce, prefill)?;
+        let _ = self.blocks.insert(compact.block_id, (block, now));
+        Ok(compact)
+    }
+
+    /// Checks if a block is known, complete or being rebuilt
+    pub fn contains(&self, block_id: &Hash) -> bool {
+        self.blocks.contains_key(block_id) || self.partial.contains_key(block_id)
+    }
+
+    /// Handle a compact block from a peer, rebuilding it from the transactions we have.
+    /// Returns `None` if the block is already known; if it is being rebuilt from the
+    /// compact block of another peer, this one is kept to fall back on.
+    pub fn handle_compact<'a, I>(
+        &mut self,
+        from: &SocketAddr,
+        compact: CompactBlock,
+        pool: I,
+        now: Instant,
+    ) -> Result<Option<Reconstruction>>
+    where
+        I: IntoIterator<Item = (&'a Hash, &'a Vec<u8>)>,
+    {
+        self.expire(now);
+        if self.blocks.contains_key(&compact.block_id) {
+            return Ok(None);
+        }
+        if let Some(rebuild) = self.partial.get_mut(&compact.block_id) {
+            if rebuild.peer != *from
+                && rebuild.announcers.len() < MAX_BLOCK_ANNOUNCERS
+                && !rebuild.announcers.iter().any(|(peer, _)| peer == from)
+            {
+                rebuild.announcers.push((*from, compact));
+            }
+            return Ok(None);
+        }
+        let rebuild = Rebuild {
+            peer: *from,
+            partial: PartialBlock::new(compact, pool),
+            deadline: now,
+            started_at: now,
+            announcers: Vec::new(),
+        };
+        self.advance(rebuild, now).map(Some)
+    }
+
+    /// Answer a request for transactions of a block we relayed.
+    pub fn handle_get_block_txn(
+        &mut self,
+        from: &SocketAddr,
+        block_id: Hash,
+        indexes: Vec<u32>,
+        now: Instant,
+    ) -> Outgoing {
+        self.expire(now);
+        let block = match self.blocks.get(&block_id) {
+            Some((block, _)) => block,
+            None => return Vec::new(),
+        };
+        let transactions = indexes
+            .iter()
+            .filter_map(|index| block.transactions.get(*index as usize).cloned())
+            .collect::<Vec<_>>();
+        if transactions.len() != indexes.len() {
+            log::debug!("Peer {:?} requested transactions out of range", from);
+            return Vec::new();
+        }
+        vec![(
+            *from,
+            Message::BlockTxn {
+                block_id,
+                indexes,
+                transactions,
+            },
+        )]
+    }
+
+    /// Handle transactions of a block we are rebuilding.
+    /// Returns `None` if the block is not being rebuilt.
+    pub fn handle_block_txn(
+        &mut self,
+        from: &SocketAddr,
+        block_id: Hash,
+        indexes: Vec<u32>,
+        transactions: Vec<Vec<u8>>,
+        now: Instant,
+    ) -> Result<Option<Reconstruction>> {
+        let mut rebuild = match self.partial.remove(&block_id) {
+            Some(rebuild) if rebuild.peer == *from => rebuild,
+            Some(rebuild) => {
+                let _ = self.partial.insert(block_id, rebuild);
+                return Ok(None);
+            }
+            None => return Ok(None),
+        };
+        rebuild.partial.fill(&indexes, transactions);
+        self.advance(rebuild, now).map(Some)
+    }
+
+    /// Rebuild the blocks whose sender did not send the missing transactions in time,
+    /// or sent an invalid block, from the compact block of the next peer which
+    /// announced them, and give up on those without announcers left.
+    /// Returns the outcome of each new attempt, along with the peer it is made with.
+    pub fn check_requests<'a, I>(
+        &mut self,
+        pool: I,
+        now: Instant,
+    ) -> Vec<(SocketAddr, Result<Reconstruction>)>
+    where
+        I: IntoIterator<Item = (&'a Hash, &'a Vec<u8>)> + Clone,
+    {
+        self.expire(now);
+        let stalled = self
+            .partial
+            .iter()
+            .filter(|(_, rebuild)| now >= rebuild.deadline)
+            .map(|(block_id, _)| *block_id)
+            .collect::<Vec<_>>();
+        let mut attempts = Vec::new();
+        for block_id in stalled {
+            let mut rebuild = match self.partial.remove(&block_id) {
+                Some(rebuild) => rebuild,
+                None => continue,
+            };
+            log::debug!("Rebuilding {:?} from {:?} stalled", block_id, rebuild.peer);
+            if rebuild.announcers.is_empty() {
+                continue;
+            }
+            let (peer, compact) = rebuild.announcers.remove(0);
+            rebuild.peer = peer;
+            rebuild.partial = PartialBlock::new(compact, pool.clone());
+            attempts.push((peer, self.advance(rebuild, now)));
+        }
+        attempts
+    }
+
+    /// Complete a block, or request the transactions it misses from its sender.
+    /// An invalid block is rebuilt from the next announcer on the next check.
+    fn advance(&mut self, mut rebuild: Rebuild, now: Instant) -> Result<Reconstruction> {
+        let block_id = *rebuild.partial.block_id();
+        let block = match rebuild.partial.complete() {
+            Ok(block) => block,
+            Err(error) => {
+                if !rebuild.announcers.is_empty() {
+                    rebuild.deadline = now;
+                    let _ = self.partial.insert(block_id, rebuild);
+                }
+                return Err(error);
+            }
+        };
+        if let Some(block) = block {
+            let _ = self.blocks.insert(block_id, (block.clone(), now));
+            return Ok(Reconstruction::Complete(block));
+        }
+        let request = Message::GetBlockTxn {
+            block_id,
+            indexes: rebuild.partial.missing(),
+        };
+        let peer = rebuild.peer;
+        rebuild.deadline = now + self.request_timeout;
+        let _ = self.partial.insert(block_id, rebuild);
+        Ok(Reconstruction::Incomplete(vec![(peer, request)]))
+    }
+
+    /// Forget the blocks kept for longer than the retention time,
+    /// and the blocks whose rebuilding stalled for as long.
+    fn expire(&mut self, now: Instant) {
+        let retention = self.retention;
+        self.blocks
+            .retain(|_, (_, stored_at)| now.saturating_duration_since(*stored_at) <= retention);
+        self.partial
+            .retain(|_, rebuild| now.saturating_duration_since(rebuild.started_at) <= retention);
+    }
+}
+
+impl Default for CompactRelay {
+    fn default() -> Self {
+        Self::new(DEFAULT_BLOCK_RETENTION, DEFAULT_REQUEST_TIMEOUT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transactions(count: usize) -> Vec<Vec<u8>> {
+        (0..count)
+            .map(|index| format!("transaction {}", index).into_bytes())
+            .collect()
+    }
+
+    fn pool(transactions: &[Vec<u8>]) -> Vec<(Hash, Vec<u8>)> {
+        transactions
+            .iter()
+            .map(|transaction| (Hash::from_bytes(transaction), transaction.clone()))
+            .collect()
+    }
+
+    fn block(count: usize) -> Block {
+        Block {
+            header: b"header".to_vec(),
+            transactions: transactions(count),
+        }
+    }
+
+    fn complete(reconstruction: Result<Option<Reconstruction>>) -> Option<Block> {
+        match reconstruction {
+            Ok(Some(Reconstruction::Complete(block))) => Some(block),
+            _ => None,
+        }
+    }
+
+    fn requested(reconstruction: Result<Option<Reconstruction>>) -> Vec<u32> {
+        match reconstruction {
+            Ok(Some(Reconstruction::Incomplete(outgoing))) => outgoing
+                .into_iter()
+                .flat_map(|(_, message)| match message {
+                    Message::GetBlockTxn { indexes, .. } => indexes,
+                    _ => Vec::new(),
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_rebuild_from_pool() {
+        let now = Instant::now();
+        let peer = SocketAddr::from(([10, 0, 0, 1], 9000));
+        let block = block(10);
+        let mut sender = CompactRelay::default();
+        let compact = sender
+            .relay(block.clone(), 7, &[0], now)
+            .expect("Failed to build a compact block");
+        assert_eq!((compact.short_ids.len(), compact.prefilled.len()), (9, 1));
+
+        // The receiver lacks transactions 3 and 5, and requests only those.
+        let mut known = pool(&block.transactions);
+        let _ = known.remove(5);
+        let _ = known.remove(3);
+        let mut receiver = CompactRelay::default();
+        let pool = known.iter().map(|(id, tx)| (id, tx));
+        let first = receiver.handle_compact(&peer, compact.clone(), pool, now);
+        assert_eq!(requested(first), vec![3, 5]);
+        let again = receiver.handle_compact(&peer, compact.clone(), Vec::new(), now);
+        assert!(matches!(again, Ok(None)));
+
+        let answer = sender.handle_get_block_txn(&peer, compact.block_id, vec![3, 5], now);
+        let (indexes, transactions) = match answer.into_iter().next() {
+            Some((
+                _,
+                Message::BlockTxn {
+                    indexes,
+                    transactions,
+                    ..
+                },
+            )) => (indexes, transactions),
+            _ => (Vec::new(), Vec::new()),
+        };
+        assert_eq!(
+            transactions,
+            vec![block.transactions[3].clone(), block.transactions[5].clone()]
+        );
+        let rebuilt =
+            receiver.handle_block_txn(&peer, compact.block_id, indexes, transactions, now);
+        assert_eq!(complete(rebuilt), Some(block));
+    }
+
+    #[test]
+    fn test_short_id_collisions() {
+        let now = Instant::now();
+        let peer = SocketAddr::from(([10, 0, 0, 1], 9000));
+        let block = block(4);
+        let compact = CompactBlock::new(&block, 7, &[]).expect("Failed to build a compact block");
+        let ids = block
+            .transactions
+            .iter()
+            .map(|transaction| compact.short_id(&Hash::from_bytes(transaction)))
+            .collect::<Vec<_>>();
+        assert_eq!(ids, compact.short_ids);
+        let resalted = CompactBlock::new(&block, 8, &[]).expect("Failed to build a compact block");
+        assert_ne!(ids, resalted.short_ids);
+
+        // Two transactions of the pool share the short ID of transaction 1:
+        // the receiver cannot tell which is the right one, and requests it.
+        let mut known = pool(&block.transactions);
+        known.push((known[1].0, b"impostor".to_vec()));
+        let partial = PartialBlock::new(compact.clone(), known.iter().map(|(id, tx)| (id, tx)));
+        assert_eq!(partial.missing(), vec![1]);
+
+        // Two transactions of the block share a short ID: both are requested.
+        let mut colliding = compact.clone();
+        colliding.short_ids[3] = colliding.short_ids[2];
+        let known = pool(&block.transactions);
+        let partial = PartialBlock::new(colliding, known.iter().map(|(id, tx)| (id, tx)));
+        assert_eq!(partial.missing(), vec![2, 3]);
+
+        // A pool transaction matches the short ID of a different block transaction:
+        // the rebuilt block does not match its ID, so the transactions are refetched.
+        let impostor = b"impostor".to_vec();
+        let mut known = pool(&block.transactions[..2]);
+        known.push((Hash::from_bytes(&block.transactions[2]), impostor.clone()));
+        let mut receiver = CompactRelay::default();
+        let first = receiver.handle_compact(
+            &peer,
+            compact.clone(),
+            known.iter().map(|(id, tx)| (id, tx)),
+            now,
+        );
+        assert_eq!(requested(first), vec![3]);
+        let second = receiver.handle_block_txn(
+            &peer,
+            compact.block_id,
+            vec![3],
+            vec![block.transactions[3].clone()],
+            now,
+        );
+        assert_eq!(requested(second), vec![0, 1, 2, 3]);
+        let rebuilt = receiver.handle_block_txn(
+            &peer,
+            compact.block_id,
+            vec![0, 1, 2, 3],
+            block.transactions.clone(),
+            now,
+        );
+        assert_eq!(complete(rebuilt), Some(block.clone()));
+
+        // A sender which keeps sending wrong transactions is caught.
+        let mut receiver = CompactRelay::default();
+        let _ = receiver.handle_compact(&peer, compact.clone(), Vec::new(), now);
+        let wrong = vec![impostor; 4];
+        let _ = receiver.handle_block_txn(
+            &peer,
+            compact.block_id,
+            vec![0, 1, 2, 3],
+            wrong.clone(),
+            now,
+        );
+        assert!(receiver
+            .handle_block_txn(&peer, compact.block_id, vec![0, 1, 2, 3], wrong, now)
+            .is_err());
+
+        // So is one whose block has nothing left to request once it does not match.
+        let mut prefilled =
+            CompactBlock::new(&block, 7, &[0, 1, 2, 3]).expect("Failed to build a compact block");
+        prefilled.prefilled[0].1 = b"forged".to_vec();
+        let mut receiver = CompactRelay::default();
+        assert!(matches!(
+            receiver.handle_compact(&peer, prefilled, Vec::new(), now),
+            Err(Error::InvalidBlock(_))
+        ));
+    }
+
+    #[test]
+    fn test_fall_back_on_next_announcer() {
+        let now = Instant::now();
+        let (attacker, honest) = (
+            SocketAddr::from(([10, 0, 0, 1], 9000)),
+            SocketAddr::from(([10, 0, 0, 2], 9000)),
+        );
+        let block = block(10);
+        let compact = CompactBlock::new(&block, 7, &[0]).expect("Failed to build a compact block");
+        let mut forged = compact.clone();
+        forged.short_ids = vec![0; forged.short_ids.len()];
+        let known = pool(&block.transactions);
+        let pool = known.iter().map(|(id, tx)| (id, tx));
+
+        // The first sender announces bad short IDs and never answers; the compact
+        // blocks of the peers announcing the block meanwhile are kept.
+        let mut receiver = CompactRelay::default();
+        let first = receiver.handle_compact(&attacker, forged, pool.clone(), now);
+        assert_eq!(requested(first), (1..10).collect::<Vec<_>>());
+        let second = receiver.handle_compact(&honest, compact, pool.clone(), now);
+        assert!(matches!(second, Ok(None)));
+        assert!(receiver.check_requests(pool.clone(), now).is_empty());
+
+        // Once the request times out, the block is rebuilt from the next announcer.
+        let later = now + DEFAULT_REQUEST_TIMEOUT;
+        let mut attempts = receiver.check_requests(pool.clone(), later);
+        assert_eq!(attempts.len(), 1);
+        let (peer, rebuilt) = attempts.remove(0);
+        assert_eq!(peer, honest);
+        assert_eq!(complete(rebuilt.map(Some)), Some(block));
+        assert!(receiver.check_requests(pool, later).is_empty());
+    }
+}
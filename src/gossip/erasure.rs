@@ -0,0 +1,565 @@
+use super::Outgoing;
+use crate::{crypto::hash::Hash, error::Error, Message, Result};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// Default number of chunks a payload is split into
+pub const DEFAULT_DATA_CHUNKS: usize = 16;
+/// Default number of extra chunks, any of which can stand in for a lost one
+pub const DEFAULT_PARITY_CHUNKS: usize = 16;
+
+/// Maximum number of payloads being rebuilt at once
+pub const MAX_ASSEMBLIES: usize = 64;
+/// Maximum number of payloads being rebuilt at once from chunks a single peer sent first
+pub const MAX_ASSEMBLIES_PER_PEER: usize = 8;
+
+/// Largest number of chunks, data and parity, a payload can be coded into
+const MAX_CHUNKS: usize = 256;
+
+/// A rebuilt payload, along with the root identifying it
+pub type Decoded = (Hash, Vec<u8>);
+
+/// How payloads are split into chunks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErasureConfig {
+    /// Number of chunks a payload is split into; any as many chunks rebuild it
+    pub data_chunks: usize,
+    /// Number of extra chunks, so that as many chunks can be lost
+    pub parity_chunks: usize,
+}
+
+impl Default for ErasureConfig {
+    fn default() -> Self {
+        Self {
+            data_chunks: DEFAULT_DATA_CHUNKS,
+            parity_chunks: DEFAULT_PARITY_CHUNKS,
+        }
+    }
+}
+
+/// How a payload was split, shared by all its chunks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkLayout {
+    /// Number of chunks needed to rebuild the payload
+    pub data_chunks: u16,
+    /// Number of extra chunks
+    pub parity_chunks: u16,
+    /// Length of the payload, in bytes
+    pub length: u64,
+}
+
+impl ChunkLayout {
+    /// Total number of chunks
+    pub fn chunks(&self) -> usize {
+        usize::from(self.data_chunks) + usize::from(self.parity_chunks)
+    }
+
+    /// Length of each chunk, in bytes
+    pub fn chunk_length(&self) -> usize {
+        let data_chunks = u64::from(self.data_chunks.max(1));
+        (self.length.div_ceil(data_chunks).max(1)) as usize
+    }
+
+    /// Checks if the layout can be decoded
+    pub fn is_valid(&self) -> bool {
+        self.data_chunks > 0 && self.chunks() <= MAX_CHUNKS
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        [
+            &self.data_chunks.to_be_bytes()[..],
+            &self.parity_chunks.to_be_bytes(),
+            &self.length.to_be_bytes(),
+        ]
+        .concat()
+    }
+}
+
+/// Erasure-coded piece of a payload, along with the proof it belongs to it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Chunk {
+    /// Merkle root of the chunks of the payload, which identifies it
+    pub root: Hash,
+    /// How the payload was split
+    pub layout: ChunkLayout,
+    /// Position of the chunk; data chunks come first
+    pub index: u16,
+    /// Content of the chunk
+    pub data: Vec<u8>,
+    /// Hashes of the siblings of the chunk up the Merkle tree
+    pub proof: Vec<Hash>,
+}
+
+impl Chunk {
+    /// Checks if the chunk belongs to the payload identified by its root,
+    /// and fits its layout
+    pub fn is_valid(&self) -> bool {
+        let layout = &self.layout;
+        let chunks = layout.chunks();
+        layout.is_valid()
+            && usize::from(self.index) < chunks
+            && self.data.len() == layout.chunk_length()
+            && self.proof.len() == tree_depth(chunks)
+            && fold_proof(
+                leaf(layout, self.index, &self.data),
+                self.index,
+                &self.proof,
+            ) == self.root
+    }
+}
+
+/// Split a payload into chunks, any `data_chunks` of which rebuild it.
+/// Returns the root identifying the payload and its chunks, in order.
+pub fn encode(payload: &[u8], config: ErasureConfig) -> Result<(Hash, Vec<Chunk>)> {
+    let layout = ChunkLayout {
+        data_chunks: config.data_chunks as u16,
+        parity_chunks: config.parity_chunks as u16,
+        length: payload.len() as u64,
+    };
+    if !layout.is_valid() {
+        return Err(Error::InvalidChunkLayout);
+    }
+    let chunk_length = layout.chunk_length();
+    let mut shards = payload
+        .chunks(chunk_length)
+        .map(<[u8]>::to_vec)
+        .collect::<Vec<_>>();
+    shards.resize(layout.chunks(), Vec::new());
+    for shard in &mut shards {
+        shard.resize(chunk_length, 0);
+    }
+    ReedSolomon::new(config.data_chunks, config.parity_chunks)?.encode(&mut shards)?;
+    let leaves = shards
+        .iter()
+        .enumerate()
+        .map(|(index, data)| leaf(&layout, index as u16, data))
+        .collect::<Vec<_>>();
+    let levels = tree(leaves);
+    let root = root(&levels);
+    let chunks = shards
+        .into_iter()
+        .enumerate()
+        .map(|(index, data)| Chunk {
+            root,
+            layout,
+            index: index as u16,
+            data,
+            proof: proof(&levels, index),
+        })
+        .collect();
+    Ok((root, chunks))
+}
+
+/// Rebuild a payload from at least `data_chunks` valid chunks of its layout,
+/// given at their index. Returns `None` if the chunks do not make up the payload
+/// identified by `root`, which happens when its sender coded it inconsistently.
+pub fn decode(
+    root: &Hash,
+    layout: &ChunkLayout,
+    mut shards: Vec<Option<Vec<u8>>>,
+) -> Result<Option<Vec<u8>>> {
+    let data_chunks = usize::from(layout.data_chunks);
+    ReedSolomon::new(data_chunks, usize::from(layout.parity_chunks))?.reconstruct(&mut shards)?;
+    let shards = shards.into_iter().flatten().collect::<Vec<_>>();
+    let leaves = shards
+        .iter()
+        .enumerate()
+        .map(|(index, data)| leaf(layout, index as u16, data))
+        .collect::<Vec<_>>();
+    if self::root(&tree(leaves)) != *root {
+        return Ok(None);
+    }
+    let mut payload = shards
+        .into_iter()
+        .take(data_chunks)
+        .flatten()
+        .collect::<Vec<_>>();
+    payload.truncate(layout.length as usize);
+    Ok(Some(payload))
+}
+
+/// Hash of a chunk, committing to the layout so every chunk of a payload agrees on it
+fn leaf(layout: &ChunkLayout, index: u16, data: &[u8]) -> Hash {
+    Hash::from_byte_arrays(&[&[0], &layout.to_bytes(), &index.to_be_bytes(), data])
+}
+
+fn node(left: &Hash, right: &Hash) -> Hash {
+    Hash::from_byte_arrays(&[&[1], left.as_ref(), right.as_ref()])
+}
+
+/// Number of levels above the leaves in a tree of `leaves` leaves
+fn tree_depth(leaves: usize) -> usize {
+    leaves.next_power_of_two().trailing_zeros() as usize
+}
+
+/// Levels of the Merkle tree over `leaves`, from the leaves up to the root.
+/// A node without a sibling is paired with itself.
+fn tree(leaves: Vec<Hash>) -> Vec<Vec<Hash>> {
+    let mut levels = vec![leaves];
+    while levels.last().is_some_and(|level| level.len() > 1) {
+        let next = levels
+            .last()
+            .map(|level| {
+                level
+                    .chunks(2)
+                    .map(|pair| node(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+                    .collect()
+            })
+            .unwrap_or_default();
+        levels.push(next);
+    }
+    levels
+}
+
+fn root(levels: &[Vec<Hash>]) -> Hash {
+    levels
+        .last()
+        .and_then(|level| level.first())
+        .copied()
+        .unwrap_or_else(|| Hash::from_bytes(&[]))
+}
+
+fn proof(levels: &[Vec<Hash>], mut index: usize) -> Vec<Hash> {
+    let mut proof = Vec::new();
+    for level in &levels[..levels.len().saturating_sub(1)] {
+        let sibling = level.get(index ^ 1).or_else(|| level.get(index));
+        proof.extend(sibling.copied());
+        index /= 2;
+    }
+    proof
+}
+
+/// Root of the tree a leaf at `index` belongs to, according to its proof
+fn fold_proof(leaf: Hash, index: u16, proof: &[Hash]) -> Hash {
+    let (root, _) = proof.iter().fold((leaf, index), |(hash, index), sibling| {
+        if index % 2 == 0 {
+            (node(&hash, sibling), index / 2)
+        } else {
+            (node(sibling, &hash), index / 2)
+        }
+    });
+    root
+}
+
+/// Chunks received of a payload
+#[derive(Debug, Clone)]
+struct Assembly {
+    opened_by: Option<SocketAddr>,
+    layout: ChunkLayout,
+    shards: Vec<Option<Vec<u8>>>,
+    received: usize,
+    decoded: bool,
+    started_at: Instant,
+}
+
+/// Dissemination of erasure-coded payloads.
+/// The chunks of a payload are spread across different peers, each of which
+/// relays the chunks it receives, so that the payload travels along many paths
+/// and is rebuilt as soon as enough chunks arrived from any of them.
+/// Chunks are relayed once proven against their root, before the payload is rebuilt,
+/// so payloads are never checked by a validator before being relayed.
+#[derive(Debug, Clone)]
+pub struct ErasureRelay {
+    config: ErasureConfig,
+    retention: Duration,
+    assemblies: HashMap<Hash, Assembly>,
+}
+
+impl ErasureRelay {
+    /// Creates a new `ErasureRelay`, keeping track of payloads for `retention`.
+    pub fn new(config: ErasureConfig, retention: Duration) -> Self {
+        Self {
+            config,
+            retention,
+            assemblies: Default::default(),
+        }
+    }
+
+    /// Split a payload into chunks and spread them across our peers.
+    /// Returns the root identifying the payload and the messages to send.
+    pub fn broadcast(
+        &mut self,
+        payload: &[u8],
+        peers: &[&SocketAddr],
+        now: Instant,
+    ) -> Result<(Hash, Outgoing)> {
+        self.expire(now);
+        let (root, chunks) = encode(payload, self.config)?;
+        let layout = chunks
+            .first()
+            .map(|chunk| chunk.layout)
+            .ok_or(Error::InvalidChunkLayout)?;
+        let _ = self.assemblies.insert(
+            root,
+            Assembly {
+                opened_by: None,
+                layout,
+                shards: chunks
+                    .iter()
+                    .map(|chunk| Some(chunk.data.clone()))
+                    .collect(),
+                received: chunks.len(),
+                decoded: true,
+                started_at: now,
+            },
+        );
+        if peers.is_empty() {
+            return Ok((root, Vec::new()));
+        }
+        let outgoing = chunks
+            .into_iter()
+            .enumerate()
+            .map(|(index, chunk)| (*peers[index % peers.len()], Message::Chunk(chunk)))
+            .collect();
+        Ok((root, outgoing))
+    }
+
+    /// Handle a chunk from a peer, relaying it to our other peers if it is new.
+    /// Returns the payload once it can be rebuilt, along with its root, and the
+    /// messages to send. Chunks which do not match their root are rejected with
+    /// `Error::InvalidChunk`. Chunks of new payloads are dropped while
+    /// `MAX_ASSEMBLIES` payloads, or `MAX_ASSEMBLIES_PER_PEER` payloads first sent
+    /// by the same peer, are being rebuilt.
+    pub fn handle_chunk(
+        &mut self,
+        from: &SocketAddr,
+        chunk: Chunk,
+        peers: &[&SocketAddr],
+        now: Instant,
+    ) -> Result<(Option<Decoded>, Outgoing)> {
+        if !chunk.is_valid() {
+            return Err(Error::InvalidChunk(chunk.root));
+        }
+        self.expire(now);
+        if !self.assemblies.contains_key(&chunk.root) && !self.may_open(from) {
+            log::debug!("Dropping chunk of {:?} from {:?}", chunk.root, from);
+            return Ok((None, Vec::new()));
+        }
+        let assembly = self
+            .assemblies
+            .entry(chunk.root)
+            .or_insert_with(|| Assembly {
+                opened_by: Some(*from),
+                layout: chunk.layout,
+                shards: vec![None; chunk.layout.chunks()],
+                received: 0,
+                decoded: false,
+                started_at: now,
+            });
+        if assembly.layout != chunk.layout {
+            return Err(Error::InvalidChunk(chunk.root));
+        }
+        match assembly.shards.get_mut(usize::from(chunk.index)) {
+            Some(slot @ None) => *slot = Some(chunk.data.clone()),
+            _ => return Ok((None, Vec::new())),
+        }
+        assembly.received += 1;
+        let outgoing = peers
+            .iter()
+            .filter(|peer| **peer != from)
+            .map(|peer| (**peer, Message::Chunk(chunk.clone())))
+            .collect();
+        if assembly.decoded || assembly.received < usize::from(assembly.layout.data_chunks) {
+            return Ok((None, outgoing));
+        }
+        assembly.decoded = true;
+        let payload = decode(&chunk.root, &assembly.layout, assembly.shards.clone())?;
+        if payload.is_none() {
+            log::warn!("Chunks of {:?} do not make up its payload", chunk.root);
+        }
+        Ok((payload.map(|payload| (chunk.root, payload)), outgoing))
+    }
+
+    /// Checks if a peer may start the rebuilding of a new payload
+    fn may_open(&self, from: &SocketAddr) -> bool {
+        let pending = self
+            .assemblies
+            .values()
+            .filter(|assembly| !assembly.decoded);
+        let opened_by_peer = pending
+            .clone()
+            .filter(|assembly| assembly.opened_by == Some(*from))
+            .count();
+        pending.count() < MAX_ASSEMBLIES && opened_by_peer < MAX_ASSEMBLIES_PER_PEER
+    }
+
+    /// Forget the payloads tracked for longer than the retention time.
+    fn expire(&mut self, now: Instant) {
+        let retention = self.retention;
+        self.assemblies
+            .retain(|_, assembly| now.saturating_duration_since(assembly.started_at) <= retention);
+    }
+}
+
+impl Default for ErasureRelay {
+    fn default() -> Self {
+        Self::new(ErasureConfig::default(), super::DEFAULT_BROADCAST_RETENTION)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::Network;
+    use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+    fn payload(length: usize, rng: &mut StdRng) -> Vec<u8> {
+        (0..length).map(|_| rng.gen()).collect()
+    }
+
+    #[test]
+    fn test_any_k_of_n_chunks_rebuild() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let config = ErasureConfig {
+            data_chunks: 4,
+            parity_chunks: 3,
+        };
+        for length in [0, 1, 1000, 4096] {
+            let payload = payload(length, &mut rng);
+            let (root, chunks) = encode(&payload, config).expect("Failed to encode a payload");
+            assert_eq!(chunks.len(), 7);
+            assert!(chunks.iter().all(Chunk::is_valid));
+
+            let mut indexes = (0..chunks.len()).collect::<Vec<_>>();
+            indexes.shuffle(&mut rng);
+            let mut shards = vec![None; chunks.len()];
+            for index in &indexes[..3] {
+                shards[*index] = Some(chunks[*index].data.clone());
+            }
+            assert!(decode(&root, &chunks[0].layout, shards.clone()).is_err());
+            shards[indexes[3]] = Some(chunks[indexes[3]].data.clone());
+            let decoded = decode(&root, &chunks[0].layout, shards);
+            assert_eq!(decoded.ok().flatten(), Some(payload));
+        }
+    }
+
+    #[test]
+    fn test_bad_chunks_rejected() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let peer = SocketAddr::from(([10, 0, 0, 1], 9000));
+        let (_, chunks) =
+            encode(&payload(1000, &mut rng), ErasureConfig::default()).expect("Failed to encode");
+        let mut relay = ErasureRelay::default();
+
+        let mut corrupted = chunks[3].clone();
+        corrupted.data[0] ^= 1;
+        let mut misplaced = chunks[3].clone();
+        misplaced.index = 4;
+        let mut relaid_out = chunks[3].clone();
+        relaid_out.layout.length += 1;
+        let mut forged = chunks[3].clone();
+        let _ = forged.proof.pop();
+        for chunk in [corrupted, misplaced, relaid_out, forged] {
+            let handled = relay.handle_chunk(&peer, chunk, &[], Instant::now());
+            assert!(matches!(handled, Err(Error::InvalidChunk(_))));
+        }
+        let handled = relay.handle_chunk(&peer, chunks[3].clone(), &[], Instant::now());
+        assert!(matches!(handled, Ok((None, _))));
+
+        // Chunks which prove right but were coded inconsistently do not rebuild a payload.
+        let config = ErasureConfig {
+            data_chunks: 2,
+            parity_chunks: 2,
+        };
+        let (_, mut chunks) = encode(&payload(100, &mut rng), config).expect("Failed to encode");
+        let leaves = chunks
+            .iter()
+            .enumerate()
+            .map(|(index, chunk)| {
+                let data = if index == 3 {
+                    vec![0; chunk.data.len()]
+                } else {
+                    chunk.data.clone()
+                };
+                leaf(&chunk.layout, index as u16, &data)
+            })
+            .collect();
+        let levels = tree(leaves);
+        for (index, chunk) in chunks.iter_mut().enumerate() {
+            chunk.root = root(&levels);
+            chunk.proof = proof(&levels, index);
+        }
+        chunks[3].data = vec![0; chunks[3].data.len()];
+        let mut relay = ErasureRelay::default();
+        for chunk in [chunks[1].clone(), chunks[3].clone()] {
+            let handled = relay.handle_chunk(&peer, chunk, &[], Instant::now());
+            assert!(matches!(handled, Ok((None, _))));
+        }
+    }
+
+    #[test]
+    fn test_chunks_spread_across_peers() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let nodes = 50;
+        let mut network = Network::new(nodes, 4, &mut rng, |_| ErasureRelay::default());
+        let payload = payload(64 * 1024, &mut rng);
+        let now = Instant::now();
+        let peers = network.topology.peers(0);
+        let (root, outgoing) = network.nodes[0]
+            .broadcast(&payload, &peers, now)
+            .expect("Failed to broadcast");
+
+        // The source sends each chunk once, to different peers in turn.
+        assert_eq!(outgoing.len(), DEFAULT_DATA_CHUNKS + DEFAULT_PARITY_CHUNKS);
+        let first_hops = outgoing.iter().map(|(to, _)| to).collect::<Vec<_>>();
+        assert!(first_hops.windows(2).all(|pair| pair[0] != pair[1]));
+
+        // Some chunks are lost on the way; every node rebuilds the payload anyway.
+        let mut rebuilt = vec![false; nodes];
+        rebuilt[0] = true;
+        network.run(0, outgoing, |relay, node, from, message, peers| {
+            let chunk = match message {
+                Message::Chunk(chunk) if !rng.gen_bool(0.2) => chunk,
+                _ => return Vec::new(),
+            };
+            let (decoded, outgoing) = relay
+                .handle_chunk(from, chunk, peers, now)
+                .unwrap_or_default();
+            if let Some((id, decoded)) = decoded {
+                assert_eq!((id, &decoded), (root, &payload));
+                rebuilt[node] = true;
+            }
+            outgoing
+        });
+        assert!(rebuilt.iter().all(|rebuilt| *rebuilt));
+    }
+
+    #[test]
+    fn test_assembly_limits() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let now = Instant::now();
+        let config = ErasureConfig {
+            data_chunks: 2,
+            parity_chunks: 1,
+        };
+        let first_chunk = |rng: &mut StdRng| {
+            let (_, chunks) = encode(&payload(100, rng), config).expect("Failed to encode");
+            chunks[0].clone()
+        };
+        let mut relay = ErasureRelay::default();
+        let flooder = SocketAddr::from(([10, 0, 0, 1], 9000));
+        for _ in 0..MAX_ASSEMBLIES_PER_PEER {
+            let handled = relay.handle_chunk(&flooder, first_chunk(&mut rng), &[&flooder], now);
+            assert!(handled.is_ok_and(|(_, outgoing)| outgoing.is_empty()));
+        }
+        let peer = SocketAddr::from(([10, 0, 1, 1], 9000));
+        let chunk = first_chunk(&mut rng);
+        let handled = relay.handle_chunk(&flooder, chunk.clone(), &[&peer], now);
+        assert!(handled.is_ok_and(|(_, outgoing)| outgoing.is_empty()));
+
+        // Other peers may still start payloads, up to the overall limit.
+        let handled = relay.handle_chunk(&peer, chunk, &[&flooder], now);
+        assert!(handled.is_ok_and(|(_, outgoing)| outgoing.len() == 1));
+        for node in 2..=(MAX_ASSEMBLIES - MAX_ASSEMBLIES_PER_PEER) {
+            let other = SocketAddr::from(([10, 0, node as u8, 1], 9000));
+            let _ = relay.handle_chunk(&other, first_chunk(&mut rng), &[], now);
+        }
+        let handled = relay.handle_chunk(&peer, first_chunk(&mut rng), &[&flooder], now);
+        assert!(handled.is_ok_and(|(_, outgoing)| outgoing.is_empty()));
+    }
+}
@@ -0,0 +1,519 @@
+use super::{GossipConfig, Outgoing};
+use crate::{crypto::hash::Hash, messaging::seen_cache::SeenCache, Message};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, net::SocketAddr, time::Instant};
+
+/// Default number of object IDs remembered as known by each peer
+pub const DEFAULT_KNOWN_INVENTORY: usize = 4096;
+/// Maximum number of objects announced by a single INV message; the excess is dropped
+pub const MAX_INV_ITEMS: usize = 1000;
+/// Maximum number of objects requested from a single peer at once
+pub const MAX_REQUESTS_PER_PEER: usize = 256;
+
+/// Kinds of objects exchanged by inventory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ObjectKind {
+    /// A block
+    Block,
+    /// A transaction
+    Transaction,
+}
+
+/// Kind and ID of an object, the ID being the hash of its content
+pub type InventoryItem = (ObjectKind, Hash);
+
+/// An object requested from a peer
+#[derive(Debug, Clone)]
+struct Request {
+    peer: SocketAddr,
+    sent_at: Instant,
+    announcers: Vec<SocketAddr>,
+}
+
+/// Outcome of checking the requests in flight
+#[derive(Debug, Default)]
+pub struct RequestCheck {
+    /// Requests to send to the next announcers
+    pub outgoing: Outgoing,
+    /// Peers which left requests unanswered, once each
+    pub timed_out: Vec<SocketAddr>,
+}
+
+/// Announce/request exchange of objects (INV, GETDATA and DATA).
+/// Objects are announced by ID to the peers not known to have them, and sent only
+/// to the peers which request them. A request left unanswered is sent to the next
+/// peer which announced the object. Methods return the messages to send.
+#[derive(Debug, Clone)]
+pub struct Inventory {
+    config: GossipConfig,
+    store: HashMap<InventoryItem, (Vec<u8>, Instant)>,
+    known: HashMap<SocketAddr, SeenCache>,
+    requested: HashMap<InventoryItem, Request>,
+}
+
+impl Inventory {
+    /// Creates a new `Inventory`.
+    pub fn new(config: GossipConfig) -> Self {
+        Self {
+            config,
+            store: Default::default(),
+            known: Default::default(),
+            requested: Default::default(),
+        }
+    }
+
+    /// Checks if we have an object
+    pub fn contains(&self, item: &InventoryItem) -> bool {
+        self.store.contains_key(item)
+    }
+
+    /// Checks if a peer is known to have an object
+    pub fn peer_knows(&self, peer: &SocketAddr, item: &InventoryItem, now: Instant) -> bool {
+        self.known
+            .get(peer)
+            .is_some_and(|known| known.contains(&item_id(item), now))
+    }
+
+    /// Add an object without announcing it, when it is relayed by other means.
+    /// Returns its ID.
+    pub fn insert(&mut self, kind: ObjectKind, payload: Vec<u8>, now: Instant) -> Hash {
+        self.expire(now);
+        let item = (kind, Hash::from_bytes(&payload));
+        let _ = self.store.insert(item, (payload, now));
+        item.1
+    }
+
+    /// Add an object of ours and announce it to our peers.
+    /// Returns its ID and the messages to send.
+    pub fn announce(
+        &mut self,
+        kind: ObjectKind,
+        payload: Vec<u8>,
+        peers: &[&SocketAddr],
+        now: Instant,
+    ) -> (Hash, Outgoing) {
+        let item = (kind, Hash::from_bytes(&payload));
+        let _ = self.store.insert(item, (payload, now));
+        (item.1, self.announcements(&item, None, peers, now))
+    }
+
+    /// Handle the announcement of objects by a peer.
+    /// Returns a request for those we neither have nor already requested, within the
+    /// limit of requests in flight to the peer; the others are requested from this peer
+    /// if the first request times out. Items over `MAX_INV_ITEMS` are dropped.
+    pub fn handle_inv(
+        &mut self,
+        from: &SocketAddr,
+        items: Vec<InventoryItem>,
+        now: Instant,
+    ) -> Outgoing {
+        self.expire(now);
+        if items.len() > MAX_INV_ITEMS {
+            log::debug!("Peer {:?} announced {} objects at once", from, items.len());
+        }
+        let mut in_flight = self
+            .requested
+            .values()
+            .filter(|request| request.peer == *from)
+            .count();
+        let mut wanted = Vec::new();
+        for item in items.into_iter().take(MAX_INV_ITEMS) {
+            self.mark_known(from, &item, now);
+            if self.store.contains_key(&item) {
+                continue;
+            }
+            match self.requested.get_mut(&item) {
+                Some(request) => {
+                    if request.peer != *from && !request.announcers.contains(from) {
+                        request.announcers.push(*from);
+                    }
+                }
+                None if in_flight >= MAX_REQUESTS_PER_PEER => {}
+                None => {
+                    let _ = self.requested.insert(
+                        item,
+                        Request {
+                            peer: *from,
+                            sent_at: now,
+                            announcers: Vec::new(),
+                        },
+                    );
+                    in_flight += 1;
+                    wanted.push(item);
+                }
+            }
+        }
+        if wanted.is_empty() {
+            return Vec::new();
+        }
+        vec![(*from, Message::GetData(wanted))]
+    }
+
+    /// Handle a request for objects by a peer.
+    /// Returns those we have.
+    pub fn handle_getdata(
+        &mut self,
+        from: &SocketAddr,
+        items: Vec<InventoryItem>,
+        now: Instant,
+    ) -> Outgoing {
+        self.expire(now);
+        let mut outgoing = Vec::new();
+        for item in items {
+            if let Some((payload, _)) = self.store.get(&item) {
+                outgoing.push((
+                    *from,
+                    Message::Data {
+                        kind: item.0,
+                        payload: payload.clone(),
+                    },
+                ));
+                self.mark_known(from, &item, now);
+            }
+        }
+        outgoing
+    }
+
+    /// Handle an object sent by a peer.
+    /// Returns the object if we requested it, and the messages announcing it to the
+    /// peers not known to have it. Unrequested objects are dropped.
+    pub fn handle_data(
+        &mut self,
+        from: &SocketAddr,
+        kind: ObjectKind,
+        payload: Vec<u8>,
+        peers: &[&SocketAddr],
+        now: Instant,
+    ) -> (Option<(Hash, Vec<u8>)>, Outgoing) {
+        let item = (kind, Hash::from_bytes(&payload));
+        self.mark_known(from, &item, now);
+        if self.requested.remove(&item).is_none() {
+            log::debug!(
+                "Dropping unrequested {:?} {:?} from {:?}",
+                kind,
+                item.1,
+                from
+            );
+            return (None, Vec::new());
+        }
+        let _ = self.store.insert(item, (payload.clone(), now));
+        let outgoing = self.announcements(&item, Some(from), peers, now);
+        (Some((item.1, payload)), outgoing)
+    }
+
+    /// Reject an object sent by a peer as invalid: it is neither stored nor announced,
+    /// and is requested from the next peer which announced it.
+    pub fn reject(
+        &mut self,
+        from: &SocketAddr,
+        kind: ObjectKind,
+        payload: &[u8],
+        now: Instant,
+    ) -> Outgoing {
+        let item = (kind, Hash::from_bytes(payload));
+        self.mark_known(from, &item, now);
+        let request = match self.requested.get_mut(&item) {
+            Some(request) if request.peer == *from => request,
+            _ => return Vec::new(),
+        };
+        if request.announcers.is_empty() {
+            let _ = self.requested.remove(&item);
+            return Vec::new();
+        }
+        request.peer = request.announcers.remove(0);
+        request.sent_at = now;
+        vec![(request.peer, Message::GetData(vec![item]))]
+    }
+
+    /// Re-request the objects whose request timed out from the next peer which
+    /// announced them, and give up on those without announcers left.
+    /// Should be called periodically.
+    pub fn check_requests(&mut self, now: Instant) -> RequestCheck {
+        let request_timeout = self.config.request_timeout;
+        let mut check = RequestCheck::default();
+        let mut retries = HashMap::<SocketAddr, Vec<InventoryItem>>::new();
+        self.requested.retain(|item, request| {
+            if now.saturating_duration_since(request.sent_at) < request_timeout {
+                return true;
+            }
+            log::debug!("Request for {:?} to {:?} timed out", item, request.peer);
+            if !check.timed_out.contains(&request.peer) {
+                check.timed_out.push(request.peer);
+            }
+            if request.announcers.is_empty() {
+                return false;
+            }
+            request.peer = request.announcers.remove(0);
+            request.sent_at = now;
+            retries.entry(request.peer).or_default().push(*item);
+            true
+        });
+        check.outgoing = retries
+            .into_iter()
+            .map(|(peer, items)| (peer, Message::GetData(items)))
+            .collect();
+        check
+    }
+
+    /// Forget what the peers we are no longer connected to know, and request what
+    /// we requested from them from the next connected peer which announced it.
+    /// Returns the requests to send.
+    pub fn retain_peers(&mut self, peers: &[&SocketAddr], now: Instant) -> Outgoing {
+        self.known.retain(|peer, _| peers.contains(&peer));
+        let mut retries = HashMap::<SocketAddr, Vec<InventoryItem>>::new();
+        self.requested.retain(|item, request| {
+            request
+                .announcers
+                .retain(|announcer| peers.contains(&announcer));
+            if peers.contains(&&request.peer) {
+                return true;
+            }
+            if request.announcers.is_empty() {
+                return false;
+            }
+            request.peer = request.announcers.remove(0);
+            request.sent_at = now;
+            retries.entry(request.peer).or_default().push(*item);
+            true
+        });
+        retries
+            .into_iter()
+            .map(|(peer, items)| (peer, Message::GetData(items)))
+            .collect()
+    }
+
+    /// Announce an object to the peers not known to have it, other than its sender.
+    fn announcements(
+        &mut self,
+        item: &InventoryItem,
+        from: Option<&SocketAddr>,
+        peers: &[&SocketAddr],
+        now: Instant,
+    ) -> Outgoing {
+        let targets = peers
+            .iter()
+            .filter(|peer| Some(**peer) != from && !self.peer_knows(peer, item, now))
+            .map(|peer| **peer)
+            .collect::<Vec<_>>();
+        for peer in &targets {
+            self.mark_known(peer, item, now);
+        }
+        targets
+            .into_iter()
+            .map(|peer| (peer, Message::Inv(vec![*item])))
+            .collect()
+    }
+
+    /// Record that a peer has an object, so it is not announced to it.
+    pub fn mark_known(&mut self, peer: &SocketAddr, item: &InventoryItem, now: Instant) {
+        let retention = self.config.retention;
+        let _ = self
+            .known
+            .entry(*peer)
+            .or_insert_with(|| SeenCache::new(DEFAULT_KNOWN_INVENTORY, retention))
+            .insert(item_id(item), now);
+    }
+
+    /// Forget the objects kept for longer than the retention time.
+    fn expire(&mut self, now: Instant) {
+        let retention = self.config.retention;
+        self.store
+            .retain(|_, (_, stored_at)| now.saturating_duration_since(*stored_at) <= retention);
+    }
+}
+
+impl Default for Inventory {
+    fn default() -> Self {
+        Self::new(GossipConfig::default())
+    }
+}
+
+/// Identifies an object along with its kind, so a block and a transaction with
+/// the same content are told apart.
+fn item_id((kind, id): &InventoryItem) -> Hash {
+    let kind = match kind {
+        ObjectKind::Block => 0u8,
+        ObjectKind::Transaction => 1u8,
+    };
+    Hash::from_byte_arrays(&[&[kind], id.as_ref()])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::Network;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn test_objects_sent_once_per_node() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut network = Network::new(30, 4, &mut rng, |_| Inventory::default());
+        let now = Instant::now();
+        let peers = network.topology.peers(0);
+        let (id, outgoing) =
+            network.nodes[0].announce(ObjectKind::Block, vec![7; 4096], &peers, now);
+        let (mut received, mut data_sent) = (vec![0; 30], 0);
+        network.run(
+            0,
+            outgoing,
+            |inventory, node, from, message, peers| match message {
+                Message::Inv(items) => inventory.handle_inv(from, items, now),
+                Message::GetData(items) => inventory.handle_getdata(from, items, now),
+                Message::Data { kind, payload } => {
+                    data_sent += 1;
+                    let (object, outgoing) = inventory.handle_data(from, kind, payload, peers, now);
+                    received[node] += usize::from(object.is_some());
+                    outgoing
+                }
+                _ => Vec::new(),
+            },
+        );
+
+        assert!(received[1..].iter().all(|received| *received == 1));
+        assert_eq!(data_sent, 29);
+        let item = (ObjectKind::Block, id);
+        assert!(network.nodes.iter().all(|node| node.contains(&item)));
+        // A peer which announced or received an object is not told about it again.
+        let (from, to) = (network.topology.addrs[0], network.topology.links[0][0]);
+        assert!(network.nodes[0].peer_knows(&to, &item, now));
+        assert!(network.nodes[0].handle_inv(&to, vec![item], now).is_empty());
+        assert!(!network.nodes[1].peer_knows(&from, &(ObjectKind::Transaction, id), now));
+    }
+
+    #[test]
+    fn test_rerequest_from_next_announcer() {
+        let now = Instant::now();
+        let silent = SocketAddr::from(([10, 0, 0, 1], 9000));
+        let honest = SocketAddr::from(([10, 0, 0, 2], 9000));
+        let item = (ObjectKind::Transaction, Hash::from_bytes(b"tx"));
+        let mut inventory = Inventory::default();
+
+        let requests = inventory.handle_inv(&silent, vec![item], now);
+        assert!(matches!(
+            requests.as_slice(),
+            [(to, Message::GetData(items))] if *to == silent && *items == vec![item]
+        ));
+        assert!(inventory.handle_inv(&honest, vec![item], now).is_empty());
+        assert!(inventory.check_requests(now).outgoing.is_empty());
+
+        let later = now + GossipConfig::default().request_timeout;
+        let check = inventory.check_requests(later);
+        assert_eq!(check.timed_out, vec![silent]);
+        assert!(matches!(
+            check.outgoing.as_slice(),
+            [(to, Message::GetData(items))] if *to == honest && *items == vec![item]
+        ));
+
+        let (object, _) =
+            inventory.handle_data(&honest, ObjectKind::Transaction, b"tx".to_vec(), &[], later);
+        assert_eq!(object.map(|(id, _)| id), Some(item.1));
+        assert!(inventory.contains(&item));
+
+        // Objects nobody asked for are dropped.
+        let (object, _) =
+            inventory.handle_data(&honest, ObjectKind::Block, b"spam".to_vec(), &[], later);
+        assert!(object.is_none());
+    }
+
+    #[test]
+    fn test_rerequest_after_rejection() {
+        let now = Instant::now();
+        let liar = SocketAddr::from(([10, 0, 0, 1], 9000));
+        let honest = SocketAddr::from(([10, 0, 0, 2], 9000));
+        let item = (ObjectKind::Transaction, Hash::from_bytes(b"tx"));
+        let mut inventory = Inventory::default();
+        let _ = inventory.handle_inv(&liar, vec![item], now);
+        let _ = inventory.handle_inv(&honest, vec![item], now);
+
+        // Only the peer the object was requested from can get it rejected.
+        assert!(inventory
+            .reject(&honest, ObjectKind::Transaction, b"tx", now)
+            .is_empty());
+        let requests = inventory.reject(&liar, ObjectKind::Transaction, b"tx", now);
+        assert!(matches!(
+            requests.as_slice(),
+            [(to, Message::GetData(items))] if *to == honest && *items == vec![item]
+        ));
+        assert!(!inventory.contains(&item));
+        assert!(inventory.check_requests(now).timed_out.is_empty());
+
+        // Without announcers left, the object is no longer awaited.
+        assert!(inventory
+            .reject(&honest, ObjectKind::Transaction, b"tx", now)
+            .is_empty());
+        let (object, _) =
+            inventory.handle_data(&honest, ObjectKind::Transaction, b"tx".to_vec(), &[], now);
+        assert!(object.is_none());
+    }
+
+    #[test]
+    fn test_inv_limits() {
+        let now = Instant::now();
+        let flooder = SocketAddr::from(([10, 0, 0, 1], 9000));
+        let other = SocketAddr::from(([10, 0, 0, 2], 9000));
+        let items = (0..MAX_INV_ITEMS + 10)
+            .map(|_| (ObjectKind::Transaction, Hash::random()))
+            .collect::<Vec<_>>();
+        let mut inventory = Inventory::default();
+        let requests = inventory.handle_inv(&flooder, items.clone(), now);
+        assert!(matches!(
+            requests.as_slice(),
+            [(_, Message::GetData(wanted))] if *wanted == items[..MAX_REQUESTS_PER_PEER]
+        ));
+
+        // Once the peer has as many requests in flight as allowed, its
+        // announcements are dropped, while other peers are still asked.
+        let more = vec![(ObjectKind::Block, Hash::random())];
+        assert!(inventory.handle_inv(&flooder, more.clone(), now).is_empty());
+        assert_eq!(inventory.handle_inv(&other, more, now).len(), 1);
+        // Dropped announcements are not kept to fall back on either.
+        // Peers which leave several requests unanswered are reported once.
+        let check = inventory.check_requests(now + GossipConfig::default().request_timeout);
+        assert_eq!(check.timed_out.len(), 2);
+        assert!(check.timed_out.contains(&flooder) && check.timed_out.contains(&other));
+        assert!(check.outgoing.is_empty());
+    }
+
+    #[test]
+    fn test_requests_to_disconnected_peers() {
+        let now = Instant::now();
+        let (gone, announcer, silent) = (
+            SocketAddr::from(([10, 0, 0, 1], 9000)),
+            SocketAddr::from(([10, 0, 0, 2], 9000)),
+            SocketAddr::from(([10, 0, 0, 3], 9000)),
+        );
+        let (first, second) = (
+            (ObjectKind::Block, Hash::random()),
+            (ObjectKind::Transaction, Hash::random()),
+        );
+        let mut inventory = Inventory::default();
+        let _ = inventory.handle_inv(&gone, vec![first, second], now);
+        let _ = inventory.handle_inv(&announcer, vec![first], now);
+        let _ = inventory.handle_inv(&silent, vec![second], now);
+
+        // What was requested from a peer which disconnected is requested from the
+        // next connected announcer, or given up on, without the peer timing out.
+        let outgoing = inventory.retain_peers(&[&announcer], now);
+        assert!(matches!(
+            outgoing.as_slice(),
+            [(to, Message::GetData(items))] if *to == announcer && *items == [first]
+        ));
+        let check = inventory.check_requests(now + GossipConfig::default().request_timeout);
+        assert_eq!(check.timed_out, vec![announcer]);
+    }
+
+    #[test]
+    fn test_give_up_without_announcers() {
+        let now = Instant::now();
+        let peer = SocketAddr::from(([10, 0, 0, 1], 9000));
+        let item = (ObjectKind::Block, Hash::random());
+        let mut inventory = Inventory::default();
+        let _ = inventory.handle_inv(&peer, vec![item], now);
+        let later = now + GossipConfig::default().request_timeout;
+        assert_eq!(inventory.check_requests(later).timed_out, vec![peer]);
+        let check = inventory.check_requests(later + GossipConfig::default().request_timeout);
+        assert!(check.timed_out.is_empty() && check.outgoing.is_empty());
+        // Once given up on, the object can be requested again.
+        assert_eq!(inventory.handle_inv(&peer, vec![item], later).len(), 1);
+    }
+}
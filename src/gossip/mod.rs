@@ -0,0 +1,482 @@
+use crate::{crypto::hash::Hash, messaging::seen_cache::SeenCache, Message, Result};
+use bytes::Bytes;
+use inventory::{MAX_INV_ITEMS, MAX_REQUESTS_PER_PEER};
+use qp2p::Connection as QuicConnection;
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+/// Block relay with short transaction IDs
+pub mod compact;
+/// Payloads split into erasure-coded chunks
+pub mod erasure;
+/// Announce/request exchange of blocks and transactions
+pub mod inventory;
+/// Epidemic broadcast trees
+pub mod plumtree;
+/// Application objects and the checks they pass before being relayed
+pub mod validation;
+
+/// Default number of peers each broadcast is relayed to
+pub const DEFAULT_FANOUT: usize = 6;
+/// Default time during which broadcasts are kept to answer requests for them
+pub const DEFAULT_BROADCAST_RETENTION: Duration = Duration::from_secs(2 * 60);
+/// Default time after which a requested broadcast is requested from another peer
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Messages to send, along with the address of their recipient
+pub type Outgoing = Vec<(SocketAddr, Message)>;
+
+/// How broadcasts are disseminated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GossipConfig {
+    /// Number of peers each broadcast is relayed to
+    pub fanout: usize,
+    /// Payload size above which broadcasts are announced by ID and sent only on request.
+    /// With `None`, every broadcast is pushed in full.
+    pub lazy_threshold: Option<usize>,
+    /// Time during which broadcasts are kept to answer requests for them
+    pub retention: Duration,
+    /// Time after which a requested broadcast is requested from another peer
+    pub request_timeout: Duration,
+    /// Disseminate broadcasts along epidemic broadcast trees rather than by push gossip
+    pub plumtree: bool,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        Self {
+            fanout: DEFAULT_FANOUT,
+            lazy_threshold: None,
+            retention: DEFAULT_BROADCAST_RETENTION,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            plumtree: false,
+        }
+    }
+}
+
+/// A payload disseminated to the whole network
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Broadcast {
+    /// Blake3 hash of the origin, nonce and payload
+    pub id: Hash,
+    /// Node ID of the node which broadcast the payload, as claimed by the broadcast.
+    /// It is not authenticated, and only tells apart the IDs of broadcasts.
+    pub origin: Hash,
+    /// Random number telling apart identical payloads broadcast several times
+    pub nonce: u64,
+    /// Number of hops the broadcast made so far
+    pub hops: u32,
+    /// Broadcast data
+    pub payload: Vec<u8>,
+}
+
+impl Broadcast {
+    /// Creates a new `Broadcast`, computing its ID.
+    pub fn new(origin: Hash, nonce: u64, payload: Vec<u8>) -> Self {
+        Self {
+            id: Self::compute_id(&origin, nonce, &payload),
+            origin,
+            nonce,
+            hops: 0,
+            payload,
+        }
+    }
+
+    /// Computes the ID of a broadcast
+    pub fn compute_id(origin: &Hash, nonce: u64, payload: &[u8]) -> Hash {
+        Hash::from_byte_arrays(&[origin.as_ref(), &nonce.to_be_bytes(), payload])
+    }
+
+    /// Checks that the ID matches the content
+    pub fn is_valid(&self) -> bool {
+        self.id == Self::compute_id(&self.origin, self.nonce, &self.payload)
+    }
+}
+
+/// A broadcast requested from a peer
+#[derive(Debug, Clone)]
+struct Request {
+    peer: SocketAddr,
+    sent_at: Instant,
+    announcers: Vec<SocketAddr>,
+}
+
+/// Epidemic dissemination of broadcasts.
+/// Every broadcast is relayed to `fanout` random peers the first time it is seen,
+/// either in full or, above the lazy threshold, announced by ID (IHAVE) and sent
+/// to the peers which ask for it (IWANT). Methods return the messages to send.
+#[derive(Debug, Clone)]
+pub struct Gossip {
+    config: GossipConfig,
+    seen: SeenCache,
+    store: HashMap<Hash, (Broadcast, Instant)>,
+    requested: HashMap<Hash, Request>,
+}
+
+impl Gossip {
+    /// Creates a new `Gossip`.
+    pub fn new(config: GossipConfig) -> Self {
+        Self {
+            config,
+            seen: SeenCache::default(),
+            store: Default::default(),
+            requested: Default::default(),
+        }
+    }
+
+    /// Retrieves the configuration
+    pub fn config(&self) -> &GossipConfig {
+        &self.config
+    }
+
+    /// Start disseminating a payload.
+    /// Returns the ID of the broadcast and the messages to send.
+    pub fn broadcast<R: Rng>(
+        &mut self,
+        origin: &Hash,
+        payload: Vec<u8>,
+        peers: &[&SocketAddr],
+        now: Instant,
+        rng: &mut R,
+    ) -> (Hash, Outgoing) {
+        let broadcast = Broadcast::new(*origin, rng.gen(), payload);
+        let id = broadcast.id;
+        let _ = self.seen.insert(id, now);
+        (id, self.relay(broadcast, None, peers, now, rng))
+    }
+
+    /// Handle a broadcast received from a peer.
+    /// Returns the broadcast, with its hop count updated, if it was not seen before,
+    /// and the messages relaying it.
+    pub fn handle_broadcast<R: Rng>(
+        &mut self,
+        from: &SocketAddr,
+        mut broadcast: Broadcast,
+        peers: &[&SocketAddr],
+        now: Instant,
+        rng: &mut R,
+    ) -> (Option<Broadcast>, Outgoing) {
+        if !broadcast.is_valid() {
+            log::warn!("Dropping broadcast {:?} with a forged ID", broadcast.id);
+            return (None, Vec::new());
+        }
+        let _ = self.requested.remove(&broadcast.id);
+        if !self.seen.insert(broadcast.id, now) {
+            return (None, Vec::new());
+        }
+        broadcast.hops = broadcast.hops.saturating_add(1);
+        let outgoing = self.relay(broadcast.clone(), Some(from), peers, now, rng);
+        (Some(broadcast), outgoing)
+    }
+
+    /// Handle the announcement of broadcasts by a peer.
+    /// Returns a request for those we have not seen nor already requested, within the
+    /// limit of requests in flight to the peer. The peer is remembered as an announcer
+    /// of those already requested from another, to request them from if the pending
+    /// request times out. IDs over `MAX_INV_ITEMS` are dropped.
+    pub fn handle_ihave(&mut self, from: &SocketAddr, ids: Vec<Hash>, now: Instant) -> Outgoing {
+        self.expire(now);
+        if ids.len() > MAX_INV_ITEMS {
+            log::debug!("Peer {:?} announced {} broadcasts at once", from, ids.len());
+        }
+        let mut in_flight = self
+            .requested
+            .values()
+            .filter(|request| request.peer == *from)
+            .count();
+        let mut wanted = Vec::new();
+        for id in ids.into_iter().take(MAX_INV_ITEMS) {
+            if self.seen.contains(&id, now) {
+                continue;
+            }
+            match self.requested.get_mut(&id) {
+                Some(request) => {
+                    if request.peer != *from && !request.announcers.contains(from) {
+                        request.announcers.push(*from);
+                    }
+                }
+                None if in_flight >= MAX_REQUESTS_PER_PEER => {}
+                None => {
+                    let _ = self.requested.insert(
+                        id,
+                        Request {
+                            peer: *from,
+                            sent_at: now,
+                            announcers: Vec::new(),
+                        },
+                    );
+                    in_flight += 1;
+                    wanted.push(id);
+                }
+            }
+        }
+        if wanted.is_empty() {
+            return Vec::new();
+        }
+        vec![(*from, Message::IWant(wanted))]
+    }
+
+    /// Request again the broadcasts whose request timed out, from other peers which
+    /// announced them, and give up on those without announcers left.
+    pub fn check_requests(&mut self, now: Instant) -> Outgoing {
+        let request_timeout = self.config.request_timeout;
+        let mut retries = HashMap::<SocketAddr, Vec<Hash>>::new();
+        self.requested.retain(|id, request| {
+            if now.saturating_duration_since(request.sent_at) < request_timeout {
+                return true;
+            }
+            log::debug!("Request for {:?} to {:?} timed out", id, request.peer);
+            if request.announcers.is_empty() {
+                return false;
+            }
+            request.peer = request.announcers.remove(0);
+            request.sent_at = now;
+            retries.entry(request.peer).or_default().push(*id);
+            true
+        });
+        retries
+            .into_iter()
+            .map(|(peer, ids)| (peer, Message::IWant(ids)))
+            .collect()
+    }
+
+    /// Handle a request for broadcasts by a peer.
+    /// Returns those we still have.
+    pub fn handle_iwant(&mut self, from: &SocketAddr, ids: Vec<Hash>, now: Instant) -> Outgoing {
+        self.expire(now);
+        ids.iter()
+            .filter_map(|id| self.store.get(id))
+            .map(|(broadcast, _)| (*from, Message::Broadcast(broadcast.clone())))
+            .collect()
+    }
+
+    /// Keep a broadcast to answer requests, and relay it to `fanout` random peers
+    /// other than the one we got it from.
+    fn relay<R: Rng>(
+        &mut self,
+        broadcast: Broadcast,
+        from: Option<&SocketAddr>,
+        peers: &[&SocketAddr],
+        now: Instant,
+        rng: &mut R,
+    ) -> Outgoing {
+        self.expire(now);
+        let targets = peers
+            .iter()
+            .filter(|peer| Some(**peer) != from)
+            .collect::<Vec<_>>();
+        let targets = targets
+            .choose_multiple(rng, self.config.fanout)
+            .map(|peer| ***peer)
+            .collect::<Vec<_>>();
+        let lazy = self
+            .config
+            .lazy_threshold
+            .is_some_and(|threshold| broadcast.payload.len() > threshold);
+        let message = if lazy {
+            Message::IHave(vec![broadcast.id])
+        } else {
+            Message::Broadcast(broadcast.clone())
+        };
+        let _ = self.store.insert(broadcast.id, (broadcast, now));
+        targets
+            .into_iter()
+            .map(|target| (target, message.clone()))
+            .collect()
+    }
+
+    /// Forget the broadcasts kept for longer than the retention time.
+    fn expire(&mut self, now: Instant) {
+        let retention = self.config.retention;
+        self.store
+            .retain(|_, (_, stored_at)| now.saturating_duration_since(*stored_at) <= retention);
+    }
+}
+
+impl Default for Gossip {
+    fn default() -> Self {
+        Self::new(GossipConfig::default())
+    }
+}
+
+/// Send gossip messages to peers.
+pub async fn send(outgoing: Outgoing, quic: &mut QuicConnection) -> Result<()> {
+    for (socket_addr, message) in outgoing {
+        let user_msg_bytes = (
+            Bytes::from("Gossip"),
+            Bytes::from(socket_addr.to_string()),
+            Bytes::from(bincode::serialize(&message)?),
+        );
+        quic.send(user_msg_bytes).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::Network;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// Broadcast from a node and deliver messages until the network is quiet. Returns the
+    /// broadcasts delivered to each node, and the number of messages sent.
+    fn broadcast(
+        network: &mut Network<Gossip>,
+        origin: usize,
+        payload: Vec<u8>,
+        now: Instant,
+        rng: &mut StdRng,
+    ) -> (Vec<Vec<Broadcast>>, usize) {
+        let peers = network.topology.peers(origin);
+        let (_, outgoing) =
+            network.nodes[origin].broadcast(&Hash::random(), payload, &peers, now, rng);
+        let mut delivered = vec![Vec::new(); network.nodes.len()];
+        let mut sent = 0;
+        network.run(origin, outgoing, |gossip, node, from, message, peers| {
+            sent += 1;
+            match message {
+                Message::Broadcast(broadcast) => {
+                    let (new, outgoing) = gossip.handle_broadcast(from, broadcast, peers, now, rng);
+                    delivered[node].extend(new);
+                    outgoing
+                }
+                Message::IHave(ids) => gossip.handle_ihave(from, ids, now),
+                Message::IWant(ids) => gossip.handle_iwant(from, ids, now),
+                _ => Vec::new(),
+            }
+        });
+        (delivered, sent)
+    }
+
+    #[test]
+    fn test_push_reaches_everyone_once() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let config = GossipConfig {
+            fanout: 4,
+            ..Default::default()
+        };
+        let mut network = Network::new(100, 6, &mut rng, |_| Gossip::new(config));
+        let (delivered, sent) =
+            broadcast(&mut network, 0, b"block".to_vec(), Instant::now(), &mut rng);
+
+        let reached = delivered[1..]
+            .iter()
+            .filter(|delivered| delivered.len() == 1)
+            .count();
+        assert!(reached >= 95);
+        assert!(delivered.iter().all(|delivered| delivered.len() <= 1));
+        assert!(delivered[0].is_empty());
+        // Each node relays once, to `fanout` peers.
+        assert!(sent <= 100 * 4);
+        let hops = delivered.iter().flatten().map(|broadcast| broadcast.hops);
+        assert!(hops.clone().all(|hops| hops >= 1));
+        assert!(hops.max().unwrap_or_default() < 20);
+    }
+
+    #[test]
+    fn test_lazy_push_for_large_payloads() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let config = GossipConfig {
+            fanout: 4,
+            lazy_threshold: Some(16),
+            ..Default::default()
+        };
+        let mut network = Network::new(50, 6, &mut rng, |_| Gossip::new(config));
+        let now = Instant::now();
+        let (delivered, _) = broadcast(&mut network, 0, vec![7; 1024], now, &mut rng);
+        let reached = delivered[1..]
+            .iter()
+            .filter(|delivered| delivered.len() == 1)
+            .count();
+        assert!(reached >= 45);
+        assert!(delivered.iter().all(|delivered| delivered.len() <= 1));
+
+        // Broadcasts already seen or requested are not requested again.
+        let peer = network.topology.addrs[1];
+        let id = delivered[1].first().map(|broadcast| broadcast.id);
+        let id = id.unwrap_or_else(Hash::random);
+        assert!(network.nodes[1]
+            .handle_ihave(&peer, vec![id], now)
+            .is_empty());
+        let unknown = Hash::random();
+        assert_eq!(
+            network.nodes[1]
+                .handle_ihave(&peer, vec![unknown], now)
+                .len(),
+            1
+        );
+        assert!(network.nodes[1]
+            .handle_ihave(&peer, vec![unknown], now)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_rerequest_from_next_announcer() {
+        let now = Instant::now();
+        let (first, second) = (
+            SocketAddr::from(([10, 0, 0, 1], 9000)),
+            SocketAddr::from(([10, 0, 0, 2], 9000)),
+        );
+        let mut gossip = Gossip::default();
+        let id = Hash::random();
+        let outgoing = gossip.handle_ihave(&first, vec![id], now);
+        assert!(
+            matches!(&outgoing[..], [(to, Message::IWant(ids))] if *to == first && ids == &[id])
+        );
+        assert!(gossip.handle_ihave(&second, vec![id], now).is_empty());
+        assert!(gossip.check_requests(now).is_empty());
+
+        // Once the request times out, the broadcast is requested from the next announcer.
+        let later = now + DEFAULT_REQUEST_TIMEOUT;
+        let outgoing = gossip.check_requests(later);
+        assert!(
+            matches!(&outgoing[..], [(to, Message::IWant(ids))] if *to == second && ids == &[id])
+        );
+
+        // Without announcers left, the broadcast is no longer awaited.
+        let latest = later + DEFAULT_REQUEST_TIMEOUT;
+        assert!(gossip.check_requests(latest).is_empty());
+        assert_eq!(gossip.handle_ihave(&first, vec![id], latest).len(), 1);
+    }
+
+    #[test]
+    fn test_ihave_limits() {
+        let now = Instant::now();
+        let (flooder, other) = (
+            SocketAddr::from(([10, 0, 0, 1], 9000)),
+            SocketAddr::from(([10, 0, 0, 2], 9000)),
+        );
+        let ids = (0..MAX_INV_ITEMS + 10)
+            .map(|_| Hash::random())
+            .collect::<Vec<_>>();
+        let mut gossip = Gossip::default();
+        let outgoing = gossip.handle_ihave(&flooder, ids.clone(), now);
+        assert!(matches!(
+            outgoing.as_slice(),
+            [(_, Message::IWant(wanted))] if *wanted == ids[..MAX_REQUESTS_PER_PEER]
+        ));
+
+        // Once the peer has as many requests in flight as allowed, its
+        // announcements are dropped, while other peers are still asked.
+        let more = vec![Hash::random()];
+        assert!(gossip.handle_ihave(&flooder, more.clone(), now).is_empty());
+        assert_eq!(gossip.handle_ihave(&other, more, now).len(), 1);
+    }
+
+    #[test]
+    fn test_forged_broadcast_dropped() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let peer = SocketAddr::from(([10, 0, 0, 1], 9000));
+        let mut gossip = Gossip::default();
+        let mut broadcast = Broadcast::new(Hash::random(), 1, b"block".to_vec());
+        broadcast.payload = b"forged".to_vec();
+        let (new, outgoing) =
+            gossip.handle_broadcast(&peer, broadcast, &[&peer], Instant::now(), &mut rng);
+        assert!(new.is_none() && outgoing.is_empty());
+    }
+}
@@ -0,0 +1,451 @@
+use super::{
+    inventory::{MAX_INV_ITEMS, MAX_REQUESTS_PER_PEER},
+    Broadcast, GossipConfig, Outgoing,
+};
+use crate::{crypto::hash::Hash, messaging::seen_cache::SeenCache, Message};
+use rand::Rng;
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    time::Instant,
+};
+
+/// A broadcast announced by lazy peers but not received yet
+#[derive(Debug, Clone)]
+struct Missing {
+    announcers: Vec<SocketAddr>,
+    graft_at: Instant,
+}
+
+/// Epidemic broadcast trees.
+/// Broadcasts are pushed in full to eager peers, which form a spanning tree, and
+/// announced by ID (IHAVE) to lazy peers. A peer sending a broadcast we already have
+/// is pruned from the tree (PRUNE); a broadcast announced but not received in time
+/// is requested from its announcer, which joins the tree (GRAFT).
+/// New peers start eager, so the first broadcast floods and later ones follow the tree.
+#[derive(Debug, Clone)]
+pub struct Plumtree {
+    config: GossipConfig,
+    eager: HashSet<SocketAddr>,
+    lazy: HashSet<SocketAddr>,
+    seen: SeenCache,
+    store: HashMap<Hash, (Broadcast, Instant)>,
+    missing: HashMap<Hash, Missing>,
+}
+
+impl Plumtree {
+    /// Creates a new `Plumtree`.
+    pub fn new(config: GossipConfig) -> Self {
+        Self {
+            config,
+            eager: Default::default(),
+            lazy: Default::default(),
+            seen: SeenCache::default(),
+            store: Default::default(),
+            missing: Default::default(),
+        }
+    }
+
+    /// Peers broadcasts are pushed to in full
+    pub fn eager_peers(&self) -> impl Iterator<Item = &SocketAddr> {
+        self.eager.iter()
+    }
+
+    /// Peers broadcasts are announced to
+    pub fn lazy_peers(&self) -> impl Iterator<Item = &SocketAddr> {
+        self.lazy.iter()
+    }
+
+    /// Start disseminating a payload.
+    /// Returns the ID of the broadcast and the messages to send.
+    pub fn broadcast<R: Rng>(
+        &mut self,
+        origin: &Hash,
+        payload: Vec<u8>,
+        peers: &[&SocketAddr],
+        now: Instant,
+        rng: &mut R,
+    ) -> (Hash, Outgoing) {
+        self.update_peers(peers);
+        let broadcast = Broadcast::new(*origin, rng.gen(), payload);
+        let id = broadcast.id;
+        let _ = self.seen.insert(id, now);
+        (id, self.relay(broadcast, None, now))
+    }
+
+    /// Handle a broadcast received from a peer.
+    /// Returns the broadcast, with its hop count updated, if it was not seen before,
+    /// and the messages relaying it or pruning the sender.
+    pub fn handle_broadcast(
+        &mut self,
+        from: &SocketAddr,
+        mut broadcast: Broadcast,
+        peers: &[&SocketAddr],
+        now: Instant,
+    ) -> (Option<Broadcast>, Outgoing) {
+        if !broadcast.is_valid() {
+            log::warn!("Dropping broadcast {:?} with a forged ID", broadcast.id);
+            return (None, Vec::new());
+        }
+        self.update_peers(peers);
+        if !self.seen.insert(broadcast.id, now) {
+            log::trace!("Pruning {:?}, which sent a duplicate broadcast", from);
+            self.make_lazy(from);
+            return (None, vec![(*from, Message::Prune)]);
+        }
+        let _ = self.missing.remove(&broadcast.id);
+        self.make_eager(from);
+        broadcast.hops = broadcast.hops.saturating_add(1);
+        let outgoing = self.relay(broadcast.clone(), Some(from), now);
+        (Some(broadcast), outgoing)
+    }
+
+    /// Handle the announcement of broadcasts by a lazy peer.
+    /// Those we have not seen are grafted from it if they do not arrive in time.
+    /// IDs over `MAX_INV_ITEMS` are dropped, as are those announced by a peer which
+    /// already announced `MAX_REQUESTS_PER_PEER` missing broadcasts.
+    pub fn handle_ihave(&mut self, from: &SocketAddr, ids: Vec<Hash>, now: Instant) {
+        if ids.len() > MAX_INV_ITEMS {
+            log::debug!("Peer {:?} announced {} broadcasts at once", from, ids.len());
+        }
+        let graft_at = now + self.config.request_timeout;
+        let mut announced = self
+            .missing
+            .values()
+            .filter(|missing| missing.announcers.contains(from))
+            .count();
+        for id in ids.into_iter().take(MAX_INV_ITEMS) {
+            if announced >= MAX_REQUESTS_PER_PEER {
+                break;
+            }
+            if self.seen.contains(&id, now) {
+                continue;
+            }
+            let missing = self.missing.entry(id).or_insert_with(|| Missing {
+                announcers: Vec::new(),
+                graft_at,
+            });
+            if !missing.announcers.contains(from) {
+                missing.announcers.push(*from);
+                announced += 1;
+            }
+        }
+    }
+
+    /// Handle a request from a peer to join the tree, sending it the broadcasts it missed.
+    /// Only peers we are connected to join; at most `MAX_INV_ITEMS` broadcasts are sent.
+    pub fn handle_graft(&mut self, from: &SocketAddr, ids: Vec<Hash>, now: Instant) -> Outgoing {
+        self.expire(now);
+        if !self.eager.contains(from) && !self.lazy.contains(from) {
+            log::debug!("Ignoring graft from {:?}, which is not a peer", from);
+            return Vec::new();
+        }
+        self.make_eager(from);
+        ids.iter()
+            .take(MAX_INV_ITEMS)
+            .filter_map(|id| self.store.get(id))
+            .map(|(broadcast, _)| (*from, Message::Broadcast(broadcast.clone())))
+            .collect()
+    }
+
+    /// Handle a request from a peer to leave the tree.
+    pub fn handle_prune(&mut self, from: &SocketAddr) {
+        self.make_lazy(from);
+    }
+
+    /// Graft the announcers of the broadcasts which did not arrive in time,
+    /// trying the next announcer of a broadcast each time the previous one fails.
+    /// Should be called periodically.
+    pub fn graft_missing(&mut self, now: Instant) -> Outgoing {
+        let mut grafts = HashMap::<SocketAddr, Vec<Hash>>::new();
+        let request_timeout = self.config.request_timeout;
+        self.missing.retain(|id, missing| {
+            if missing.graft_at > now {
+                return true;
+            }
+            if missing.announcers.is_empty() {
+                return false;
+            }
+            let announcer = missing.announcers.remove(0);
+            grafts.entry(announcer).or_default().push(*id);
+            missing.graft_at = now + request_timeout;
+            true
+        });
+        for announcer in grafts.keys() {
+            self.make_eager(announcer);
+        }
+        grafts
+            .into_iter()
+            .map(|(announcer, ids)| (announcer, Message::Graft(ids)))
+            .collect()
+    }
+
+    /// Keep a broadcast to answer grafts, push it to the eager peers and announce it
+    /// to the lazy ones, other than the peer we got it from.
+    fn relay(&mut self, broadcast: Broadcast, from: Option<&SocketAddr>, now: Instant) -> Outgoing {
+        self.expire(now);
+        let outgoing = self
+            .eager
+            .iter()
+            .filter(|peer| Some(*peer) != from)
+            .map(|peer| (*peer, Message::Broadcast(broadcast.clone())))
+            .chain(
+                self.lazy
+                    .iter()
+                    .filter(|peer| Some(*peer) != from)
+                    .map(|peer| (*peer, Message::IHave(vec![broadcast.id]))),
+            )
+            .collect();
+        let _ = self.store.insert(broadcast.id, (broadcast, now));
+        outgoing
+    }
+
+    /// Add new peers as eager peers, and forget the peers we are no longer connected to.
+    fn update_peers(&mut self, peers: &[&SocketAddr]) {
+        let current = peers.iter().copied().copied().collect::<HashSet<_>>();
+        self.eager.retain(|peer| current.contains(peer));
+        self.lazy.retain(|peer| current.contains(peer));
+        for peer in current {
+            if !self.lazy.contains(&peer) {
+                let _ = self.eager.insert(peer);
+            }
+        }
+    }
+
+    fn make_eager(&mut self, peer: &SocketAddr) {
+        let _ = self.lazy.remove(peer);
+        let _ = self.eager.insert(*peer);
+    }
+
+    fn make_lazy(&mut self, peer: &SocketAddr) {
+        let _ = self.eager.remove(peer);
+        let _ = self.lazy.insert(*peer);
+    }
+
+    /// Forget the broadcasts kept for longer than the retention time.
+    fn expire(&mut self, now: Instant) {
+        let retention = self.config.retention;
+        self.store
+            .retain(|_, (_, stored_at)| now.saturating_duration_since(*stored_at) <= retention);
+    }
+}
+
+impl Default for Plumtree {
+    fn default() -> Self {
+        Self::new(GossipConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::Network;
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::time::Duration;
+
+    /// Plumtree nodes on a random topology, some of which may be down
+    struct Simulation {
+        network: Network<Plumtree>,
+        down: HashSet<usize>,
+        delivered: Vec<HashSet<Hash>>,
+        copies: usize,
+    }
+
+    impl Simulation {
+        fn new(size: usize, degree: usize, rng: &mut StdRng) -> Self {
+            Self {
+                network: Network::new(size, degree, rng, |_| Plumtree::default()),
+                down: HashSet::new(),
+                delivered: vec![HashSet::new(); size],
+                copies: 0,
+            }
+        }
+
+        /// Deliver messages until the network is quiet.
+        fn run(&mut self, from: usize, outgoing: Outgoing, now: Instant) {
+            let (down, delivered, copies) = (&self.down, &mut self.delivered, &mut self.copies);
+            self.network
+                .run(from, outgoing, |tree, node, from, message, peers| {
+                    if down.contains(&node) {
+                        return Vec::new();
+                    }
+                    match message {
+                        Message::Broadcast(broadcast) => {
+                            *copies += 1;
+                            let (new, outgoing) =
+                                tree.handle_broadcast(from, broadcast, peers, now);
+                            delivered[node].extend(new.map(|broadcast| broadcast.id));
+                            outgoing
+                        }
+                        Message::IHave(ids) => {
+                            tree.handle_ihave(from, ids, now);
+                            Vec::new()
+                        }
+                        Message::Graft(ids) => tree.handle_graft(from, ids, now),
+                        Message::Prune => {
+                            tree.handle_prune(from);
+                            Vec::new()
+                        }
+                        _ => Vec::new(),
+                    }
+                });
+        }
+
+        fn broadcast(&mut self, origin: usize, now: Instant, rng: &mut StdRng) -> Hash {
+            self.copies = 0;
+            let peers = self.network.topology.peers(origin);
+            let (id, outgoing) = self.network.nodes[origin].broadcast(
+                &Hash::random(),
+                b"block".to_vec(),
+                &peers,
+                now,
+                rng,
+            );
+            let _ = self.delivered[origin].insert(id);
+            self.run(origin, outgoing, now);
+            id
+        }
+
+        /// Let the graft timers of every live node run out.
+        fn graft(&mut self, now: Instant) {
+            for node in 0..self.network.nodes.len() {
+                if self.down.contains(&node) {
+                    continue;
+                }
+                let outgoing = self.network.nodes[node].graft_missing(now);
+                self.run(node, outgoing, now);
+            }
+        }
+
+        fn reached(&self, id: &Hash) -> usize {
+            (0..self.network.nodes.len())
+                .filter(|node| !self.down.contains(node) && self.delivered[*node].contains(id))
+                .count()
+        }
+    }
+
+    #[test]
+    fn test_tree_converges_to_one_copy_per_node() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut simulation = Simulation::new(100, 6, &mut rng);
+        let now = Instant::now();
+
+        // The first broadcast floods the network, and duplicates prune the tree.
+        let first = simulation.broadcast(0, now, &mut rng);
+        assert_eq!(simulation.reached(&first), 100);
+        let flood = simulation.copies;
+        assert!(flood > 300);
+
+        // Later broadcasts, from any node, follow the tree.
+        for origin in [0, 42, 99] {
+            let id = simulation.broadcast(origin, now, &mut rng);
+            assert_eq!(simulation.reached(&id), 100);
+            assert!(simulation.copies < 130);
+        }
+        let links = (0..100)
+            .map(|node| simulation.network.topology.links[node].len())
+            .sum::<usize>();
+        let eager = (0..100)
+            .map(|node| simulation.network.nodes[node].eager_peers().count())
+            .sum::<usize>();
+        assert!(eager < links / 2);
+    }
+
+    #[test]
+    fn test_graft_repairs_tree() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut simulation = Simulation::new(100, 6, &mut rng);
+        let now = Instant::now();
+        let _ = simulation.broadcast(0, now, &mut rng);
+        let _ = simulation.broadcast(0, now, &mut rng);
+
+        // Silently losing interior nodes of the tree cuts off their subtrees,
+        // which only hear about the broadcast through lazy announcements.
+        let interior = (1..100)
+            .filter(|node| simulation.network.nodes[*node].eager_peers().count() > 2)
+            .take(5)
+            .collect::<Vec<_>>();
+        simulation.down.extend(interior);
+        let id = simulation.broadcast(0, now, &mut rng);
+        let live = 100 - simulation.down.len();
+        assert!(simulation.reached(&id) < live);
+
+        let mut clock = now;
+        for _ in 0..5 {
+            clock += GossipConfig::default().request_timeout + Duration::from_secs(1);
+            simulation.graft(clock);
+        }
+        assert_eq!(simulation.reached(&id), live);
+
+        // The repaired tree delivers the next broadcast without grafting.
+        let id = simulation.broadcast(0, clock, &mut rng);
+        assert_eq!(simulation.reached(&id), live);
+    }
+
+    #[test]
+    fn test_graft_serves_missed_broadcast() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let a = SocketAddr::from(([10, 0, 0, 1], 9000));
+        let b = SocketAddr::from(([10, 0, 0, 2], 9000));
+        let now = Instant::now();
+        let mut sender = Plumtree::default();
+        sender.handle_prune(&b);
+        let (id, outgoing) =
+            sender.broadcast(&Hash::random(), b"block".to_vec(), &[&b], now, &mut rng);
+        assert!(matches!(
+            outgoing.as_slice(),
+            [(to, Message::IHave(ids))] if *to == b && *ids == vec![id]
+        ));
+
+        let mut receiver = Plumtree::default();
+        receiver.handle_ihave(&a, vec![id], now);
+        assert!(receiver.graft_missing(now).is_empty());
+        let later = now + GossipConfig::default().request_timeout;
+        let grafts = receiver.graft_missing(later);
+        assert!(matches!(
+            grafts.as_slice(),
+            [(to, Message::Graft(ids))] if *to == a && *ids == vec![id]
+        ));
+        assert!(receiver.eager_peers().any(|peer| *peer == a));
+
+        let served = sender.handle_graft(&b, vec![id], later);
+        assert_eq!(served.len(), 1);
+        assert!(sender.eager_peers().any(|peer| *peer == b));
+
+        // Grafts from nodes which are not peers are ignored.
+        let stranger = SocketAddr::from(([10, 0, 0, 3], 9000));
+        assert!(sender.handle_graft(&stranger, vec![id], later).is_empty());
+        assert!(!sender.eager_peers().any(|peer| *peer == stranger));
+    }
+
+    #[test]
+    fn test_ihave_limits() {
+        let now = Instant::now();
+        let (flooder, other) = (
+            SocketAddr::from(([10, 0, 0, 1], 9000)),
+            SocketAddr::from(([10, 0, 0, 2], 9000)),
+        );
+        let ids = (0..MAX_INV_ITEMS + 10)
+            .map(|_| Hash::random())
+            .collect::<Vec<_>>();
+        let mut tree = Plumtree::default();
+        tree.handle_ihave(&flooder, ids.clone(), now);
+        tree.handle_ihave(&flooder, vec![Hash::random()], now);
+        tree.handle_ihave(&other, ids[..1].to_vec(), now);
+
+        // Only the first announcements of the peer are kept to graft from.
+        let later = now + GossipConfig::default().request_timeout;
+        let grafted = tree
+            .graft_missing(later)
+            .into_iter()
+            .flat_map(|(_, message)| match message {
+                Message::Graft(ids) => ids,
+                _ => Vec::new(),
+            })
+            .collect::<HashSet<_>>();
+        assert_eq!(
+            grafted,
+            ids[..MAX_REQUESTS_PER_PEER].iter().copied().collect()
+        );
+    }
+}
@@ -0,0 +1,114 @@
+use super::inventory::ObjectKind;
+use crate::crypto::hash::Hash;
+use std::marker::PhantomData;
+
+/// A block or transaction of the application, as relayed by nodes
+pub trait BroadcastObject: Sized {
+    /// Decodes an object received from a peer; `None` if the bytes are not one
+    fn decode(bytes: &[u8]) -> Option<Self>;
+
+    /// ID of the object
+    fn id(&self) -> Hash;
+
+    /// Size of the object, in bytes
+    fn size(&self) -> usize;
+
+    /// Checks which can be made without any state, such as the format, limits and
+    /// signatures, and are cheap enough to run before relaying the object
+    fn cheap_validate(&self) -> bool;
+}
+
+/// Hook the application registers on a node to check objects before they are relayed.
+/// Objects failing it are dropped and their sender is penalised.
+pub trait Validator: Send + Sync {
+    /// Checks if an object received from a peer is worth relaying
+    fn validate(&self, kind: ObjectKind, payload: &[u8]) -> bool;
+}
+
+/// Validator decoding blocks as `B` and transactions as `T`, rejecting those
+/// over a size limit or failing their cheap checks
+#[derive(Debug, Clone, Copy)]
+pub struct ObjectValidator<B, T> {
+    max_block_size: usize,
+    max_transaction_size: usize,
+    objects: PhantomData<fn() -> (B, T)>,
+}
+
+impl<B: BroadcastObject, T: BroadcastObject> ObjectValidator<B, T> {
+    /// Creates a new `ObjectValidator` with the given size limits, in bytes.
+    pub fn new(max_block_size: usize, max_transaction_size: usize) -> Self {
+        Self {
+            max_block_size,
+            max_transaction_size,
+            objects: PhantomData,
+        }
+    }
+}
+
+impl<B: BroadcastObject, T: BroadcastObject> Validator for ObjectValidator<B, T> {
+    fn validate(&self, kind: ObjectKind, payload: &[u8]) -> bool {
+        match kind {
+            ObjectKind::Block => check::<B>(payload, self.max_block_size),
+            ObjectKind::Transaction => check::<T>(payload, self.max_transaction_size),
+        }
+    }
+}
+
+fn check<O: BroadcastObject>(payload: &[u8], max_size: usize) -> bool {
+    match O::decode(payload) {
+        Some(object) if object.size() > max_size => {
+            log::debug!("Object {:?} is over {} bytes", object.id(), max_size);
+            false
+        }
+        Some(object) => object.cheap_validate(),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Transaction moving `amount` out of an account, valid if the amount is not zero
+    struct Transfer {
+        amount: u64,
+        memo: Vec<u8>,
+    }
+
+    impl BroadcastObject for Transfer {
+        fn decode(bytes: &[u8]) -> Option<Self> {
+            let (amount, memo) = bytes.split_first_chunk::<8>()?;
+            Some(Self {
+                amount: u64::from_be_bytes(*amount),
+                memo: memo.to_vec(),
+            })
+        }
+
+        fn id(&self) -> Hash {
+            Hash::from_byte_arrays(&[&self.amount.to_be_bytes(), &self.memo])
+        }
+
+        fn size(&self) -> usize {
+            8 + self.memo.len()
+        }
+
+        fn cheap_validate(&self) -> bool {
+            self.amount > 0
+        }
+    }
+
+    fn transfer(amount: u64, memo: &[u8]) -> Vec<u8> {
+        [&amount.to_be_bytes()[..], memo].concat()
+    }
+
+    #[test]
+    fn test_object_validator() {
+        let validator = ObjectValidator::<Transfer, Transfer>::new(1024, 16);
+        let check = |kind, payload: Vec<u8>| validator.validate(kind, &payload);
+        assert!(check(ObjectKind::Transaction, transfer(5, b"rent")));
+        assert!(!check(ObjectKind::Transaction, transfer(0, b"rent")));
+        assert!(!check(ObjectKind::Transaction, b"short".to_vec()));
+        assert!(!check(ObjectKind::Transaction, transfer(5, &[0; 9])));
+        assert!(check(ObjectKind::Block, transfer(5, &[0; 9])));
+    }
+}
@@ -6,6 +6,8 @@ use crate::{
     },
     PublicId, Result,
 };
+use ed25519_dalek::ExpandedSecretKey;
+use rand::{thread_rng, Rng};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 /// The various public keys belonging to a node
@@ -30,8 +32,23 @@ impl Default for Identity {
 
 impl Identity {
     /// Creates a new random `Identity`.
+    /// Its node ID is derived from its signing key.
     pub fn new() -> Self {
-        todo!()
+        let mut rng = thread_rng();
+        let secret_key = PrivateKey::random();
+        let encryption_secret_key = EncryptionSecretKey::from(rng.gen::<[u8; 32]>());
+        let signing_secret_key = SigningSecretKey::from_bytes(&rng.gen::<[u8; 32]>())
+            .expect("Failed to create an `ed25519_dalek` secret key");
+        let signing_public_key = SigningPublicKey::from(&signing_secret_key);
+        Self {
+            node_id: PublicId::derive_node_id(&signing_public_key),
+            public_key: secret_key.public_key(),
+            secret_key,
+            encryption_public_key: EncryptionPublicKey::from(&encryption_secret_key),
+            encryption_secret_key,
+            signing_secret_key,
+            signing_public_key,
+        }
     }
 
     /// Verify that a message was sent from peer to `Self`,
@@ -50,6 +67,11 @@ impl Identity {
         todo!()
     }
 
+    /// Sign a given message with the Ed25519 signing key
+    pub fn sign_with_signing_key(&self, msg: &[u8]) -> ed25519_dalek::Signature {
+        ExpandedSecretKey::from(&self.signing_secret_key).sign(msg, &self.signing_public_key)
+    }
+
     /// Verify a message's signature
     pub fn verify_signature(&self, _msg: &[u8], _sig: &Signature) -> Result<()> {
         todo!()
@@ -14,6 +14,19 @@ pub struct PublicId {
     pub signing_public_key: SigningPublicKey,
 }
 
+impl PublicId {
+    /// Node ID of the holder of a signing key
+    pub fn derive_node_id(signing_public_key: &SigningPublicKey) -> Hash {
+        Hash::from_bytes(signing_public_key.as_bytes())
+    }
+
+    /// Checks that the node ID is derived from the signing key,
+    /// so it cannot be claimed by a node holding another key.
+    pub fn has_valid_node_id(&self) -> bool {
+        self.node_id == Self::derive_node_id(&self.signing_public_key)
+    }
+}
+
 impl Serialize for PublicId {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -40,16 +40,27 @@ pub mod config;
 pub mod connection;
 /// Cryptographic primitives
 pub mod crypto;
+/// Kademlia peer discovery
+pub mod dht;
 /// Implements error types
 pub mod error;
 /// Node and network-related events
 pub mod event;
+/// Epidemic broadcast
+pub mod gossip;
 /// Identity of a node
 pub mod identity;
+/// Unconfirmed transactions
+pub mod mempool;
 /// Messaging protocol
 pub mod messaging;
 /// Functionality of a node on the network
 pub mod node;
+/// Saving state to disk
+mod persistence;
+/// Fixtures shared by the tests of several modules
+#[cfg(test)]
+mod test_utils;
 
 pub use config::Config;
 pub use connection::{
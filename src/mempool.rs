@@ -0,0 +1,325 @@
+use crate::{crypto::hash::Hash, gossip::compact::Block, messaging::seen_cache::SeenCache};
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+/// Default total size of the transactions held, in bytes
+pub const DEFAULT_MEMPOOL_SIZE: usize = 64 * 1024 * 1024;
+/// Default time after which an unconfirmed transaction is dropped
+pub const DEFAULT_MEMPOOL_EXPIRY: Duration = Duration::from_secs(60 * 60);
+
+/// Number of confirmed transaction IDs remembered, so they are not taken in again
+const CONFIRMED_CAPACITY: usize = 64 * 1024;
+
+/// Hook the application registers on a node to rank transactions, such as by fee rate.
+/// Transactions of the lowest priority are evicted first.
+pub trait Prioritiser: Send + Sync {
+    /// Priority of a transaction
+    fn priority(&self, transaction: &[u8]) -> u64;
+}
+
+impl<F: Fn(&[u8]) -> u64 + Send + Sync> Prioritiser for F {
+    fn priority(&self, transaction: &[u8]) -> u64 {
+        self(transaction)
+    }
+}
+
+/// Limits of the mempool
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MempoolConfig {
+    /// Total size of the transactions held, in bytes
+    pub max_size: usize,
+    /// Time after which an unconfirmed transaction is dropped
+    pub expiry: Duration,
+}
+
+impl Default for MempoolConfig {
+    fn default() -> Self {
+        Self {
+            max_size: DEFAULT_MEMPOOL_SIZE,
+            expiry: DEFAULT_MEMPOOL_EXPIRY,
+        }
+    }
+}
+
+/// Outcome of offering a transaction to the mempool
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Admission {
+    /// The transaction was added, evicting the given ones to make room
+    Added(Vec<Hash>),
+    /// The transaction is already held, or was confirmed
+    Known,
+    /// The transaction does not fit, even by evicting those of lower priority
+    Rejected,
+}
+
+/// A transaction held, along with its priority
+#[derive(Debug, Clone)]
+struct Entry {
+    transaction: Vec<u8>,
+    priority: u64,
+    sequence: u64,
+    added_at: Instant,
+}
+
+/// Unconfirmed transactions, keyed by the hash of their content.
+/// When full, transactions of the lowest priority are evicted first, and among
+/// those the oldest. The priority, such as a fee rate, is given by the application.
+#[derive(Debug, Clone)]
+pub struct Mempool {
+    config: MempoolConfig,
+    entries: HashMap<Hash, Entry>,
+    by_priority: BTreeMap<(u64, u64), Hash>,
+    by_age: BTreeMap<(Instant, u64), Hash>,
+    size: usize,
+    sequence: u64,
+    confirmed: SeenCache,
+}
+
+impl Mempool {
+    /// Creates a new, empty `Mempool`.
+    pub fn new(config: MempoolConfig) -> Self {
+        Self {
+            config,
+            entries: Default::default(),
+            by_priority: Default::default(),
+            by_age: Default::default(),
+            size: 0,
+            sequence: 0,
+            confirmed: SeenCache::new(CONFIRMED_CAPACITY, config.expiry),
+        }
+    }
+
+    /// Number of transactions held
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Checks if no transaction is held
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Total size of the transactions held, in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Checks if a transaction is held
+    pub fn contains(&self, id: &Hash) -> bool {
+        self.entries.contains_key(id)
+    }
+
+    /// Retrieves a transaction
+    pub fn get(&self, id: &Hash) -> Option<&Vec<u8>> {
+        self.entries.get(id).map(|entry| &entry.transaction)
+    }
+
+    /// Transactions held, along with their ID
+    pub fn iter(&self) -> impl Iterator<Item = (&Hash, &Vec<u8>)> + Clone {
+        self.entries
+            .iter()
+            .map(|(id, entry)| (id, &entry.transaction))
+    }
+
+    /// Offer a transaction, evicting transactions of lower priority if it does not fit.
+    pub fn insert(&mut self, transaction: Vec<u8>, priority: u64, now: Instant) -> Admission {
+        let _ = self.expire(now);
+        let id = Hash::from_bytes(&transaction);
+        if self.entries.contains_key(&id) || self.confirmed.contains(&id, now) {
+            return Admission::Known;
+        }
+        if transaction.len() > self.config.max_size {
+            return Admission::Rejected;
+        }
+
+        // Pick the transactions to evict before evicting any, so a rejected
+        // transaction leaves the mempool untouched.
+        let room = self.config.max_size - transaction.len();
+        let mut size = self.size;
+        let mut evicted = Vec::new();
+        for ((lowest, _), id) in &self.by_priority {
+            if size <= room || *lowest >= priority {
+                break;
+            }
+            size -= self
+                .entries
+                .get(id)
+                .map_or(0, |entry| entry.transaction.len());
+            evicted.push(*id);
+        }
+        if size > room {
+            return Admission::Rejected;
+        }
+        for id in &evicted {
+            let _ = self.remove(id);
+        }
+
+        self.sequence += 1;
+        self.size += transaction.len();
+        let _ = self.by_priority.insert((priority, self.sequence), id);
+        let _ = self.by_age.insert((now, self.sequence), id);
+        let _ = self.entries.insert(
+            id,
+            Entry {
+                transaction,
+                priority,
+                sequence: self.sequence,
+                added_at: now,
+            },
+        );
+        Admission::Added(evicted)
+    }
+
+    /// Remove a transaction
+    pub fn remove(&mut self, id: &Hash) -> Option<Vec<u8>> {
+        let entry = self.entries.remove(id)?;
+        let _ = self.by_priority.remove(&(entry.priority, entry.sequence));
+        let _ = self.by_age.remove(&(entry.added_at, entry.sequence));
+        self.size -= entry.transaction.len();
+        Some(entry.transaction)
+    }
+
+    /// Remove the transactions of an announced block, and remember them as confirmed
+    /// so they are not taken in again. Returns the number of transactions removed.
+    pub fn remove_block(&mut self, block: &Block, now: Instant) -> usize {
+        block
+            .transactions
+            .iter()
+            .map(|transaction| Hash::from_bytes(transaction))
+            .filter(|id| {
+                let _ = self.confirmed.insert(*id, now);
+                self.remove(id).is_some()
+            })
+            .count()
+    }
+
+    /// Drop the transactions held for longer than the expiry time.
+    /// Returns their IDs.
+    pub fn expire(&mut self, now: Instant) -> Vec<Hash> {
+        let mut expired = Vec::new();
+        while let Some((&(added_at, _), &id)) = self.by_age.first_key_value() {
+            if now.saturating_duration_since(added_at) <= self.config.expiry {
+                break;
+            }
+            let _ = self.remove(&id);
+            expired.push(id);
+        }
+        expired
+    }
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::new(MempoolConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transaction(tag: u8, size: usize) -> Vec<u8> {
+        let mut transaction = vec![tag; size];
+        transaction[0] = tag;
+        transaction
+    }
+
+    #[test]
+    fn test_priority_eviction() {
+        let now = Instant::now();
+        let mut mempool = Mempool::new(MempoolConfig {
+            max_size: 300,
+            expiry: DEFAULT_MEMPOOL_EXPIRY,
+        });
+        let (cheap, old, rich) = (
+            transaction(1, 100),
+            transaction(2, 100),
+            transaction(3, 100),
+        );
+        assert_eq!(
+            mempool.insert(old.clone(), 5, now),
+            Admission::Added(Vec::new())
+        );
+        assert_eq!(
+            mempool.insert(cheap.clone(), 1, now),
+            Admission::Added(Vec::new())
+        );
+        assert_eq!(
+            mempool.insert(rich.clone(), 9, now),
+            Admission::Added(Vec::new())
+        );
+        assert_eq!(mempool.insert(rich, 9, now), Admission::Known);
+        assert_eq!(mempool.size(), 300);
+
+        // A transaction which does not fit evicts those of lower priority, lowest first,
+        // and the oldest among equals.
+        let newer = transaction(4, 100);
+        let evicted = mempool.insert(newer.clone(), 5, now);
+        assert_eq!(evicted, Admission::Added(vec![Hash::from_bytes(&cheap)]));
+        let large = transaction(5, 200);
+        let evicted = mempool.insert(large.clone(), 6, now);
+        let expected = vec![Hash::from_bytes(&old), Hash::from_bytes(&newer)];
+        assert_eq!(evicted, Admission::Added(expected));
+        assert_eq!((mempool.len(), mempool.size()), (2, 300));
+
+        // One which would need to evict a transaction of higher priority is rejected,
+        // leaving the mempool untouched.
+        assert_eq!(
+            mempool.insert(transaction(6, 200), 5, now),
+            Admission::Rejected
+        );
+        assert_eq!(
+            mempool.insert(transaction(7, 301), 99, now),
+            Admission::Rejected
+        );
+        assert!(mempool.contains(&Hash::from_bytes(&large)));
+        assert_eq!((mempool.len(), mempool.size()), (2, 300));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let now = Instant::now();
+        let mut mempool = Mempool::default();
+        let first = transaction(1, 10);
+        let _ = mempool.insert(first.clone(), 0, now);
+        let later = now + DEFAULT_MEMPOOL_EXPIRY / 2;
+        let _ = mempool.insert(transaction(2, 10), 0, later);
+        let expired = mempool.expire(now + DEFAULT_MEMPOOL_EXPIRY + Duration::from_secs(1));
+        assert_eq!(expired, vec![Hash::from_bytes(&first)]);
+        assert_eq!((mempool.len(), mempool.size()), (1, 10));
+    }
+
+    #[test]
+    fn test_removal_on_block() {
+        let now = Instant::now();
+        let mut mempool = Mempool::default();
+        let transactions = (0..5).map(|tag| transaction(tag, 10)).collect::<Vec<_>>();
+        for transaction in &transactions {
+            let _ = mempool.insert(transaction.clone(), 0, now);
+        }
+        let block = Block {
+            header: b"header".to_vec(),
+            transactions: vec![
+                transactions[1].clone(),
+                transactions[3].clone(),
+                transaction(9, 10),
+            ],
+        };
+        assert_eq!(mempool.remove_block(&block, now), 2);
+        assert_eq!(mempool.len(), 3);
+        assert!(mempool.get(&Hash::from_bytes(&transactions[1])).is_none());
+        assert_eq!(
+            mempool.get(&Hash::from_bytes(&transactions[0])),
+            Some(&transactions[0])
+        );
+
+        // Confirmed transactions are not taken in again, even if never held.
+        assert_eq!(
+            mempool.insert(transactions[3].clone(), 0, now),
+            Admission::Known
+        );
+        assert_eq!(mempool.insert(transaction(9, 10), 0, now), Admission::Known);
+    }
+}
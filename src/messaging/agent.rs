@@ -0,0 +1,272 @@
+use super::envelope::Envelope;
+use crate::{crypto::hash::Hash, PublicId};
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+
+/// Default number of hops an agent makes before its messages expire
+pub const DEFAULT_AGENT_TTL: u32 = 32;
+
+/// Size of the visited-node filter, in bytes
+const VISITED_FILTER_BYTES: usize = 256;
+/// Number of bits set in the visited-node filter for each node
+const VISITED_FILTER_HASHES: usize = 4;
+
+/// Bloom filter of the nodes an agent visited.
+/// May claim a node was visited when it was not, but never the opposite.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VisitedFilter {
+    bits: Vec<u8>,
+}
+
+impl VisitedFilter {
+    /// Creates a new, empty `VisitedFilter`.
+    pub fn new() -> Self {
+        Self {
+            bits: vec![0; VISITED_FILTER_BYTES],
+        }
+    }
+
+    /// Positions of the bits standing for a node.
+    /// Node IDs are hashes already, so their bytes are used directly.
+    fn positions(node_id: &Hash) -> impl Iterator<Item = usize> + '_ {
+        node_id
+            .as_ref()
+            .chunks_exact(2)
+            .take(VISITED_FILTER_HASHES)
+            .map(|pair| {
+                usize::from(u16::from_be_bytes([pair[0], pair[1]])) % (VISITED_FILTER_BYTES * 8)
+            })
+    }
+
+    /// Record that a node was visited.
+    pub fn insert(&mut self, node_id: &Hash) {
+        for position in Self::positions(node_id) {
+            if let Some(byte) = self.bits.get_mut(position / 8) {
+                *byte |= 1 << (position % 8);
+            }
+        }
+    }
+
+    /// Checks if a node was probably visited
+    pub fn contains(&self, node_id: &Hash) -> bool {
+        Self::positions(node_id).all(|position| {
+            self.bits
+                .get(position / 8)
+                .is_some_and(|byte| byte & (1 << (position % 8)) != 0)
+        })
+    }
+}
+
+impl Default for VisitedFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Messages carried from node to node by a random walk
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Agent {
+    /// Each message along with its recipient
+    pub payload: Vec<(PublicId, Envelope)>,
+    /// Hops left and nodes visited
+    pub walk: Walk,
+}
+
+impl Agent {
+    /// Creates a new `Agent` carrying no message yet.
+    pub fn new(walk: Walk) -> Self {
+        Self {
+            payload: Vec::new(),
+            walk,
+        }
+    }
+}
+
+/// State of the random walk of an agent
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Walk {
+    /// Random identifier of the walk, telling a walk coming back apart from another one
+    pub id: Hash,
+    /// Number of hops left before the messages of the agent expire
+    pub ttl: u32,
+    /// Nodes the agent went through
+    pub visited: VisitedFilter,
+}
+
+impl Walk {
+    /// Creates a new `Walk` of at most `ttl` hops, starting from `origin`.
+    pub fn new(ttl: u32, origin: &Hash) -> Self {
+        let mut visited = VisitedFilter::new();
+        visited.insert(origin);
+        Self {
+            id: Hash::random(),
+            ttl,
+            visited,
+        }
+    }
+
+    /// Record that the agent reached a node.
+    pub fn visit(&mut self, node_id: &Hash) {
+        self.visited.insert(node_id);
+    }
+
+    /// Checks if the agent has no hop left
+    pub fn is_expired(&self) -> bool {
+        self.ttl == 0
+    }
+
+    /// Pick the peer the agent goes to next, and use up a hop.
+    /// Peers the agent did not visit are preferred; when it visited all of them,
+    /// any peer will do. Peers which did not identify themselves count as not visited.
+    pub fn next_hop<'a, R: Rng>(
+        &mut self,
+        peers: &[(&'a SocketAddr, Option<Hash>)],
+        rng: &mut R,
+    ) -> Option<&'a SocketAddr> {
+        let unvisited = peers
+            .iter()
+            .filter(|(_, node_id)| !node_id.is_some_and(|node_id| self.visited.contains(&node_id)))
+            .collect::<Vec<_>>();
+        let next = match unvisited.choose(rng) {
+            Some((addr, _)) => Some(*addr),
+            None => peers.choose(rng).map(|(addr, _)| *addr),
+        };
+        self.ttl = self.ttl.saturating_sub(1);
+        next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{messaging::Messaging, test_utils::Network, Event, Identity};
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::time::Instant;
+
+    /// Send a message from `source` to `target`, and pass the agent carrying it
+    /// from node to node until it is delivered or runs out of hops.
+    fn delivers(
+        network: &mut Network<(Identity, Messaging)>,
+        source: usize,
+        target: usize,
+        ttl: u32,
+        biased: bool,
+        rng: &mut StdRng,
+    ) -> bool {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let now = Instant::now();
+        let message = rng.gen::<u64>().to_be_bytes().to_vec();
+        let recipient = network.nodes[target].0.public_id();
+        let (identity, messaging) = &mut network.nodes[source];
+        if messaging
+            .send_message(identity, &recipient, &message)
+            .is_err()
+        {
+            return false;
+        }
+        let origin = identity.public_id().node_id;
+        let mut agent = Agent::new(Walk::new(ttl, &origin));
+        let mut node = source;
+        loop {
+            let mut boarded = network.nodes[node].1.board(agent, now);
+            let peers = network.topology.links[node]
+                .iter()
+                .map(|addr| {
+                    let node_id = network.nodes[network.topology.index(addr)]
+                        .0
+                        .public_id()
+                        .node_id;
+                    (addr, biased.then_some(node_id))
+                })
+                .collect::<Vec<_>>();
+            let from = network.topology.addrs[node];
+            node = match boarded.walk.next_hop(&peers, rng) {
+                Some(addr) => network.topology.index(addr),
+                None => return false,
+            };
+            let (identity, messaging) = &mut network.nodes[node];
+            let received = messaging.receive_agent(identity, &from, boarded, now, &tx);
+            let delivered = rx
+                .try_iter()
+                .any(|event| matches!(event, Event::NewMessage(content) if content == message));
+            match received {
+                _ if delivered => return true,
+                Ok(Some(next)) => agent = next,
+                _ => return false,
+            }
+        }
+    }
+
+    fn delivery_rate(
+        network: &mut Network<(Identity, Messaging)>,
+        ttl: u32,
+        biased: bool,
+        trials: usize,
+        rng: &mut StdRng,
+    ) -> f64 {
+        let size = network.nodes.len();
+        let delivered = (0..trials)
+            .filter(|_| {
+                let source = rng.gen_range(0..size);
+                let target = (source + rng.gen_range(1..size)) % size;
+                delivers(network, source, target, ttl, biased, rng)
+            })
+            .count();
+        delivered as f64 / trials as f64
+    }
+
+    #[test]
+    fn test_visited_filter() {
+        let mut filter = VisitedFilter::new();
+        let visited = (0..64).map(|_| Hash::random()).collect::<Vec<_>>();
+        for node_id in &visited {
+            filter.insert(node_id);
+        }
+        assert!(visited.iter().all(|node_id| filter.contains(node_id)));
+        let false_positives = (0..1000)
+            .filter(|_| filter.contains(&Hash::random()))
+            .count();
+        assert!(false_positives < 10);
+    }
+
+    #[test]
+    fn test_walk_prefers_unvisited_peers() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let visited = SocketAddr::from(([10, 0, 0, 1], 9000));
+        let fresh = SocketAddr::from(([10, 0, 0, 2], 9000));
+        let visited_id = Hash::random();
+        let mut walk = Walk::new(10, &visited_id);
+        let peers = [(&visited, Some(visited_id)), (&fresh, Some(Hash::random()))];
+        for ttl in (0..10).rev() {
+            assert_eq!(walk.next_hop(&peers, &mut rng), Some(&fresh));
+            assert_eq!(walk.ttl, ttl);
+        }
+        assert!(walk.is_expired());
+        assert_eq!(walk.next_hop(&peers[..1], &mut rng), Some(&visited));
+    }
+
+    #[test]
+    fn test_delivery_rate_against_ttl() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut network = Network::new(200, 4, &mut rng, |_| {
+            let mut messaging = Messaging::new();
+            messaging.set_agent_ttl(1024);
+            (Identity::new(), messaging)
+        });
+        let trials = 500;
+        let rates = [4, 16, 64, 256, 1024]
+            .iter()
+            .map(|ttl| delivery_rate(&mut network, *ttl, true, trials, &mut rng))
+            .collect::<Vec<_>>();
+
+        // A longer walk reaches more nodes.
+        assert!(rates.windows(2).all(|pair| pair[0] <= pair[1] + 0.05));
+        assert!(rates[0] < 0.1);
+        assert!(rates[4] > 0.99);
+
+        // Avoiding visited nodes reaches the target in fewer hops than a blind walk.
+        let blind = delivery_rate(&mut network, 64, false, trials, &mut rng);
+        assert!(rates[2] > blind);
+    }
+}
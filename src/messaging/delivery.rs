@@ -0,0 +1,253 @@
+use super::envelope::Envelope;
+use crate::{connection::reconnect::Backoff, crypto::hash::Hash, PublicId, Result};
+use ed25519_dalek::{Signature, Verifier};
+use rand::Rng;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Default delay before a message without receipt is sent again
+pub const DEFAULT_RETRANSMIT_DELAY: Duration = Duration::from_secs(2);
+/// Default upper bound on the delay between two retransmissions
+pub const DEFAULT_MAX_RETRANSMIT_DELAY: Duration = Duration::from_secs(60);
+/// Default number of retransmissions before a message is reported as unsent
+pub const DEFAULT_MAX_RETRANSMITS: u32 = 5;
+
+/// Bytes the recipient of a message signs to acknowledge it
+pub fn receipt_bytes(id: &Hash) -> Result<Vec<u8>> {
+    Ok(bincode::serialize(&("receipt", id))?)
+}
+
+/// Checks that a receipt for message `id` was signed by `signer`
+pub fn verify_receipt(signer: &PublicId, id: &Hash, signature: &[u8]) -> bool {
+    let signed_bytes = match receipt_bytes(id) {
+        Ok(bytes) => bytes,
+        Err(_) => return false,
+    };
+    Signature::from_bytes(signature)
+        .map(|signature| {
+            signer
+                .signing_public_key
+                .verify(&signed_bytes, &signature)
+                .is_ok()
+        })
+        .unwrap_or(false)
+}
+
+/// A message waiting for its delivery receipt
+#[derive(Debug, Clone)]
+struct Unacknowledged {
+    token: u64,
+    dst: PublicId,
+    message: Vec<u8>,
+    envelope: Envelope,
+    next_attempt: Instant,
+}
+
+/// Action to take for a message whose receipt is overdue
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Retransmission {
+    /// Send the envelope to the recipient again
+    Resend(Box<PublicId>, Box<Envelope>),
+    /// Give up on the message
+    GaveUp {
+        /// Token returned when the message was sent
+        token: u64,
+        /// Payload of the message
+        message: Vec<u8>,
+    },
+}
+
+/// Messages sent with a request for a delivery receipt, and their retransmissions.
+#[derive(Debug, Clone)]
+pub struct DeliveryTracker {
+    backoff: Backoff,
+    unacknowledged: HashMap<Hash, Unacknowledged>,
+}
+
+impl DeliveryTracker {
+    /// Creates a new `DeliveryTracker`.
+    pub fn new(backoff: Backoff) -> Self {
+        Self {
+            backoff,
+            unacknowledged: Default::default(),
+        }
+    }
+
+    /// Number of retransmissions of a message at most
+    pub fn max_attempts(&self) -> u32 {
+        self.backoff.max_attempts
+    }
+
+    /// Wait for the receipt of a message which was just sent.
+    pub fn track<R: Rng>(
+        &mut self,
+        token: u64,
+        dst: PublicId,
+        message: Vec<u8>,
+        envelope: Envelope,
+        now: Instant,
+        rng: &mut R,
+    ) {
+        let _ = self.unacknowledged.insert(
+            envelope.id,
+            Unacknowledged {
+                token,
+                dst,
+                message,
+                next_attempt: now + self.backoff.delay(0, rng),
+                envelope,
+            },
+        );
+    }
+
+    /// Handle a receipt.
+    /// Returns the recipient and the payload of the message if the receipt
+    /// is for a message we wait for, and signed by its recipient.
+    pub fn acknowledge(&mut self, id: &Hash, signature: &[u8]) -> Option<(PublicId, Vec<u8>)> {
+        let dst = self.unacknowledged.get(id)?.dst;
+        if !verify_receipt(&dst, id, signature) {
+            log::warn!("Dropping a forged receipt for message {:?}", id);
+            return None;
+        }
+        self.unacknowledged
+            .remove(id)
+            .map(|entry| (entry.dst, entry.message))
+    }
+
+    /// Retrieves the messages whose receipt is overdue, and schedules the following attempts.
+    /// Messages which used up their retransmissions are given up on.
+    pub fn due<R: Rng>(&mut self, now: Instant, rng: &mut R) -> Vec<Retransmission> {
+        let mut due = Vec::new();
+        let mut gave_up = Vec::new();
+        for (id, entry) in &mut self.unacknowledged {
+            if entry.next_attempt > now {
+                continue;
+            }
+            if entry.envelope.attempt >= self.backoff.max_attempts {
+                gave_up.push(*id);
+                continue;
+            }
+            entry.envelope.attempt += 1;
+            entry.next_attempt = now + self.backoff.delay(entry.envelope.attempt, rng);
+            due.push(Retransmission::Resend(
+                Box::new(entry.dst),
+                Box::new(entry.envelope.clone()),
+            ));
+        }
+        for id in gave_up {
+            if let Some(entry) = self.unacknowledged.remove(&id) {
+                due.push(Retransmission::GaveUp {
+                    token: entry.token,
+                    message: entry.message,
+                });
+            }
+        }
+        due
+    }
+
+    /// Number of messages waiting for their receipt
+    pub fn len(&self) -> usize {
+        self.unacknowledged.len()
+    }
+
+    /// Checks if no message is waiting for its receipt
+    pub fn is_empty(&self) -> bool {
+        self.unacknowledged.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        crypto::{SigningPublicKey, SigningSecretKey},
+        test_utils::connection_info,
+    };
+    use ed25519_dalek::ExpandedSecretKey;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn backoff() -> Backoff {
+        Backoff {
+            base_delay: Duration::from_secs(2),
+            max_delay: Duration::from_secs(8),
+            max_attempts: 2,
+        }
+    }
+
+    #[test]
+    fn test_retransmits_then_gives_up() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let dst = connection_info(Hash::random(), 9000).public_id;
+        let envelope = Envelope::new(Hash::random(), 1, b"hello".to_vec());
+        let start = Instant::now();
+        let mut tracker = DeliveryTracker::new(backoff());
+        tracker.track(1, dst, b"hello".to_vec(), envelope.clone(), start, &mut rng);
+        assert!(tracker.due(start, &mut rng).is_empty());
+
+        let mut clock = start;
+        let mut outcomes = Vec::new();
+        while !tracker.is_empty() {
+            clock += Duration::from_millis(500);
+            outcomes.extend(tracker.due(clock, &mut rng));
+        }
+        let resent = outcomes
+            .iter()
+            .filter_map(|outcome| match outcome {
+                Retransmission::Resend(to, resent) if **to == dst => Some(*resent.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            resent
+                .iter()
+                .map(|resent| resent.attempt)
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert!(resent.iter().all(|resent| resent.id == envelope.id));
+        assert_eq!(
+            outcomes.last(),
+            Some(&Retransmission::GaveUp {
+                token: 1,
+                message: b"hello".to_vec(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_signed_receipt() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let secret =
+            SigningSecretKey::from_bytes(&[7; 32]).expect("Failed to create a signing key");
+        let mut dst = connection_info(Hash::random(), 9000).public_id;
+        dst.signing_public_key = SigningPublicKey::from(&secret);
+        let envelope = Envelope::new(Hash::random(), 1, b"hello".to_vec());
+        let id = envelope.id;
+        let mut tracker = DeliveryTracker::new(backoff());
+        tracker.track(
+            1,
+            dst,
+            b"hello".to_vec(),
+            envelope,
+            Instant::now(),
+            &mut rng,
+        );
+
+        let sign = |id: &Hash| {
+            let bytes = receipt_bytes(id).unwrap_or_default();
+            ExpandedSecretKey::from(&secret)
+                .sign(&bytes, &dst.signing_public_key)
+                .to_bytes()
+                .to_vec()
+        };
+        assert_eq!(tracker.acknowledge(&id, &[0; 64]), None);
+        assert_eq!(tracker.acknowledge(&Hash::random(), &sign(&id)), None);
+        assert_eq!(
+            tracker.acknowledge(&id, &sign(&id)),
+            Some((dst, b"hello".to_vec()))
+        );
+        assert!(tracker.is_empty());
+    }
+}
@@ -0,0 +1,144 @@
+use crate::{crypto::hash::Hash, PublicId};
+use serde::{Deserialize, Serialize};
+
+/// An application message along with its identity.
+/// The ID is derived from the content, so copies of the same message share it
+/// and a tampered ID is detected. It also covers where receipts are sent and how
+/// often the message may be retransmitted, so relays cannot change either.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Envelope {
+    /// Blake3 hash of the sender, sequence number, payload, receipt recipient
+    /// and number of retransmissions
+    pub id: Hash,
+    /// Node ID of the sender
+    pub sender: Hash,
+    /// Sequence number of the message among those of the sender
+    pub seq: u64,
+    /// Encoded message
+    pub payload: Vec<u8>,
+    /// Number of the transmission, zero for the first one
+    pub attempt: u32,
+    /// Public identity of the sender, if it asked for a delivery receipt
+    pub ack_to: Option<PublicId>,
+    /// Number of retransmissions the sender makes at most
+    pub max_attempts: u32,
+}
+
+impl Envelope {
+    /// Creates a new `Envelope`, computing its ID.
+    pub fn new(sender: Hash, seq: u64, payload: Vec<u8>) -> Self {
+        Self {
+            id: Self::compute_id(&sender, seq, &payload, None, 0),
+            sender,
+            seq,
+            payload,
+            attempt: 0,
+            ack_to: None,
+            max_attempts: 0,
+        }
+    }
+
+    /// Creates a new `Envelope` asking for a delivery receipt sent to `ack_to`,
+    /// which is retransmitted up to `max_attempts` times.
+    pub fn with_receipt(
+        sender: Hash,
+        seq: u64,
+        payload: Vec<u8>,
+        ack_to: PublicId,
+        max_attempts: u32,
+    ) -> Self {
+        Self {
+            id: Self::compute_id(&sender, seq, &payload, Some(&ack_to), max_attempts),
+            sender,
+            seq,
+            payload,
+            attempt: 0,
+            ack_to: Some(ack_to),
+            max_attempts,
+        }
+    }
+
+    /// Computes the ID of a message
+    pub fn compute_id(
+        sender: &Hash,
+        seq: u64,
+        payload: &[u8],
+        ack_to: Option<&PublicId>,
+        max_attempts: u32,
+    ) -> Hash {
+        let ack_to = bincode::serialize(&ack_to).unwrap_or_default();
+        Hash::from_byte_arrays(&[
+            sender.as_ref(),
+            &seq.to_be_bytes(),
+            payload,
+            &ack_to,
+            &max_attempts.to_be_bytes(),
+        ])
+    }
+
+    /// Checks that the ID matches the content of the envelope,
+    /// and that the transmission is one the sender makes
+    pub fn is_valid(&self) -> bool {
+        self.attempt <= self.max_attempts
+            && self.id
+                == Self::compute_id(
+                    &self.sender,
+                    self.seq,
+                    &self.payload,
+                    self.ack_to.as_ref(),
+                    self.max_attempts,
+                )
+    }
+
+    /// Identifies a single transmission of the message.
+    /// Each retransmission is forwarded once, while the message is delivered once.
+    pub fn transmission_id(&self) -> Hash {
+        Hash::from_byte_arrays(&[self.id.as_ref(), &self.attempt.to_be_bytes()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Identity;
+
+    #[test]
+    fn test_id_covers_content() {
+        let sender = Hash::random();
+        let envelope = Envelope::new(sender, 1, b"hello".to_vec());
+        assert!(envelope.is_valid());
+        assert_eq!(envelope.id, Envelope::new(sender, 1, b"hello".to_vec()).id);
+        assert_ne!(envelope.id, Envelope::new(sender, 2, b"hello".to_vec()).id);
+
+        let mut tampered = envelope;
+        tampered.payload = b"hullo".to_vec();
+        assert!(!tampered.is_valid());
+    }
+
+    #[test]
+    fn test_id_covers_receipts_and_retransmissions() {
+        let (sender, relay) = (Identity::new(), Identity::new());
+        let envelope = Envelope::with_receipt(
+            sender.public_id().node_id,
+            1,
+            b"hello".to_vec(),
+            sender.public_id(),
+            2,
+        );
+        assert!(envelope.is_valid());
+
+        let mut diverted = envelope.clone();
+        diverted.ack_to = Some(relay.public_id());
+        assert!(!diverted.is_valid());
+        let mut extended = envelope.clone();
+        extended.max_attempts = 3;
+        assert!(!extended.is_valid());
+
+        // Retransmissions are valid only up to the number the sender makes.
+        let mut resent = envelope;
+        resent.attempt = 2;
+        assert!(resent.is_valid());
+        resent.attempt = 3;
+        assert!(!resent.is_valid());
+    }
+}
@@ -1,4 +1,18 @@
-use crate::{crypto::hash::Hash, PublicId, SharedRoutingTable};
+use crate::{
+    connection::{
+        connection_types::ConnectionInfo, peer_exchange::SignedPeerRecord, relay::CircuitError,
+        routing::RoutingDelta,
+    },
+    crypto::hash::Hash,
+    gossip::{
+        compact::CompactBlock,
+        erasure::Chunk,
+        inventory::{InventoryItem, ObjectKind},
+        Broadcast,
+    },
+    messaging::agent::Agent,
+    PublicId, SharedRoutingTable,
+};
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
 
@@ -36,10 +50,7 @@ pub enum Message {
     Contacts(Vec<SocketAddr>),
 
     /// Message from an agent
-    AgentMessage {
-        /// Agent payload
-        payload: Vec<(PublicId, Vec<u8>)>,
-    },
+    AgentMessage(Agent),
 
     /// Routing information
     RoutingTable {
@@ -47,5 +58,170 @@ pub enum Message {
         shared_routing_table: SharedRoutingTable,
         /// Source of the message
         source: Hash,
+        /// Signature of the source over the shared routing information
+        signature: Vec<u8>,
+    },
+
+    /// Incremental routing information
+    RoutingDelta {
+        /// Entries changed since the version last shared with the recipient
+        delta: RoutingDelta,
+        /// Source of the message
+        source: Hash,
+        /// Signature of the source over the delta
+        signature: Vec<u8>,
+    },
+
+    /// Request for a full routing table, sent when a gap is detected in the deltas
+    RoutingSnapshotRequest {
+        /// Source of the message
+        source: Hash,
+    },
+
+    /// Kademlia request for the nodes closest to a target
+    FindNode {
+        /// ID being looked up
+        target: Hash,
+        /// Connection information of the requester
+        sender: ConnectionInfo,
+    },
+
+    /// Kademlia response carrying the nodes closest to a target
+    Nodes {
+        /// ID being looked up
+        target: Hash,
+        /// Connection information of the responder
+        sender: ConnectionInfo,
+        /// Closest nodes known to the responder
+        nodes: Vec<ConnectionInfo>,
+    },
+
+    /// Liveness probe
+    Ping {
+        /// Nonce to be echoed back
+        nonce: u64,
+    },
+
+    /// Answer to a liveness probe
+    Pong {
+        /// Nonce of the probe being answered
+        nonce: u64,
+    },
+
+    /// Addresses of nodes, each signed by its owner
+    PeerRecords(Vec<SignedPeerRecord>),
+
+    /// Address the recipient was seen at by the sender, for NAT detection
+    ObservedAddress(SocketAddr),
+
+    /// Request to be introduced to a node through a rendezvous peer,
+    /// or the introduction itself when relayed by the rendezvous peer
+    ConnectRequest {
+        /// Node which wants to connect
+        source: PublicId,
+        /// Node to connect to
+        target: Hash,
+        /// External addresses of the source as observed by the rendezvous peer,
+        /// empty when sent to the rendezvous peer
+        addrs: Vec<SocketAddr>,
+    },
+
+    /// Request to a relay for a circuit to a node it is connected to
+    RelayConnect {
+        /// Circuit identifier, chosen by the requester
+        circuit: u64,
+        /// Node to connect to
+        target: Hash,
     },
+
+    /// Notice from a relay that a circuit was opened
+    RelayOpened {
+        /// Circuit identifier
+        circuit: u64,
+        /// Public identity of the other end, as announced by the relay
+        peer: PublicId,
+    },
+
+    /// Bytes carried through a circuit, opaque to the relay
+    RelayData {
+        /// Circuit identifier
+        circuit: u64,
+        /// Encoded end-to-end frame
+        payload: Vec<u8>,
+    },
+
+    /// Notice that a circuit was refused or closed
+    RelayClosed {
+        /// Circuit identifier
+        circuit: u64,
+        /// Reason for closing the circuit
+        reason: CircuitError,
+    },
+
+    /// Notice that the sender is leaving the network
+    Goodbye {
+        /// Reason for leaving
+        reason: String,
+    },
+
+    /// Signed acknowledgement of the delivery of a message
+    Receipt {
+        /// ID of the message
+        id: Hash,
+        /// Signature of the recipient over the ID
+        signature: Vec<u8>,
+    },
+
+    /// Payload disseminated to the whole network
+    Broadcast(Broadcast),
+
+    /// Announcement of the IDs of broadcasts the sender has
+    IHave(Vec<Hash>),
+
+    /// Request for broadcasts announced by the receiver
+    IWant(Vec<Hash>),
+
+    /// Request to stop pushing broadcasts to the sender, and announce them instead
+    Prune,
+
+    /// Request to push broadcasts to the sender again, starting with the given missed ones
+    Graft(Vec<Hash>),
+
+    /// Announcement of objects the sender has
+    Inv(Vec<InventoryItem>),
+
+    /// Request for objects announced by the receiver
+    GetData(Vec<InventoryItem>),
+
+    /// Object requested by the receiver, identified by the hash of its payload
+    Data {
+        /// Kind of object
+        kind: ObjectKind,
+        /// Content of the object
+        payload: Vec<u8>,
+    },
+
+    /// Block with short IDs in place of the transactions the receiver probably has
+    CompactBlock(CompactBlock),
+
+    /// Request for the transactions of a compact block the receiver could not rebuild
+    GetBlockTxn {
+        /// ID of the block
+        block_id: Hash,
+        /// Indexes of the missing transactions in the block
+        indexes: Vec<u32>,
+    },
+
+    /// Transactions of a block requested by the receiver
+    BlockTxn {
+        /// ID of the block
+        block_id: Hash,
+        /// Indexes of the transactions in the block
+        indexes: Vec<u32>,
+        /// The transactions, in the order of their indexes
+        transactions: Vec<Vec<u8>>,
+    },
+
+    /// Erasure-coded piece of a payload
+    Chunk(Chunk),
 }
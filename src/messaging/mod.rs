@@ -1,21 +1,49 @@
-use crate::{Event, Identity, Message, PublicId, Result};
+use crate::{
+    connection::reconnect::Backoff, crypto::hash::Hash, persistence, Event, Identity, Message,
+    PublicId, Result,
+};
+use agent::{Agent, Walk, DEFAULT_AGENT_TTL};
 use bytes::Bytes;
 use crossbeam_channel::Sender;
+use delivery::{receipt_bytes, DeliveryTracker, Retransmission};
 use ed25519_dalek::{Signature, Verifier};
+use envelope::Envelope;
 use qp2p::{Connection as QuicConnection, Endpoint as QuicEndpoint};
 use rand::Rng;
-use std::net::SocketAddr;
+use seen_cache::SeenCache;
+use serde::{Deserialize, Serialize};
+use std::{fs, net::SocketAddr, path::Path, time::Instant};
 
+/// Hop limit and visited nodes of agent random walks
+pub mod agent;
+/// Delivery receipts and retransmission of messages
+pub mod delivery;
+/// Identity of application messages
+pub mod envelope;
 /// Types of peer-to-peer messages
 pub mod message;
+/// Cache of recently seen message IDs
+pub mod seen_cache;
 
+/// Number of agent messages carrying each outgoing message; recipients drop the extra copies by ID
 const OUTBOX_COPIES: usize = 3;
 
 /// Messaging functionality
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Messaging {
-    outbox: Vec<(PublicId, Vec<u8>, usize)>,
+    outbox: Vec<(PublicId, Envelope, usize)>,
     pending: Vec<(Bytes, u64)>,
+    seq: u64,
+    #[serde(skip)]
+    seen: SeenCache,
+    #[serde(skip)]
+    carried: SeenCache,
+    #[serde(skip)]
+    delivered: SeenCache,
+    #[serde(skip)]
+    delivery: Option<DeliveryTracker>,
+    #[serde(skip)]
+    agent_ttl: Option<u32>,
 }
 
 impl Messaging {
@@ -24,100 +52,264 @@ impl Messaging {
         Self {
             outbox: Default::default(),
             pending: Default::default(),
+            seq: 0,
+            seen: Default::default(),
+            carried: Default::default(),
+            delivered: Default::default(),
+            delivery: None,
+            agent_ttl: None,
         }
     }
 
-    /// Send an ordinary message to a peer
-    pub fn send_message(&mut self, dst: &PublicId, message: &[u8]) -> Result<()> {
-        self.outbox.push((
-            *dst,
-            bincode::serialize(&Message::UserMessage(message.to_vec()))?,
-            OUTBOX_COPIES,
-        ));
+    /// Ask recipients for signed delivery receipts, and retransmit messages with `backoff`
+    /// until a receipt arrives or the retransmissions run out.
+    /// With `None`, messages are sent without receipts.
+    pub fn set_retransmission(&mut self, backoff: Option<Backoff>) {
+        self.delivery = backoff.map(DeliveryTracker::new);
+    }
+
+    /// Number of hops agents make before their messages expire.
+    /// Agents reaching us with more hops left are cut down to it.
+    pub fn agent_ttl(&self) -> u32 {
+        self.agent_ttl.unwrap_or(DEFAULT_AGENT_TTL)
+    }
+
+    /// Set the number of hops agents make before their messages expire.
+    pub fn set_agent_ttl(&mut self, ttl: u32) {
+        self.agent_ttl = Some(ttl);
+    }
+
+    /// Load the unsent messages from a file, or start with none if it does not exist.
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::new());
+        }
+        Ok(bincode::deserialize(&fs::read(path)?)?)
+    }
+
+    /// Atomically write the outbox and the pending messages to a file.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        persistence::save(self, path)
+    }
+
+    /// Checks if no message is waiting to be sent
+    pub fn is_empty(&self) -> bool {
+        self.outbox.is_empty() && self.pending.is_empty()
+    }
+
+    /// Wrap a user message in an envelope and queue it for a peer,
+    /// asking for a delivery receipt if retransmission is enabled.
+    /// Returns the token reported along with the outcome of the delivery.
+    fn enqueue(
+        &mut self,
+        sender: &PublicId,
+        dst: &PublicId,
+        message: &Message,
+        user_message: &[u8],
+    ) -> Result<u64> {
+        self.seq += 1;
+        let token = self.seq;
+        let payload = bincode::serialize(message)?;
+        let now = Instant::now();
+        let envelope = match self.delivery.as_mut() {
+            Some(delivery) => {
+                let envelope = Envelope::with_receipt(
+                    sender.node_id,
+                    token,
+                    payload,
+                    *sender,
+                    delivery.max_attempts(),
+                );
+                delivery.track(
+                    token,
+                    *dst,
+                    user_message.to_vec(),
+                    envelope.clone(),
+                    now,
+                    &mut rand::thread_rng(),
+                );
+                envelope
+            }
+            None => Envelope::new(sender.node_id, token, payload),
+        };
+        self.queue(dst, envelope, now);
+        Ok(token)
+    }
+
+    /// Queue an envelope for a peer.
+    /// The transmission counts as seen, so copies coming back to us with agents other than
+    /// the one it leaves with are not forwarded again.
+    fn queue(&mut self, dst: &PublicId, envelope: Envelope, now: Instant) {
+        let _ = self.seen.insert(envelope.transmission_id(), now);
+        self.outbox
+            .retain(|(_, queued, _)| queued.id != envelope.id);
+        self.outbox.push((*dst, envelope, OUTBOX_COPIES));
+    }
+
+    /// Queue a signed receipt for a message we received.
+    fn send_receipt(&mut self, self_id: &Identity, dst: &PublicId, id: &Hash) -> Result<()> {
+        let signature = self_id
+            .sign_with_signing_key(&receipt_bytes(id)?)
+            .to_bytes()
+            .to_vec();
+        let message = Message::Receipt { id: *id, signature };
+        self.seq += 1;
+        let envelope = Envelope::new(
+            self_id.public_id().node_id,
+            self.seq,
+            bincode::serialize(&message)?,
+        );
+        self.queue(dst, envelope, Instant::now());
         Ok(())
     }
 
-    /// Send a message to a peer using public key encryption
-    pub fn send_encrypted_message(&mut self, dst: &PublicId, message: &[u8]) -> Result<()> {
+    /// Send again the messages whose receipt is overdue.
+    /// Emits an `Event::UnsentUserMessage` for each message which used up its retransmissions.
+    /// Should be called periodically when retransmission is enabled.
+    pub fn retransmit<R: Rng>(
+        &mut self,
+        now: Instant,
+        rng: &mut R,
+        tx: &Sender<Event>,
+    ) -> Result<()> {
+        let due = match self.delivery.as_mut() {
+            Some(delivery) => delivery.due(now, rng),
+            None => return Ok(()),
+        };
+        for retransmission in due {
+            match retransmission {
+                Retransmission::Resend(dst, envelope) => {
+                    log::trace!(
+                        "Retransmitting message {:?}, attempt {}",
+                        envelope.id,
+                        envelope.attempt
+                    );
+                    self.queue(&dst, *envelope, now);
+                }
+                Retransmission::GaveUp { token, message } => {
+                    log::debug!("Giving up on message with token {}", token);
+                    tx.send(Event::UnsentUserMessage { message, token })?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle a receipt, emitting an `Event::SentUserMessage` if it is genuine.
+    fn handle_receipt(&mut self, id: &Hash, signature: &[u8], tx: &Sender<Event>) -> Result<()> {
+        let acknowledged = self
+            .delivery
+            .as_mut()
+            .and_then(|delivery| delivery.acknowledge(id, signature));
+        if let Some((peer, message)) = acknowledged {
+            self.outbox.retain(|(_, queued, _)| queued.id != *id);
+            tx.send(Event::SentUserMessage { peer, message })?;
+        }
+        Ok(())
+    }
+
+    /// Send an ordinary message to a peer.
+    /// Returns the token reported along with the outcome of the delivery.
+    pub fn send_message(
+        &mut self,
+        self_id: &Identity,
+        dst: &PublicId,
+        message: &[u8],
+    ) -> Result<u64> {
+        self.enqueue(
+            &self_id.public_id(),
+            dst,
+            &Message::UserMessage(message.to_vec()),
+            message,
+        )
+    }
+
+    /// Send a message to a peer using public key encryption.
+    /// Returns the token reported along with the outcome of the delivery.
+    pub fn send_encrypted_message(
+        &mut self,
+        self_id: &Identity,
+        dst: &PublicId,
+        message: &[u8],
+    ) -> Result<u64> {
         let cypher_text = dst.public_key.0.encrypt(message);
         let cypher_bytes = bincode::serialize(&cypher_text)?;
-        self.outbox.push((
-            *dst,
-            bincode::serialize(&Message::EncryptedMessage(cypher_bytes))?,
-            OUTBOX_COPIES,
-        ));
-        Ok(())
+        self.enqueue(
+            &self_id.public_id(),
+            dst,
+            &Message::EncryptedMessage(cypher_bytes),
+            message,
+        )
     }
 
-    /// Send a message to a peer using authenticated encryption
+    /// Send a message to a peer using authenticated encryption.
+    /// Returns the token reported along with the outcome of the delivery.
     pub fn send_authenticated_message(
         &mut self,
         self_id: &Identity,
         dst: &PublicId,
         message: &[u8],
-    ) -> Result<()> {
+    ) -> Result<u64> {
         let cypher_bytes = self_id.authenticate_message(dst, message);
-        self.outbox.push((
-            *dst,
-            bincode::serialize(&Message::AuthenticatedMessage {
+        self.enqueue(
+            &self_id.public_id(),
+            dst,
+            &Message::AuthenticatedMessage {
                 message: cypher_bytes,
                 sender: self_id.public_id(),
-            })?,
-            OUTBOX_COPIES,
-        ));
-        Ok(())
+            },
+            message,
+        )
     }
 
-    /// Sign a message and send it
+    /// Sign a message and send it.
+    /// Returns the token reported along with the outcome of the delivery.
     pub fn send_signed_message(
         &mut self,
         self_id: &Identity,
         dst: &PublicId,
         message: &[u8],
-    ) -> Result<()> {
+    ) -> Result<u64> {
         let signature = self_id.sign_message(message);
-        self.outbox.push((
-            *dst,
-            bincode::serialize(&Message::SignedMessage {
+        self.enqueue(
+            &self_id.public_id(),
+            dst,
+            &Message::SignedMessage {
                 message: message.to_vec(),
                 signature: signature.as_bytes().to_vec(),
                 sender: self_id.public_id(),
-            })?,
-            OUTBOX_COPIES,
-        ));
-        Ok(())
+            },
+            message,
+        )
     }
 
     /// Send agent message
     pub async fn send_agent_message(
         &mut self,
-        mut payload: Vec<(PublicId, Vec<u8>)>,
-        active_connections: &[&SocketAddr],
+        agent: Agent,
+        active_peers: &[(&SocketAddr, Option<Hash>)],
         first: bool,
         quic: &mut QuicConnection,
     ) -> Result<()> {
-        if active_connections.is_empty() {
+        if active_peers.is_empty() {
             log::error!("No active connections!");
             return Ok(());
         }
-        self.send_pending_messages(active_connections, quic).await?;
-        let _ = self
-            .outbox
-            .iter_mut()
-            .map(|(a, b, c)| {
-                payload.push((*a, b.to_vec()));
-                let _ = std::mem::replace(c, *c - 1);
-            })
-            .collect::<Vec<()>>();
-
-        self.outbox.retain(|(_, _, count)| *count > 0);
-        let mut rng = rand::thread_rng();
-        let rand_val = rng.gen_range(0..active_connections.len());
-        if let Some(addr) = active_connections.get(rand_val) {
+        let active_connections = active_peers
+            .iter()
+            .map(|(addr, _)| *addr)
+            .collect::<Vec<_>>();
+        self.send_pending_messages(&active_connections, quic)
+            .await?;
+        let Agent { payload, mut walk } = self.board(agent, Instant::now());
+        if let Some(addr) = walk.next_hop(active_peers, &mut rand::thread_rng()) {
             let user_msg_bytes = (
                 Bytes::from("Agent message"),
                 Bytes::from(addr.to_string()),
-                Bytes::from(bincode::serialize(&Message::AgentMessage { payload })?),
+                Bytes::from(bincode::serialize(&Message::AgentMessage(Agent {
+                    payload,
+                    walk,
+                }))?),
             );
             quic.send(user_msg_bytes).await?;
             if first {
@@ -127,6 +319,18 @@ impl Messaging {
         Ok(())
     }
 
+    /// Load the queued messages onto an agent leaving us.
+    /// Each message leaves with up to `OUTBOX_COPIES` agents.
+    fn board(&mut self, mut agent: Agent, now: Instant) -> Agent {
+        for (dst, envelope, copies) in &mut self.outbox {
+            let _ = self.carried.insert(carried_id(envelope, &agent.walk), now);
+            agent.payload.push((*dst, envelope.clone()));
+            *copies -= 1;
+        }
+        self.outbox.retain(|(_, _, copies)| *copies > 0);
+        agent
+    }
+
     async fn send_pending_messages(
         &mut self,
         active_connections: &[&SocketAddr],
@@ -163,41 +367,111 @@ impl Messaging {
         &mut self,
         self_id: &Identity,
         peer: &mut QuicEndpoint,
-        mut payload: Vec<(PublicId, Vec<u8>)>,
-        active_connections: &[&SocketAddr],
+        agent: Agent,
+        active_peers: &[(&SocketAddr, Option<Hash>)],
         quic: &mut QuicConnection,
         tx: &Sender<Event>,
     ) -> Result<()> {
+        let agent = self.receive_agent(self_id, &peer.local_addr(), agent, Instant::now(), tx)?;
+        match agent {
+            Some(agent) => {
+                self.send_agent_message(agent, active_peers, false, quic)
+                    .await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Deliver the messages of an agent meant for us, and return the agent carrying
+    /// the others further, unless it ran out of hops.
+    fn receive_agent(
+        &mut self,
+        self_id: &Identity,
+        peer_addr: &SocketAddr,
+        agent: Agent,
+        now: Instant,
+        tx: &Sender<Event>,
+    ) -> Result<Option<Agent>> {
         let self_pub_id = self_id.public_id();
+        let Agent {
+            mut payload,
+            mut walk,
+        } = agent;
+        walk.ttl = walk.ttl.min(self.agent_ttl());
+        walk.visit(&self_pub_id.node_id);
         let mut forward = vec![];
-        while let Some((target_pub_id, msg)) = payload.pop() {
+        while let Some((target_pub_id, envelope)) = payload.pop() {
+            if !self.accept(&envelope, &walk, now) {
+                continue;
+            }
             if target_pub_id == self_pub_id {
-                self.handle_message(peer, msg, self_id, tx)?;
+                // Retransmissions are acknowledged again, in case the receipt was lost,
+                // but delivered only once.
+                if let Some(ack_to) = envelope.ack_to {
+                    self.send_receipt(self_id, &ack_to, &envelope.id)?;
+                }
+                if self.delivered.insert(envelope.id, now) {
+                    self.handle_message(peer_addr, envelope.payload, self_id, tx)?;
+                }
             } else {
-                forward.push((target_pub_id, msg));
+                forward.push((target_pub_id, envelope));
             }
         }
-        self.send_agent_message(forward, active_connections, false, quic)
-            .await
+        if walk.is_expired() {
+            if !forward.is_empty() {
+                log::debug!("Agent ran out of hops with {} messages", forward.len());
+                let expired = forward
+                    .into_iter()
+                    .map(|(target, envelope)| (target, envelope.id))
+                    .collect();
+                tx.send(Event::ExpiredMessages(expired))?;
+            }
+            return Ok(None);
+        }
+        Ok(Some(Agent {
+            payload: forward,
+            walk,
+        }))
+    }
+
+    /// Checks that an envelope is genuine and that no other agent carried its
+    /// transmission through us, and records it as carried by this agent.
+    /// Each transmission is thus forwarded by a single agent, which keeps it
+    /// if its walk comes back to us.
+    fn accept(&mut self, envelope: &Envelope, walk: &Walk, now: Instant) -> bool {
+        if !envelope.is_valid() {
+            log::warn!("Dropping message {:?} with a forged ID", envelope.id);
+            return false;
+        }
+        let carried_id = carried_id(envelope, walk);
+        if self.carried.contains(&carried_id, now) {
+            return true;
+        }
+        if !self.seen.insert(envelope.transmission_id(), now) {
+            log::trace!("Dropping duplicate message {:?}", envelope.id);
+            return false;
+        }
+        let _ = self.carried.insert(carried_id, now);
+        true
     }
 
     fn handle_message(
         &mut self,
-        peer: &mut QuicEndpoint,
+        peer_addr: &SocketAddr,
         msg: Vec<u8>,
         self_id: &Identity,
         tx: &Sender<Event>,
     ) -> Result<()> {
         match bincode::deserialize::<Message>(&msg) {
             Ok(Message::UserMessage(content)) => {
-                log::trace!("Peer at {:?} sent: {:?}", &peer.local_addr(), &content[..4]);
+                log::trace!("Peer at {:?} sent: {:?}", peer_addr, &content[..4]);
                 tx.send(Event::NewMessage(content))?;
                 Ok(())
             }
             Ok(Message::EncryptedMessage(content)) => {
                 log::trace!(
                     "Peer at {:?} sent an encrypted message: {:?}",
-                    &peer.local_addr(),
+                    peer_addr,
                     &content[..4]
                 );
                 let decrypted_msg = self_id.decrypt_message(&content)?;
@@ -207,7 +481,7 @@ impl Messaging {
             Ok(Message::AuthenticatedMessage { message, sender }) => {
                 log::warn!(
                     "Peer at {:?} sent an authenticated message: {:?}",
-                    &peer.local_addr(),
+                    peer_addr,
                     &message[..4]
                 );
                 let verified_msg = self_id.verify_message(sender, &message)?;
@@ -221,7 +495,7 @@ impl Messaging {
             }) => {
                 log::trace!(
                     "Peer at {:?} sent a signed message: {:?}",
-                    &peer.local_addr(),
+                    peer_addr,
                     &message[..4]
                 );
                 if let Ok(signature) = Signature::from_bytes(&signature) {
@@ -233,6 +507,7 @@ impl Messaging {
                 }
                 Ok(())
             }
+            Ok(Message::Receipt { id, signature }) => self.handle_receipt(&id, &signature, tx),
             _ => {
                 log::error!("Unexpected message!");
                 Ok(())
@@ -240,3 +515,102 @@ impl Messaging {
         }
     }
 }
+
+/// Identifies a transmission as carried by an agent
+fn carried_id(envelope: &Envelope, walk: &Walk) -> Hash {
+    Hash::from_byte_arrays(&[envelope.transmission_id().as_ref(), walk.id.as_ref()])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::connection_info;
+
+    #[test]
+    fn test_outbox_persistence() {
+        let path = std::env::temp_dir().join(format!("outbox_{}", Hash::random()));
+        let dst = connection_info(Hash::random(), 9000).public_id;
+        let mut messaging = Messaging::new();
+        messaging
+            .outbox
+            .push((dst, Envelope::new(Hash::random(), 1, b"hello".to_vec()), 1));
+        assert!(messaging
+            .handle_unsent_message(Bytes::from_static(b"unsent"), 7)
+            .is_ok());
+        assert!(messaging.save(&path).is_ok());
+
+        let loaded = Messaging::load(&path);
+        assert!(loaded.is_ok());
+        let loaded = loaded.unwrap_or_default();
+        assert_eq!(loaded.outbox, messaging.outbox);
+        assert_eq!(loaded.pending, messaging.pending);
+        let _ = fs::remove_file(&path);
+        assert!(Messaging::load(&path).is_ok_and(|messaging| messaging.is_empty()));
+    }
+
+    #[test]
+    fn test_exactly_once() {
+        let now = Instant::now();
+        let mut messaging = Messaging::new();
+        let (walk, copy) = (Walk::new(8, &Hash::random()), Walk::new(8, &Hash::random()));
+        let envelope = Envelope::new(Hash::random(), 1, b"hello".to_vec());
+        assert!(messaging.accept(&envelope, &walk, now));
+        assert!(!messaging.accept(&envelope, &copy, now));
+
+        let mut forged = Envelope::new(Hash::random(), 2, b"hello".to_vec());
+        forged.id = Hash::random();
+        assert!(!messaging.accept(&forged, &walk, now));
+    }
+
+    #[test]
+    fn test_walk_revisiting_a_node() {
+        let now = Instant::now();
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let peer_addr = SocketAddr::from(([10, 0, 0, 1], 9000));
+        let (sender, relay, recipient) = (Identity::new(), Identity::new(), Identity::new());
+        let mut source = Messaging::new();
+        let mut messaging = Messaging::new();
+        assert!(source
+            .send_message(&sender, &recipient.public_id(), b"hello")
+            .is_ok());
+        let walk = Walk::new(8, &sender.public_id().node_id);
+        let agent = source.board(Agent::new(walk), now);
+        let copy = source.board(Agent::new(Walk::new(8, &sender.public_id().node_id)), now);
+
+        // The walk comes back to the relay, and to the sender, with its message.
+        let carry = |messaging: &mut Messaging, identity: &Identity, agent: Agent| {
+            messaging
+                .receive_agent(identity, &peer_addr, agent, now, &tx)
+                .expect("Failed to receive the agent")
+                .expect("The walk ran out of hops")
+        };
+        let agent = carry(&mut messaging, &relay, agent);
+        assert_eq!(agent.payload.len(), 1);
+        let agent = carry(&mut messaging, &relay, agent);
+        assert_eq!(agent.payload.len(), 1);
+        let agent = carry(&mut source, &sender, agent);
+        assert_eq!(agent.payload.len(), 1);
+
+        // Another copy of the message is dropped by the nodes the first one went through.
+        let copy = carry(&mut messaging, &relay, copy);
+        assert!(copy.payload.is_empty());
+        // The message is delivered once, however many times it reaches its recipient.
+        let mut inbox = Messaging::new();
+        for _ in 0..2 {
+            let _ = inbox.receive_agent(&recipient, &peer_addr, agent.clone(), now, &tx);
+        }
+        assert_eq!(rx.try_iter().count(), 1);
+    }
+
+    #[test]
+    fn test_agent_ttl_is_capped() {
+        let (tx, _rx) = crossbeam_channel::unbounded();
+        let peer_addr = SocketAddr::from(([10, 0, 0, 1], 9000));
+        let mut messaging = Messaging::new();
+        messaging.set_agent_ttl(8);
+        let agent = Agent::new(Walk::new(u32::MAX, &Hash::random()));
+        let agent =
+            messaging.receive_agent(&Identity::new(), &peer_addr, agent, Instant::now(), &tx);
+        assert!(agent.is_ok_and(|agent| agent.is_some_and(|agent| agent.walk.ttl == 8)));
+    }
+}
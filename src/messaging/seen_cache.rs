@@ -0,0 +1,108 @@
+use crate::crypto::hash::Hash;
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// Default number of IDs remembered by a `SeenCache`
+pub const DEFAULT_SEEN_CAPACITY: usize = 65_536;
+/// Default time during which an ID is remembered by a `SeenCache`
+pub const DEFAULT_SEEN_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// IDs of the messages seen recently.
+/// IDs are forgotten once they are older than the window, or when the cache is full,
+/// oldest first.
+#[derive(Debug, Clone)]
+pub struct SeenCache {
+    capacity: usize,
+    window: Duration,
+    seen: HashMap<Hash, Instant>,
+    order: VecDeque<(Hash, Instant)>,
+}
+
+impl SeenCache {
+    /// Creates a new `SeenCache` remembering up to `capacity` IDs for `window`.
+    pub fn new(capacity: usize, window: Duration) -> Self {
+        Self {
+            capacity,
+            window,
+            seen: Default::default(),
+            order: Default::default(),
+        }
+    }
+
+    /// Record that a message was seen.
+    /// Returns `true` if it was not seen within the window.
+    pub fn insert(&mut self, id: Hash, now: Instant) -> bool {
+        self.expire(now);
+        if self.seen.contains_key(&id) {
+            return false;
+        }
+        if self.seen.len() >= self.capacity {
+            if let Some((oldest, _)) = self.order.pop_front() {
+                let _ = self.seen.remove(&oldest);
+            }
+        }
+        let _ = self.seen.insert(id, now);
+        self.order.push_back((id, now));
+        true
+    }
+
+    /// Checks if a message was seen within the window
+    pub fn contains(&self, id: &Hash, now: Instant) -> bool {
+        self.seen
+            .get(id)
+            .is_some_and(|seen_at| now.saturating_duration_since(*seen_at) <= self.window)
+    }
+
+    /// Number of IDs remembered
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    /// Checks if no ID is remembered
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((id, seen_at)) = self.order.front() {
+            if now.saturating_duration_since(*seen_at) <= self.window {
+                break;
+            }
+            let _ = self.seen.remove(id);
+            let _ = self.order.pop_front();
+        }
+    }
+}
+
+impl Default for SeenCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_SEEN_CAPACITY, DEFAULT_SEEN_WINDOW)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_and_capacity() {
+        let start = Instant::now();
+        let window = Duration::from_secs(60);
+        let mut cache = SeenCache::new(2, window);
+        let (a, b, c) = (Hash::random(), Hash::random(), Hash::random());
+
+        assert!(cache.insert(a, start));
+        assert!(!cache.insert(a, start + Duration::from_secs(1)));
+        assert!(cache.insert(b, start + Duration::from_secs(2)));
+        assert!(cache.insert(c, start + Duration::from_secs(3)));
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.contains(&a, start + Duration::from_secs(3)));
+
+        let later = start + window + Duration::from_secs(3);
+        assert!(cache.contains(&c, later));
+        assert!(cache.insert(b, later));
+        assert_eq!(cache.len(), 2);
+    }
+}
@@ -1,11 +1,30 @@
 use crate::{
-    connection::connection_types::{ConnectionInfo, ConnectionMap},
+    connection::{
+        address_book::AddressBook,
+        connection_types::{ConnectionInfo, ConnectionMap},
+        peer_exchange::unix_timestamp,
+        peer_score::{BanList, Misbehaviour},
+    },
+    crypto::hash::Hash,
+    dht::{self, Dht},
+    error::Error,
+    gossip::{
+        self,
+        compact::{Block, CompactRelay, Reconstruction},
+        erasure::ErasureRelay,
+        inventory::{Inventory, ObjectKind},
+        plumtree::Plumtree,
+        validation::Validator,
+        Gossip,
+    },
+    mempool::{Admission, Mempool, Prioritiser},
+    messaging::agent::{Agent, Walk},
     Config, Connection, Event, Identity, Message, Messaging, PublicId, Result,
 };
 use bytes::Bytes;
 use crossbeam_channel::{Receiver, Select, Sender};
 use qp2p::{Connection as QuicConnection, Endpoint as QuicEndpoint};
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::Instant};
 
 /// Representation of a peer-to-peer node
 pub struct Node {
@@ -13,6 +32,16 @@ pub struct Node {
     identity: Identity,
     connection: Connection,
     messaging: Messaging,
+    dht: Dht,
+    gossip: Gossip,
+    plumtree: Option<Plumtree>,
+    inventory: Inventory,
+    compact_relay: CompactRelay,
+    erasure_relay: ErasureRelay,
+    validator: Option<Box<dyn Validator>>,
+    mempool: Mempool,
+    prioritiser: Option<Box<dyn Prioritiser>>,
+    address_book: AddressBook,
     channel_tx: Sender<Event>,
     channel_rx: Receiver<Event>,
 }
@@ -26,12 +55,45 @@ impl Node {
     /// Creates a new `Node` with specified configuration.
     pub fn with_config(config: Config) -> Result<(Self, Receiver<Event>)> {
         let (channel_tx, channel_rx) = crossbeam_channel::unbounded::<Event>();
+        let identity = Identity::new();
+        let dht = Dht::new(identity.public_id().node_id);
+        let address_book = match config.address_book() {
+            Some(path) => AddressBook::load(path)?,
+            None => AddressBook::new(),
+        };
+        let mut messaging = match config.outbox() {
+            Some(path) => Messaging::load(path)?,
+            None => Messaging::new(),
+        };
+        messaging.set_retransmission(config.retransmission());
+        messaging.set_agent_ttl(config.agent_ttl());
+        let connection = Connection::with_config(&config)?;
+        let gossip = Gossip::new(config.gossip());
+        let plumtree = config
+            .gossip()
+            .plumtree
+            .then(|| Plumtree::new(config.gossip()));
+        let inventory = Inventory::new(config.gossip());
+        let compact_relay =
+            CompactRelay::new(config.gossip().retention, config.gossip().request_timeout);
+        let erasure_relay = ErasureRelay::new(config.erasure(), config.gossip().retention);
+        let mempool = Mempool::new(config.mempool());
         Ok((
             Self {
                 config,
-                identity: Identity::new(),
-                connection: Connection::new(),
-                messaging: Messaging::new(),
+                identity,
+                connection,
+                messaging,
+                dht,
+                gossip,
+                plumtree,
+                inventory,
+                compact_relay,
+                erasure_relay,
+                validator: None,
+                mempool,
+                prioritiser: None,
+                address_book,
                 channel_tx,
                 channel_rx: channel_rx.clone(),
             },
@@ -52,11 +114,27 @@ impl Node {
         }
     }
 
-    /// Bootstrap to the network using all contacts
+    /// Bootstrap to the network using a few contacts,
+    /// preferring peers from the address book which were good recently.
+    /// The rest of the network is discovered through a self-lookup
+    /// once the first of them has identified itself.
     pub async fn bootstrap(&mut self, quic: &mut QuicEndpoint) -> Result<()> {
+        let now = unix_timestamp();
+        let mut connected = 0;
+        for info in self.address_book.select_for_bootstrap(dht::ALPHA, now) {
+            match self.connection.connect_to(&info, quic).await {
+                Ok(true) => connected += 1,
+                Ok(false) => {}
+                Err(err) => {
+                    log::debug!("Failed to reach known peer {:?}: {}", info, err);
+                    self.address_book.mark_failed(&info.public_id.node_id, now);
+                }
+            }
+        }
         let nodes = self
             .config
             .bootstrap_nodes()
+            .take(dht::ALPHA.saturating_sub(connected))
             .cloned()
             .collect::<Vec<SocketAddr>>();
         self.connection.bootstrap(&nodes, quic).await
@@ -69,57 +147,531 @@ impl Node {
         addr: SocketAddr,
         quic: &mut QuicEndpoint,
     ) -> Result<()> {
-        log::trace!("Bootstrapping with peer at: {:?}", &addr);
+        log::trace!("Bootstrapping with peer at: {:?}", addr);
         self.connection.bootstrap_with(&addr, quic).await
     }
 
     /// Connect to a peer.
     /// Used when both a peer's socket address and public key are known.
+    /// Returns `false` if the connection was not started, as the peer is banned
+    /// or no outbound slot is left for it.
     pub async fn connect_to(
         &mut self,
         info: &ConnectionInfo,
         quic: &mut QuicEndpoint,
-    ) -> Result<()> {
-        log::trace!("Connecting to peer at: {:?}", &info);
+    ) -> Result<bool> {
+        log::trace!("Connecting to peer at: {:?}", info);
         self.connection.connect_to(info, quic).await
     }
 
+    /// Garbage-collect routes which have outlived the configured time-to-live.
+    /// Emits an `Event::RouteLost` for each expired route.
+    pub fn expire_routes(&mut self) -> Result<()> {
+        self.connection.expire_routes(
+            self.config.route_ttl(),
+            self.config.route_hold_down(),
+            &self.channel_tx,
+        )
+    }
+
+    /// Ping quiet peers and drop unresponsive ones, and close the relayed circuits
+    /// which outlived their duration.
+    /// Emits an `Event::Disconnected` for each dropped peer.
+    /// Should be called periodically.
+    pub async fn check_liveness(&mut self, quic: &mut QuicConnection) -> Result<()> {
+        self.connection
+            .check_liveness(&self.config.timeouts(), &self.channel_tx, quic)
+            .await?;
+        self.connection.expire_circuits(quic).await
+    }
+
+    /// Send again the messages whose delivery receipt is overdue.
+    /// Emits an `Event::UnsentUserMessage` for each message we gave up on.
+    /// Should be called periodically when delivery receipts are enabled.
+    pub fn retransmit_messages(&mut self) -> Result<()> {
+        self.messaging
+            .retransmit(Instant::now(), &mut rand::thread_rng(), &self.channel_tx)
+    }
+
+    /// Try to reconnect to lost bootstrap nodes and allow-listed peers.
+    /// Emits an `Event::ConnectionFailure` for each failed attempt.
+    /// Should be called periodically.
+    pub async fn reconnect(&mut self, quic: &mut QuicEndpoint) -> Result<()> {
+        self.connection.reconnect(&self.channel_tx, quic).await
+    }
+
+    /// Share signed peer records with our connections,
+    /// advertising our external address as well when we are behind a NAT.
+    /// Should be called periodically.
+    pub async fn exchange_peers(
+        &mut self,
+        endpoint: &QuicEndpoint,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        let local_addr = endpoint.local_addr();
+        let mut addrs = vec![local_addr];
+        if self.connection.is_behind_nat(&local_addr) {
+            addrs.extend(self.connection.external_addr());
+        }
+        self.connection
+            .share_peer_records(&self.identity, addrs, quic)
+            .await
+    }
+
+    /// Retrieves our external address as reflected by our peers
+    pub fn external_addr(&self) -> Option<SocketAddr> {
+        self.connection.external_addr()
+    }
+
+    /// Connect to a peer behind a NAT through a rendezvous peer we are both connected to.
+    /// The rendezvous peer tells both sides the other's external addresses,
+    /// and both dial each other at the same time. The target follows the introduction
+    /// only if the rendezvous peer is one of its bootstrap or allow-listed peers.
+    pub async fn connect_via(
+        &mut self,
+        target: Hash,
+        rendezvous: &SocketAddr,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        log::trace!("Asking {:?} to introduce us to {:?}", rendezvous, target);
+        self.connection
+            .request_introduction(self.identity.public_id(), target, rendezvous, quic)
+            .await
+    }
+
+    /// Reach a peer which cannot accept connections through a relay we are both connected to.
+    /// Returns the identifier of the circuit; an `Event::CircuitEstablished`
+    /// is emitted once the peer proved its identity end to end.
+    pub async fn connect_through_relay(
+        &mut self,
+        target: PublicId,
+        relay: &SocketAddr,
+        quic: &mut QuicConnection,
+    ) -> Result<u64> {
+        log::trace!("Opening a circuit to {:?} through {:?}", target, relay);
+        self.connection.open_circuit(relay, target, quic).await
+    }
+
+    /// Send a message through an established circuit
+    pub async fn send_circuit_message(
+        &mut self,
+        circuit: u64,
+        msg: &[u8],
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        self.connection
+            .send_circuit_message(circuit, msg.to_vec(), quic)
+            .await
+    }
+
+    /// Close a circuit through a relay
+    pub async fn close_circuit(&mut self, circuit: u64, quic: &mut QuicConnection) -> Result<()> {
+        self.connection
+            .close_circuit(circuit, &self.channel_tx, quic)
+            .await
+    }
+
+    /// Retrieves the verified connection information learnt through peer exchange
+    pub fn known_peers(&self) -> Vec<ConnectionInfo> {
+        self.connection.known_peers()
+    }
+
+    /// Connect to peers learnt through peer exchange
+    pub async fn connect_to_known_peers(&mut self, quic: &mut QuicEndpoint) -> Result<()> {
+        self.connection.connect_to_known_peers(quic).await
+    }
+
+    /// Retrieves the book of known peers
+    pub fn address_book(&self) -> &AddressBook {
+        &self.address_book
+    }
+
+    /// Write the address book to the configured file, if any
+    pub fn save_address_book(&self) -> Result<()> {
+        match self.config.address_book() {
+            Some(path) => self.address_book.save(path),
+            None => Ok(()),
+        }
+    }
+
+    /// Retrieves the DHT used for peer discovery
+    pub fn dht(&self) -> &Dht {
+        &self.dht
+    }
+
+    /// Look up the nodes closest to `target` in the DHT
+    pub async fn find_node(
+        &mut self,
+        target: Hash,
+        endpoint: &QuicEndpoint,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        let self_info = self.connection_info(endpoint);
+        self.dht.start_lookup(&self_info, target, quic).await
+    }
+
+    /// Refresh stale DHT buckets and time out unanswered DHT queries.
+    /// Nodes which did not answer in time are penalised.
+    /// Should be called periodically.
+    pub async fn refresh_dht(
+        &mut self,
+        endpoint: &QuicEndpoint,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        let self_info = self.connection_info(endpoint);
+        for info in self.dht.refresh(&self_info, quic).await? {
+            let _ = self.connection.record_misbehaviour(
+                &info.socket_addr,
+                Some(&info.public_id.node_id),
+                Misbehaviour::Timeout,
+            );
+        }
+        Ok(())
+    }
+
+    /// Retrieves the list of banned peers
+    pub fn ban_list(&self) -> &BanList {
+        self.connection.peer_scores().bans()
+    }
+
+    /// Checks if a peer is banned, by address or by node ID
+    pub fn is_banned(&self, socket_addr: &SocketAddr, node_id: Option<&Hash>) -> bool {
+        self.connection.is_banned(socket_addr, node_id)
+    }
+
+    /// Lift the ban on a node ID and on a socket address
+    pub fn unban(&mut self, socket_addr: Option<&SocketAddr>, node_id: Option<&Hash>) {
+        let bans = self.connection.peer_scores_mut().bans_mut();
+        if let Some(socket_addr) = socket_addr {
+            let _ = bans.unban_addr(socket_addr);
+        }
+        if let Some(node_id) = node_id {
+            let _ = bans.unban_node(node_id);
+        }
+    }
+
+    /// Write the ban list to the configured file, if any.
+    /// Bans which have run out are dropped first.
+    pub fn save_ban_list(&mut self) -> Result<()> {
+        let bans = self.connection.peer_scores_mut().bans_mut();
+        bans.expire(unix_timestamp());
+        match self.config.ban_list() {
+            Some(path) => bans.save(path),
+            None => Ok(()),
+        }
+    }
+
+    /// Write the unsent messages to the configured file, if any
+    pub fn save_outbox(&self) -> Result<()> {
+        match self.config.outbox() {
+            Some(path) => self.messaging.save(path),
+            None => Ok(()),
+        }
+    }
+
+    /// Leave the network gracefully.
+    /// Our peers are told we are leaving, so they withdraw their routes through us at once.
+    /// Unsent messages, known peers and bans are written to disk if persistence is on,
+    /// even if some peers could not be told, and the QUIC endpoint is closed.
+    /// Resolves once everything is done.
+    pub async fn shutdown(
+        &mut self,
+        reason: &str,
+        endpoint: &QuicEndpoint,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        log::info!("Shutting down: {}", reason);
+        let goodbye = self.connection.say_goodbye(reason, quic).await;
+        let outbox = self.save_outbox();
+        let address_book = self.save_address_book();
+        let bans = self.save_ban_list();
+        endpoint.close();
+        goodbye.and(outbox).and(address_book).and(bans)
+    }
+
     /// Register a selector for events
     pub fn register_selector<'a>(&'a mut self, selector: &mut Select<'a>) -> usize {
         selector.recv(&self.channel_rx)
     }
 
-    /// Send a message to a peer
-    pub fn send_message(&mut self, dst: &PublicId, msg: &[u8]) -> Result<()> {
+    /// Send a message to a peer.
+    /// Returns the token reported in `Event::UnsentUserMessage` if delivery fails.
+    pub fn send_message(&mut self, dst: &PublicId, msg: &[u8]) -> Result<u64> {
         log::trace!("Sending message to {:?}", dst);
-        self.messaging.send_message(dst, msg)
+        self.messaging.send_message(&self.identity, dst, msg)
     }
 
-    /// Send a message to a peer using public-key encryption
-    pub fn send_encrypted_message(&mut self, dst: &PublicId, msg: &[u8]) -> Result<()> {
+    /// Send a message to a peer using public-key encryption.
+    /// Returns the token reported in `Event::UnsentUserMessage` if delivery fails.
+    pub fn send_encrypted_message(&mut self, dst: &PublicId, msg: &[u8]) -> Result<u64> {
         log::trace!("Sending encrypted message to {:?}", dst);
-        self.messaging.send_encrypted_message(dst, msg)
+        self.messaging
+            .send_encrypted_message(&self.identity, dst, msg)
     }
 
-    /// Send a message to a peer using authenticated encryption
-    pub fn send_authenticated_message(&mut self, dst: &PublicId, msg: &[u8]) -> Result<()> {
+    /// Send a message to a peer using authenticated encryption.
+    /// Returns the token reported in `Event::UnsentUserMessage` if delivery fails.
+    pub fn send_authenticated_message(&mut self, dst: &PublicId, msg: &[u8]) -> Result<u64> {
         log::trace!("Sending authenticated message to {:?}", dst);
         self.messaging
             .send_authenticated_message(&self.identity, dst, msg)
     }
 
-    /// Send a message along with a signature
-    pub fn send_signed_message(&mut self, dst: &PublicId, msg: &[u8]) -> Result<()> {
+    /// Send a message along with a signature.
+    /// Returns the token reported in `Event::UnsentUserMessage` if delivery fails.
+    pub fn send_signed_message(&mut self, dst: &PublicId, msg: &[u8]) -> Result<u64> {
         log::trace!("Sending signed message to {:?}", dst);
         self.messaging.send_signed_message(&self.identity, dst, msg)
     }
 
+    /// Disseminate a payload to the whole network.
+    /// Returns the ID of the broadcast; other nodes emit an `Event::NewBroadcast` on receipt.
+    pub async fn broadcast(&mut self, payload: &[u8], quic: &mut QuicConnection) -> Result<Hash> {
+        let origin = self.identity.public_id().node_id;
+        let peers = self.connection.active_connections();
+        let (now, rng) = (Instant::now(), &mut rand::thread_rng());
+        let (id, outgoing) = match self.plumtree.as_mut() {
+            Some(tree) => tree.broadcast(&origin, payload.to_vec(), &peers, now, rng),
+            None => self
+                .gossip
+                .broadcast(&origin, payload.to_vec(), &peers, now, rng),
+        };
+        log::trace!("Broadcasting {:?}", id);
+        gossip::send(outgoing, quic).await?;
+        Ok(id)
+    }
+
+    /// Announce a block or transaction to our peers, sending it to those which request it.
+    /// Returns the ID of the object, the hash of its content; other nodes emit an
+    /// `Event::NewObject` on receipt.
+    pub async fn announce(
+        &mut self,
+        kind: ObjectKind,
+        payload: &[u8],
+        quic: &mut QuicConnection,
+    ) -> Result<Hash> {
+        let (id, outgoing) = self.inventory.announce(
+            kind,
+            payload.to_vec(),
+            &self.connection.active_connections(),
+            Instant::now(),
+        );
+        log::trace!("Announcing {:?} {:?}", kind, id);
+        gossip::send(outgoing, quic).await?;
+        Ok(id)
+    }
+
+    /// Register the hook checking blocks and transactions before they are relayed.
+    /// Only objects of a known kind are checked: those exchanged by inventory and as
+    /// compact blocks. Broadcast payloads, whether sent whole or in chunks, are opaque
+    /// and relayed unchecked. Without one, every object is relayed.
+    pub fn set_validator(&mut self, validator: impl Validator + 'static) {
+        self.validator = Some(Box::new(validator));
+    }
+
+    /// Checks if an object received from a peer passes the registered validator
+    fn is_valid(&self, kind: ObjectKind, payload: &[u8]) -> bool {
+        self.validator
+            .as_ref()
+            .is_none_or(|validator| validator.validate(kind, payload))
+    }
+
+    /// Register the hook ranking transactions in the mempool.
+    /// Without one, all transactions have the same priority.
+    pub fn set_prioritiser(&mut self, prioritiser: impl Prioritiser + 'static) {
+        self.prioritiser = Some(Box::new(prioritiser));
+    }
+
+    /// Offer a transaction to the mempool, announcing it to our peers if it is added.
+    /// Other nodes emit an `Event::NewObject` when it enters their mempool, and relay
+    /// it in turn; each peer is sent a transaction once.
+    pub async fn submit_transaction(
+        &mut self,
+        transaction: &[u8],
+        quic: &mut QuicConnection,
+    ) -> Result<Admission> {
+        let admission = self.admit(transaction.to_vec());
+        if let Admission::Added(_) = admission {
+            let _ = self
+                .announce(ObjectKind::Transaction, transaction, quic)
+                .await?;
+        }
+        Ok(admission)
+    }
+
+    /// Offer a transaction to the mempool, with the priority the application gives it.
+    fn admit(&mut self, transaction: Vec<u8>) -> Admission {
+        let priority = self
+            .prioritiser
+            .as_ref()
+            .map_or(0, |prioritiser| prioritiser.priority(&transaction));
+        let admission = self.mempool.insert(transaction, priority, Instant::now());
+        if let Admission::Added(evicted) = &admission {
+            for id in evicted {
+                log::trace!("Evicted transaction {:?} from the mempool", id);
+            }
+        }
+        admission
+    }
+
+    /// Disseminate a large payload as erasure-coded chunks spread across our peers.
+    /// Returns the root identifying the payload; other nodes emit an
+    /// `Event::NewChunkedBroadcast` once they received enough chunks to rebuild it.
+    pub async fn broadcast_chunked(
+        &mut self,
+        payload: &[u8],
+        quic: &mut QuicConnection,
+    ) -> Result<Hash> {
+        let (root, outgoing) = self.erasure_relay.broadcast(
+            payload,
+            &self.connection.active_connections(),
+            Instant::now(),
+        )?;
+        log::trace!("Broadcasting {:?} in {} chunks", root, outgoing.len());
+        gossip::send(outgoing, quic).await?;
+        Ok(root)
+    }
+
+    /// Relay a block to our peers as a compact block, sending in full only the
+    /// transactions missing from our mempool. Its transactions are then removed from it.
+    /// Returns the ID of the block; other nodes emit an `Event::NewObject` once they
+    /// rebuilt it.
+    pub async fn relay_block(&mut self, block: Block, quic: &mut QuicConnection) -> Result<Hash> {
+        let id = block.id()?;
+        log::trace!("Relaying block {:?}", id);
+        self.send_compact_block(&block, None, quic).await?;
+        let _ = self.mempool.remove_block(&block, Instant::now());
+        Ok(id)
+    }
+
+    /// Send a block as a compact block to the peers not known to have it, other than its sender.
+    async fn send_compact_block(
+        &mut self,
+        block: &Block,
+        from: Option<&SocketAddr>,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        let now = Instant::now();
+        let prefill = block
+            .transactions
+            .iter()
+            .enumerate()
+            .filter(|(_, transaction)| !self.mempool.contains(&Hash::from_bytes(transaction)))
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+        let payload = block.to_bytes()?;
+        let compact = self
+            .compact_relay
+            .relay(block.clone(), rand::random(), &prefill, now)?;
+        let item = (
+            ObjectKind::Block,
+            self.inventory.insert(ObjectKind::Block, payload, now),
+        );
+        let mut outgoing = Vec::new();
+        for peer in self.connection.active_connections() {
+            if Some(peer) != from && !self.inventory.peer_knows(peer, &item, now) {
+                self.inventory.mark_known(peer, &item, now);
+                outgoing.push((*peer, Message::CompactBlock(compact.clone())));
+            }
+        }
+        gossip::send(outgoing, quic).await
+    }
+
+    /// Emit a block rebuilt from a compact block, and relay it further if it is valid.
+    async fn complete_block(
+        &mut self,
+        from: &SocketAddr,
+        block: Block,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        let id = block.id()?;
+        let payload = block.to_bytes()?;
+        if !self.is_valid(ObjectKind::Block, &payload) {
+            log::warn!("Peer {:?} sent an invalid block {:?}", from, id);
+            return self
+                .connection
+                .penalise(from, Misbehaviour::InvalidObject, quic);
+        }
+        self.send_compact_block(&block, Some(from), quic).await?;
+        let _ = self.mempool.remove_block(&block, Instant::now());
+        self.channel_tx.send(Event::NewObject {
+            kind: ObjectKind::Block,
+            id,
+            payload,
+        })?;
+        Ok(())
+    }
+
+    /// Complete a block rebuilt from a compact block, request the transactions it
+    /// misses, or penalise the peer which sent it if it is invalid.
+    async fn handle_reconstruction(
+        &mut self,
+        from: &SocketAddr,
+        reconstruction: Result<Option<Reconstruction>>,
+        quic: &mut QuicConnection,
+    ) -> Result<()> {
+        match reconstruction {
+            Ok(Some(Reconstruction::Complete(block))) => {
+                self.complete_block(from, block, quic).await
+            }
+            Ok(Some(Reconstruction::Incomplete(outgoing))) => gossip::send(outgoing, quic).await,
+            Ok(None) => Ok(()),
+            Err(Error::InvalidBlock(id)) => {
+                log::warn!("Peer {:?} sent an invalid block {:?}", from, id);
+                self.connection
+                    .penalise(from, Misbehaviour::InvalidObject, quic)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Rebuild the blocks whose missing transactions did not arrive in time from the
+    /// compact block of the next peer which announced them. Should be called periodically.
+    pub async fn check_block_requests(&mut self, quic: &mut QuicConnection) -> Result<()> {
+        let attempts = self
+            .compact_relay
+            .check_requests(self.mempool.iter(), Instant::now());
+        for (peer, reconstruction) in attempts {
+            self.handle_reconstruction(&peer, reconstruction.map(Some), quic)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Request again the objects whose request timed out, from other peers which
+    /// announced them, and penalise the peers which left the requests unanswered.
+    /// Should be called periodically.
+    pub async fn check_inventory_requests(&mut self, quic: &mut QuicConnection) -> Result<()> {
+        let now = Instant::now();
+        let retries = self
+            .inventory
+            .retain_peers(&self.connection.active_connections(), now);
+        gossip::send(retries, quic).await?;
+        let check = self.inventory.check_requests(now);
+        for peer in check.timed_out {
+            self.connection
+                .penalise(&peer, Misbehaviour::Timeout, quic)?;
+        }
+        gossip::send(check.outgoing, quic).await
+    }
+
+    /// Request the broadcasts announced by lazy peers which did not arrive in time
+    /// from the next peer which announced them. With Plumtree, this repairs the
+    /// broadcast tree. Should be called periodically.
+    pub async fn graft_missing_broadcasts(&mut self, quic: &mut QuicConnection) -> Result<()> {
+        let outgoing = match self.plumtree.as_mut() {
+            Some(tree) => tree.graft_missing(Instant::now()),
+            None => self.gossip.check_requests(Instant::now()),
+        };
+        gossip::send(outgoing, quic).await
+    }
+
     /// Handle an incoming node event
     pub async fn handle_incoming_event(&mut self) -> Result<()> {
         if let Ok(event) = self.channel_rx.recv() {
             match event {
                 Event::ConnectedTo(peer) => {
-                    log::trace!("Connected to peer: {:?}", &peer);
+                    log::trace!("Connected to peer: {:?}", peer);
                     // let deploy_agent = self.connection.handle_successful_connection(self_id, peer, sender, quic).await?;
                     // if deploy_agent {
                     //     self.messaging.send_agent_message(payload, active_connections, first, quic).await?;
@@ -137,57 +689,451 @@ impl Node {
         }
     }
 
-    async fn handle_incoming_message(
+    /// Connection information of the sender of a DHT message, as verified when it
+    /// identified itself. Messages from unidentified peers are ignored, and peers
+    /// claiming another identity or address are penalised.
+    fn dht_sender(
+        &mut self,
+        peer_addr: &SocketAddr,
+        claimed: &ConnectionInfo,
+        quic: &mut QuicConnection,
+    ) -> Result<Option<ConnectionInfo>> {
+        match self.connection.identified_info(peer_addr) {
+            Some(info) if info == *claimed => Ok(Some(info)),
+            Some(_) => {
+                self.connection
+                    .penalise(peer_addr, Misbehaviour::RoutingLie, quic)?;
+                Ok(None)
+            }
+            None => {
+                log::debug!(
+                    "Ignoring DHT message from unidentified peer {:?}",
+                    peer_addr
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// Handle a message received from a peer.
+    /// Should be called with every message read from the peer connections.
+    pub async fn handle_incoming_message(
         &mut self,
         peer: &mut QuicEndpoint,
         msg: &Bytes,
         quic: &mut QuicConnection,
     ) -> Result<()> {
-        match bincode::deserialize::<Message>(msg)? {
+        self.connection.observe_activity(&peer.local_addr());
+        let message = match bincode::deserialize::<Message>(msg) {
+            Ok(message) => message,
+            Err(err) => {
+                log::warn!(
+                    "Peer {:?} sent an undecodable message: {}",
+                    peer.local_addr(),
+                    err
+                );
+                return self.connection.penalise(
+                    &peer.local_addr(),
+                    Misbehaviour::UndecodableMessage,
+                    quic,
+                );
+            }
+        };
+        match message {
             Message::Identification(public_id) => {
-                let deploy_agent = self
+                let identified = self
                     .connection
                     .handle_peer_identification(
-                        &self.identity.public_id(),
+                        &self.identity,
                         peer,
                         &public_id,
                         &self.channel_tx,
                         quic,
                     )
                     .await?;
-                if deploy_agent {
+                if identified.deploy_agent {
+                    let agent = Agent::new(Walk::new(
+                        self.config.agent_ttl(),
+                        &self.identity.public_id().node_id,
+                    ));
                     self.messaging
-                        .send_agent_message(
-                            Vec::new(),
-                            &self.connection.active_connections(),
-                            false,
-                            quic,
-                        )
+                        .send_agent_message(agent, &self.connection.active_peers(), false, quic)
                         .await?;
                 }
+                if !identified.established {
+                    return Ok(());
+                }
+                let first_contact = self.dht.table().is_empty();
+                let info = ConnectionInfo {
+                    public_id,
+                    socket_addr: peer.local_addr(),
+                };
+                // Only addresses we dialled are known to accept connections; those
+                // of inbound peers are not vouched for until we connect to them.
+                if identified.outbound {
+                    self.address_book.mark_good(info, None, unix_timestamp());
+                } else {
+                    let _ = self
+                        .address_book
+                        .add(info, &info.socket_addr, unix_timestamp());
+                }
+                self.dht.observe(info, quic).await?;
+                if first_contact {
+                    let self_info = self.connection_info(peer);
+                    self.dht.self_lookup(&self_info, quic).await?;
+                }
                 Ok(())
             }
-            Message::AgentMessage { payload } => {
+            Message::FindNode { target, sender } => {
+                let self_info = self.connection_info(peer);
+                match self.dht_sender(&peer.local_addr(), &sender, quic)? {
+                    Some(sender) => {
+                        self.dht
+                            .handle_find_node(&self_info, sender, target, quic)
+                            .await
+                    }
+                    None => Ok(()),
+                }
+            }
+            Message::Nodes {
+                target,
+                sender,
+                nodes,
+            } => {
+                let self_info = self.connection_info(peer);
+                if nodes.iter().any(|info| !info.public_id.has_valid_node_id()) {
+                    log::warn!(
+                        "Peer at {:?} sent nodes whose ID is not derived from their key",
+                        peer.local_addr()
+                    );
+                    return self.connection.penalise(
+                        &peer.local_addr(),
+                        Misbehaviour::RoutingLie,
+                        quic,
+                    );
+                }
+                match self.dht_sender(&peer.local_addr(), &sender, quic)? {
+                    Some(sender) => {
+                        self.dht
+                            .handle_nodes(&self_info, sender, target, nodes, quic)
+                            .await
+                    }
+                    None => Ok(()),
+                }
+            }
+            Message::Ping { nonce } => {
+                self.connection
+                    .handle_ping(&peer.local_addr(), nonce, quic)
+                    .await
+            }
+            Message::Pong { nonce } => {
+                self.dht.handle_pong(nonce);
+                let peer_addr = peer.local_addr();
+                if let Some(rtt) = self.connection.handle_pong(&peer_addr, nonce) {
+                    if let Some(id) = self.connection.peer_id(&peer_addr) {
+                        self.address_book.observe_rtt(&id.node_id, rtt);
+                    }
+                }
+                Ok(())
+            }
+            Message::AgentMessage(agent) => {
                 log::trace!("Got a message from an agent");
                 self.messaging
                     .handle_agent_message(
                         &self.identity,
                         peer,
-                        payload,
-                        &self.connection.active_connections(),
+                        agent,
+                        &self.connection.active_peers(),
                         quic,
                         &self.channel_tx,
                     )
                     .await?;
                 Ok(())
             }
+            Message::RoutingTable {
+                shared_routing_table,
+                source,
+                signature,
+            } => {
+                self.connection
+                    .update_routing_table(
+                        &self.identity,
+                        &peer.local_addr(),
+                        &source,
+                        shared_routing_table,
+                        &signature,
+                        quic,
+                    )
+                    .await
+            }
+            Message::RoutingDelta {
+                delta,
+                source,
+                signature,
+            } => {
+                self.connection
+                    .handle_routing_delta(
+                        &self.identity,
+                        &peer.local_addr(),
+                        &source,
+                        delta,
+                        &signature,
+                        quic,
+                    )
+                    .await
+            }
+            Message::RoutingSnapshotRequest { source } => {
+                log::trace!("Peer {:?} requested our routing table", source);
+                self.connection
+                    .handle_routing_snapshot_request(&self.identity, &peer.local_addr(), quic)
+                    .await
+            }
+            Message::PeerRecords(records) => {
+                let source = peer.local_addr();
+                let now = unix_timestamp();
+                for info in self
+                    .connection
+                    .handle_peer_records(&source, records, quic)?
+                {
+                    let _ = self.address_book.add(info, &source, now);
+                }
+                Ok(())
+            }
+            Message::ObservedAddress(observed) => {
+                self.connection
+                    .handle_observed_address(&peer.local_addr(), observed);
+                Ok(())
+            }
+            Message::ConnectRequest {
+                source,
+                target,
+                addrs,
+            } => {
+                let self_id = self.identity.public_id().node_id;
+                let peer_addr = peer.local_addr();
+                for info in self
+                    .connection
+                    .handle_connect_request(&self_id, &peer_addr, source, target, addrs, quic)
+                    .await?
+                {
+                    if let Err(err) = self.connection.connect_to(&info, peer).await {
+                        log::debug!("Failed to punch through to {:?}: {}", info, err);
+                    }
+                }
+                Ok(())
+            }
+            Message::RelayConnect { circuit, target } => {
+                self.connection
+                    .handle_relay_connect(&peer.local_addr(), circuit, target, quic)
+                    .await
+            }
+            Message::RelayOpened { circuit, peer: id } => {
+                self.connection
+                    .handle_relay_opened(&self.identity, &peer.local_addr(), circuit, id, quic)
+                    .await
+            }
+            Message::RelayData { circuit, payload } => {
+                self.connection
+                    .handle_relay_data(
+                        &self.identity,
+                        &peer.local_addr(),
+                        circuit,
+                        payload,
+                        &self.channel_tx,
+                        quic,
+                    )
+                    .await
+            }
+            Message::RelayClosed { circuit, reason } => {
+                self.connection
+                    .handle_relay_closed(
+                        &peer.local_addr(),
+                        circuit,
+                        reason,
+                        &self.channel_tx,
+                        quic,
+                    )
+                    .await
+            }
+            Message::Broadcast(broadcast) => {
+                let peer_addr = peer.local_addr();
+                let peers = self.connection.active_connections();
+                let (new, outgoing) = match self.plumtree.as_mut() {
+                    Some(tree) => {
+                        tree.handle_broadcast(&peer_addr, broadcast, &peers, Instant::now())
+                    }
+                    None => self.gossip.handle_broadcast(
+                        &peer_addr,
+                        broadcast,
+                        &peers,
+                        Instant::now(),
+                        &mut rand::thread_rng(),
+                    ),
+                };
+                if let Some(broadcast) = new {
+                    self.channel_tx.send(Event::NewBroadcast {
+                        id: broadcast.id,
+                        hops: broadcast.hops,
+                        payload: broadcast.payload,
+                    })?;
+                }
+                gossip::send(outgoing, quic).await
+            }
+            Message::IHave(ids) => {
+                let outgoing = match self.plumtree.as_mut() {
+                    Some(tree) => {
+                        tree.handle_ihave(&peer.local_addr(), ids, Instant::now());
+                        Vec::new()
+                    }
+                    None => self
+                        .gossip
+                        .handle_ihave(&peer.local_addr(), ids, Instant::now()),
+                };
+                gossip::send(outgoing, quic).await
+            }
+            Message::IWant(ids) => {
+                let outgoing = self
+                    .gossip
+                    .handle_iwant(&peer.local_addr(), ids, Instant::now());
+                gossip::send(outgoing, quic).await
+            }
+            Message::Prune => {
+                if let Some(tree) = self.plumtree.as_mut() {
+                    tree.handle_prune(&peer.local_addr());
+                }
+                Ok(())
+            }
+            Message::Graft(ids) => match self.plumtree.as_mut() {
+                Some(tree) => {
+                    let outgoing = tree.handle_graft(&peer.local_addr(), ids, Instant::now());
+                    gossip::send(outgoing, quic).await
+                }
+                None => Ok(()),
+            },
+            Message::Inv(items) => {
+                let outgoing = self
+                    .inventory
+                    .handle_inv(&peer.local_addr(), items, Instant::now());
+                gossip::send(outgoing, quic).await
+            }
+            Message::GetData(items) => {
+                let outgoing =
+                    self.inventory
+                        .handle_getdata(&peer.local_addr(), items, Instant::now());
+                gossip::send(outgoing, quic).await
+            }
+            Message::Data { kind, payload } if !self.is_valid(kind, &payload) => {
+                let addr = peer.local_addr();
+                log::warn!("Peer {:?} sent an invalid {:?}", addr, kind);
+                let outgoing = self.inventory.reject(&addr, kind, &payload, Instant::now());
+                gossip::send(outgoing, quic).await?;
+                self.connection
+                    .penalise(&addr, Misbehaviour::InvalidObject, quic)
+            }
+            Message::Data { kind, payload } => {
+                let (object, outgoing) = self.inventory.handle_data(
+                    &peer.local_addr(),
+                    kind,
+                    payload,
+                    &self.connection.active_connections(),
+                    Instant::now(),
+                );
+                let (id, payload) = match object {
+                    Some(object) => object,
+                    None => return Ok(()),
+                };
+                // Transactions are relayed only if they enter the mempool,
+                // and those of a block leave it.
+                match kind {
+                    ObjectKind::Transaction => {
+                        if let Admission::Known | Admission::Rejected = self.admit(payload.clone())
+                        {
+                            return Ok(());
+                        }
+                    }
+                    ObjectKind::Block => match Block::from_bytes(&payload) {
+                        Ok(block) => {
+                            let _ = self.mempool.remove_block(&block, Instant::now());
+                        }
+                        Err(err) => log::debug!("Could not decode block {:?}: {}", id, err),
+                    },
+                }
+                self.channel_tx
+                    .send(Event::NewObject { kind, id, payload })?;
+                gossip::send(outgoing, quic).await
+            }
+            Message::CompactBlock(compact) => {
+                let (addr, now) = (peer.local_addr(), Instant::now());
+                self.inventory
+                    .mark_known(&addr, &(ObjectKind::Block, compact.block_id), now);
+                let reconstruction =
+                    self.compact_relay
+                        .handle_compact(&addr, compact, self.mempool.iter(), now);
+                self.handle_reconstruction(&addr, reconstruction, quic)
+                    .await
+            }
+            Message::GetBlockTxn { block_id, indexes } => {
+                let outgoing = self.compact_relay.handle_get_block_txn(
+                    &peer.local_addr(),
+                    block_id,
+                    indexes,
+                    Instant::now(),
+                );
+                gossip::send(outgoing, quic).await
+            }
+            Message::BlockTxn {
+                block_id,
+                indexes,
+                transactions,
+            } => {
+                let addr = peer.local_addr();
+                let reconstruction = self.compact_relay.handle_block_txn(
+                    &addr,
+                    block_id,
+                    indexes,
+                    transactions,
+                    Instant::now(),
+                );
+                self.handle_reconstruction(&addr, reconstruction, quic)
+                    .await
+            }
+            Message::Chunk(chunk) => {
+                let addr = peer.local_addr();
+                let handled = self.erasure_relay.handle_chunk(
+                    &addr,
+                    chunk,
+                    &self.connection.active_connections(),
+                    Instant::now(),
+                );
+                match handled {
+                    Ok((payload, outgoing)) => {
+                        if let Some((root, payload)) = payload {
+                            self.channel_tx
+                                .send(Event::NewChunkedBroadcast { root, payload })?;
+                        }
+                        gossip::send(outgoing, quic).await
+                    }
+                    Err(Error::InvalidChunk(root)) => {
+                        log::warn!("Peer {:?} sent an invalid chunk of {:?}", addr, root);
+                        self.connection
+                            .penalise(&addr, Misbehaviour::InvalidObject, quic)
+                    }
+                    Err(error) => Err(error),
+                }
+            }
+            Message::Goodbye { reason } => {
+                log::debug!("Peer {:?} is leaving: {}", peer.local_addr(), reason);
+                self.connection
+                    .handle_goodbye(&peer.local_addr(), &reason, &self.channel_tx)
+            }
             Message::Contacts(contacts) => {
                 self.connection.bootstrap(&contacts, peer).await?;
                 Ok(())
             }
             message => {
-                log::error!("Peer {:?} sent us: {:?}", peer.local_addr(), &message);
-                Ok(())
+                log::error!("Peer {:?} sent us: {:?}", peer.local_addr(), message);
+                self.connection
+                    .penalise(&peer.local_addr(), Misbehaviour::Spam, quic)
             }
         }
     }
@@ -0,0 +1,15 @@
+use crate::Result;
+use serde::Serialize;
+use std::{fs::File, io::Write, path::Path};
+
+/// Atomically write a value to a file: it is written to a temporary file next to it,
+/// flushed to disk, then renamed over the target, so a crash leaves either the old
+/// or the new content.
+pub(crate) fn save<T: Serialize>(value: &T, path: &Path) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&bincode::serialize(value)?)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
@@ -0,0 +1,110 @@
+use crate::{
+    connection::connection_types::ConnectionInfo,
+    crypto::{
+        hash::Hash, signature::PrivateKey, EncryptionPublicKey, SigningPublicKey, SigningSecretKey,
+    },
+    gossip::Outgoing,
+    Message, PublicId,
+};
+use rand::{rngs::StdRng, thread_rng, Rng};
+use std::{collections::VecDeque, net::SocketAddr};
+
+/// In-process network of `size` nodes, each linked to `degree` random others
+pub(crate) struct Topology {
+    /// Address of each node
+    pub(crate) addrs: Vec<SocketAddr>,
+    /// Addresses of the peers of each node
+    pub(crate) links: Vec<Vec<SocketAddr>>,
+}
+
+impl Topology {
+    pub(crate) fn new(size: usize, degree: usize, rng: &mut StdRng) -> Self {
+        let addrs = (0..size)
+            .map(|node| SocketAddr::from(([10, 0, (node / 256) as u8, node as u8], 9000)))
+            .collect::<Vec<_>>();
+        let mut links = vec![Vec::new(); size];
+        for node in 0..size {
+            while links[node].len() < degree {
+                let other = rng.gen_range(0..size);
+                if other != node && !links[node].contains(&addrs[other]) {
+                    links[node].push(addrs[other]);
+                    links[other].push(addrs[node]);
+                }
+            }
+        }
+        Self { addrs, links }
+    }
+
+    /// Index of the node at an address
+    pub(crate) fn index(&self, addr: &SocketAddr) -> usize {
+        self.addrs
+            .iter()
+            .position(|other| other == addr)
+            .unwrap_or(0)
+    }
+
+    /// Peers of a node
+    pub(crate) fn peers(&self, node: usize) -> Vec<&SocketAddr> {
+        self.links[node].iter().collect()
+    }
+}
+
+/// Nodes on a random topology, passing messages to each other in process
+pub(crate) struct Network<N> {
+    pub(crate) topology: Topology,
+    pub(crate) nodes: Vec<N>,
+}
+
+impl<N> Network<N> {
+    pub(crate) fn new(
+        size: usize,
+        degree: usize,
+        rng: &mut StdRng,
+        node: impl FnMut(usize) -> N,
+    ) -> Self {
+        Self {
+            topology: Topology::new(size, degree, rng),
+            nodes: (0..size).map(node).collect(),
+        }
+    }
+
+    /// Deliver messages sent by a node, and those sent in response, until the network is
+    /// quiet. `handle` is given the receiving node and its index, the sender, the message
+    /// and the peers of the receiving node, and returns the messages it sends.
+    pub(crate) fn run<F>(&mut self, from: usize, outgoing: Outgoing, mut handle: F)
+    where
+        F: FnMut(&mut N, usize, &SocketAddr, Message, &[&SocketAddr]) -> Outgoing,
+    {
+        let mut queue = outgoing
+            .into_iter()
+            .map(|(to, message)| (self.topology.addrs[from], to, message))
+            .collect::<VecDeque<_>>();
+        while let Some((from, to, message)) = queue.pop_front() {
+            let node = self.topology.index(&to);
+            let peers = self.topology.peers(node);
+            let outgoing = handle(&mut self.nodes[node], node, &from, message, &peers);
+            queue.extend(
+                outgoing
+                    .into_iter()
+                    .map(|(next, message)| (to, next, message)),
+            );
+        }
+    }
+}
+
+/// Connection information of a peer with random keys, on `port` of the loopback address
+pub(crate) fn connection_info(node_id: Hash, port: u16) -> ConnectionInfo {
+    let secret = thread_rng().gen::<[u8; 32]>();
+    let signing_secret_key =
+        SigningSecretKey::from_bytes(&secret).expect("Failed to create a signing key");
+    let signing_public_key = SigningPublicKey::from(&signing_secret_key);
+    ConnectionInfo {
+        public_id: PublicId {
+            node_id,
+            public_key: PrivateKey::random().public_key(),
+            encryption_public_key: EncryptionPublicKey::from(secret),
+            signing_public_key,
+        },
+        socket_addr: SocketAddr::from(([127, 0, 0, 1], port)),
+    }
+}